@@ -0,0 +1,315 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Peer capabilities and their negotiation
+
+use crate::VideoResolution;
+use serde::{Deserialize, Serialize};
+
+/// Video codecs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+    Mjpeg,
+    Raw,
+}
+
+/// Clipboard formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ClipboardFormat {
+    Text,
+    Html,
+    Image,
+    Files,
+}
+
+/// Input device classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum InputDeviceClass {
+    Keyboard,
+    Mouse,
+    Touch,
+    Pen,
+    Gamepad,
+}
+
+/// Capability kinds, used to gate message types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum CapabilityKind {
+    Video,
+    Input,
+    Clipboard,
+    Metrics,
+    Discovery,
+}
+
+/// Capability with its parameters
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Capability {
+    /// Video streaming; codecs are listed in order of preference
+    Video {
+        codecs: Vec<VideoCodec>,
+        max_resolution: VideoResolution,
+        max_fps: u32,
+    },
+    /// Input forwarding
+    Input { devices: Vec<InputDeviceClass> },
+    /// Clipboard sharing
+    Clipboard { formats: Vec<ClipboardFormat>, max_size: u64 },
+    /// Metrics exchange
+    Metrics,
+    /// Service announcement and query
+    Discovery,
+}
+
+impl Capability {
+    /// Get the kind of this capability
+    pub fn kind(&self) -> CapabilityKind {
+        match self {
+            Capability::Video { .. } => CapabilityKind::Video,
+            Capability::Input { .. } => CapabilityKind::Input,
+            Capability::Clipboard { .. } => CapabilityKind::Clipboard,
+            Capability::Metrics => CapabilityKind::Metrics,
+            Capability::Discovery => CapabilityKind::Discovery,
+        }
+    }
+
+    /// Intersect with the peer's capability of the same kind.
+    ///
+    /// Lists keep `self`'s order of preference, limits take the lower value.
+    /// Returns `None` when the kinds differ or nothing usable remains.
+    pub fn intersect(&self, other: &Capability) -> Option<Capability> {
+        let capability = match (self, other) {
+            (
+                Capability::Video { codecs, max_resolution, max_fps },
+                Capability::Video { codecs: other_codecs, max_resolution: other_resolution, max_fps: other_fps },
+            ) => Capability::Video {
+                codecs: common(codecs, other_codecs),
+                max_resolution: VideoResolution {
+                    width: max_resolution.width.min(other_resolution.width),
+                    height: max_resolution.height.min(other_resolution.height),
+                },
+                max_fps: (*max_fps).min(*other_fps),
+            },
+            (Capability::Input { devices }, Capability::Input { devices: other_devices }) => Capability::Input {
+                devices: common(devices, other_devices),
+            },
+            (
+                Capability::Clipboard { formats, max_size },
+                Capability::Clipboard { formats: other_formats, max_size: other_size },
+            ) => Capability::Clipboard {
+                formats: common(formats, other_formats),
+                max_size: (*max_size).min(*other_size),
+            },
+            (Capability::Metrics, Capability::Metrics) => Capability::Metrics,
+            (Capability::Discovery, Capability::Discovery) => Capability::Discovery,
+            _ => return None,
+        };
+
+        if capability.is_usable() {
+            Some(capability)
+        } else {
+            None
+        }
+    }
+
+    /// Check that the parameters leave something to use
+    fn is_usable(&self) -> bool {
+        match self {
+            Capability::Video { codecs, max_resolution, max_fps } => {
+                !codecs.is_empty() && max_resolution.width > 0 && max_resolution.height > 0 && *max_fps > 0
+            }
+            Capability::Input { devices } => !devices.is_empty(),
+            Capability::Clipboard { formats, max_size } => !formats.is_empty() && *max_size > 0,
+            Capability::Metrics | Capability::Discovery => true,
+        }
+    }
+}
+
+/// Items of `preferred` that are also in `other`, in `preferred` order
+fn common<T: PartialEq + Copy>(preferred: &[T], other: &[T]) -> Vec<T> {
+    let mut result = Vec::new();
+    for item in preferred {
+        if other.contains(item) && !result.contains(item) {
+            result.push(*item);
+        }
+    }
+    result
+}
+
+/// Set of capabilities with at most one entry per kind, ordered by kind
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<Capability>", into = "Vec<Capability>")]
+pub struct CapabilitySet {
+    capabilities: Vec<Capability>,
+}
+
+impl CapabilitySet {
+    /// Create a set; when a kind is repeated the first entry wins
+    pub fn new(capabilities: impl IntoIterator<Item = Capability>) -> Self {
+        let mut set: Vec<Capability> = Vec::new();
+        for capability in capabilities {
+            if !set.iter().any(|existing| existing.kind() == capability.kind()) {
+                set.push(capability);
+            }
+        }
+        set.sort_by_key(Capability::kind);
+        CapabilitySet { capabilities: set }
+    }
+
+    /// Everything this implementation can do
+    pub fn full() -> Self {
+        CapabilitySet::new(vec![
+            Capability::Video {
+                codecs: vec![VideoCodec::H264, VideoCodec::H265, VideoCodec::Vp9, VideoCodec::Mjpeg, VideoCodec::Raw],
+                max_resolution: VideoResolution { width: 3840, height: 2160 },
+                max_fps: 60,
+            },
+            Capability::Input {
+                devices: vec![InputDeviceClass::Keyboard, InputDeviceClass::Mouse],
+            },
+            Capability::Clipboard {
+                formats: vec![ClipboardFormat::Text, ClipboardFormat::Html, ClipboardFormat::Image],
+                max_size: 16 * 1024 * 1024,
+            },
+            Capability::Metrics,
+            Capability::Discovery,
+        ])
+    }
+
+    /// Negotiate the authoritative set on the server.
+    ///
+    /// Only kinds offered by both sides survive; parameters are intersected
+    /// with the server's order of preference. The result does not depend on
+    /// the order in which either side listed its capabilities.
+    pub fn negotiate(server: &CapabilitySet, client: &CapabilitySet) -> CapabilitySet {
+        CapabilitySet::new(
+            server.capabilities.iter()
+                .filter_map(|ours| client.get(ours.kind()).and_then(|theirs| ours.intersect(theirs))),
+        )
+    }
+
+    /// Get the capability of a kind
+    pub fn get(&self, kind: CapabilityKind) -> Option<&Capability> {
+        self.capabilities.iter().find(|capability| capability.kind() == kind)
+    }
+
+    /// Check if a capability kind is present
+    pub fn supports(&self, kind: CapabilityKind) -> bool {
+        self.get(kind).is_some()
+    }
+
+    /// Iterate over the capabilities
+    pub fn iter(&self) -> impl Iterator<Item = &Capability> {
+        self.capabilities.iter()
+    }
+
+    /// Kinds present in the set
+    pub fn kinds(&self) -> Vec<CapabilityKind> {
+        self.capabilities.iter().map(Capability::kind).collect()
+    }
+
+    /// Number of capabilities
+    pub fn len(&self) -> usize {
+        self.capabilities.len()
+    }
+
+    /// Check if the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.capabilities.is_empty()
+    }
+}
+
+impl From<Vec<Capability>> for CapabilitySet {
+    fn from(capabilities: Vec<Capability>) -> Self {
+        CapabilitySet::new(capabilities)
+    }
+}
+
+impl From<CapabilitySet> for Vec<Capability> {
+    fn from(set: CapabilitySet) -> Self {
+        set.capabilities
+    }
+}
+
+impl FromIterator<Capability> for CapabilitySet {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        CapabilitySet::new(iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(codecs: Vec<VideoCodec>, width: u32, height: u32, fps: u32) -> Capability {
+        Capability::Video {
+            codecs,
+            max_resolution: VideoResolution { width, height },
+            max_fps: fps,
+        }
+    }
+
+    #[test]
+    fn test_video_intersection_uses_server_preference() {
+        let server = video(vec![VideoCodec::H265, VideoCodec::H264, VideoCodec::Mjpeg], 3840, 2160, 60);
+        let client = video(vec![VideoCodec::Mjpeg, VideoCodec::H264], 1920, 1200, 144);
+
+        assert_eq!(
+            server.intersect(&client),
+            Some(video(vec![VideoCodec::H264, VideoCodec::Mjpeg], 1920, 1200, 60))
+        );
+    }
+
+    #[test]
+    fn test_negotiation_drops_unshared_kinds() {
+        let server = CapabilitySet::full();
+        let client = CapabilitySet::new(vec![
+            Capability::Input { devices: vec![InputDeviceClass::Touch, InputDeviceClass::Keyboard] },
+            Capability::Clipboard { formats: vec![ClipboardFormat::Files], max_size: 1024 },
+            Capability::Metrics,
+        ]);
+
+        let negotiated = CapabilitySet::negotiate(&server, &client);
+
+        assert_eq!(negotiated.kinds(), vec![CapabilityKind::Input, CapabilityKind::Metrics]);
+        assert_eq!(
+            negotiated.get(CapabilityKind::Input),
+            Some(&Capability::Input { devices: vec![InputDeviceClass::Keyboard] })
+        );
+        assert!(!negotiated.supports(CapabilityKind::Clipboard));
+    }
+
+    #[test]
+    fn test_negotiation_is_order_independent() {
+        let client_a = CapabilitySet::new(vec![Capability::Metrics, video(vec![VideoCodec::H264], 1280, 720, 30)]);
+        let client_b = CapabilitySet::new(vec![video(vec![VideoCodec::H264], 1280, 720, 30), Capability::Metrics]);
+
+        let server = CapabilitySet::full();
+        assert_eq!(client_a, client_b);
+        assert_eq!(CapabilitySet::negotiate(&server, &client_a), CapabilitySet::negotiate(&server, &client_b));
+    }
+
+    #[test]
+    fn test_capability_set_serde_round_trip() {
+        let set = CapabilitySet::full();
+        let json = serde_json::to_string(&set).unwrap();
+        assert!(json.starts_with('['));
+        assert_eq!(serde_json::from_str::<CapabilitySet>(&json).unwrap(), set);
+    }
+}
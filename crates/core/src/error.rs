@@ -14,6 +14,7 @@
 
 //! Error types for Soft KVM
 
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 /// Result type alias for Soft KVM operations
@@ -55,6 +56,200 @@ pub enum KvmError {
     #[error("Timeout error")]
     Timeout,
 
+    #[error("Peer reported {code}: {message}")]
+    Remote { code: ErrorCode, message: String },
+
     #[error("Generic error: {0}")]
     GenericError(String),
 }
+
+impl KvmError {
+    /// Error code reported to a peer for this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvmError::Io(_) => ErrorCode::InternalError,
+            KvmError::Serialization(_) => ErrorCode::InvalidMessage,
+            KvmError::Network(_) => ErrorCode::ServiceUnavailable,
+            KvmError::Security(_) => ErrorCode::AuthFailed,
+            KvmError::Timeout => ErrorCode::Timeout,
+            KvmError::Remote { code, .. } => *code,
+            KvmError::Video(_)
+            | KvmError::Input(_)
+            | KvmError::Service(_)
+            | KvmError::Discovery(_)
+            | KvmError::Platform(_)
+            | KvmError::Config(_)
+            | KvmError::GenericError(_) => ErrorCode::InternalError,
+        }
+    }
+
+    /// Error for a code received from a peer
+    pub fn from_code(code: ErrorCode, message: String) -> Self {
+        match code {
+            ErrorCode::AuthFailed => KvmError::Security(message),
+            ErrorCode::Timeout => KvmError::Timeout,
+            code => KvmError::Remote { code, message },
+        }
+    }
+}
+
+/// Error codes carried in Error and Goodbye messages.
+///
+/// Codes below 4000 follow the WebSocket close codes (RFC 6455); codes from
+/// 4000 are specific to Soft KVM. Codes this version doesn't know are kept
+/// as `Unknown` so they can still be shown and forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    /// 1000: the session ended normally
+    NormalClosure,
+    /// 1001: the peer is shutting down
+    GoingAway,
+    /// 1002: the peer broke the protocol
+    ProtocolViolation,
+    /// 1003: a message type that wasn't negotiated
+    UnsupportedData,
+    /// 1007: a message that couldn't be decoded
+    InvalidMessage,
+    /// 1008: refused by policy
+    PolicyViolation,
+    /// 1009: a message over the size limit
+    MessageTooLarge,
+    /// 1011: the peer failed to handle a request
+    InternalError,
+    /// 1013: temporarily unable to serve, try again later
+    ServiceUnavailable,
+    /// 4000: no common protocol version
+    VersionMismatch,
+    /// 4001: credentials were rejected
+    AuthFailed,
+    /// 4002: the server has no room for another session
+    ServerFull,
+    /// 4003: another controller took over the session
+    Kicked,
+    /// 4004: the session to resume is unknown or expired
+    SessionNotFound,
+    /// 4005: the peer stopped responding
+    Timeout,
+    /// A code this version doesn't know
+    Unknown(u32),
+}
+
+impl ErrorCode {
+    /// Numeric code on the wire
+    pub fn code(&self) -> u32 {
+        match self {
+            ErrorCode::NormalClosure => 1000,
+            ErrorCode::GoingAway => 1001,
+            ErrorCode::ProtocolViolation => 1002,
+            ErrorCode::UnsupportedData => 1003,
+            ErrorCode::InvalidMessage => 1007,
+            ErrorCode::PolicyViolation => 1008,
+            ErrorCode::MessageTooLarge => 1009,
+            ErrorCode::InternalError => 1011,
+            ErrorCode::ServiceUnavailable => 1013,
+            ErrorCode::VersionMismatch => 4000,
+            ErrorCode::AuthFailed => 4001,
+            ErrorCode::ServerFull => 4002,
+            ErrorCode::Kicked => 4003,
+            ErrorCode::SessionNotFound => 4004,
+            ErrorCode::Timeout => 4005,
+            ErrorCode::Unknown(code) => *code,
+        }
+    }
+
+    /// Short reason suitable for showing to the user
+    pub fn description(&self) -> &'static str {
+        match self {
+            ErrorCode::NormalClosure => "closed",
+            ErrorCode::GoingAway => "peer shutting down",
+            ErrorCode::ProtocolViolation => "protocol error",
+            ErrorCode::UnsupportedData => "unsupported message",
+            ErrorCode::InvalidMessage => "invalid message",
+            ErrorCode::PolicyViolation => "refused by policy",
+            ErrorCode::MessageTooLarge => "message too large",
+            ErrorCode::InternalError => "internal error",
+            ErrorCode::ServiceUnavailable => "service unavailable",
+            ErrorCode::VersionMismatch => "version mismatch",
+            ErrorCode::AuthFailed => "auth failed",
+            ErrorCode::ServerFull => "server full",
+            ErrorCode::Kicked => "kicked by another controller",
+            ErrorCode::SessionNotFound => "session not found",
+            ErrorCode::Timeout => "timed out",
+            ErrorCode::Unknown(_) => "unknown error",
+        }
+    }
+
+    /// Check if trying again later may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::GoingAway | ErrorCode::ServiceUnavailable | ErrorCode::ServerFull | ErrorCode::Timeout
+        )
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            1000 => ErrorCode::NormalClosure,
+            1001 => ErrorCode::GoingAway,
+            1002 => ErrorCode::ProtocolViolation,
+            1003 => ErrorCode::UnsupportedData,
+            1007 => ErrorCode::InvalidMessage,
+            1008 => ErrorCode::PolicyViolation,
+            1009 => ErrorCode::MessageTooLarge,
+            1011 => ErrorCode::InternalError,
+            1013 => ErrorCode::ServiceUnavailable,
+            4000 => ErrorCode::VersionMismatch,
+            4001 => ErrorCode::AuthFailed,
+            4002 => ErrorCode::ServerFull,
+            4003 => ErrorCode::Kicked,
+            4004 => ErrorCode::SessionNotFound,
+            4005 => ErrorCode::Timeout,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.description(), self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code_round_trip() {
+        for code in (1000..1020).chain(4000..4010) {
+            assert_eq!(ErrorCode::from(code).code(), code);
+        }
+        assert_eq!(ErrorCode::from(4002), ErrorCode::ServerFull);
+        assert_eq!(ErrorCode::from(4999), ErrorCode::Unknown(4999));
+
+        // Numbers on the wire
+        assert_eq!(serde_json::to_string(&ErrorCode::Kicked).unwrap(), "4003");
+        assert_eq!(serde_json::from_str::<ErrorCode>("1000").unwrap(), ErrorCode::NormalClosure);
+        assert_eq!(ErrorCode::VersionMismatch.to_string(), "version mismatch (4000)");
+    }
+
+    #[test]
+    fn test_kvm_error_codes() {
+        assert_eq!(KvmError::Security("bad token".to_string()).code(), ErrorCode::AuthFailed);
+        assert!(matches!(KvmError::from_code(ErrorCode::AuthFailed, "bad token".to_string()), KvmError::Security(_)));
+
+        let error = KvmError::from_code(ErrorCode::ServerFull, "8 of 8 sessions in use".to_string());
+        assert_eq!(error.code(), ErrorCode::ServerFull);
+        assert!(error.code().is_retryable());
+        assert!(!ErrorCode::Kicked.is_retryable());
+    }
+}
@@ -16,10 +16,12 @@
 //!
 //! Core types and utilities for Soft KVM
 
+pub mod capability;
 pub mod error;
 pub mod types;
 pub mod utils;
 
+pub use capability::*;
 pub use error::*;
 pub use types::*;
 pub use utils::*;
@@ -102,6 +102,10 @@ pub struct Metrics {
     pub active_connections: u32,
     pub video_latency_p99: f64,
     pub input_latency_p99: f64,
+    #[serde(default)]
+    pub network_rtt_ms: Option<f64>,
+    #[serde(default)]
+    pub clock_offset_ms: Option<f64>, // how far the peer's clock is ahead
 }
 
 /// Input configuration
@@ -139,7 +143,7 @@ pub enum MouseEvent {
 }
 
 /// Mouse button types
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum MouseButton {
     Left,
     Right,
@@ -0,0 +1,428 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unicast and broadcast discovery for networks that filter multicast
+//!
+//! Speaks the same DNS-SD messages as [`crate::mdns`], but on its own UDP
+//! port (`BeaconConfig::port`) and without the multicast group. Every
+//! `interval` seconds the browser sends a PTR query to each static peer and,
+//! with `broadcast` on, to the broadcast address; the responder answers
+//! queries by unicast to the sender. With `broadcast` on, registered
+//! services are also broadcast every `interval` seconds (a beacon) and a
+//! goodbye is broadcast when they are unregistered.
+//!
+//! Static peers are `host`, `host:port` or an IPv4 range such as
+//! `10.20.0.0/24`; without a port they are probed on `BeaconConfig::port`.
+//! Services learnt from a static peer are marked `ServiceSource::Static`,
+//! anything else `ServiceSource::Broadcast`.
+
+use crate::dns::{Message, Question, TYPE_PTR};
+use crate::mdns::{answer_records, service_records, service_type_name, Browse};
+use crate::{ServiceInfo, ServiceMap, ServiceSource};
+use soft_kvm_core::*;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task;
+use tracing::{debug, trace, warn};
+
+/// Default beacon port
+pub const BEACON_PORT: u16 = 9353;
+
+/// Shortest prefix accepted for a static range, so a typo can't make us
+/// probe millions of addresses
+const MIN_RANGE_PREFIX: u8 = 16;
+
+/// Largest beacon packet
+const MAX_PACKET_SIZE: usize = 9000;
+
+/// Unicast and broadcast discovery settings
+#[derive(Debug, Clone)]
+pub struct BeaconConfig {
+    /// UDP port to listen on and to probe peers on
+    pub port: u16,
+    /// Probe and announce with broadcasts
+    pub broadcast: bool,
+    /// Destination of broadcasts, e.g. a subnet's directed broadcast address
+    pub broadcast_address: Ipv4Addr,
+    /// Peers probed by unicast
+    pub peers: Vec<StaticPeer>,
+    /// Seconds between probes, and between beacons
+    pub interval: u64,
+    /// TTL of answered and broadcast records, in seconds; longer than
+    /// `interval` so services don't expire between probes
+    pub ttl: u32,
+}
+
+impl Default for BeaconConfig {
+    fn default() -> Self {
+        BeaconConfig {
+            port: BEACON_PORT,
+            broadcast: true,
+            broadcast_address: Ipv4Addr::BROADCAST,
+            peers: Vec::new(),
+            interval: 30,
+            ttl: 120,
+        }
+    }
+}
+
+/// Peer probed by unicast
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StaticPeer {
+    /// Host name or address, resolved at every probe
+    Host { host: String, port: Option<u16> },
+    /// Every host address in an IPv4 network
+    Range { network: Ipv4Addr, prefix: u8 },
+}
+
+impl StaticPeer {
+    /// Addresses to probe
+    async fn targets(&self, default_port: u16) -> Vec<SocketAddr> {
+        match self {
+            StaticPeer::Host { host, port } => {
+                let port = port.unwrap_or(default_port);
+                match tokio::net::lookup_host((host.as_str(), port)).await {
+                    Ok(addresses) => addresses.collect(),
+                    Err(e) => {
+                        debug!("Failed to resolve static peer {}: {}", host, e);
+                        Vec::new()
+                    }
+                }
+            }
+            StaticPeer::Range { network, prefix } => {
+                let first = u32::from(*network);
+                let size = 1u64 << (32 - *prefix as u32);
+                // Leave out the network and broadcast addresses where there are any
+                let hosts = if size > 2 { 1..size - 1 } else { 0..size };
+                hosts.map(|offset| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(first + offset as u32), default_port)))
+                    .collect()
+            }
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self, ip) {
+            (StaticPeer::Range { network, prefix }, IpAddr::V4(ip)) => {
+                let mask = u32::MAX << (32 - *prefix as u32);
+                u32::from(ip) & mask == u32::from(*network)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for StaticPeer {
+    type Err = KvmError;
+
+    fn from_str(peer: &str) -> KvmResult<Self> {
+        let invalid = || KvmError::Discovery(format!("Invalid static peer: {}", peer));
+
+        if let Some((network, prefix)) = peer.split_once('/') {
+            let network: Ipv4Addr = network.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            if !(MIN_RANGE_PREFIX..=32).contains(&prefix) {
+                return Err(KvmError::Discovery(format!(
+                    "Static range {} is too large; use a prefix of at least /{}", peer, MIN_RANGE_PREFIX
+                )));
+            }
+            let mask = u32::MAX << (32 - prefix as u32);
+            return Ok(StaticPeer::Range { network: Ipv4Addr::from(u32::from(network) & mask), prefix });
+        }
+        if let Ok(address) = peer.parse::<SocketAddr>() {
+            return Ok(StaticPeer::Host { host: address.ip().to_string(), port: Some(address.port()) });
+        }
+        if let Ok(ip) = peer.parse::<IpAddr>() {
+            return Ok(StaticPeer::Host { host: ip.to_string(), port: None });
+        }
+        match peer.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !host.contains(':') => {
+                Ok(StaticPeer::Host { host: host.to_string(), port: Some(port.parse().map_err(|_| invalid())?) })
+            }
+            None if !peer.is_empty() => Ok(StaticPeer::Host { host: peer.to_string(), port: None }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Unicast and broadcast responder and browser sharing one socket
+pub(crate) struct Beacon {
+    socket: UdpSocket,
+    config: BeaconConfig,
+    /// Services published by this host
+    local: Mutex<HashMap<ServiceId, ServiceInfo>>,
+    browse: Mutex<Option<Browse>>,
+    /// Addresses the static host peers resolved to at the last probe
+    static_hosts: Mutex<HashSet<IpAddr>>,
+    tasks: Mutex<Vec<task::JoinHandle<()>>>,
+}
+
+impl Beacon {
+    /// Bind the beacon port and start answering probes
+    pub(crate) fn start(config: BeaconConfig) -> KvmResult<Arc<Beacon>> {
+        let socket = bind(&config)?;
+        let beacon = Arc::new(Beacon {
+            socket,
+            config,
+            local: Mutex::new(HashMap::new()),
+            browse: Mutex::new(None),
+            static_hosts: Mutex::new(HashSet::new()),
+            tasks: Mutex::new(Vec::new()),
+        });
+
+        // Both run until `shutdown`
+        let receiver = Arc::clone(&beacon);
+        let mut tasks = vec![task::spawn(async move {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            loop {
+                match receiver.socket.recv_from(&mut buf).await {
+                    Ok((len, source)) => receiver.handle_packet(&buf[..len], source).await,
+                    Err(e) => warn!("Beacon receive failed: {}", e),
+                }
+            }
+        })];
+        if beacon.config.broadcast {
+            let announcer = Arc::downgrade(&beacon);
+            let interval = Duration::from_secs(beacon.config.interval.max(1));
+            tasks.push(task::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    let Some(beacon) = announcer.upgrade() else {
+                        break;
+                    };
+                    let local: Vec<ServiceInfo> = beacon.local.lock().unwrap().values().cloned().collect();
+                    for info in local {
+                        if let Err(e) = beacon.announce(&info, beacon.config.ttl).await {
+                            warn!("Failed to broadcast {}: {}", info.name, e);
+                        }
+                    }
+                }
+            }));
+        }
+        *beacon.tasks.lock().unwrap() = tasks;
+        Ok(beacon)
+    }
+
+    /// Stop receiving and announcing
+    pub(crate) fn shutdown(&self) {
+        for handle in self.tasks.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+    }
+
+    /// Publish a service, broadcasting it at once if broadcasts are on
+    pub(crate) async fn register(&self, info: ServiceInfo) -> KvmResult<()> {
+        self.local.lock().unwrap().insert(info.id.clone(), info.clone());
+        if self.config.broadcast {
+            self.announce(&info, self.config.ttl).await?;
+        }
+        Ok(())
+    }
+
+    /// Withdraw a service, broadcasting a goodbye if broadcasts are on
+    pub(crate) async fn unregister(&self, id: &ServiceId) -> KvmResult<()> {
+        let removed = self.local.lock().unwrap().remove(id);
+        if let (Some(info), true) = (removed, self.config.broadcast) {
+            self.announce(&info, 0).await?;
+        }
+        Ok(())
+    }
+
+    async fn announce(&self, info: &ServiceInfo, ttl: u32) -> KvmResult<()> {
+        debug!("Broadcasting {} with TTL {}", info.name, ttl);
+        let (ptr, records) = service_records(info, ttl);
+        let mut answers = vec![ptr];
+        answers.extend(records);
+        self.send(&Message::response(answers, Vec::new()), self.broadcast_target()).await
+    }
+
+    /// Probe for a service type, collecting results in `services`.
+    ///
+    /// Probes until the returned task is aborted.
+    pub(crate) fn browse(self: &Arc<Self>, service_type: &ServiceType, services: Arc<ServiceMap>) -> task::JoinHandle<()> {
+        *self.browse.lock().unwrap() = Some(Browse::new(service_type.clone(), services));
+        let service_type = service_type_name(service_type);
+
+        let beacon = Arc::downgrade(self);
+        let interval = Duration::from_secs(self.config.interval.max(1));
+        task::spawn(async move {
+            loop {
+                let Some(beacon) = beacon.upgrade() else {
+                    break;
+                };
+                let questions = match beacon.browse.lock().unwrap().as_ref() {
+                    Some(browse) => browse.questions(),
+                    None => vec![Question::new(service_type.clone(), TYPE_PTR)],
+                };
+                beacon.probe(&Message::query(questions)).await;
+                drop(beacon);
+
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    /// Stop collecting browse results
+    pub(crate) fn stop_browsing(&self) {
+        self.browse.lock().unwrap().take();
+    }
+
+    /// Send a query to every static peer, and broadcast it
+    async fn probe(&self, query: &Message) {
+        let mut static_hosts = HashSet::new();
+        let mut targets = Vec::new();
+        for peer in &self.config.peers {
+            let peer_targets = peer.targets(self.config.port).await;
+            if matches!(peer, StaticPeer::Host { .. }) {
+                static_hosts.extend(peer_targets.iter().map(SocketAddr::ip));
+            }
+            targets.extend(peer_targets);
+        }
+        *self.static_hosts.lock().unwrap() = static_hosts;
+        if self.config.broadcast {
+            targets.push(self.broadcast_target());
+        }
+
+        trace!("Probing {} addresses", targets.len());
+        for target in targets {
+            if let Err(e) = self.send(query, target).await {
+                trace!("Probe to {} failed: {}", target, e);
+            }
+        }
+    }
+
+    fn broadcast_target(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(self.config.broadcast_address, self.config.port))
+    }
+
+    async fn send(&self, message: &Message, target: SocketAddr) -> KvmResult<()> {
+        let packet = message.encode()?;
+        self.socket.send_to(&packet, target).await?;
+        Ok(())
+    }
+
+    async fn handle_packet(&self, data: &[u8], source: SocketAddr) {
+        let message = match Message::decode(data) {
+            Ok(message) => message,
+            Err(e) => {
+                trace!("Ignoring malformed beacon packet from {}: {}", source, e);
+                return;
+            }
+        };
+
+        if message.response {
+            self.handle_response(&message, source.ip()).await;
+        } else if !message.questions.is_empty() {
+            if let Err(e) = self.answer(&message, source).await {
+                warn!("Failed to answer probe from {}: {}", source, e);
+            }
+        }
+    }
+
+    /// Answer a probe by unicast
+    async fn answer(&self, query: &Message, source: SocketAddr) -> KvmResult<()> {
+        let (answers, additionals) = {
+            let local = self.local.lock().unwrap();
+            answer_records(local.values(), &query.questions, self.config.ttl)
+        };
+        if answers.is_empty() {
+            return Ok(());
+        }
+        let mut response = Message::response(answers, additionals);
+        response.id = query.id;
+        self.send(&response, source).await
+    }
+
+    /// Update the browse cache from a response
+    async fn handle_response(&self, response: &Message, source: IpAddr) {
+        let (upserts, removals, services) = {
+            let mut browse = self.browse.lock().unwrap();
+            let Some(browse) = browse.as_mut() else {
+                return;
+            };
+            let (upserts, removals) = browse.update(response, source);
+            (upserts, removals, browse.services())
+        };
+
+        for id in removals {
+            if let Some(info) = services.withdraw(&id).await {
+                debug!("Service {} said goodbye", info.name);
+            }
+        }
+        let source = self.source_of(source);
+        for mut info in upserts {
+            info.source = source;
+            services.upsert(info).await;
+        }
+    }
+
+    fn source_of(&self, ip: IpAddr) -> ServiceSource {
+        if self.static_hosts.lock().unwrap().contains(&ip) || self.config.peers.iter().any(|peer| peer.contains(ip)) {
+            ServiceSource::Static
+        } else {
+            ServiceSource::Broadcast
+        }
+    }
+}
+
+/// Bind the beacon port, shared with other resolvers on the host
+fn bind(config: &BeaconConfig) -> KvmResult<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_broadcast(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.port).into())
+        .map_err(|e| KvmError::Discovery(format!("Failed to bind beacon port {}: {}", config.port, e)))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_static_peers() {
+        let parse = |peer: &str| peer.parse::<StaticPeer>();
+        assert_eq!(parse("kvm-lab.corp").unwrap(), StaticPeer::Host { host: "kvm-lab.corp".to_string(), port: None });
+        assert_eq!(parse("kvm-lab.corp:9400").unwrap(), StaticPeer::Host { host: "kvm-lab.corp".to_string(), port: Some(9400) });
+        assert_eq!(parse("[fe80::1]:9400").unwrap(), StaticPeer::Host { host: "fe80::1".to_string(), port: Some(9400) });
+        assert_eq!(parse("fe80::1").unwrap(), StaticPeer::Host { host: "fe80::1".to_string(), port: None });
+        assert_eq!(parse("10.20.0.77/24").unwrap(), StaticPeer::Range { network: Ipv4Addr::new(10, 20, 0, 0), prefix: 24 });
+        for invalid in ["", "host:", "host:port", "10.0.0.0/8", "10.0.0.0/33", "10.0.0/24"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_targets_skip_network_and_broadcast() {
+        let range: StaticPeer = "192.168.7.0/30".parse().unwrap();
+        let targets = range.targets(BEACON_PORT).await;
+        assert_eq!(targets, vec![
+            "192.168.7.1:9353".parse::<SocketAddr>().unwrap(),
+            "192.168.7.2:9353".parse().unwrap(),
+        ]);
+        assert!(range.contains("192.168.7.3".parse().unwrap()));
+        assert!(!range.contains("192.168.7.4".parse().unwrap()));
+
+        let single: StaticPeer = "192.168.7.9/32".parse().unwrap();
+        assert_eq!(single.targets(BEACON_PORT).await.len(), 1);
+    }
+}
@@ -0,0 +1,476 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DNS message codec for multicast DNS
+//!
+//! Covers what DNS-SD needs (RFC 1035, RFC 6762 section 18): questions and
+//! A, AAAA, PTR, SRV and TXT records. Other record types are skipped on
+//! decode. Names are decoded with compression but always encoded in full,
+//! which RFC 6762 allows.
+
+use soft_kvm_core::{KvmError, KvmResult};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Top bit of a record's class: the record replaces cached ones (RFC 6762 10.2)
+const CACHE_FLUSH: u16 = 0x8000;
+/// Top bit of a question's class: a unicast reply is acceptable (RFC 6762 5.4)
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// QR and AA, set on every mDNS response
+const RESPONSE_FLAGS: u16 = 0x8400;
+const MAX_LABEL_LEN: usize = 63;
+/// Compression pointers followed before a name is rejected as a loop
+const MAX_POINTERS: usize = 32;
+
+/// Domain name as a list of labels. Labels may contain dots, as DNS-SD
+/// instance names often do.
+#[derive(Debug, Clone, Default)]
+pub struct Name(Vec<String>);
+
+impl Name {
+    /// Parse a dotted name; a trailing dot is optional
+    pub fn new(name: &str) -> Self {
+        Name(name.split('.').filter(|label| !label.is_empty()).map(str::to_string).collect())
+    }
+
+    /// Name of `label` under `parent`, e.g. a service instance under its type
+    pub fn child(label: &str, parent: &Name) -> Self {
+        let mut labels = Vec::with_capacity(parent.0.len() + 1);
+        labels.push(truncate_label(label).to_string());
+        labels.extend(parent.0.iter().cloned());
+        Name(labels)
+    }
+
+    /// First label, e.g. the instance part of a service instance name
+    pub fn first_label(&self) -> Option<&str> {
+        self.0.first().map(String::as_str)
+    }
+
+    /// Name without its first label
+    pub fn parent(&self) -> Name {
+        Name(self.0.iter().skip(1).cloned().collect())
+    }
+
+    /// Case-insensitive key for maps
+    pub fn key(&self) -> String {
+        self.0.iter().map(|label| label.to_ascii_lowercase()).collect::<Vec<_>>().join("\u{0}")
+    }
+}
+
+impl PartialEq for Name {
+    /// Names compare case-insensitively
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for Name {}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for label in &self.0 {
+            write!(f, "{}.", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cut a label to 63 bytes without splitting a character
+fn truncate_label(label: &str) -> &str {
+    let mut end = label.len().min(MAX_LABEL_LEN);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    &label[..end]
+}
+
+/// Question section entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: Name,
+    pub qtype: u16,
+    pub unicast_response: bool,
+}
+
+impl Question {
+    pub fn new(name: Name, qtype: u16) -> Self {
+        Question { name, qtype, unicast_response: false }
+    }
+
+    /// Check if a record answers the question
+    pub fn is_answered_by(&self, record: &Record) -> bool {
+        (self.qtype == TYPE_ANY || self.qtype == record.rtype()) && self.name == record.name
+    }
+}
+
+/// Record data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(Name),
+    Srv { priority: u16, weight: u16, port: u16, target: Name },
+    /// `key=value` strings
+    Txt(Vec<String>),
+}
+
+/// Resource record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: Name,
+    pub ttl: u32,
+    pub cache_flush: bool,
+    pub data: RData,
+}
+
+impl Record {
+    /// Record that other hosts don't also publish (RFC 6762 10.2)
+    pub fn unique(name: Name, ttl: u32, data: RData) -> Self {
+        Record { name, ttl, cache_flush: true, data }
+    }
+
+    /// Record several hosts may publish under one name, such as a PTR
+    pub fn shared(name: Name, ttl: u32, data: RData) -> Self {
+        Record { name, ttl, cache_flush: false, data }
+    }
+
+    /// Record type
+    pub fn rtype(&self) -> u16 {
+        match self.data {
+            RData::A(_) => TYPE_A,
+            RData::Aaaa(_) => TYPE_AAAA,
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Txt(_) => TYPE_TXT,
+        }
+    }
+}
+
+/// DNS message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    /// Authority and additional records
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    /// Multicast query
+    pub fn query(questions: Vec<Question>) -> Self {
+        Message { questions, ..Message::default() }
+    }
+
+    /// Multicast response
+    pub fn response(answers: Vec<Record>, additionals: Vec<Record>) -> Self {
+        Message { response: true, answers, additionals, ..Message::default() }
+    }
+
+    /// Every record in the message
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.answers.iter().chain(&self.additionals)
+    }
+
+    /// Encode to wire format
+    pub fn encode(&self) -> KvmResult<Vec<u8>> {
+        let mut out = Vec::with_capacity(512);
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&(if self.response { RESPONSE_FLAGS } else { 0 }).to_be_bytes());
+        for count in [self.questions.len(), self.answers.len(), 0, self.additionals.len()] {
+            let count = u16::try_from(count).map_err(|_| KvmError::Discovery("Too many DNS records".to_string()))?;
+            out.extend_from_slice(&count.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            encode_name(&mut out, &question.name)?;
+            out.extend_from_slice(&question.qtype.to_be_bytes());
+            let class = if question.unicast_response { CLASS_IN | UNICAST_RESPONSE } else { CLASS_IN };
+            out.extend_from_slice(&class.to_be_bytes());
+        }
+        for record in self.records() {
+            encode_record(&mut out, record)?;
+        }
+        Ok(out)
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> KvmResult<Message> {
+        let mut reader = Reader { data, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let answers = reader.u16()?;
+        let authorities = reader.u16()?;
+        let additionals = reader.u16()?;
+
+        let mut message = Message {
+            id,
+            response: flags & 0x8000 != 0,
+            ..Message::default()
+        };
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let class = reader.u16()?;
+            message.questions.push(Question {
+                name,
+                qtype,
+                unicast_response: class & UNICAST_RESPONSE != 0,
+            });
+        }
+        for _ in 0..answers {
+            if let Some(record) = reader.record()? {
+                message.answers.push(record);
+            }
+        }
+        for _ in 0..(authorities as usize + additionals as usize) {
+            if let Some(record) = reader.record()? {
+                message.additionals.push(record);
+            }
+        }
+        Ok(message)
+    }
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &Name) -> KvmResult<()> {
+    for label in &name.0 {
+        let label = truncate_label(label);
+        if label.is_empty() {
+            return Err(KvmError::Discovery(format!("Empty label in DNS name {}", name)));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+fn encode_record(out: &mut Vec<u8>, record: &Record) -> KvmResult<()> {
+    encode_name(out, &record.name)?;
+    out.extend_from_slice(&record.rtype().to_be_bytes());
+    let class = if record.cache_flush { CLASS_IN | CACHE_FLUSH } else { CLASS_IN };
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&record.ttl.to_be_bytes());
+
+    let mut data = Vec::new();
+    match &record.data {
+        RData::A(ip) => data.extend_from_slice(&ip.octets()),
+        RData::Aaaa(ip) => data.extend_from_slice(&ip.octets()),
+        RData::Ptr(name) => encode_name(&mut data, name)?,
+        RData::Srv { priority, weight, port, target } => {
+            data.extend_from_slice(&priority.to_be_bytes());
+            data.extend_from_slice(&weight.to_be_bytes());
+            data.extend_from_slice(&port.to_be_bytes());
+            encode_name(&mut data, target)?;
+        }
+        RData::Txt(entries) => {
+            for entry in entries {
+                let len = u8::try_from(entry.len())
+                    .map_err(|_| KvmError::Discovery(format!("TXT entry longer than 255 bytes: {}", entry)))?;
+                data.push(len);
+                data.extend_from_slice(entry.as_bytes());
+            }
+            // A TXT record always has at least one string (RFC 6763 6.1)
+            if entries.is_empty() {
+                data.push(0);
+            }
+        }
+    }
+    let len = u16::try_from(data.len()).map_err(|_| KvmError::Discovery("DNS record too long".to_string()))?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(&data);
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> KvmResult<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)
+            .ok_or_else(|| KvmError::Discovery("Truncated DNS message".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> KvmResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> KvmResult<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> KvmResult<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a possibly compressed name
+    fn name(&mut self) -> KvmResult<Name> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        // Where reading continues once the name is done
+        let mut resume = None;
+        let mut pointers = 0;
+
+        loop {
+            let len = *self.data.get(pos)
+                .ok_or_else(|| KvmError::Discovery("Truncated DNS name".to_string()))? as usize;
+            match len {
+                0 => {
+                    pos += 1;
+                    break;
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let low = *self.data.get(pos + 1)
+                        .ok_or_else(|| KvmError::Discovery("Truncated DNS name".to_string()))? as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(KvmError::Discovery("DNS name compression loop".to_string()));
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = ((len & 0x3F) << 8) | low;
+                }
+                len if len <= MAX_LABEL_LEN => {
+                    let label = self.data.get(pos + 1..pos + 1 + len)
+                        .ok_or_else(|| KvmError::Discovery("Truncated DNS label".to_string()))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                _ => return Err(KvmError::Discovery(format!("Invalid DNS label length {}", len))),
+            }
+        }
+
+        self.pos = resume.unwrap_or(pos);
+        Ok(Name(labels))
+    }
+
+    /// Read a record; `None` for types DNS-SD doesn't use
+    fn record(&mut self) -> KvmResult<Option<Record>> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        if end > self.data.len() {
+            return Err(KvmError::Discovery("Truncated DNS record".to_string()));
+        }
+
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                let bytes = self.bytes(4)?;
+                Some(RData::A(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])))
+            }
+            TYPE_AAAA if len == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(self.bytes(16)?);
+                Some(RData::Aaaa(Ipv6Addr::from(octets)))
+            }
+            TYPE_PTR => Some(RData::Ptr(self.name()?)),
+            TYPE_SRV => Some(RData::Srv {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            }),
+            TYPE_TXT => {
+                let mut entries = Vec::new();
+                while self.pos < end {
+                    let len = self.u8()? as usize;
+                    let entry = self.bytes(len)?;
+                    if !entry.is_empty() {
+                        entries.push(String::from_utf8_lossy(entry).into_owned());
+                    }
+                }
+                Some(RData::Txt(entries))
+            }
+            _ => None,
+        };
+        // Skip what wasn't read, e.g. unknown record types
+        self.pos = end;
+
+        Ok(data.map(|data| Record {
+            name,
+            ttl,
+            cache_flush: class & CACHE_FLUSH != 0,
+            data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let service = Name::new("_soft-kvm-server._tcp.local.");
+        let instance = Name::child("Desk 2.0 (left)", &service);
+        let host = Name::new("soft-kvm-1234.local");
+        let message = Message::response(
+            vec![Record::shared(service.clone(), 4500, RData::Ptr(instance.clone()))],
+            vec![
+                Record::unique(instance.clone(), 120, RData::Srv { priority: 0, weight: 0, port: 9000, target: host.clone() }),
+                Record::unique(instance.clone(), 120, RData::Txt(vec!["id=abc".to_string()])),
+                Record::unique(host.clone(), 120, RData::A(Ipv4Addr::new(192, 168, 1, 20))),
+                Record::unique(host, 120, RData::Aaaa("fe80::1".parse().unwrap())),
+            ],
+        );
+
+        let decoded = Message::decode(&message.encode().unwrap()).unwrap();
+        assert_eq!(decoded, message);
+        // The dot in the instance label survives
+        assert_eq!(decoded.additionals[0].name.first_label(), Some("Desk 2.0 (left)"));
+        assert_eq!(decoded.additionals[0].name.parent(), service);
+        assert_eq!(Name::new("_SOFT-KVM-server._tcp.local"), service);
+    }
+
+    #[test]
+    fn test_decode_compressed_names_and_skip_unknown_records() {
+        #[rustfmt::skip]
+        let packet = [
+            0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0,
+            // PTR _kvm._tcp.local -> box._kvm._tcp.local, written with a pointer
+            4, b'_', b'k', b'v', b'm', 4, b'_', b't', b'c', b'p', 5, b'l', b'o', b'c', b'a', b'l', 0,
+            0, 12, 0, 1, 0, 0, 0x11, 0x94, 0, 6,
+            3, b'b', b'o', b'x', 0xC0, 12,
+            // NSEC record pointing at the name above, to be skipped
+            0xC0, 39, 0, 47, 0x80, 1, 0, 0, 0, 120, 0, 2, 0xAB, 0xCD,
+        ];
+        let message = Message::decode(&packet).unwrap();
+        assert!(message.response);
+        assert_eq!(message.answers.len(), 1);
+        assert_eq!(message.answers[0].data, RData::Ptr(Name::new("box._kvm._tcp.local")));
+
+        // A pointer to itself is rejected rather than followed forever
+        let looping = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xC0, 12, 0, 12, 0, 1];
+        assert!(Message::decode(&looping).is_err());
+        assert!(Message::decode(&packet[..40]).is_err());
+    }
+}
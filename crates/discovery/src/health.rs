@@ -0,0 +1,146 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Health probing of discovered services
+//!
+//! Every `interval` seconds each listed service gets a TCP connection to its
+//! announced address, closed as soon as it is established. The time to
+//! connect is the RTT. Listeners recognize a connection closed before
+//! sending anything and don't count it as a failed handshake. After
+//! `failure_threshold` failed probes in a row the service is marked
+//! unreachable, long before its TTL runs out; one successful probe marks
+//! it reachable again. Subscribers get a `ServiceEvent::Updated` whenever
+//! reachability changes.
+
+use crate::ServiceMap;
+use serde::{Deserialize, Serialize};
+use soft_kvm_core::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::task;
+use tracing::trace;
+
+/// Health prober settings
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Seconds between probes of a service
+    pub interval: u64,
+    /// Seconds to wait for a connection
+    pub timeout: u64,
+    /// Failed probes in a row before a service counts as unreachable
+    pub failure_threshold: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            interval: 10,
+            timeout: 2,
+            failure_threshold: 2,
+        }
+    }
+}
+
+/// Outcome of the probes of a service
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceHealth {
+    /// `None` until the first probe settles it
+    pub reachable: Option<bool>,
+    /// Round-trip time of the last successful probe
+    pub rtt_ms: Option<f64>,
+    /// Error of the last failed probe
+    pub last_error: Option<String>,
+    pub last_probe: Option<chrono::DateTime<chrono::Utc>>,
+    /// Failed probes since the last successful one
+    pub failures: u32,
+}
+
+impl ServiceHealth {
+    /// Record a probe; returns whether reachability changed
+    pub(crate) fn record(&mut self, result: Result<Duration, String>, failure_threshold: u32) -> bool {
+        let was_reachable = self.reachable;
+        self.last_probe = Some(chrono::Utc::now());
+        match result {
+            Ok(rtt) => {
+                self.rtt_ms = Some(rtt.as_secs_f64() * 1000.0);
+                self.failures = 0;
+                self.reachable = Some(true);
+            }
+            Err(e) => {
+                self.last_error = Some(e);
+                self.failures += 1;
+                if self.failures >= failure_threshold.max(1) {
+                    self.reachable = Some(false);
+                }
+            }
+        }
+        self.reachable != was_reachable
+    }
+}
+
+/// Connect to `address` and measure how long it took
+async fn probe(address: &NetworkAddress, timeout: Duration) -> Result<Duration, String> {
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, TcpStream::connect((address.ip.as_str(), address.port))).await {
+        Ok(Ok(_)) => Ok(started.elapsed()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("No connection within {} ms", timeout.as_millis())),
+    }
+}
+
+/// Probe every listed service until the returned task is aborted
+pub(crate) fn spawn(services: Arc<ServiceMap>, config: HealthConfig) -> task::JoinHandle<()> {
+    task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        let timeout = Duration::from_secs(config.timeout.max(1));
+        loop {
+            interval.tick().await;
+
+            let mut probes = task::JoinSet::new();
+            for info in services.list().await {
+                probes.spawn(async move { (info.id, probe(&info.address, timeout).await) });
+            }
+            while let Some(done) = probes.join_next().await {
+                let Ok((id, result)) = done else {
+                    continue;
+                };
+                trace!("Probe of {:?}: {:?}", id, result);
+                services.record_probe(&id, result, config.failure_threshold).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unreachable_after_threshold() {
+        let mut health = ServiceHealth::default();
+        assert!(health.record(Ok(Duration::from_millis(3)), 2));
+        assert_eq!((health.reachable, health.rtt_ms), (Some(true), Some(3.0)));
+
+        // One failure isn't enough
+        assert!(!health.record(Err("Connection refused".to_string()), 2));
+        assert_eq!(health.reachable, Some(true));
+        assert!(health.record(Err("Connection refused".to_string()), 2));
+        assert_eq!((health.reachable, health.failures), (Some(false), 2));
+        assert_eq!(health.last_error.as_deref(), Some("Connection refused"));
+
+        assert!(health.record(Ok(Duration::from_millis(1)), 2));
+        assert_eq!((health.reachable, health.failures), (Some(true), 0));
+    }
+}
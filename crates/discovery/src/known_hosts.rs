@@ -0,0 +1,260 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Peers remembered across restarts
+//!
+//! Every service a resolver discovers is remembered with its last address
+//! and certificate fingerprint, and `ServiceResolver::mark_connected` adds
+//! the time of the last connection. The file is JSON in the platform data
+//! directory, rewritten whenever an entry changes. Only the
+//! `MAX_KNOWN_HOSTS` most recently used entries are kept.
+//!
+//! A fingerprint is pinned the first time it is seen. A service that later
+//! announces a different one, or none, isn't merged with the remembered
+//! entry; the entry has to be forgotten first. Connections check the pin
+//! through the transport's `TlsConfig::pinned_fingerprint`, see
+//! `ServiceResolver::pinned_fingerprint`.
+
+use crate::{ServiceInfo, ServiceSource};
+use crate::health::ServiceHealth;
+use crate::metadata::ServiceMetadata;
+use serde::{Deserialize, Serialize};
+use soft_kvm_core::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Most entries kept
+const MAX_KNOWN_HOSTS: usize = 64;
+
+/// File name in the data directory
+const KNOWN_HOSTS_FILE: &str = "known_hosts.json";
+
+/// A remembered peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownHost {
+    pub id: ServiceId,
+    pub name: String,
+    pub service_type: ServiceType,
+    pub address: NetworkAddress,
+    /// Pinned SHA-256 of the peer's TLS certificate
+    pub cert_fingerprint: Option<String>,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+    pub last_connected: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl KnownHost {
+    /// Listing for the remembered host until discovery sees it again
+    pub(crate) fn to_service_info(&self, ttl: u32) -> ServiceInfo {
+        ServiceInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            service_type: self.service_type.clone(),
+            address: self.address.clone(),
+            // Listed for `ttl` seconds from now, so discovery has time to confirm it
+            last_seen: chrono::Utc::now(),
+            ttl,
+            metadata: ServiceMetadata {
+                cert_fingerprint: self.cert_fingerprint.clone(),
+                ..ServiceMetadata::default()
+            },
+            source: ServiceSource::Remembered,
+            health: ServiceHealth::default(),
+        }
+    }
+
+    fn last_used(&self) -> chrono::DateTime<chrono::Utc> {
+        self.last_connected.map_or(self.last_seen, |connected| connected.max(self.last_seen))
+    }
+}
+
+/// Known hosts file
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: HashMap<ServiceId, KnownHost>,
+}
+
+impl KnownHosts {
+    /// `known_hosts.json` in the platform data directory
+    pub fn default_path() -> KvmResult<PathBuf> {
+        directories::ProjectDirs::from("com", "soft-kvm", "soft-kvm")
+            .map(|dirs| dirs.data_dir().join(KNOWN_HOSTS_FILE))
+            .ok_or_else(|| KvmError::Discovery("No data directory for known hosts".to_string()))
+    }
+
+    /// Load the file at `path`; a missing file is an empty list
+    pub fn load(path: impl Into<PathBuf>) -> KvmResult<Self> {
+        let path = path.into();
+        let hosts: Vec<KnownHost> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| KvmError::Discovery(format!("Corrupt known hosts file {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        debug!("Loaded {} known hosts from {}", hosts.len(), path.display());
+        Ok(KnownHosts {
+            path,
+            hosts: hosts.into_iter().map(|host| (host.id.clone(), host)).collect(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, id: &ServiceId) -> Option<&KnownHost> {
+        self.hosts.get(id)
+    }
+
+    /// Remembered hosts, most recently used first
+    pub fn hosts(&self) -> Vec<KnownHost> {
+        let mut hosts: Vec<KnownHost> = self.hosts.values().cloned().collect();
+        hosts.sort_by_key(|host| std::cmp::Reverse(host.last_used()));
+        hosts
+    }
+
+    /// Whether `info` may be merged with what is remembered about it: true
+    /// unless a fingerprint is pinned and `info` doesn't announce that one
+    pub fn validate(&self, info: &ServiceInfo) -> bool {
+        match self.hosts.get(&info.id).and_then(|host| host.cert_fingerprint.as_ref()) {
+            Some(pinned) => info.metadata.cert_fingerprint.as_ref() == Some(pinned),
+            None => true,
+        }
+    }
+
+    /// Remember a discovered service; returns whether anything besides
+    /// `last_seen` changed. Call `validate` first.
+    pub fn remember(&mut self, info: &ServiceInfo) -> bool {
+        let host = self.hosts.entry(info.id.clone()).or_insert_with(|| KnownHost {
+            id: info.id.clone(),
+            name: String::new(),
+            service_type: info.service_type.clone(),
+            address: info.address.clone(),
+            cert_fingerprint: None,
+            last_seen: info.last_seen,
+            last_connected: None,
+        });
+        let changed = host.name != info.name
+            || host.address.ip != info.address.ip
+            || host.address.port != info.address.port
+            || (host.cert_fingerprint.is_none() && info.metadata.cert_fingerprint.is_some());
+        host.name = info.name.clone();
+        host.address = info.address.clone();
+        host.cert_fingerprint = host.cert_fingerprint.take().or_else(|| info.metadata.cert_fingerprint.clone());
+        host.last_seen = info.last_seen;
+        changed
+    }
+
+    /// Record a connection, pinning `cert_fingerprint` if none is yet
+    pub fn mark_connected(&mut self, id: &ServiceId, cert_fingerprint: Option<String>) -> KvmResult<()> {
+        let host = self.hosts.get_mut(id)
+            .ok_or_else(|| KvmError::Discovery(format!("Unknown host {}", id.0)))?;
+        host.last_connected = Some(chrono::Utc::now());
+        if host.cert_fingerprint.is_none() {
+            host.cert_fingerprint = cert_fingerprint;
+        }
+        Ok(())
+    }
+
+    /// Forget a host, e.g. after it legitimately changed its certificate
+    pub fn forget(&mut self, id: &ServiceId) -> Option<KnownHost> {
+        self.hosts.remove(id)
+    }
+
+    /// Write the file, dropping the least recently used entries beyond
+    /// `MAX_KNOWN_HOSTS`
+    pub fn save(&mut self) -> KvmResult<()> {
+        let hosts = self.hosts();
+        for stale in hosts.iter().skip(MAX_KNOWN_HOSTS) {
+            self.hosts.remove(&stale.id);
+        }
+        let hosts = &hosts[..hosts.len().min(MAX_KNOWN_HOSTS)];
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_vec_pretty(hosts)
+            .map_err(|e| KvmError::Discovery(format!("Failed to encode known hosts: {}", e)))?;
+        // Replace the file in one step so a crash can't leave half of it
+        let temporary = self.path.with_extension("json.tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, fingerprint: Option<&str>) -> ServiceInfo {
+        let mut info = KnownHost {
+            id: ServiceId(uuid::Uuid::new_v4()),
+            name: name.to_string(),
+            service_type: ServiceType::Server,
+            address: NetworkAddress { ip: "192.168.1.20".to_string(), port: 9000 },
+            cert_fingerprint: None,
+            last_seen: chrono::Utc::now(),
+            last_connected: None,
+        }.to_service_info(120);
+        info.source = ServiceSource::Mdns;
+        info.metadata.cert_fingerprint = fingerprint.map(str::to_string);
+        info
+    }
+
+    #[test]
+    fn test_known_hosts_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("soft-kvm-known-hosts-{}", uuid::Uuid::new_v4()));
+        let path = dir.join(KNOWN_HOSTS_FILE);
+
+        let desk = service("Desk", Some("aa"));
+        let lab = service("Lab", None);
+        let mut known = KnownHosts::load(&path).unwrap();
+        assert!(known.hosts().is_empty());
+        assert!(known.remember(&desk));
+        assert!(!known.remember(&desk));
+        assert!(known.remember(&lab));
+        known.mark_connected(&lab.id, Some("bb".to_string())).unwrap();
+        known.save().unwrap();
+
+        let known = KnownHosts::load(&path).unwrap();
+        let hosts = known.hosts();
+        assert_eq!(hosts.len(), 2);
+        // Most recently connected first, fingerprint pinned on connect
+        assert_eq!(hosts[0].id, lab.id);
+        assert!(hosts[0].last_connected.is_some());
+        assert_eq!(hosts[0].cert_fingerprint.as_deref(), Some("bb"));
+        assert_eq!(hosts[1].address.ip, "192.168.1.20");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pinned_fingerprint_must_match() {
+        let mut known = KnownHosts::load(std::env::temp_dir().join(format!("soft-kvm-{}.json", uuid::Uuid::new_v4()))).unwrap();
+        let mut desk = service("Desk", Some("aa"));
+        known.remember(&desk);
+
+        assert!(known.validate(&desk));
+        // Dropping the fingerprint doesn't get around the pin
+        desk.metadata.cert_fingerprint = None;
+        assert!(!known.validate(&desk));
+        desk.metadata.cert_fingerprint = Some("cc".to_string());
+        assert!(!known.validate(&desk));
+
+        known.forget(&desk.id);
+        assert!(known.validate(&desk));
+    }
+}
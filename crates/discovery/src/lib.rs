@@ -16,14 +16,52 @@
 //!
 //! Service discovery functionality for Soft KVM
 
+pub mod beacon;
+pub mod dns;
+pub mod health;
+pub mod known_hosts;
+pub mod mdns;
+pub mod metadata;
+
 use soft_kvm_core::*;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, RwLock};
 use tokio::task;
 use tracing::{debug, info, warn, error};
 use std::net::IpAddr;
 use serde::{Serialize, Deserialize};
+use crate::beacon::{Beacon, BeaconConfig};
+use crate::health::{HealthConfig, ServiceHealth};
+use crate::known_hosts::{KnownHost, KnownHosts};
+use crate::mdns::{Mdns, MdnsConfig};
+use crate::metadata::ServiceMetadata;
+
+/// Seconds a service stays listed after it was last seen, unless its
+/// announcement says otherwise
+pub const DEFAULT_SERVICE_TTL: u32 = 300;
+
+/// Default seconds between sweeps for expired services
+pub const DEFAULT_SWEEP_INTERVAL: u64 = 60;
+
+fn default_service_ttl() -> u32 {
+    DEFAULT_SERVICE_TTL
+}
+
+/// How a service was found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceSource {
+    /// Multicast DNS, or registered on this host
+    #[default]
+    Mdns,
+    /// Answered a unicast probe to a configured peer
+    Static,
+    /// Answered a broadcast probe, or broadcast a beacon
+    Broadcast,
+    /// Loaded from the known hosts file and not seen again yet
+    Remembered,
+}
 
 /// Service information
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +71,21 @@ pub struct ServiceInfo {
     pub service_type: ServiceType,
     pub address: NetworkAddress,
     pub last_seen: chrono::DateTime<chrono::Utc>,
+    /// Seconds after `last_seen` the service expires; discovered services
+    /// take it from their mDNS records, capped by the resolver's expiry window
+    #[serde(default = "default_service_ttl")]
+    pub ttl: u32,
+    /// What the service announces about itself in its TXT record
+    #[serde(default)]
+    pub metadata: ServiceMetadata,
+    /// Where the service was first found; another source announcing it
+    /// later refreshes it without changing this, except that a remembered
+    /// service takes the source that confirmed it
+    #[serde(default)]
+    pub source: ServiceSource,
+    /// Results of health probes, if the resolver runs them
+    #[serde(default)]
+    pub health: ServiceHealth,
 }
 
 impl ServiceInfo {
@@ -40,105 +93,349 @@ impl ServiceInfo {
     pub fn is_expired(&self) -> bool {
         let now = chrono::Utc::now();
         let elapsed = now.signed_duration_since(self.last_seen);
-        elapsed > chrono::Duration::seconds(300) // 5 minutes
+        elapsed > chrono::Duration::seconds(self.ttl as i64)
+    }
+
+    /// Whether health probes found the service down
+    pub fn is_unreachable(&self) -> bool {
+        self.health.reachable == Some(false)
+    }
+}
+
+/// Change in the services known to a resolver
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "service", rename_all = "snake_case")]
+pub enum ServiceEvent {
+    /// A service was discovered or registered
+    Added(ServiceInfo),
+    /// A known service changed its address, name, TTL or metadata, or
+    /// became reachable or unreachable
+    Updated(ServiceInfo),
+    /// A service said goodbye, was unregistered or expired
+    Removed(ServiceInfo),
+}
+
+/// Buffered service events per subscriber
+const SERVICE_EVENT_CAPACITY: usize = 64;
+
+/// Services known to a resolver; every change is published to subscribers
+pub(crate) struct ServiceMap {
+    services: RwLock<HashMap<ServiceId, ServiceInfo>>,
+    events: broadcast::Sender<ServiceEvent>,
+    /// Longest TTL kept, in seconds
+    expiry_window: u32,
+    known_hosts: Option<Mutex<KnownHosts>>,
+}
+
+impl ServiceMap {
+    pub(crate) fn new() -> Self {
+        let (events, _) = broadcast::channel(SERVICE_EVENT_CAPACITY);
+        ServiceMap {
+            services: RwLock::new(HashMap::new()),
+            events,
+            expiry_window: DEFAULT_SERVICE_TTL,
+            known_hosts: None,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ServiceEvent> {
+        self.events.subscribe()
+    }
+
+    /// Services that haven't expired
+    async fn list(&self) -> Vec<ServiceInfo> {
+        let services = self.services.read().await;
+        services.values()
+            .filter(|service| !service.is_expired())
+            .cloned()
+            .collect()
+    }
+
+    /// Insert or refresh a discovered service, remembering it in the known
+    /// hosts file. An announcement that contradicts the pinned fingerprint
+    /// is dropped.
+    pub(crate) async fn upsert(&self, info: ServiceInfo) {
+        if let Some(mut known_hosts) = self.known_hosts() {
+            if !known_hosts.validate(&info) {
+                warn!("Ignoring {}: its certificate fingerprint doesn't match the remembered one", info.name);
+                return;
+            }
+            if known_hosts.remember(&info) {
+                if let Err(e) = known_hosts.save() {
+                    warn!("Failed to save known hosts to {}: {}", known_hosts.path().display(), e);
+                }
+            }
+        }
+        self.insert(info).await
+    }
+
+    /// Insert or refresh a service. A refresh that only moves `last_seen`
+    /// isn't published.
+    async fn insert(&self, mut info: ServiceInfo) {
+        info.ttl = info.ttl.min(self.expiry_window);
+        let mut services = self.services.write().await;
+        if let Some(known) = services.get(&info.id) {
+            if known.source != ServiceSource::Remembered {
+                info.source = known.source;
+            }
+            info.health = known.health.clone();
+        }
+        let event = match services.get(&info.id) {
+            None => {
+                debug!("Discovered {} at {}:{}", info.name, info.address.ip, info.address.port);
+                Some(ServiceEvent::Added(info.clone()))
+            }
+            Some(known) if known.name != info.name
+                || known.address.ip != info.address.ip
+                || known.address.port != info.address.port
+                || known.ttl != info.ttl
+                || known.source != info.source
+                || known.metadata != info.metadata => Some(ServiceEvent::Updated(info.clone())),
+            Some(_) => None,
+        };
+        services.insert(info.id.clone(), info);
+        if let Some(event) = event {
+            // Nobody may be subscribed
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// Remove a service that said goodbye. A remembered entry stays: it
+    /// wasn't listed because of an announcement, so it can't be withdrawn
+    /// by one.
+    pub(crate) async fn withdraw(&self, id: &ServiceId) -> Option<ServiceInfo> {
+        let remembered = self.services.read().await.get(id).is_some_and(|info| info.source == ServiceSource::Remembered);
+        if remembered {
+            return None;
+        }
+        self.remove(id).await
+    }
+
+    pub(crate) async fn remove(&self, id: &ServiceId) -> Option<ServiceInfo> {
+        let mut services = self.services.write().await;
+        let removed = services.remove(id)?;
+        let _ = self.events.send(ServiceEvent::Removed(removed.clone()));
+        Some(removed)
+    }
+
+    fn known_hosts(&self) -> Option<std::sync::MutexGuard<'_, KnownHosts>> {
+        self.known_hosts.as_ref().map(|known_hosts| known_hosts.lock().unwrap())
+    }
+
+    /// List remembered hosts of `service_type` that aren't listed yet
+    async fn merge_known_hosts(&self, service_type: &ServiceType) {
+        let remembered: Vec<ServiceInfo> = match self.known_hosts() {
+            Some(known_hosts) => known_hosts.hosts().iter()
+                .filter(|host| &host.service_type == service_type)
+                .map(|host| host.to_service_info(self.expiry_window))
+                .collect(),
+            None => return,
+        };
+        for info in remembered {
+            let listed = self.services.read().await.contains_key(&info.id);
+            if !listed {
+                self.insert(info).await;
+            }
+        }
+    }
+
+    /// Record a health probe, publishing a change in reachability
+    pub(crate) async fn record_probe(&self, id: &ServiceId, result: Result<std::time::Duration, String>, failure_threshold: u32) {
+        let mut services = self.services.write().await;
+        let Some(info) = services.get_mut(id) else {
+            return;
+        };
+        if info.health.record(result, failure_threshold) {
+            if info.is_unreachable() {
+                warn!("{} is unreachable: {}", info.name, info.health.last_error.as_deref().unwrap_or_default());
+            } else {
+                debug!("{} is reachable", info.name);
+            }
+            let _ = self.events.send(ServiceEvent::Updated(info.clone()));
+        }
+    }
+
+    /// Remove expired services; returns how many are left
+    async fn remove_expired(&self) -> usize {
+        let mut services = self.services.write().await;
+        let expired_ids: Vec<ServiceId> = services
+            .iter()
+            .filter(|(_, info)| info.is_expired())
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired_ids {
+            if let Some(info) = services.remove(&id) {
+                debug!("Removed expired service: {:?}", id);
+                let _ = self.events.send(ServiceEvent::Removed(info));
+            }
+        }
+        services.len()
     }
 }
 
 /// Service resolver for mDNS discovery
 pub struct ServiceResolver {
-    services: Arc<RwLock<HashMap<ServiceId, ServiceInfo>>>,
+    services: Arc<ServiceMap>,
     service_type: ServiceType,
+    mdns_config: MdnsConfig,
+    mdns: Arc<RwLock<Option<Arc<Mdns>>>>,
+    beacon_config: Option<BeaconConfig>,
+    beacon: Arc<RwLock<Option<Arc<Beacon>>>>,
     discovery_handle: Arc<RwLock<Option<task::JoinHandle<()>>>>,
-    registration_handle: Arc<RwLock<Option<task::JoinHandle<()>>>>,
+    beacon_handle: Arc<RwLock<Option<task::JoinHandle<()>>>>,
+    sweep_interval: u64,
+    health_config: Option<HealthConfig>,
+    health_handle: Arc<RwLock<Option<task::JoinHandle<()>>>>,
+    cleanup_handle: Arc<RwLock<Option<task::JoinHandle<()>>>>,
 }
 
 impl ServiceResolver {
     /// Create a new service resolver
     pub fn new(service_type: ServiceType) -> Self {
         ServiceResolver {
-            services: Arc::new(RwLock::new(HashMap::new())),
+            services: Arc::new(ServiceMap::new()),
             service_type,
+            mdns_config: MdnsConfig::default(),
+            mdns: Arc::new(RwLock::new(None)),
+            beacon_config: None,
+            beacon: Arc::new(RwLock::new(None)),
             discovery_handle: Arc::new(RwLock::new(None)),
-            registration_handle: Arc::new(RwLock::new(None)),
+            beacon_handle: Arc::new(RwLock::new(None)),
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            health_config: None,
+            health_handle: Arc::new(RwLock::new(None)),
+            cleanup_handle: Arc::new(RwLock::new(None)),
         }
     }
 
+    /// Use different mDNS settings
+    pub fn with_mdns_config(mut self, config: MdnsConfig) -> Self {
+        self.mdns_config = config;
+        self
+    }
+
+    /// Sweep for expired services every `seconds` instead of every
+    /// `DEFAULT_SWEEP_INTERVAL`
+    pub fn with_sweep_interval(mut self, seconds: u64) -> Self {
+        self.sweep_interval = seconds;
+        self
+    }
+
+    /// Expire services at most `seconds` after they were last seen, even if
+    /// their TTL is longer. Defaults to `DEFAULT_SERVICE_TTL`.
+    pub fn with_expiry_window(mut self, seconds: u32) -> Self {
+        self.services_mut().expiry_window = seconds;
+        self
+    }
+
+    /// Remember discovered services in `known_hosts`, and list the ones
+    /// remembered from earlier runs as soon as discovery starts
+    pub fn with_known_hosts(mut self, known_hosts: KnownHosts) -> Self {
+        self.services_mut().known_hosts = Some(Mutex::new(known_hosts));
+        self
+    }
+
+    fn services_mut(&mut self) -> &mut ServiceMap {
+        // Tasks share the map only once the resolver is in use
+        Arc::get_mut(&mut self.services).expect("service map configured after use")
+    }
+
+    /// Probe discovered services while discovery runs
+    pub fn with_health_probe(mut self, config: HealthConfig) -> Self {
+        self.health_config = Some(config);
+        self
+    }
+
+    /// Also discover through static peers and broadcasts, for networks
+    /// that filter multicast
+    pub fn with_beacon_config(mut self, config: BeaconConfig) -> Self {
+        self.beacon_config = Some(config);
+        self
+    }
+
+    /// Beacon responder, started on first use if configured
+    async fn beacon(&self) -> KvmResult<Option<Arc<Beacon>>> {
+        let Some(config) = &self.beacon_config else {
+            return Ok(None);
+        };
+        let mut beacon = self.beacon.write().await;
+        if let Some(beacon) = beacon.as_ref() {
+            return Ok(Some(Arc::clone(beacon)));
+        }
+        let started = Beacon::start(config.clone())?;
+        debug!("Beacon listening on port {}", config.port);
+        *beacon = Some(Arc::clone(&started));
+        Ok(Some(started))
+    }
+
+    /// mDNS responder, started on first use
+    async fn mdns(&self) -> KvmResult<Arc<Mdns>> {
+        let mut mdns = self.mdns.write().await;
+        if let Some(mdns) = mdns.as_ref() {
+            return Ok(Arc::clone(mdns));
+        }
+        let started = Mdns::start(self.mdns_config.clone())?;
+        debug!("mDNS responder listening on port {}", self.mdns_config.port);
+        *mdns = Some(Arc::clone(&started));
+        Ok(started)
+    }
+
     /// Start service discovery
     pub async fn start_discovery(&self) -> KvmResult<()> {
         info!("Starting mDNS service discovery for {:?}", self.service_type);
 
         // すでに実行中の場合は何もしない
         let mut discovery_handle = self.discovery_handle.write().await;
-        if discovery_handle.is_some() {
+        let mut beacon_handle = self.beacon_handle.write().await;
+        if discovery_handle.is_some() || beacon_handle.is_some() {
             debug!("Service discovery already running");
             return Ok(());
         }
 
-        // mDNSサービス名の設定
-        let service_name = match self.service_type {
-            ServiceType::Server => "_soft-kvm-server._tcp.local.",
-            ServiceType::Client => "_soft-kvm-client._tcp.local.",
-        };
+        // 前回までに見つけたホストを先に一覧に載せる
+        self.services.merge_known_hosts(&self.service_type).await;
 
-        let services_clone = Arc::clone(&self.services);
-
-        // mDNS discovery taskを開始
-        let handle = task::spawn(async move {
-            debug!("Starting mDNS discovery for service: {}", service_name);
-
-            // 実際のmDNS実装は簡易的なものに置き換え
-            // 本番実装では適切なmDNSライブラリを使用
-            info!("mDNS discovery placeholder - would discover services via {}", service_name);
+        // マルチキャストが使えなくてもビーコンがあれば続行する
+        match self.mdns().await {
+            Ok(mdns) => *discovery_handle = Some(mdns.browse(&self.service_type, Arc::clone(&self.services))),
+            Err(e) if self.beacon_config.is_some() => warn!("mDNS unavailable, using the beacon only: {}", e),
+            Err(e) => return Err(e),
+        }
+        if let Some(beacon) = self.beacon().await? {
+            *beacon_handle = Some(beacon.browse(&self.service_type, Arc::clone(&self.services)));
+        }
 
-            // 定期的なクリーンアップタスク
-            let cleanup_services = Arc::clone(&services_clone);
-            let cleanup_handle = task::spawn(async move {
-                let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        // 定期クリーンアップを開始
+        let mut cleanup_handle = self.cleanup_handle.write().await;
+        if cleanup_handle.is_none() {
+            let services_clone = Arc::clone(&self.services);
+            let sweep_interval = std::time::Duration::from_secs(self.sweep_interval.max(1));
+            *cleanup_handle = Some(task::spawn(async move {
+                let mut interval = tokio::time::interval(sweep_interval);
                 loop {
                     interval.tick().await;
-                    Self::cleanup_expired_services(&cleanup_services).await;
+                    Self::cleanup_expired_services(&services_clone).await;
                 }
-            });
-
-            // ダミーのサービス検出（テスト用）
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-            info!("mDNS discovery simulation completed");
-        });
-
-        *discovery_handle = Some(handle);
+            }));
+        }
 
-        // 定期クリーンアップを開始
-        let services_clone = Arc::clone(&self.services);
-        let cleanup_handle = task::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
-            loop {
-                interval.tick().await;
-                Self::cleanup_expired_services(&services_clone).await;
-            }
-        });
+        // 到達性の監視を開始
+        if let Some(config) = &self.health_config {
+            *self.health_handle.write().await = Some(health::spawn(Arc::clone(&self.services), config.clone()));
+        }
 
         Ok(())
     }
 
 
     /// Clean up expired services
-    async fn cleanup_expired_services(services: &Arc<RwLock<HashMap<ServiceId, ServiceInfo>>>) {
+    async fn cleanup_expired_services(services: &ServiceMap) {
         debug!("Cleaning up expired services");
 
-        let mut services_map = services.write().await;
-        let expired_ids: Vec<ServiceId> = services_map
-            .iter()
-            .filter(|(_, info)| info.is_expired())
-            .map(|(id, _)| id.clone())
-            .collect();
-
-        for id in expired_ids {
-            services_map.remove(&id);
-            debug!("Removed expired service: {:?}", id);
-        }
-
-        if !services_map.is_empty() {
-            debug!("Active services: {}", services_map.len());
+        let active = services.remove_expired().await;
+        if active > 0 {
+            debug!("Active services: {}", active);
         }
     }
 
@@ -146,9 +443,16 @@ impl ServiceResolver {
     pub async fn stop_discovery(&self) -> KvmResult<()> {
         info!("Stopping service discovery");
 
-        let mut discovery_handle = self.discovery_handle.write().await;
-        if let Some(handle) = discovery_handle.take() {
-            handle.abort();
+        for handle in [&self.discovery_handle, &self.beacon_handle, &self.health_handle] {
+            if let Some(handle) = handle.write().await.take() {
+                handle.abort();
+            }
+        }
+        if let Some(mdns) = self.mdns.read().await.as_ref() {
+            mdns.stop_browsing();
+        }
+        if let Some(beacon) = self.beacon.read().await.as_ref() {
+            beacon.stop_browsing();
         }
 
         Ok(())
@@ -156,11 +460,13 @@ impl ServiceResolver {
 
     /// Get available services
     pub async fn get_available_services(&self) -> Vec<ServiceInfo> {
-        let services = self.services.read().await;
-        services.values()
-            .filter(|service| !service.is_expired())
-            .cloned()
-            .collect()
+        self.services.list().await
+    }
+
+    /// Subscribe to changes in the available services. Events start from
+    /// now; call `get_available_services` for what is already known.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServiceEvent> {
+        self.services.subscribe()
     }
 
     /// Register a service and advertise it via mDNS
@@ -168,76 +474,336 @@ impl ServiceResolver {
         info!("Registering service: {} ({:?})", info.name, info.service_type);
 
         // ローカルサービスマップに追加
-        {
-            let mut services = self.services.write().await;
-            services.insert(info.id.clone(), info.clone());
+        self.services.insert(info.clone()).await;
+
+        // 告知後はクエリに応答し続ける
+        if let Some(beacon) = self.beacon().await? {
+            beacon.register(info.clone()).await?;
+            if let Err(e) = self.mdns().await {
+                warn!("mDNS unavailable, announcing through the beacon only: {}", e);
+                return Ok(());
+            }
         }
+        self.mdns().await?.register(info).await
+    }
 
-        // すでに登録中の場合は何もしない
-        let mut registration_handle = self.registration_handle.write().await;
-        if registration_handle.is_some() {
-            debug!("Service already registered");
-            return Ok(());
+    /// Unregister a service and stop mDNS advertisement
+    pub async fn unregister_service(&self, id: &ServiceId) -> KvmResult<()> {
+        info!("Unregistering service: {:?}", id);
+
+        // ローカルサービスマップから削除
+        self.services.remove(id).await;
+
+        // グッバイパケットを送信
+        if let Some(mdns) = self.mdns.read().await.as_ref() {
+            mdns.unregister(id).await?;
+        }
+        if let Some(beacon) = self.beacon.read().await.as_ref() {
+            beacon.unregister(id).await?;
         }
 
-        // mDNSサービス名を設定
-        let service_type_name = match info.service_type {
-            ServiceType::Server => "_soft-kvm-server._tcp",
-            ServiceType::Client => "_soft-kvm-client._tcp",
-        };
+        Ok(())
+    }
+}
 
-        let hostname = info.name.clone();
-        let port = info.address.port;
-        let service_name = format!("{}.{}", hostname, service_type_name);
+impl ServiceResolver {
+    /// Hosts remembered in the known hosts file, most recently used first
+    pub fn known_hosts(&self) -> Vec<KnownHost> {
+        self.services.known_hosts().map(|known_hosts| known_hosts.hosts()).unwrap_or_default()
+    }
 
-        let services_clone = Arc::clone(&self.services);
+    /// Record a connection to a remembered host, pinning the fingerprint of
+    /// its certificate if none is yet
+    pub fn mark_connected(&self, id: &ServiceId, cert_fingerprint: Option<String>) -> KvmResult<()> {
+        let mut known_hosts = self.services.known_hosts()
+            .ok_or_else(|| KvmError::Discovery("No known hosts file configured".to_string()))?;
+        known_hosts.mark_connected(id, cert_fingerprint)?;
+        known_hosts.save()
+    }
 
-        // mDNS registration taskを開始
-        let handle = task::spawn(async move {
-            debug!("Starting mDNS registration for service: {}", service_name);
+    /// Certificate fingerprint pinned for a remembered host, to check when
+    /// connecting to it
+    pub fn pinned_fingerprint(&self, id: &ServiceId) -> Option<String> {
+        self.services.known_hosts()?.get(id)?.cert_fingerprint.clone()
+    }
 
-            // 実際のmDNS実装は簡易的なものに置き換え
-            // 本番実装では適切なmDNSライブラリを使用
-            info!("mDNS registration placeholder - would register service {} at {}:{}", hostname, hostname, port);
+    /// Forget a remembered host, so a new certificate fingerprint is accepted
+    pub fn forget_host(&self, id: &ServiceId) -> KvmResult<()> {
+        let mut known_hosts = self.services.known_hosts()
+            .ok_or_else(|| KvmError::Discovery("No known hosts file configured".to_string()))?;
+        if known_hosts.forget(id).is_some() {
+            known_hosts.save()?;
+        }
+        Ok(())
+    }
+}
 
-            // 定期的にサービスを更新（TTLを維持）
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(300)); // 5分毎
-            loop {
-                interval.tick().await;
+impl Drop for ServiceResolver {
+    fn drop(&mut self) {
+        for handle in [&self.discovery_handle, &self.beacon_handle, &self.health_handle, &self.cleanup_handle] {
+            if let Some(handle) = handle.try_write().ok().and_then(|mut handle| handle.take()) {
+                handle.abort();
+            }
+        }
+        if let Some(mdns) = self.mdns.try_write().ok().and_then(|mut mdns| mdns.take()) {
+            mdns.shutdown();
+        }
+        if let Some(beacon) = self.beacon.try_write().ok().and_then(|mut beacon| beacon.take()) {
+            beacon.shutdown();
+        }
+    }
+}
 
-                // サービスがまだ有効かチェック
-                let services = services_clone.read().await;
-                if !services.contains_key(&info.id) {
-                    debug!("Service no longer registered, stopping mDNS advertisement");
-                    break;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn loopback(port: u16) -> MdnsConfig {
+        MdnsConfig {
+            port,
+            interface: Ipv4Addr::LOCALHOST,
+            ..MdnsConfig::default()
+        }
+    }
+
+    fn server(name: &str, port: u16) -> ServiceInfo {
+        ServiceInfo {
+            id: ServiceId(uuid::Uuid::new_v4()),
+            name: name.to_string(),
+            service_type: ServiceType::Server,
+            address: NetworkAddress::localhost(port),
+            last_seen: chrono::Utc::now(),
+            ttl: DEFAULT_SERVICE_TTL,
+            metadata: ServiceMetadata::default(),
+            source: ServiceSource::Mdns,
+            health: ServiceHealth::default(),
+        }
+    }
 
-                debug!("Refreshing mDNS registration for {}", service_name);
+    /// Poll until the services satisfy `done`
+    async fn wait_for(resolver: &ServiceResolver, done: impl Fn(&[ServiceInfo]) -> bool) -> Vec<ServiceInfo> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let services = resolver.get_available_services().await;
+                if done(&services) {
+                    return services;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
             }
-        });
+        })
+        .await
+        .expect("discovery timed out")
+    }
+
+    #[tokio::test]
+    async fn test_resolvers_discover_each_other_over_multicast() {
+        let host = ServiceResolver::new(ServiceType::Server).with_mdns_config(loopback(25353));
+        let viewer = ServiceResolver::new(ServiceType::Server).with_mdns_config(loopback(25353));
+
+        // Registered before the viewer browses: found by querying
+        let desk = server("Desk", 9000);
+        host.register_service(desk.clone()).await.unwrap();
+        viewer.start_discovery().await.unwrap();
+        let found = wait_for(&viewer, |services| !services.is_empty()).await;
+        assert_eq!(found[0].id, desk.id);
+        assert_eq!(found[0].name, "Desk");
+        assert_eq!((found[0].address.ip.as_str(), found[0].address.port), ("127.0.0.1", 9000));
+        assert_eq!(found[0].ttl, MdnsConfig::default().ttl);
+
+        // Registered while browsing: found from the announcement
+        let lab = server("Lab bench 2", 9001);
+        host.register_service(lab.clone()).await.unwrap();
+        wait_for(&viewer, |services| services.iter().any(|s| s.id == lab.id)).await;
+
+        // A goodbye removes the service at once
+        host.unregister_service(&desk.id).await.unwrap();
+        let remaining = wait_for(&viewer, |services| services.len() == 1).await;
+        assert_eq!(remaining[0].id, lab.id);
+
+        viewer.stop_discovery().await.unwrap();
+    }
 
-        *registration_handle = Some(handle);
+    #[tokio::test]
+    async fn test_services_expire_after_their_ttl() {
+        let config = MdnsConfig { ttl: 1, ..loopback(25354) };
+        let host = ServiceResolver::new(ServiceType::Server).with_mdns_config(config.clone());
+        let viewer = ServiceResolver::new(ServiceType::Server).with_mdns_config(config);
 
-        Ok(())
+        viewer.start_discovery().await.unwrap();
+        host.register_service(server("Desk", 9000)).await.unwrap();
+        wait_for(&viewer, |services| services.len() == 1).await;
+
+        // The host vanishes without a goodbye; nobody answers the next queries
+        drop(host);
+        wait_for(&viewer, |services| services.is_empty()).await;
     }
 
-    /// Unregister a service and stop mDNS advertisement
-    pub async fn unregister_service(&self, id: &ServiceId) -> KvmResult<()> {
-        info!("Unregistering service: {:?}", id);
+    async fn next_event(events: &mut broadcast::Receiver<ServiceEvent>) -> ServiceEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("no service event")
+            .unwrap()
+    }
 
-        // ローカルサービスマップから削除
-        {
-            let mut services = self.services.write().await;
-            services.remove(id);
+    #[tokio::test]
+    async fn test_subscribers_follow_announcements() {
+        let host = ServiceResolver::new(ServiceType::Server).with_mdns_config(loopback(25355));
+        let viewer = ServiceResolver::new(ServiceType::Server).with_mdns_config(loopback(25355));
+        let mut events = viewer.subscribe();
+        viewer.start_discovery().await.unwrap();
+
+        let mut desk = server("Desk", 9000);
+        host.register_service(desk.clone()).await.unwrap();
+        assert!(matches!(next_event(&mut events).await, ServiceEvent::Added(info) if info.id == desk.id));
+
+        // Re-announcing with new metadata updates it; plain refreshes don't
+        desk.metadata.controllers = 1;
+        host.register_service(desk.clone()).await.unwrap();
+        assert!(matches!(next_event(&mut events).await, ServiceEvent::Updated(info) if info.metadata.controllers == 1));
+
+        host.unregister_service(&desk.id).await.unwrap();
+        assert!(matches!(next_event(&mut events).await, ServiceEvent::Removed(info) if info.id == desk.id));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_publishes_expiry() {
+        let services = ServiceMap::new();
+        let mut events = services.subscribe();
+
+        let mut stale = server("Stale", 9000);
+        stale.ttl = 1;
+        stale.last_seen = chrono::Utc::now() - chrono::Duration::seconds(5);
+        services.upsert(stale.clone()).await;
+        services.upsert(server("Fresh", 9001)).await;
+        assert_eq!(services.list().await.len(), 1);
+
+        assert_eq!(services.remove_expired().await, 1);
+        let mut kinds = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            kinds.push(match event {
+                ServiceEvent::Added(info) => format!("added {}", info.name),
+                ServiceEvent::Updated(info) => format!("updated {}", info.name),
+                ServiceEvent::Removed(info) => format!("removed {}", info.name),
+            });
         }
+        assert_eq!(kinds, vec!["added Stale", "added Fresh", "removed Stale"]);
+    }
 
-        // mDNS登録タスクを停止
-        let mut registration_handle = self.registration_handle.write().await;
-        if let Some(handle) = registration_handle.take() {
-            handle.abort();
-            debug!("Stopped mDNS registration task");
+    #[tokio::test]
+    async fn test_static_peers_are_probed_without_multicast() {
+        // Separate mDNS ports keep the resolvers from seeing each other by multicast
+        let host = ServiceResolver::new(ServiceType::Server)
+            .with_mdns_config(loopback(25356))
+            .with_beacon_config(BeaconConfig { port: 25370, broadcast: false, ..BeaconConfig::default() });
+        let viewer = ServiceResolver::new(ServiceType::Server)
+            .with_mdns_config(loopback(25357))
+            .with_beacon_config(BeaconConfig {
+                port: 25371,
+                broadcast: false,
+                peers: vec!["127.0.0.1:25370".parse().unwrap()],
+                interval: 1,
+                ..BeaconConfig::default()
+            });
+
+        let desk = server("Desk", 9000);
+        host.register_service(desk.clone()).await.unwrap();
+        viewer.start_discovery().await.unwrap();
+        let found = wait_for(&viewer, |services| !services.is_empty()).await;
+        assert_eq!(found[0].id, desk.id);
+        assert_eq!(found[0].source, ServiceSource::Static);
+        assert_eq!((found[0].address.ip.as_str(), found[0].address.port), ("127.0.0.1", 9000));
+
+        viewer.stop_discovery().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prober_marks_dead_hosts_unreachable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let host = ServiceResolver::new(ServiceType::Server).with_mdns_config(loopback(25358));
+        let viewer = ServiceResolver::new(ServiceType::Server)
+            .with_mdns_config(loopback(25358))
+            .with_health_probe(HealthConfig { interval: 1, timeout: 1, failure_threshold: 1 });
+        let mut events = viewer.subscribe();
+        viewer.start_discovery().await.unwrap();
+        host.register_service(server("Desk", port)).await.unwrap();
+
+        assert!(matches!(next_event(&mut events).await, ServiceEvent::Added(_)));
+        match next_event(&mut events).await {
+            ServiceEvent::Updated(info) => {
+                assert_eq!(info.health.reachable, Some(true));
+                assert!(info.health.rtt_ms.is_some());
+            }
+            event => panic!("unexpected {:?}", event),
         }
 
-        Ok(())
+        // Still announced, but nothing accepts connections any more
+        drop(listener);
+        match next_event(&mut events).await {
+            ServiceEvent::Updated(info) => {
+                assert!(info.is_unreachable());
+                assert!(info.health.last_error.is_some());
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        assert!(viewer.get_available_services().await[0].is_unreachable());
+    }
+
+    #[tokio::test]
+    async fn test_expiry_window_caps_ttl() {
+        let services = ServiceMap { expiry_window: 1, ..ServiceMap::new() };
+        let mut desk = server("Desk", 9000);
+        desk.last_seen = chrono::Utc::now() - chrono::Duration::seconds(5);
+        services.upsert(desk).await;
+        assert!(services.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remembered_hosts_are_listed_then_validated() {
+        let path = std::env::temp_dir().join(format!("soft-kvm-known-hosts-{}.json", uuid::Uuid::new_v4()));
+        let mut desk = server("Desk", 9000);
+        desk.metadata.cert_fingerprint = Some("aa".repeat(32));
+        let mut known_hosts = KnownHosts::load(&path).unwrap();
+        known_hosts.remember(&desk);
+        known_hosts.save().unwrap();
+
+        let host = ServiceResolver::new(ServiceType::Server).with_mdns_config(loopback(25359));
+        let viewer = ServiceResolver::new(ServiceType::Server)
+            .with_mdns_config(loopback(25359))
+            .with_known_hosts(KnownHosts::load(&path).unwrap());
+        let mut events = viewer.subscribe();
+
+        // Listed before anything answers
+        viewer.start_discovery().await.unwrap();
+        match next_event(&mut events).await {
+            ServiceEvent::Added(info) => assert_eq!((info.id, info.source), (desk.id.clone(), ServiceSource::Remembered)),
+            event => panic!("unexpected {:?}", event),
+        }
+
+        // Someone announcing the same ID with another certificate is ignored
+        let mut impostor = desk.clone();
+        impostor.metadata.cert_fingerprint = Some("bb".repeat(32));
+        host.register_service(impostor).await.unwrap();
+        let lab = server("Lab", 9001);
+        host.register_service(lab.clone()).await.unwrap();
+        assert!(matches!(next_event(&mut events).await, ServiceEvent::Added(info) if info.id == lab.id));
+        host.unregister_service(&desk.id).await.unwrap();
+
+        // The real one confirms the entry
+        host.register_service(desk.clone()).await.unwrap();
+        match next_event(&mut events).await {
+            ServiceEvent::Updated(info) => assert_eq!((info.id, info.source), (desk.id.clone(), ServiceSource::Mdns)),
+            event => panic!("unexpected {:?}", event),
+        }
+
+        viewer.mark_connected(&desk.id, None).unwrap();
+        let remembered = KnownHosts::load(&path).unwrap().hosts();
+        assert_eq!(remembered[0].id, desk.id);
+        assert!(remembered[0].last_connected.is_some());
+        std::fs::remove_file(path).unwrap();
     }
 }
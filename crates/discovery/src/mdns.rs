@@ -0,0 +1,617 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multicast DNS service discovery (RFC 6762, RFC 6763)
+//!
+//! A service is published as `<name>._soft-kvm-server._tcp.local.` (or
+//! `_soft-kvm-client`) with four records:
+//!
+//! ```text
+//! _soft-kvm-server._tcp.local.        PTR  Desk._soft-kvm-server._tcp.local.
+//! Desk._soft-kvm-server._tcp.local.   SRV  0 0 9000 soft-kvm-<id>.local.
+//! Desk._soft-kvm-server._tcp.local.   TXT  "id=<service id>"
+//! soft-kvm-<id>.local.                A    192.168.1.20
+//! ```
+//!
+//! Registering a service announces it twice, a second apart, and the
+//! responder answers queries for it from then on; unregistering sends the
+//! records again with TTL 0 (a goodbye). A browser queries for the PTR at
+//! growing intervals, one second at first and at most
+//! `MdnsConfig::max_query_interval`, and turns complete answers into
+//! `ServiceInfo`s. An entry's `last_seen` moves on with every answer and it
+//! expires `ttl` seconds later unless answered again; a goodbye removes it
+//! at once. When the A record is missing the sender's address is used.
+
+use crate::dns::{Message, Name, Question, RData, Record, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_PTR, TYPE_SRV, TYPE_TXT};
+use crate::health::ServiceHealth;
+use crate::metadata::ServiceMetadata;
+use crate::{ServiceInfo, ServiceMap, ServiceSource};
+use soft_kvm_core::*;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task;
+use tracing::{debug, trace, warn};
+
+/// mDNS IPv4 multicast group
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+/// mDNS port
+pub const MDNS_PORT: u16 = 5353;
+
+/// Largest mDNS packet (RFC 6762 17)
+const MAX_PACKET_SIZE: usize = 9000;
+
+/// TTL of replies to legacy unicast queries (RFC 6762 6.7)
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+/// Multicast DNS settings
+#[derive(Debug, Clone)]
+pub struct MdnsConfig {
+    /// UDP port, `MDNS_PORT` outside of tests
+    pub port: u16,
+    /// Address of the interface to use; unspecified lets the OS pick
+    pub interface: Ipv4Addr,
+    /// TTL of announced records, in seconds
+    pub ttl: u32,
+    /// Longest interval between browse queries, in seconds
+    pub max_query_interval: u64,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            port: MDNS_PORT,
+            interface: Ipv4Addr::UNSPECIFIED,
+            ttl: 120,
+            max_query_interval: 60,
+        }
+    }
+}
+
+/// DNS-SD service type name, e.g. `_soft-kvm-server._tcp.local.`
+pub fn service_type_name(service_type: &ServiceType) -> Name {
+    match service_type {
+        ServiceType::Server => Name::new("_soft-kvm-server._tcp.local."),
+        ServiceType::Client => Name::new("_soft-kvm-client._tcp.local."),
+    }
+}
+
+/// Records published for a service
+pub(crate) fn service_records(info: &ServiceInfo, ttl: u32) -> (Record, Vec<Record>) {
+    let service_type = service_type_name(&info.service_type);
+    let instance = Name::child(&info.name, &service_type);
+    let host = Name::new(&format!("soft-kvm-{}.local.", info.id.0.simple()));
+
+    let ptr = Record::shared(service_type, ttl, RData::Ptr(instance.clone()));
+    let mut records = vec![
+        Record::unique(instance.clone(), ttl, RData::Srv { priority: 0, weight: 0, port: info.address.port, target: host.clone() }),
+        Record::unique(instance, ttl, RData::Txt(txt_entries(info))),
+    ];
+    // Without an address record browsers fall back to the sender's address
+    match info.address.ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) if !ip.is_unspecified() => records.push(Record::unique(host, ttl, RData::A(ip))),
+        Ok(IpAddr::V6(ip)) if !ip.is_unspecified() => records.push(Record::unique(host, ttl, RData::Aaaa(ip))),
+        _ => {}
+    }
+    (ptr, records)
+}
+
+/// TXT entries for a service: its ID followed by its metadata
+fn txt_entries(info: &ServiceInfo) -> Vec<String> {
+    let mut entries = vec![format!("id={}", info.id.0)];
+    entries.extend(info.metadata.to_txt());
+    entries
+}
+
+/// Answers to `questions` from the records of `services`, and the
+/// additional records that go with them
+pub(crate) fn answer_records<'a>(services: impl Iterator<Item = &'a ServiceInfo>, questions: &[Question], ttl: u32) -> (Vec<Record>, Vec<Record>) {
+    let mut answers = Vec::new();
+    let mut additionals = Vec::new();
+    for info in services {
+        let (ptr, records) = service_records(info, ttl);
+        for question in questions {
+            if question.is_answered_by(&ptr) {
+                answers.push(ptr.clone());
+                additionals.extend(records.iter().cloned());
+            } else {
+                let matching: Vec<&Record> = records.iter().filter(|record| question.is_answered_by(record)).collect();
+                if !matching.is_empty() {
+                    answers.extend(matching.into_iter().cloned());
+                    // The address goes along with the SRV that names it
+                    if question.qtype == TYPE_SRV || question.qtype == TYPE_ANY {
+                        additionals.extend(records.iter().filter(|record| record.name != question.name).cloned());
+                    }
+                }
+            }
+        }
+    }
+    additionals.retain(|record| !answers.contains(record));
+    additionals.dedup();
+    (answers, additionals)
+}
+
+/// Browse state for a service type
+pub(crate) struct Browse {
+    kind: ServiceType,
+    service_type: Name,
+    services: Arc<ServiceMap>,
+    /// Instances seen so far, by `Name::key`
+    instances: HashMap<String, Instance>,
+    /// Host addresses, by `Name::key`
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+/// What is known about a service instance
+struct Instance {
+    name: Name,
+    srv: Option<(u16, Name, u32)>,
+    txt: Vec<String>,
+    /// Set once the instance became a `ServiceInfo`
+    id: Option<ServiceId>,
+}
+
+/// Multicast DNS responder and browser sharing one socket
+pub(crate) struct Mdns {
+    socket: UdpSocket,
+    config: MdnsConfig,
+    /// Services published by this host
+    local: Mutex<HashMap<ServiceId, ServiceInfo>>,
+    browse: Mutex<Option<Browse>>,
+    receiver: Mutex<Option<task::JoinHandle<()>>>,
+}
+
+impl Mdns {
+    /// Join the multicast group and start answering queries
+    pub(crate) fn start(config: MdnsConfig) -> KvmResult<Arc<Mdns>> {
+        let socket = bind(&config)?;
+        let mdns = Arc::new(Mdns {
+            socket,
+            config,
+            local: Mutex::new(HashMap::new()),
+            browse: Mutex::new(None),
+            receiver: Mutex::new(None),
+        });
+
+        // Runs until `shutdown`
+        let receiver = Arc::clone(&mdns);
+        let handle = task::spawn(async move {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            loop {
+                match receiver.socket.recv_from(&mut buf).await {
+                    Ok((len, source)) => receiver.handle_packet(&buf[..len], source).await,
+                    Err(e) => warn!("mDNS receive failed: {}", e),
+                }
+            }
+        });
+        *mdns.receiver.lock().unwrap() = Some(handle);
+        Ok(mdns)
+    }
+
+    /// Stop receiving
+    pub(crate) fn shutdown(&self) {
+        if let Some(handle) = self.receiver.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    /// Publish a service, announcing it twice a second apart
+    pub(crate) async fn register(self: &Arc<Self>, info: ServiceInfo) -> KvmResult<()> {
+        self.local.lock().unwrap().insert(info.id.clone(), info.clone());
+        self.announce(&info, self.config.ttl).await?;
+
+        let mdns = Arc::downgrade(self);
+        task::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let Some(mdns) = mdns.upgrade() else {
+                return;
+            };
+            // Skip the repeat if the service was withdrawn meanwhile, and
+            // don't undo a re-registration with older records
+            let current = mdns.local.lock().unwrap().get(&info.id).cloned();
+            if let Some(info) = current {
+                if let Err(e) = mdns.announce(&info, mdns.config.ttl).await {
+                    warn!("Failed to repeat announcement of {}: {}", info.name, e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Withdraw a service, sending a goodbye
+    pub(crate) async fn unregister(&self, id: &ServiceId) -> KvmResult<()> {
+        let removed = self.local.lock().unwrap().remove(id);
+        if let Some(info) = removed {
+            self.announce(&info, 0).await?;
+        }
+        Ok(())
+    }
+
+    async fn announce(&self, info: &ServiceInfo, ttl: u32) -> KvmResult<()> {
+        debug!("Announcing {} with TTL {}", info.name, ttl);
+        let (ptr, records) = service_records(info, ttl);
+        let mut answers = vec![ptr];
+        answers.extend(records);
+        self.send(&Message::response(answers, Vec::new()), self.group()).await
+    }
+
+    /// Browse for a service type, collecting results in `services`.
+    ///
+    /// Queries until the returned task is aborted.
+    pub(crate) fn browse(self: &Arc<Self>, service_type: &ServiceType, services: Arc<ServiceMap>) -> task::JoinHandle<()> {
+        let kind = service_type.clone();
+        let service_type = service_type_name(service_type);
+        *self.browse.lock().unwrap() = Some(Browse::new(kind, services));
+
+        let mdns = Arc::downgrade(self);
+        let max_interval = Duration::from_secs(self.config.max_query_interval.max(1));
+        task::spawn(async move {
+            let mut interval = Duration::from_secs(1);
+            loop {
+                let Some(mdns) = mdns.upgrade() else {
+                    break;
+                };
+                let questions = match mdns.browse.lock().unwrap().as_ref() {
+                    Some(browse) => browse.questions(),
+                    None => vec![Question::new(service_type.clone(), TYPE_PTR)],
+                };
+                let query = Message::query(questions);
+                if let Err(e) = mdns.send(&query, mdns.group()).await {
+                    warn!("mDNS query for {} failed: {}", service_type, e);
+                }
+                drop(mdns);
+
+                tokio::time::sleep(interval).await;
+                interval = (interval * 2).min(max_interval);
+            }
+        })
+    }
+
+    /// Stop collecting browse results
+    pub(crate) fn stop_browsing(&self) {
+        self.browse.lock().unwrap().take();
+    }
+
+    fn group(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, self.config.port))
+    }
+
+    async fn send(&self, message: &Message, target: SocketAddr) -> KvmResult<()> {
+        let packet = message.encode()?;
+        self.socket.send_to(&packet, target).await?;
+        Ok(())
+    }
+
+    async fn handle_packet(&self, data: &[u8], source: SocketAddr) {
+        let message = match Message::decode(data) {
+            Ok(message) => message,
+            Err(e) => {
+                trace!("Ignoring malformed mDNS packet from {}: {}", source, e);
+                return;
+            }
+        };
+
+        if message.response {
+            self.handle_response(&message, source.ip()).await;
+        } else if !message.questions.is_empty() {
+            if let Err(e) = self.answer(&message, source).await {
+                warn!("Failed to answer mDNS query from {}: {}", source, e);
+            }
+        }
+    }
+
+    /// Answer the questions about services published here
+    async fn answer(&self, query: &Message, source: SocketAddr) -> KvmResult<()> {
+        // A query from another port comes from a plain DNS resolver, which
+        // expects a unicast reply echoing its ID and questions
+        let legacy = source.port() != self.config.port;
+        let ttl = if legacy { self.config.ttl.min(LEGACY_UNICAST_TTL) } else { self.config.ttl };
+
+        let (answers, additionals) = {
+            let local = self.local.lock().unwrap();
+            answer_records(local.values(), &query.questions, ttl)
+        };
+        if answers.is_empty() {
+            return Ok(());
+        }
+
+        let mut response = Message::response(answers, additionals);
+        if legacy {
+            response.id = query.id;
+            response.questions = query.questions.clone();
+            self.send(&response, source).await
+        } else {
+            self.send(&response, self.group()).await
+        }
+    }
+
+    /// Update the browse cache from a response
+    async fn handle_response(&self, response: &Message, source: IpAddr) {
+        let (upserts, removals, services) = {
+            let mut browse = self.browse.lock().unwrap();
+            let Some(browse) = browse.as_mut() else {
+                return;
+            };
+            let (upserts, removals) = browse.update(response, source);
+            (upserts, removals, browse.services())
+        };
+
+        if upserts.is_empty() && removals.is_empty() {
+            return;
+        }
+        for id in removals {
+            if let Some(info) = services.withdraw(&id).await {
+                debug!("Service {} said goodbye", info.name);
+            }
+        }
+        for info in upserts {
+            services.upsert(info).await;
+        }
+    }
+}
+
+impl Browse {
+    pub(crate) fn new(kind: ServiceType, services: Arc<ServiceMap>) -> Self {
+        Browse {
+            service_type: service_type_name(&kind),
+            kind,
+            services,
+            instances: HashMap::new(),
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// The PTR question, plus SRV and TXT questions for instances that
+    /// haven't answered them yet and address questions for their hosts
+    pub(crate) fn questions(&self) -> Vec<Question> {
+        let mut questions = vec![Question::new(self.service_type.clone(), TYPE_PTR)];
+        for instance in self.instances.values() {
+            match &instance.srv {
+                None => {
+                    questions.push(Question::new(instance.name.clone(), TYPE_SRV));
+                    questions.push(Question::new(instance.name.clone(), TYPE_TXT));
+                }
+                Some((_, target, _)) if self.hosts.get(&target.key()).is_none_or(Vec::is_empty) => {
+                    questions.push(Question::new(target.clone(), TYPE_A));
+                    questions.push(Question::new(target.clone(), TYPE_AAAA));
+                }
+                Some(_) => {}
+            }
+        }
+        questions
+    }
+
+    pub(crate) fn services(&self) -> Arc<ServiceMap> {
+        Arc::clone(&self.services)
+    }
+
+    fn instance(&mut self, name: &Name) -> &mut Instance {
+        self.instances.entry(name.key()).or_insert_with(|| Instance {
+            name: name.clone(),
+            srv: None,
+            txt: Vec::new(),
+            id: None,
+        })
+    }
+
+    /// Apply the records of a response; returns services to insert or
+    /// refresh and IDs of services that said goodbye
+    pub(crate) fn update(&mut self, response: &Message, source: IpAddr) -> (Vec<ServiceInfo>, Vec<ServiceId>) {
+        let mut touched = Vec::new();
+        let mut removals = Vec::new();
+        let mut goodbye = |instances: &mut HashMap<String, Instance>, name: &Name| {
+            if let Some(Instance { id: Some(id), .. }) = instances.remove(&name.key()) {
+                removals.push(id);
+            }
+        };
+
+        // Addresses first so SRV targets resolve within the same packet
+        for record in response.records() {
+            let address = match record.data {
+                RData::A(ip) => IpAddr::V4(ip),
+                RData::Aaaa(ip) => IpAddr::V6(ip),
+                _ => continue,
+            };
+            let addresses = self.hosts.entry(record.name.key()).or_default();
+            if record.cache_flush {
+                addresses.retain(|known| known.is_ipv4() != address.is_ipv4());
+            }
+            addresses.retain(|known| *known != address);
+            if record.ttl > 0 {
+                addresses.push(address);
+            }
+        }
+
+        for record in response.records() {
+            match &record.data {
+                RData::Ptr(instance) if record.name == self.service_type => {
+                    if record.ttl == 0 {
+                        goodbye(&mut self.instances, instance);
+                    } else {
+                        self.instance(instance);
+                        touched.push(instance.clone());
+                    }
+                }
+                RData::Srv { port, target, .. } if record.name.parent() == self.service_type => {
+                    if record.ttl == 0 {
+                        goodbye(&mut self.instances, &record.name);
+                    } else {
+                        self.instance(&record.name).srv = Some((*port, target.clone(), record.ttl));
+                        touched.push(record.name.clone());
+                    }
+                }
+                RData::Txt(entries) if record.name.parent() == self.service_type && record.ttl > 0 => {
+                    self.instance(&record.name).txt = entries.clone();
+                    touched.push(record.name.clone());
+                }
+                RData::A(_) | RData::Aaaa(_) => {
+                    // An address change refreshes the instances on that host
+                    for instance in self.instances.values() {
+                        if matches!(&instance.srv, Some((_, target, _)) if *target == record.name) {
+                            touched.push(instance.name.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut upserts = Vec::new();
+        touched.dedup();
+        for name in touched {
+            let Some(instance) = self.instances.get_mut(&name.key()) else {
+                continue;
+            };
+            let Some((port, target, ttl)) = &instance.srv else {
+                continue;
+            };
+            let addresses = self.hosts.get(&target.key()).map(Vec::as_slice).unwrap_or_default();
+            let ip = addresses.iter().find(|ip| ip.is_ipv4()).or(addresses.first()).copied().unwrap_or(source);
+            let metadata = match ServiceMetadata::from_txt(&instance.txt) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("Ignoring {}: {}", instance.name, e);
+                    removals.extend(instance.id.take());
+                    continue;
+                }
+            };
+            let id = instance_id(&instance.txt, &instance.name);
+            instance.id = Some(id.clone());
+
+            upserts.push(ServiceInfo {
+                id,
+                name: instance.name.first_label().unwrap_or_default().to_string(),
+                service_type: self.kind.clone(),
+                address: NetworkAddress { ip: ip.to_string(), port: *port },
+                last_seen: chrono::Utc::now(),
+                ttl: *ttl,
+                metadata,
+                source: ServiceSource::Mdns,
+                health: ServiceHealth::default(),
+            });
+        }
+        (upserts, removals)
+    }
+}
+
+/// Service ID from the `id` TXT entry, or derived from the instance name
+/// for a peer that doesn't send one
+fn instance_id(txt: &[String], name: &Name) -> ServiceId {
+    txt.iter()
+        .filter_map(|entry| entry.strip_prefix("id="))
+        .find_map(|id| uuid::Uuid::parse_str(id).ok())
+        .map(ServiceId)
+        .unwrap_or_else(|| ServiceId(uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_DNS, name.key().as_bytes())))
+}
+
+/// Bind the mDNS port, shared with other responders on the host, and join the group
+fn bind(config: &MdnsConfig) -> KvmResult<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.port).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &config.interface)
+        .map_err(|e| KvmError::Discovery(format!("Failed to join {} on {}: {}", MDNS_GROUP, config.interface, e)))?;
+    if !config.interface.is_unspecified() {
+        socket.set_multicast_if_v4(&config.interface)?;
+    }
+    // Other resolvers on this host must see our packets too
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, ip: &str) -> ServiceInfo {
+        ServiceInfo {
+            id: ServiceId(uuid::Uuid::new_v4()),
+            name: name.to_string(),
+            service_type: ServiceType::Server,
+            address: NetworkAddress { ip: ip.to_string(), port: 9000 },
+            last_seen: chrono::Utc::now(),
+            ttl: crate::DEFAULT_SERVICE_TTL,
+            metadata: ServiceMetadata::default(),
+            source: ServiceSource::Mdns,
+            health: ServiceHealth::default(),
+        }
+    }
+
+    fn browse() -> Browse {
+        Browse::new(ServiceType::Server, Arc::new(ServiceMap::new()))
+    }
+
+    #[test]
+    fn test_browse_assembles_announcements() {
+        let desk = service("Desk", "192.168.1.20");
+        let (ptr, records) = service_records(&desk, 120);
+        let source: IpAddr = "192.168.1.20".parse().unwrap();
+
+        // The PTR alone doesn't make a service yet
+        let mut browse = browse();
+        let (upserts, _) = browse.update(&Message::response(vec![ptr.clone()], Vec::new()), source);
+        assert!(upserts.is_empty());
+
+        let (upserts, _) = browse.update(&Message::response(records.clone(), Vec::new()), source);
+        assert_eq!(upserts.len(), 1);
+        assert_eq!(upserts[0].id, desk.id);
+        assert_eq!(upserts[0].name, "Desk");
+        assert_eq!(upserts[0].ttl, 120);
+        assert_eq!((upserts[0].address.ip.as_str(), upserts[0].address.port), ("192.168.1.20", 9000));
+
+        // A goodbye removes it
+        let (goodbye, goodbye_records) = service_records(&desk, 0);
+        let (upserts, removals) = browse.update(&Message::response(vec![goodbye], goodbye_records), source);
+        assert!(upserts.is_empty());
+        assert_eq!(removals, vec![desk.id]);
+    }
+
+    #[test]
+    fn test_browse_falls_back_to_sender_address() {
+        let anywhere = service("Anywhere", "0.0.0.0");
+        let (ptr, records) = service_records(&anywhere, 120);
+        assert!(records.iter().all(|record| !matches!(record.data, RData::A(_))));
+
+        let (upserts, _) = browse().update(&Message::response(vec![ptr], records), "10.1.2.3".parse().unwrap());
+        assert_eq!(upserts[0].address.ip, "10.1.2.3");
+    }
+
+    #[test]
+    fn test_browse_validates_metadata() {
+        let mut desk = service("Desk", "192.168.1.20");
+        desk.metadata.hostname = Some("Front desk".to_string());
+        desk.metadata.controllers = 1;
+        let source: IpAddr = "192.168.1.20".parse().unwrap();
+
+        let mut browse = browse();
+        let (ptr, records) = service_records(&desk, 120);
+        let (upserts, _) = browse.update(&Message::response(vec![ptr], records), source);
+        assert_eq!(upserts[0].metadata, desk.metadata);
+
+        // A malformed TXT update withdraws the listing
+        let txt = Record::unique(Name::child("Desk", &service_type_name(&ServiceType::Server)), 120, RData::Txt(vec!["ctl=many".to_string()]));
+        let (upserts, removals) = browse.update(&Message::response(vec![txt], Vec::new()), source);
+        assert!(upserts.is_empty());
+        assert_eq!(removals, vec![desk.id]);
+    }
+}
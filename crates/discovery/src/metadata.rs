@@ -0,0 +1,251 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Service metadata carried in the TXT record
+//!
+//! ```text
+//! txtvers=1
+//! host=Lab workstation
+//! pv=1.1.0-1.2.0
+//! caps=video,input,clipboard
+//! codecs=h264,vp9
+//! disp=2560x1440,1920x1080
+//! ctl=1
+//! fp=9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+//! ```
+//!
+//! Keys are short and compare case-insensitively, and a repeated key is
+//! ignored (RFC 6763 6.4). Every key is optional; unknown keys, capability
+//! kinds and codecs are skipped so newer peers can add to the schema. A known
+//! key with a malformed value makes the whole record invalid.
+
+use serde::{Deserialize, Serialize};
+use soft_kvm_core::*;
+
+/// Version of the TXT schema
+pub const TXT_VERSION: u32 = 1;
+
+/// Longest announced hostname, in bytes
+const MAX_HOSTNAME_LEN: usize = 63;
+
+const CAPABILITY_NAMES: [(CapabilityKind, &str); 5] = [
+    (CapabilityKind::Video, "video"),
+    (CapabilityKind::Input, "input"),
+    (CapabilityKind::Clipboard, "clipboard"),
+    (CapabilityKind::Metrics, "metrics"),
+    (CapabilityKind::Discovery, "discovery"),
+];
+
+const CODEC_NAMES: [(VideoCodec, &str); 7] = [
+    (VideoCodec::H264, "h264"),
+    (VideoCodec::H265, "h265"),
+    (VideoCodec::Vp8, "vp8"),
+    (VideoCodec::Vp9, "vp9"),
+    (VideoCodec::Av1, "av1"),
+    (VideoCodec::Mjpeg, "mjpeg"),
+    (VideoCodec::Raw, "raw"),
+];
+
+/// What a service announces about itself besides its address
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceMetadata {
+    /// Human-friendly name of the host
+    pub hostname: Option<String>,
+    /// Oldest protocol version spoken, if it differs from `protocol_version`
+    pub min_protocol_version: Option<String>,
+    /// Newest protocol version spoken
+    pub protocol_version: Option<String>,
+    /// Capability kinds offered
+    pub capabilities: Vec<CapabilityKind>,
+    /// Video codecs, in order of preference
+    pub codecs: Vec<VideoCodec>,
+    /// Resolution of each display, primary first
+    pub displays: Vec<VideoResolution>,
+    /// Viewers currently in control; non-zero means the host is busy
+    pub controllers: u32,
+    /// SHA-256 of the TLS certificate, as lowercase hex
+    pub cert_fingerprint: Option<String>,
+}
+
+impl ServiceMetadata {
+    /// Encode as TXT entries
+    pub fn to_txt(&self) -> Vec<String> {
+        let mut entries = vec![format!("txtvers={}", TXT_VERSION)];
+        if let Some(hostname) = &self.hostname {
+            entries.push(format!("host={}", hostname));
+        }
+        if let Some(max) = &self.protocol_version {
+            match &self.min_protocol_version {
+                Some(min) if min != max => entries.push(format!("pv={}-{}", min, max)),
+                _ => entries.push(format!("pv={}", max)),
+            }
+        }
+        if !self.capabilities.is_empty() {
+            entries.push(format!("caps={}", join(&self.capabilities, &CAPABILITY_NAMES)));
+        }
+        if !self.codecs.is_empty() {
+            entries.push(format!("codecs={}", join(&self.codecs, &CODEC_NAMES)));
+        }
+        if !self.displays.is_empty() {
+            let displays: Vec<String> = self.displays.iter().map(|d| format!("{}x{}", d.width, d.height)).collect();
+            entries.push(format!("disp={}", displays.join(",")));
+        }
+        entries.push(format!("ctl={}", self.controllers));
+        if let Some(fingerprint) = &self.cert_fingerprint {
+            entries.push(format!("fp={}", fingerprint));
+        }
+        entries
+    }
+
+    /// Parse and validate TXT entries
+    pub fn from_txt(entries: &[String]) -> KvmResult<Self> {
+        let mut metadata = ServiceMetadata::default();
+        let mut seen = Vec::new();
+
+        for entry in entries {
+            let (key, value) = entry.split_once('=').unwrap_or((entry, ""));
+            let key = key.to_ascii_lowercase();
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key.clone());
+
+            match key.as_str() {
+                "txtvers" => {
+                    let version: u32 = parse_number(&key, value)?;
+                    if version != TXT_VERSION {
+                        return Err(invalid(&key, value));
+                    }
+                }
+                "host" => {
+                    if value.is_empty() || value.len() > MAX_HOSTNAME_LEN || value.chars().any(char::is_control) {
+                        return Err(invalid(&key, value));
+                    }
+                    metadata.hostname = Some(value.to_string());
+                }
+                "pv" => {
+                    let (min, max) = value.split_once('-').unwrap_or((value, value));
+                    if !is_version(min) || !is_version(max) {
+                        return Err(invalid(&key, value));
+                    }
+                    metadata.min_protocol_version = (min != max).then(|| min.to_string());
+                    metadata.protocol_version = Some(max.to_string());
+                }
+                "caps" => metadata.capabilities = split(value, &CAPABILITY_NAMES),
+                "codecs" => metadata.codecs = split(value, &CODEC_NAMES),
+                "disp" => {
+                    metadata.displays = value.split(',')
+                        .filter(|display| !display.is_empty())
+                        .map(|display| parse_resolution(display).ok_or_else(|| invalid(&key, value)))
+                        .collect::<KvmResult<_>>()?;
+                }
+                "ctl" => metadata.controllers = parse_number(&key, value)?,
+                "fp" => {
+                    let fingerprint = value.replace(':', "").to_ascii_lowercase();
+                    if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(invalid(&key, value));
+                    }
+                    metadata.cert_fingerprint = Some(fingerprint);
+                }
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+fn invalid(key: &str, value: &str) -> KvmError {
+    KvmError::Discovery(format!("Invalid TXT entry {}={}", key, value))
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> KvmResult<T> {
+    value.parse().map_err(|_| invalid(key, value))
+}
+
+/// `major.minor.patch`
+fn is_version(version: &str) -> bool {
+    let parts: Vec<&str> = version.split('.').collect();
+    parts.len() == 3 && parts.iter().all(|part| !part.is_empty() && part.parse::<u32>().is_ok())
+}
+
+/// `<width>x<height>`
+fn parse_resolution(display: &str) -> Option<VideoResolution> {
+    let (width, height) = display.split_once('x')?;
+    let resolution = VideoResolution {
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+    };
+    (resolution.width > 0 && resolution.height > 0).then_some(resolution)
+}
+
+fn join<T: PartialEq>(items: &[T], names: &[(T, &str)]) -> String {
+    let names: Vec<&str> = items.iter()
+        .filter_map(|item| names.iter().find(|(known, _)| known == item).map(|(_, name)| *name))
+        .collect();
+    names.join(",")
+}
+
+/// Known names in a comma-separated list; unknown ones are skipped
+fn split<T: Copy + PartialEq>(value: &str, names: &[(T, &str)]) -> Vec<T> {
+    let mut items = Vec::new();
+    for name in value.split(',') {
+        if let Some((item, _)) = names.iter().find(|(_, known)| known.eq_ignore_ascii_case(name.trim())) {
+            if !items.contains(item) {
+                items.push(*item);
+            }
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_txt_round_trip() {
+        let metadata = ServiceMetadata {
+            hostname: Some("Lab workstation".to_string()),
+            min_protocol_version: Some("1.0.0".to_string()),
+            protocol_version: Some("1.2.0".to_string()),
+            capabilities: vec![CapabilityKind::Video, CapabilityKind::Input],
+            codecs: vec![VideoCodec::H264, VideoCodec::Vp9],
+            displays: vec![VideoResolution { width: 2560, height: 1440 }, VideoResolution::fhd()],
+            controllers: 1,
+            cert_fingerprint: Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string()),
+        };
+        let txt = metadata.to_txt();
+        assert!(txt.contains(&"pv=1.0.0-1.2.0".to_string()));
+        assert!(txt.contains(&"disp=2560x1440,1920x1080".to_string()));
+        assert_eq!(ServiceMetadata::from_txt(&txt).unwrap(), metadata);
+
+        // Entries from newer peers and repeated keys are skipped
+        let entries: Vec<String> = ["CAPS=video,teleport", "caps=input", "ctl=0", "color=blue", "pv=1.1.0"]
+            .iter().map(|entry| entry.to_string()).collect();
+        let parsed = ServiceMetadata::from_txt(&entries).unwrap();
+        assert_eq!(parsed.capabilities, vec![CapabilityKind::Video]);
+        assert_eq!((parsed.min_protocol_version, parsed.protocol_version.as_deref()), (None, Some("1.1.0")));
+    }
+
+    #[test]
+    fn test_malformed_entries_are_rejected() {
+        for entry in ["txtvers=2", "ctl=busy", "pv=1.2", "pv=1.0.0-", "disp=1920x", "disp=0x1080", "fp=abc123", "host="] {
+            assert!(ServiceMetadata::from_txt(&[entry.to_string()]).is_err(), "{}", entry);
+        }
+        // Fingerprints may be written with colons
+        let fingerprint = "9F:86:D0:81:88:4C:7D:65:9A:2F:EA:A0:C5:5A:D0:15:A3:BF:4F:1B:2B:0B:82:2C:D1:5D:6C:15:B0:F0:0A:08";
+        let parsed = ServiceMetadata::from_txt(&[format!("fp={}", fingerprint)]).unwrap();
+        assert_eq!(parsed.cert_fingerprint.unwrap(), "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08");
+    }
+}
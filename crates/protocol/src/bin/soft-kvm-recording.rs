@@ -0,0 +1,90 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dump session recordings as JSON lines
+//!
+//! ```text
+//! soft-kvm-recording <file> [--type <MessageType>]... [--direction sent|received]
+//!                           [--session <id>] [--from <ms>] [--until <ms>]
+//! ```
+
+use soft_kvm_protocol::messages::MessageType;
+use soft_kvm_protocol::recording::{Recording, RecordingFilter};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "usage: soft-kvm-recording <file> [--type <MessageType>]... [--direction sent|received] \
+                     [--session <id>] [--from <ms>] [--until <ms>]";
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut path = None;
+    let mut filter = RecordingFilter::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--type" => {
+                let name = value()?;
+                let message_type: MessageType = serde_json::from_value(serde_json::Value::String(name.clone()))
+                    .map_err(|_| format!("Unknown message type: {}", name))?;
+                filter.message_types.push(message_type);
+            }
+            "--direction" => filter.direction = Some(value()?.parse().map_err(|e| format!("{}", e))?),
+            "--session" => filter.session_id = Some(value()?),
+            "--from" => filter.from = Some(millis(&value()?)?),
+            "--until" => filter.until = Some(millis(&value()?)?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let path = path.ok_or_else(|| USAGE.to_string())?;
+    let recording = Recording::open(&path).map_err(|e| e.to_string())?;
+    eprintln!(
+        "Recording of {} started {}, {} messages",
+        recording.peer.as_deref().unwrap_or("unknown peer"),
+        recording.started_at,
+        recording.messages.len()
+    );
+
+    let mut out = std::io::stdout().lock();
+    for record in recording.messages.iter().filter(|record| filter.matches(record)) {
+        serde_json::to_writer(&mut out, record).map_err(|e| e.to_string())?;
+        // Stop quietly when the output is closed, e.g. piped into head
+        if writeln!(out).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn millis(value: &str) -> Result<Duration, String> {
+    value.parse().map(Duration::from_millis).map_err(|_| format!("Expected milliseconds, got {}", value))
+}
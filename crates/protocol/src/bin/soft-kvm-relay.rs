@@ -0,0 +1,99 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relay viewers on one LAN segment to servers on another
+//!
+//! ```text
+//! soft-kvm-relay --route <name> <listen> <server>... --allow <ip>...
+//!                [--viewer-cert <pem> --viewer-key <pem>] [--server-tls] [--server-ca <pem>]
+//!                [--no-announce]
+//! ```
+
+use soft_kvm_core::ServiceType;
+use soft_kvm_discovery::ServiceResolver;
+use soft_kvm_protocol::relay::{Relay, RelayConfig, RelayRoute};
+use soft_kvm_protocol::websocket::WebSocketFactory;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "usage: soft-kvm-relay --route <name> <listen> <server>... --allow <ip>... \
+                     [--viewer-cert <pem> --viewer-key <pem>] [--server-tls] [--server-ca <pem>] [--no-announce]";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(std::env::args().skip(1)).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut config = RelayConfig::default();
+    let mut announce = true;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--route" => {
+                let name = value()?;
+                let listen = value()?;
+                let server = value()?;
+                config.routes.push(RelayRoute {
+                    name,
+                    listen: listen.parse().map_err(|_| format!("Invalid listen address: {}", listen))?,
+                    server: server.parse().map_err(|_| format!("Invalid server address: {}", server))?,
+                });
+            }
+            "--allow" => {
+                let ip = value()?;
+                config.allowed_viewers.push(ip.parse().map_err(|_| format!("Invalid viewer address: {}", ip))?);
+            }
+            "--viewer-cert" => {
+                config.viewer_transport.tls.enabled = true;
+                config.viewer_transport.tls.certificate_path = Some(value()?);
+            }
+            "--viewer-key" => config.viewer_transport.tls.private_key_path = Some(value()?),
+            "--server-tls" => config.server_transport.tls.enabled = true,
+            "--server-ca" => {
+                config.server_transport.tls.enabled = true;
+                config.server_transport.tls.ca_certificate_path = Some(value()?);
+            }
+            "--no-announce" => announce = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => return Err(format!("Unexpected argument: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let factory = Arc::new(WebSocketFactory::new(config.viewer_transport.clone()));
+    let mut relay = Relay::new(config, factory).map_err(|e| format!("{}\n{}", e, USAGE))?;
+    relay.start().await.map_err(|e| e.to_string())?;
+
+    let resolver = ServiceResolver::new(ServiceType::Server);
+    if announce {
+        relay.announce(&resolver).await.map_err(|e| e.to_string())?;
+    }
+
+    tokio::signal::ctrl_c().await.map_err(|e| e.to_string())?;
+
+    if announce {
+        relay.withdraw(&resolver).await.map_err(|e| e.to_string())?;
+    }
+    relay.stop().await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,687 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Wire codecs for protocol messages
+//!
+//! Two codecs are available:
+//!
+//! * [`BinaryCodec`] - compact, versioned framing used in production
+//! * [`JsonCodec`] - human readable framing kept for debugging
+//!
+//! Every connection starts with JSON so that Hello/Welcome can be read by any
+//! peer, then switches to the codec selected in the Welcome message.
+//! [`decode_any`] detects the codec from the first bytes of a frame, so the
+//! switch does not need to be synchronized between peers.
+//!
+//! Binary frame layout (all integers big endian):
+//!
+//! ```text
+//! offset size  field
+//! 0      2     magic "SK"
+//! 2      1     wire version
+//! 3      1     flags (bit 0: compressed, bit 1: session id present,
+//!              bit 2: correlation id present)
+//! 4      1     message type
+//! 5      1     payload tag
+//! 6      8     timestamp (microseconds since UNIX epoch)
+//! 14     1     message id length, followed by the message id
+//! ..     1     session id length, followed by the session id (if flagged)
+//! ..     1     correlation id length, followed by the correlation id (if flagged)
+//! ..     4     payload length, followed by the payload
+//! ```
+//!
+//! Video frames and clipboard data carry their bytes raw at the end of the
+//! payload; the remaining control payloads are small and are embedded as JSON.
+//! A compressed payload is prefixed as described in [`crate::compression`].
+
+use crate::messages::{
+    ClipboardPayload, HeartbeatPayload, MessageHeader, MessagePayload, MessageType, PingPayload, PongPayload,
+    ProtocolMessage,
+    VideoFramePayload,
+};
+use crate::compression::{self, CompressionKind, Compressor};
+use crate::{ProtocolError, ProtocolResult};
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Magic bytes at the start of every binary frame
+pub const BINARY_MAGIC: [u8; 2] = *b"SK";
+
+/// Current binary wire format version
+pub const BINARY_WIRE_VERSION: u8 = 1;
+
+/// Size of the fixed part of the binary header
+const FIXED_HEADER_LEN: usize = 14;
+
+const FLAG_COMPRESSED: u8 = 0b0000_0001;
+const FLAG_SESSION_ID: u8 = 0b0000_0010;
+const FLAG_CORRELATION_ID: u8 = 0b0000_0100;
+
+/// Codec identifiers exchanged during Hello/Welcome
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+pub enum CodecKind {
+    /// Compact binary framing
+    Binary,
+    /// JSON framing, understood by every peer
+    #[default]
+    Json,
+}
+
+impl CodecKind {
+    /// Codecs supported by this implementation, in order of preference
+    pub fn supported() -> Vec<CodecKind> {
+        vec![CodecKind::Binary, CodecKind::Json]
+    }
+
+    /// Pick the first codec offered by the peer that we also support.
+    ///
+    /// Falls back to JSON, which every peer is required to understand.
+    pub fn negotiate(offered: &[CodecKind], supported: &[CodecKind]) -> CodecKind {
+        offered
+            .iter()
+            .find(|codec| supported.contains(codec))
+            .copied()
+            .unwrap_or(CodecKind::Json)
+    }
+
+    /// Get a codec instance for this kind
+    pub fn codec(&self) -> Arc<dyn MessageCodec> {
+        match self {
+            CodecKind::Binary => Arc::new(BinaryCodec),
+            CodecKind::Json => Arc::new(JsonCodec),
+        }
+    }
+
+    /// Get a codec instance that compresses payloads of at least `threshold`
+    /// bytes. Only the binary codec compresses.
+    pub fn codec_with(&self, compression: Option<CompressionKind>, threshold: usize) -> Arc<dyn MessageCodec> {
+        match (self, compression) {
+            (CodecKind::Binary, Some(kind)) => Arc::new(CompressingCodec::new(kind, threshold)),
+            _ => self.codec(),
+        }
+    }
+}
+
+/// Message codec trait
+pub trait MessageCodec: Send + Sync {
+    /// Codec identifier
+    fn kind(&self) -> CodecKind;
+
+    /// Encode a message into a single frame
+    fn encode(&self, message: &ProtocolMessage) -> ProtocolResult<Vec<u8>>;
+
+    /// Decode a single frame into a message
+    fn decode(&self, data: &[u8]) -> ProtocolResult<ProtocolMessage>;
+}
+
+/// Decode a frame produced by any supported codec
+pub fn decode_any(data: &[u8]) -> ProtocolResult<ProtocolMessage> {
+    decode_any_with_limit(data, compression::MAX_DECOMPRESSED_SIZE)
+}
+
+/// Decode a frame produced by any supported codec, letting a compressed
+/// payload expand to at most `limit` bytes
+pub fn decode_any_with_limit(data: &[u8], limit: usize) -> ProtocolResult<ProtocolMessage> {
+    if data.starts_with(&BINARY_MAGIC) {
+        decode_binary(data, limit)
+    } else {
+        JsonCodec.decode(data)
+    }
+}
+
+/// JSON codec (debug)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl MessageCodec for JsonCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Json
+    }
+
+    fn encode(&self, message: &ProtocolMessage) -> ProtocolResult<Vec<u8>> {
+        // Written by hand so the header can carry the payload size
+        let payload = serde_json::to_vec(&message.payload)?;
+        let header = MessageHeader {
+            payload_size: payload.len(),
+            compression: false,
+            ..message.header.clone()
+        };
+
+        let mut buf = Vec::with_capacity(payload.len() + 256);
+        buf.extend_from_slice(b"{\"header\":");
+        serde_json::to_writer(&mut buf, &header)?;
+        buf.extend_from_slice(b",\"payload\":");
+        buf.extend_from_slice(&payload);
+        buf.push(b'}');
+        Ok(buf)
+    }
+
+    fn decode(&self, data: &[u8]) -> ProtocolResult<ProtocolMessage> {
+        let message: ProtocolMessage = serde_json::from_slice(data)?;
+        check_payload_type(&message.header.message_type, &message.payload.message_type())?;
+        Ok(message)
+    }
+}
+
+/// Compact binary codec
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryCodec;
+
+impl MessageCodec for BinaryCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Binary
+    }
+
+    fn encode(&self, message: &ProtocolMessage) -> ProtocolResult<Vec<u8>> {
+        encode_binary(message, None)
+    }
+
+    fn decode(&self, data: &[u8]) -> ProtocolResult<ProtocolMessage> {
+        decode_binary(data, compression::MAX_DECOMPRESSED_SIZE)
+    }
+}
+
+/// Binary codec that compresses eligible payloads
+pub struct CompressingCodec {
+    compressor: Arc<dyn Compressor>,
+    threshold: usize,
+}
+
+impl CompressingCodec {
+    /// Create a codec compressing payloads of at least `threshold` bytes
+    pub fn new(kind: CompressionKind, threshold: usize) -> Self {
+        CompressingCodec {
+            compressor: kind.compressor(),
+            threshold,
+        }
+    }
+}
+
+impl MessageCodec for CompressingCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Binary
+    }
+
+    fn encode(&self, message: &ProtocolMessage) -> ProtocolResult<Vec<u8>> {
+        encode_binary(message, Some(self))
+    }
+
+    fn decode(&self, data: &[u8]) -> ProtocolResult<ProtocolMessage> {
+        decode_binary(data, compression::MAX_DECOMPRESSED_SIZE)
+    }
+}
+
+/// Encode a binary frame, compressing the payload if `compression` allows.
+///
+/// A message is compressed if its header asks for it or its payload is
+/// compressible and reaches the threshold.
+fn encode_binary(message: &ProtocolMessage, compression: Option<&CompressingCodec>) -> ProtocolResult<Vec<u8>> {
+    let mut payload = encode_payload(&message.payload)?;
+    let header = &message.header;
+
+    let mut flags = 0;
+    if let Some(codec) = compression {
+        let eligible = header.compression
+            || (payload.len() >= codec.threshold && compression::is_compressible(&message.payload));
+        if eligible {
+            if let Some(compressed) = compression::compress_payload(codec.compressor.as_ref(), &payload)? {
+                payload = compressed;
+                flags |= FLAG_COMPRESSED;
+            }
+        }
+    }
+    if header.session_id.is_some() {
+        flags |= FLAG_SESSION_ID;
+    }
+    if header.correlation_id.is_some() {
+        flags |= FLAG_CORRELATION_ID;
+    }
+
+    let mut buf = Vec::with_capacity(FIXED_HEADER_LEN + 64 + payload.len());
+    buf.extend_from_slice(&BINARY_MAGIC);
+    buf.push(BINARY_WIRE_VERSION);
+    buf.push(flags);
+    buf.push(header.message_type.wire_id());
+    buf.push(message.payload.message_type().wire_id());
+    buf.extend_from_slice(&header.timestamp.timestamp_micros().to_be_bytes());
+    put_short_str(&mut buf, &header.message_id)?;
+    if let Some(session_id) = &header.session_id {
+        put_short_str(&mut buf, session_id)?;
+    }
+    if let Some(correlation_id) = &header.correlation_id {
+        put_short_str(&mut buf, correlation_id)?;
+    }
+    let payload_len = u32::try_from(payload.len())
+        .map_err(|_| ProtocolError::Codec(format!("Payload too large: {} bytes", payload.len())))?;
+    buf.extend_from_slice(&payload_len.to_be_bytes());
+    buf.extend_from_slice(&payload);
+
+    Ok(buf)
+}
+
+/// Decode a binary frame; compressed payloads may expand to at most `limit` bytes
+fn decode_binary(data: &[u8], limit: usize) -> ProtocolResult<ProtocolMessage> {
+    let mut reader = WireReader::new(data);
+
+    if reader.bytes(2)? != BINARY_MAGIC {
+        return Err(ProtocolError::Codec("Invalid frame magic".to_string()));
+    }
+    let version = reader.u8()?;
+    if version != BINARY_WIRE_VERSION {
+        return Err(ProtocolError::Codec(format!("Unsupported wire version {}", version)));
+    }
+    let flags = reader.u8()?;
+    let message_type = MessageType::from_wire_id(reader.u8()?)?;
+    let payload_tag = MessageType::from_wire_id(reader.u8()?)?;
+    check_payload_type(&message_type, &payload_tag)?;
+    let micros = reader.i64()?;
+    let timestamp = chrono::Utc
+        .timestamp_micros(micros)
+        .single()
+        .ok_or_else(|| ProtocolError::Codec(format!("Invalid timestamp {}", micros)))?;
+    let message_id = reader.short_str()?;
+    let session_id = if flags & FLAG_SESSION_ID != 0 {
+        Some(reader.short_str()?)
+    } else {
+        None
+    };
+    let correlation_id = if flags & FLAG_CORRELATION_ID != 0 {
+        Some(reader.short_str()?)
+    } else {
+        None
+    };
+    let payload_len = reader.u32()? as usize;
+    let payload_bytes = reader.bytes(payload_len)?;
+    if !reader.is_empty() {
+        return Err(ProtocolError::Codec("Trailing bytes after payload".to_string()));
+    }
+    let compressed = flags & FLAG_COMPRESSED != 0;
+    let inflated;
+    let payload_bytes = if compressed {
+        inflated = compression::decompress_payload(payload_bytes, limit)?;
+        &inflated[..]
+    } else {
+        payload_bytes
+    };
+
+    let header = MessageHeader {
+        message_type,
+        message_id,
+        timestamp,
+        session_id,
+        correlation_id,
+        compression: compressed,
+        payload_size: payload_bytes.len(),
+    };
+
+    Ok(ProtocolMessage {
+        header,
+        payload: decode_payload(&payload_tag, payload_bytes)?,
+    })
+}
+
+/// Routing and queueing go by the header's type, so it has to name the payload
+fn check_payload_type(message_type: &MessageType, payload_type: &MessageType) -> ProtocolResult<()> {
+    if message_type != payload_type {
+        return Err(ProtocolError::Codec(format!("{:?} frame carries a {:?} payload", message_type, payload_type)));
+    }
+    Ok(())
+}
+
+/// Encode a payload body
+fn encode_payload(payload: &MessagePayload) -> ProtocolResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    match payload {
+        MessagePayload::VideoFrame(frame) => {
+            buf.reserve(32 + frame.format.len() + frame.data.len());
+            buf.extend_from_slice(&frame.frame_number.to_be_bytes());
+            buf.extend_from_slice(&frame.timestamp.to_be_bytes());
+            buf.extend_from_slice(&frame.width.to_be_bytes());
+            buf.extend_from_slice(&frame.height.to_be_bytes());
+            put_short_str(&mut buf, &frame.format)?;
+            buf.extend_from_slice(&frame.data);
+        }
+        MessagePayload::ClipboardData(clipboard) => {
+            buf.reserve(1 + clipboard.data_type.len() + clipboard.data.len());
+            put_short_str(&mut buf, &clipboard.data_type)?;
+            buf.extend_from_slice(&clipboard.data);
+        }
+        MessagePayload::Heartbeat(heartbeat) => {
+            buf.extend_from_slice(&heartbeat.sequence_number.to_be_bytes());
+        }
+        MessagePayload::Ping(ping) => {
+            buf.extend_from_slice(&ping.originate.to_be_bytes());
+        }
+        MessagePayload::Pong(pong) => {
+            buf.extend_from_slice(&pong.originate.to_be_bytes());
+            buf.extend_from_slice(&pong.receive.to_be_bytes());
+            buf.extend_from_slice(&pong.transmit.to_be_bytes());
+        }
+        MessagePayload::VideoStop | MessagePayload::MetricsRequest => {}
+        MessagePayload::Hello(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::Welcome(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::Goodbye(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::Error(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::AuthRequest(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::AuthResponse(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::VideoStart(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::InputEvent(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::ServiceAnnouncement(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::ServiceQuery(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::ServiceResponse(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::MetricsResponse(p) => serde_json::to_writer(&mut buf, p)?,
+        MessagePayload::Control(p) => serde_json::to_writer(&mut buf, p)?,
+    }
+    Ok(buf)
+}
+
+/// Decode a payload body
+fn decode_payload(tag: &MessageType, data: &[u8]) -> ProtocolResult<MessagePayload> {
+    let payload = match tag {
+        MessageType::VideoFrame => {
+            let mut reader = WireReader::new(data);
+            let frame_number = reader.u64()?;
+            let timestamp = reader.u64()?;
+            let width = reader.u32()?;
+            let height = reader.u32()?;
+            let format = reader.short_str()?;
+            MessagePayload::VideoFrame(VideoFramePayload {
+                frame_number,
+                timestamp,
+                width,
+                height,
+                format,
+                data: reader.rest().to_vec(),
+            })
+        }
+        MessageType::ClipboardData => {
+            let mut reader = WireReader::new(data);
+            let data_type = reader.short_str()?;
+            MessagePayload::ClipboardData(ClipboardPayload {
+                data_type,
+                data: reader.rest().to_vec(),
+            })
+        }
+        MessageType::Heartbeat => {
+            let mut reader = WireReader::new(data);
+            MessagePayload::Heartbeat(HeartbeatPayload {
+                sequence_number: reader.u64()?,
+            })
+        }
+        MessageType::VideoStop => MessagePayload::VideoStop,
+        MessageType::MetricsRequest => MessagePayload::MetricsRequest,
+        MessageType::Ping => {
+            let mut reader = WireReader::new(data);
+            MessagePayload::Ping(PingPayload { originate: reader.i64()? })
+        }
+        MessageType::Pong => {
+            let mut reader = WireReader::new(data);
+            MessagePayload::Pong(PongPayload {
+                originate: reader.i64()?,
+                receive: reader.i64()?,
+                transmit: reader.i64()?,
+            })
+        }
+        MessageType::Hello => MessagePayload::Hello(serde_json::from_slice(data)?),
+        MessageType::Welcome => MessagePayload::Welcome(serde_json::from_slice(data)?),
+        MessageType::Goodbye => MessagePayload::Goodbye(serde_json::from_slice(data)?),
+        MessageType::Error => MessagePayload::Error(serde_json::from_slice(data)?),
+        MessageType::AuthRequest => MessagePayload::AuthRequest(serde_json::from_slice(data)?),
+        MessageType::AuthResponse => MessagePayload::AuthResponse(serde_json::from_slice(data)?),
+        MessageType::VideoStart => MessagePayload::VideoStart(serde_json::from_slice(data)?),
+        MessageType::InputEvent => MessagePayload::InputEvent(serde_json::from_slice(data)?),
+        MessageType::ServiceAnnouncement => MessagePayload::ServiceAnnouncement(serde_json::from_slice(data)?),
+        MessageType::ServiceQuery => MessagePayload::ServiceQuery(serde_json::from_slice(data)?),
+        MessageType::ServiceResponse => MessagePayload::ServiceResponse(serde_json::from_slice(data)?),
+        MessageType::MetricsResponse => MessagePayload::MetricsResponse(serde_json::from_slice(data)?),
+        MessageType::Control => MessagePayload::Control(serde_json::from_slice(data)?),
+    };
+    Ok(payload)
+}
+
+/// Append a string prefixed with a one byte length
+fn put_short_str(buf: &mut Vec<u8>, value: &str) -> ProtocolResult<()> {
+    let len = u8::try_from(value.len())
+        .map_err(|_| ProtocolError::Codec(format!("String too long for header field: {} bytes", value.len())))?;
+    buf.push(len);
+    buf.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+/// Bounds-checked reader over a binary frame
+struct WireReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> WireReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        WireReader { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> ProtocolResult<&'a [u8]> {
+        let end = self.pos.checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| ProtocolError::Codec(format!(
+                "Truncated frame: need {} bytes at offset {}, have {}",
+                len,
+                self.pos,
+                self.data.len()
+            )))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> ProtocolResult<[u8; N]> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.bytes(N)?);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> ProtocolResult<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> ProtocolResult<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> ProtocolResult<u64> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> ProtocolResult<i64> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    fn short_str(&mut self) -> ProtocolResult<String> {
+        let len = self.u8()? as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|e| ProtocolError::Codec(format!("Invalid UTF-8 in header field: {}", e)))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video_frame(len: usize) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::VideoFrame,
+            MessagePayload::VideoFrame(VideoFramePayload {
+                frame_number: 7,
+                timestamp: 123_456,
+                width: 1920,
+                height: 1080,
+                format: "h264".to_string(),
+                data: (0..len).map(|i| (i % 251) as u8).collect(),
+            }),
+        )
+        .with_session("session-1".to_string())
+    }
+
+    #[test]
+    fn test_binary_round_trip_video_frame() {
+        let message = video_frame(64 * 1024);
+        let encoded = BinaryCodec.encode(&message).unwrap();
+        let decoded = BinaryCodec.decode(&encoded).unwrap();
+
+        assert_eq!(decoded.message_type(), &MessageType::VideoFrame);
+        assert_eq!(decoded.header.message_id, message.header.message_id);
+        assert_eq!(decoded.session_id(), Some(&"session-1".to_string()));
+        assert!(decoded.correlation_id().is_none());
+        assert_eq!(
+            decoded.header.timestamp.timestamp_micros(),
+            message.header.timestamp.timestamp_micros()
+        );
+        match (decoded.payload, message.payload) {
+            (MessagePayload::VideoFrame(got), MessagePayload::VideoFrame(want)) => {
+                assert_eq!(got.frame_number, want.frame_number);
+                assert_eq!(got.format, want.format);
+                assert_eq!(got.data, want.data);
+            }
+            _ => panic!("unexpected payload"),
+        }
+    }
+
+    #[test]
+    fn test_correlation_id_round_trips() {
+        let request = ProtocolMessage::new(MessageType::Ping, MessagePayload::Ping(PingPayload { originate: 1 }))
+            .with_session("session-1".to_string());
+        let reply = request.reply(MessageType::Pong, MessagePayload::Pong(PongPayload::answer(1, 2)));
+
+        for kind in CodecKind::supported() {
+            let codec = kind.codec();
+            let decoded = codec.decode(&codec.encode(&reply).unwrap()).unwrap();
+            assert_eq!(decoded.correlation_id(), Some(request.header.message_id.as_str()));
+            assert_eq!(decoded.session_id(), Some(&"session-1".to_string()));
+            assert!(matches!(decoded.payload, MessagePayload::Pong(PongPayload { originate: 1, receive: 2, .. })));
+        }
+    }
+
+    #[test]
+    fn test_binary_is_smaller_than_json_for_frames() {
+        let message = video_frame(64 * 1024);
+        let binary = BinaryCodec.encode(&message).unwrap();
+        let json = JsonCodec.encode(&message).unwrap();
+
+        assert!(binary.len() < 64 * 1024 + 128);
+        assert!(json.len() > binary.len() * 2);
+    }
+
+    #[test]
+    fn test_decode_any_detects_codec() {
+        let message = ProtocolMessage::new(
+            MessageType::Heartbeat,
+            MessagePayload::Heartbeat(HeartbeatPayload { sequence_number: 9 }),
+        );
+
+        for codec in [CodecKind::Binary, CodecKind::Json] {
+            let encoded = codec.codec().encode(&message).unwrap();
+            let decoded = decode_any(&encoded).unwrap();
+            assert!(matches!(decoded.payload, MessagePayload::Heartbeat(HeartbeatPayload { sequence_number: 9 })));
+        }
+    }
+
+    #[test]
+    fn test_truncated_frame_is_rejected() {
+        let encoded = BinaryCodec.encode(&video_frame(16)).unwrap();
+        for len in [0, 5, FIXED_HEADER_LEN, encoded.len() - 40] {
+            assert!(matches!(BinaryCodec.decode(&encoded[..len]), Err(ProtocolError::Codec(_))));
+        }
+    }
+
+    #[test]
+    fn test_payload_must_match_message_type() {
+        let mut message = ProtocolMessage::new(
+            MessageType::Heartbeat,
+            MessagePayload::Ping(PingPayload { originate: 1 }),
+        );
+        for codec in [CodecKind::Binary, CodecKind::Json] {
+            let encoded = codec.codec().encode(&message).unwrap();
+            assert!(matches!(codec.codec().decode(&encoded), Err(ProtocolError::Codec(_))));
+            assert!(matches!(decode_any(&encoded), Err(ProtocolError::Codec(_))));
+        }
+
+        message.header.message_type = MessageType::Ping;
+        for codec in [CodecKind::Binary, CodecKind::Json] {
+            assert!(decode_any(&codec.codec().encode(&message).unwrap()).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_codec_negotiation() {
+        let supported = CodecKind::supported();
+        assert_eq!(CodecKind::negotiate(&[CodecKind::Binary, CodecKind::Json], &supported), CodecKind::Binary);
+        assert_eq!(CodecKind::negotiate(&[CodecKind::Json], &supported), CodecKind::Json);
+        assert_eq!(CodecKind::negotiate(&[], &supported), CodecKind::Json);
+    }
+
+    #[test]
+    fn test_compressing_codec_picks_eligible_payloads() {
+        let codec = CodecKind::Binary.codec_with(Some(CompressionKind::Zstd), 1024);
+
+        // A raw frame is compressed and decodes transparently
+        let mut raw = video_frame(64 * 1024);
+        if let MessagePayload::VideoFrame(frame) = &mut raw.payload {
+            frame.format = "bgra".to_string();
+        }
+        let encoded = codec.encode(&raw).unwrap();
+        assert!(encoded.len() < 16 * 1024, "{} bytes", encoded.len());
+        let decoded = decode_any(&encoded).unwrap();
+        assert!(decoded.is_compressed());
+        assert_eq!(decoded.header.payload_size, encode_payload(&raw.payload).unwrap().len());
+        match decoded.payload {
+            MessagePayload::VideoFrame(frame) => assert_eq!(frame.data.len(), 64 * 1024),
+            other => panic!("unexpected payload {:?}", other),
+        }
+
+        // Already compressed video and small payloads are sent as is
+        assert!(!decode_any(&codec.encode(&video_frame(64 * 1024)).unwrap()).unwrap().is_compressed());
+        let small = ProtocolMessage::new(
+            MessageType::ClipboardData,
+            MessagePayload::ClipboardData(ClipboardPayload { data_type: "text".to_string(), data: vec![b'a'; 512] }),
+        );
+        assert!(!decode_any(&codec.encode(&small).unwrap()).unwrap().is_compressed());
+
+        // Unless the sender asks for it
+        let forced = small.with_compression(true);
+        assert!(decode_any(&codec.encode(&forced).unwrap()).unwrap().is_compressed());
+        // Without negotiated compression nothing is compressed
+        assert!(!decode_any(&BinaryCodec.encode(&forced).unwrap()).unwrap().is_compressed());
+    }
+
+    #[test]
+    fn test_payload_size_is_filled_in() {
+        let message = video_frame(4096);
+        let binary = BinaryCodec.decode(&BinaryCodec.encode(&message).unwrap()).unwrap();
+        assert_eq!(binary.header.payload_size, encode_payload(&message.payload).unwrap().len());
+
+        let json = JsonCodec.decode(&JsonCodec.encode(&message).unwrap()).unwrap();
+        assert_eq!(json.header.payload_size, serde_json::to_vec(&message.payload).unwrap().len());
+    }
+}
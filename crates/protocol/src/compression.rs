@@ -0,0 +1,240 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Payload compression
+//!
+//! The client offers its compressors in Hello and the server picks one in
+//! Welcome. From then on the binary codec compresses the payload of
+//! compressible messages (clipboard data, metrics, service responses and
+//! uncompressed video frames) once it reaches
+//! `ProtocolConfig::compression_threshold`, and of any message whose header
+//! asks for compression. A payload that doesn't shrink is sent as is.
+//!
+//! A compressed payload starts with the algorithm and the uncompressed size,
+//! so the receiver decompresses without knowing what was negotiated. A
+//! declared size above the limit is rejected before anything is inflated,
+//! and the decompressor never writes more than the declared size.
+//!
+//! The JSON codec is for debugging and never compresses.
+
+use crate::messages::MessagePayload;
+use crate::{ProtocolError, ProtocolResult};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Largest payload a compressed frame may inflate to
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Compressed payload prefix: algorithm and uncompressed size
+const PREFIX_LEN: usize = 5;
+
+/// zstd level; payloads are latency sensitive, so favour speed
+const ZSTD_LEVEL: i32 = 1;
+
+/// Video formats that are already compressed
+const COMPRESSED_VIDEO_FORMATS: &[&str] = &["h264", "h265", "hevc", "vp8", "vp9", "av1", "jpeg", "mjpeg", "png"];
+
+/// Compression algorithms exchanged during Hello/Welcome
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CompressionKind {
+    /// Better ratio, for clipboard and raw frames
+    Zstd,
+    /// Lower latency
+    Lz4,
+}
+
+impl CompressionKind {
+    /// Compressors supported by this implementation, in order of preference
+    pub fn supported() -> Vec<CompressionKind> {
+        vec![CompressionKind::Zstd, CompressionKind::Lz4]
+    }
+
+    /// Pick the first compressor offered by the peer that we also support
+    pub fn negotiate(offered: &[CompressionKind], supported: &[CompressionKind]) -> Option<CompressionKind> {
+        offered.iter().find(|kind| supported.contains(kind)).copied()
+    }
+
+    /// Get a compressor instance for this kind
+    pub fn compressor(&self) -> Arc<dyn Compressor> {
+        match self {
+            CompressionKind::Zstd => Arc::new(ZstdCompressor),
+            CompressionKind::Lz4 => Arc::new(Lz4Compressor),
+        }
+    }
+
+    fn wire_id(&self) -> u8 {
+        match self {
+            CompressionKind::Zstd => 1,
+            CompressionKind::Lz4 => 2,
+        }
+    }
+
+    fn from_wire_id(id: u8) -> ProtocolResult<Self> {
+        match id {
+            1 => Ok(CompressionKind::Zstd),
+            2 => Ok(CompressionKind::Lz4),
+            other => Err(ProtocolError::Codec(format!("Unknown compression algorithm {}", other))),
+        }
+    }
+}
+
+/// Payload compressor
+pub trait Compressor: Send + Sync {
+    /// Algorithm identifier
+    fn kind(&self) -> CompressionKind;
+
+    /// Compress a payload
+    fn compress(&self, data: &[u8]) -> ProtocolResult<Vec<u8>>;
+
+    /// Decompress a payload into at most `size` bytes
+    fn decompress(&self, data: &[u8], size: usize) -> ProtocolResult<Vec<u8>>;
+}
+
+/// zstd compressor
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdCompressor;
+
+impl Compressor for ZstdCompressor {
+    fn kind(&self) -> CompressionKind {
+        CompressionKind::Zstd
+    }
+
+    fn compress(&self, data: &[u8]) -> ProtocolResult<Vec<u8>> {
+        zstd::bulk::compress(data, ZSTD_LEVEL).map_err(|e| ProtocolError::Codec(format!("zstd compression failed: {}", e)))
+    }
+
+    fn decompress(&self, data: &[u8], size: usize) -> ProtocolResult<Vec<u8>> {
+        zstd::bulk::decompress(data, size).map_err(|e| ProtocolError::Codec(format!("zstd decompression failed: {}", e)))
+    }
+}
+
+/// LZ4 block compressor
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Compressor;
+
+impl Compressor for Lz4Compressor {
+    fn kind(&self) -> CompressionKind {
+        CompressionKind::Lz4
+    }
+
+    fn compress(&self, data: &[u8]) -> ProtocolResult<Vec<u8>> {
+        Ok(lz4_flex::block::compress(data))
+    }
+
+    fn decompress(&self, data: &[u8], size: usize) -> ProtocolResult<Vec<u8>> {
+        lz4_flex::block::decompress(data, size).map_err(|e| ProtocolError::Codec(format!("lz4 decompression failed: {}", e)))
+    }
+}
+
+/// Check if a payload is worth compressing
+pub fn is_compressible(payload: &MessagePayload) -> bool {
+    match payload {
+        MessagePayload::ClipboardData(_)
+        | MessagePayload::MetricsResponse(_)
+        | MessagePayload::ServiceResponse(_) => true,
+        MessagePayload::VideoFrame(frame) => {
+            !COMPRESSED_VIDEO_FORMATS.iter().any(|format| frame.format.eq_ignore_ascii_case(format))
+        }
+        _ => false,
+    }
+}
+
+/// Compress an encoded payload, or return `None` if it doesn't shrink
+pub(crate) fn compress_payload(compressor: &dyn Compressor, payload: &[u8]) -> ProtocolResult<Option<Vec<u8>>> {
+    let size = u32::try_from(payload.len())
+        .map_err(|_| ProtocolError::Codec(format!("Payload too large: {} bytes", payload.len())))?;
+    let compressed = compressor.compress(payload)?;
+    if compressed.len() + PREFIX_LEN >= payload.len() {
+        return Ok(None);
+    }
+
+    let mut buf = Vec::with_capacity(PREFIX_LEN + compressed.len());
+    buf.push(compressor.kind().wire_id());
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(&compressed);
+    Ok(Some(buf))
+}
+
+/// Decompress a payload produced by `compress_payload`
+pub(crate) fn decompress_payload(data: &[u8], limit: usize) -> ProtocolResult<Vec<u8>> {
+    if data.len() < PREFIX_LEN {
+        return Err(ProtocolError::Codec("Truncated compressed payload".to_string()));
+    }
+    let kind = CompressionKind::from_wire_id(data[0])?;
+    let size = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+    if size > limit {
+        return Err(ProtocolError::Codec(format!(
+            "Compressed payload expands to {} bytes, limit is {}",
+            size, limit
+        )));
+    }
+
+    let payload = kind.compressor().decompress(&data[PREFIX_LEN..], size)?;
+    if payload.len() != size {
+        return Err(ProtocolError::Codec(format!(
+            "Compressed payload expanded to {} bytes, expected {}",
+            payload.len(),
+            size
+        )));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        b"soft kvm clipboard text ".repeat(200)
+    }
+
+    #[test]
+    fn test_round_trip_each_algorithm() {
+        for kind in CompressionKind::supported() {
+            let compressed = compress_payload(kind.compressor().as_ref(), &sample()).unwrap().unwrap();
+            assert!(compressed.len() < sample().len() / 4, "{:?}", kind);
+            assert_eq!(decompress_payload(&compressed, MAX_DECOMPRESSED_SIZE).unwrap(), sample());
+        }
+    }
+
+    #[test]
+    fn test_incompressible_payload_is_left_alone() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let noise: Vec<u8> = (0..4096).map(|_| rng.gen()).collect();
+        assert!(compress_payload(&ZstdCompressor, &noise).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decompression_bomb_is_rejected() {
+        // 64MB of zeros compresses to almost nothing
+        let zeros = vec![0u8; 64 * 1024 * 1024];
+        let bomb = compress_payload(&ZstdCompressor, &zeros).unwrap().unwrap();
+        assert!(bomb.len() < 64 * 1024);
+        assert!(decompress_payload(&bomb, 1024 * 1024).is_err());
+
+        // Lying about the size doesn't help either
+        let mut forged = bomb.clone();
+        forged[1..5].copy_from_slice(&1024u32.to_be_bytes());
+        assert!(decompress_payload(&forged, 1024 * 1024).is_err());
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let ours = CompressionKind::supported();
+        assert_eq!(CompressionKind::negotiate(&[CompressionKind::Lz4], &ours), Some(CompressionKind::Lz4));
+        assert_eq!(CompressionKind::negotiate(&[], &ours), None);
+        assert_eq!(CompressionKind::negotiate(&ours, &[]), None);
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Input control arbitration between viewers
+//!
+//! Several viewers can share one host. One of them, the controller, drives
+//! input; the others are view-only and the server drops their InputEvents,
+//! whether they arrive over the WebSocket or the datagram channel.
+//!
+//! ```text
+//! viewer                          server                       controller
+//!   | --- Control(Request) ----->   |                               |
+//!   |                               | --- Control(Requested) ---->  |
+//!   |                               | <-- Control(Grant) ---------  |
+//!   | <-- Control(Changed) -------  | --- Control(Changed) ------>  |
+//! ```
+//!
+//! A request is granted at once when nobody has control, and `TakeOver`
+//! grabs control without asking unless `ControlConfig::allow_take_over` is
+//! off. `Release` passes control to the oldest pending request, as does the
+//! controller disconnecting. Peers older than `Feature::Control` cannot ask;
+//! their input claims control when nobody has it.
+//!
+//! Every change is sent to all viewers as `Control(Changed)` and published
+//! on the server's event channel as `SessionEvent::ControlChanged`.
+
+use crate::messages::{ControlPayload, MessagePayload, MessageType, ProtocolMessage};
+use crate::session::{ProtocolSession, SessionEvent, SessionMap};
+use crate::version::Feature;
+use crate::{ProtocolError, ProtocolResult};
+use serde::{Deserialize, Serialize};
+use soft_kvm_core::ErrorCode;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tracing::{debug, info};
+
+/// Control arbitration settings
+#[derive(Debug, Clone)]
+pub struct ControlConfig {
+    /// Let viewers take control without the controller's consent
+    pub allow_take_over: bool,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig { allow_take_over: true }
+    }
+}
+
+/// What a viewer may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ViewerRole {
+    /// Drives input
+    Controller,
+    /// Watches only; input is dropped
+    ViewOnly,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    controller: Option<String>,
+    /// Sessions waiting for control, oldest first
+    pending: Vec<String>,
+}
+
+impl ControlState {
+    /// Hand control to `session_id`, returning the previous controller if it changed
+    fn assign(&mut self, session_id: Option<String>) -> Option<Option<String>> {
+        if let Some(id) = &session_id {
+            self.pending.retain(|pending| pending != id);
+        }
+        if self.controller == session_id {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.controller, session_id))
+    }
+}
+
+/// Decides which of a server's sessions drives input
+#[derive(Clone)]
+pub struct ControlArbiter {
+    state: Arc<Mutex<ControlState>>,
+    sessions: SessionMap,
+    events: broadcast::Sender<SessionEvent>,
+    config: ControlConfig,
+}
+
+impl ControlArbiter {
+    /// Create an arbiter over a server's session table
+    pub fn new(sessions: SessionMap, events: broadcast::Sender<SessionEvent>, config: ControlConfig) -> Self {
+        ControlArbiter {
+            state: Arc::new(Mutex::new(ControlState::default())),
+            sessions,
+            events,
+            config,
+        }
+    }
+
+    /// Session currently driving input
+    pub fn controller(&self) -> Option<String> {
+        self.state.lock().unwrap().controller.clone()
+    }
+
+    /// Role of a session
+    pub fn role(&self, session_id: &str) -> ViewerRole {
+        if self.controller().as_deref() == Some(session_id) {
+            ViewerRole::Controller
+        } else {
+            ViewerRole::ViewOnly
+        }
+    }
+
+    /// Handle a Control message received from a session
+    pub async fn handle(&self, session: &ProtocolSession, request: &ControlPayload) -> ProtocolResult<()> {
+        let session_id = session.session_id().to_string();
+        match request {
+            ControlPayload::Request => {
+                let (changed, controller) = {
+                    let mut state = self.state.lock().unwrap();
+                    match state.controller.clone() {
+                        None => (state.assign(Some(session_id.clone())), None),
+                        Some(controller) => {
+                            if controller != session_id && !state.pending.contains(&session_id) {
+                                state.pending.push(session_id.clone());
+                            }
+                            (None, Some(controller))
+                        }
+                    }
+                };
+                match (changed, controller) {
+                    (Some(previous), _) => self.announce(previous, Some(session_id)).await,
+                    (None, Some(controller)) if controller != session_id => {
+                        self.forward_request(session, &controller).await;
+                    }
+                    _ => self.announce_to(session).await,
+                }
+            }
+            ControlPayload::TakeOver => {
+                if !self.config.allow_take_over {
+                    return Err(ProtocolError::Refused {
+                        code: ErrorCode::PolicyViolation,
+                        reason: "Taking over control is disabled; request it instead".to_string(),
+                    });
+                }
+                self.set_controller(Some(session_id)).await;
+            }
+            ControlPayload::Grant { session_id: to } => {
+                if self.controller().as_deref() != Some(session_id.as_str()) {
+                    return Err(ProtocolError::Refused {
+                        code: ErrorCode::PolicyViolation,
+                        reason: "Only the controller can grant control".to_string(),
+                    });
+                }
+                if !self.sessions.read().await.contains_key(to) {
+                    return Err(ProtocolError::Refused {
+                        code: ErrorCode::SessionNotFound,
+                        reason: format!("No session {}", to),
+                    });
+                }
+                self.set_controller(Some(to.clone())).await;
+            }
+            ControlPayload::Release => self.release(&session_id).await,
+            ControlPayload::Requested { .. } | ControlPayload::Changed { .. } => {
+                return Err(ProtocolError::InvalidMessageType(format!("{:?} is only sent by the server", request)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if input from a session may reach the host.
+    ///
+    /// A session's input claims control when nobody has it, so viewers that
+    /// cannot ask for control still work on their own.
+    pub async fn admit_input(&self, session_id: &str) -> bool {
+        let previous = {
+            let mut state = self.state.lock().unwrap();
+            match &state.controller {
+                Some(controller) => return controller == session_id,
+                None => state.assign(Some(session_id.to_string())),
+            }
+        };
+        if let Some(previous) = previous {
+            self.announce(previous, Some(session_id.to_string())).await;
+        }
+        true
+    }
+
+    /// Give control to a session, or to nobody, on behalf of the host
+    pub async fn set_controller(&self, session_id: Option<String>) {
+        let previous = self.state.lock().unwrap().assign(session_id.clone());
+        if let Some(previous) = previous {
+            self.announce(previous, session_id).await;
+        }
+    }
+
+    /// Give up control, passing it to the oldest pending request
+    pub async fn release(&self, session_id: &str) {
+        let change = {
+            let mut state = self.state.lock().unwrap();
+            state.pending.retain(|pending| pending != session_id);
+            if state.controller.as_deref() == Some(session_id) {
+                let next = (!state.pending.is_empty()).then(|| state.pending.remove(0));
+                state.assign(next.clone()).map(|previous| (previous, next))
+            } else {
+                None
+            }
+        };
+        if let Some((previous, next)) = change {
+            self.announce(previous, next).await;
+        }
+    }
+
+    /// Forget a session that has ended
+    pub async fn leave(&self, session_id: &str) {
+        self.release(session_id).await;
+    }
+
+    /// Tell a session who has control
+    pub async fn announce_to(&self, session: &ProtocolSession) {
+        let controller = self.controller();
+        send_control(session, ControlPayload::Changed { controller, previous: None }).await;
+    }
+
+    /// Ask the controller to grant control to a session
+    async fn forward_request(&self, session: &ProtocolSession, controller: &str) {
+        let peer_name = session.peer_info().peer_name.clone();
+        info!("Session {} ({}) asks {} for control", session.session_id(), peer_name, controller);
+        let _ = self.events.send(SessionEvent::ControlRequested {
+            session_id: session.session_id().to_string(),
+            peer_name: peer_name.clone(),
+        });
+
+        let target = self.sessions.read().await.get(controller).cloned();
+        if let Some(target) = target {
+            let request = ControlPayload::Requested {
+                session_id: session.session_id().to_string(),
+                peer_name,
+            };
+            send_control(&target, request).await;
+        }
+    }
+
+    /// Publish a change of controller to the host and every viewer
+    async fn announce(&self, previous: Option<String>, controller: Option<String>) {
+        info!("Input control moved from {:?} to {:?}", previous, controller);
+        let _ = self.events.send(SessionEvent::ControlChanged {
+            controller: controller.clone(),
+            previous: previous.clone(),
+        });
+
+        let viewers: Vec<ProtocolSession> = self.sessions.read().await.values().cloned().collect();
+        for viewer in viewers {
+            let changed = ControlPayload::Changed {
+                controller: controller.clone(),
+                previous: previous.clone(),
+            };
+            send_control(&viewer, changed).await;
+        }
+    }
+}
+
+/// Send a Control message if the session's peer understands it
+async fn send_control(session: &ProtocolSession, payload: ControlPayload) {
+    if !session.supports(Feature::Control) {
+        return;
+    }
+    let message = ProtocolMessage::new(MessageType::Control, MessagePayload::Control(payload))
+        .with_session(session.session_id().to_string());
+    if let Err(e) = session.send_message(message).await {
+        debug!("Failed to send control update to session {}: {}", session.session_id(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::tests::{new_session, no_sessions, test_config};
+
+    async fn viewer(sessions: &SessionMap, session_id: &str) -> ProtocolSession {
+        let mut session = new_session(session_id, &test_config());
+        session.set_authenticated(true);
+        sessions.write().await.insert(session_id.to_string(), session.clone());
+        session
+    }
+
+    fn changes(events: &mut broadcast::Receiver<SessionEvent>) -> Vec<Option<String>> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                SessionEvent::ControlChanged { controller, .. } => Some(controller),
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn received_control(session: &ProtocolSession) -> Vec<ControlPayload> {
+        let mut outbound = session.lock_outbound().await;
+        std::iter::from_fn(|| outbound.try_recv().ok())
+            .filter_map(|message| match message.payload {
+                MessagePayload::Control(payload) => Some(payload),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_request_grant_and_release() {
+        let sessions = no_sessions();
+        let (events, mut observer) = broadcast::channel(16);
+        let arbiter = ControlArbiter::new(sessions.clone(), events, ControlConfig::default());
+        let first = viewer(&sessions, "first").await;
+        let second = viewer(&sessions, "second").await;
+        let some = |id: &str| Some(id.to_string());
+
+        // Nobody has control, so the first request wins
+        arbiter.handle(&first, &ControlPayload::Request).await.unwrap();
+        assert_eq!(arbiter.role("first"), ViewerRole::Controller);
+        assert_eq!(arbiter.role("second"), ViewerRole::ViewOnly);
+
+        // The second viewer has to ask and its input is dropped meanwhile
+        arbiter.handle(&second, &ControlPayload::Request).await.unwrap();
+        assert!(!arbiter.admit_input("second").await);
+        assert!(arbiter.admit_input("first").await);
+        assert!(received_control(&first).await.contains(&ControlPayload::Requested {
+            session_id: "second".to_string(),
+            peer_name: String::new(),
+        }));
+
+        // Only the controller can grant
+        let refused = arbiter.handle(&second, &ControlPayload::Grant { session_id: "second".to_string() }).await;
+        assert_eq!(refused.unwrap_err().code(), ErrorCode::PolicyViolation);
+        arbiter.handle(&first, &ControlPayload::Grant { session_id: "second".to_string() }).await.unwrap();
+        assert_eq!(arbiter.controller(), some("second"));
+        assert!(received_control(&second).await.contains(&ControlPayload::Changed {
+            controller: some("second"),
+            previous: some("first"),
+        }));
+
+        // Releasing with nobody waiting leaves control free for the next input
+        arbiter.handle(&second, &ControlPayload::Release).await.unwrap();
+        assert_eq!(arbiter.controller(), None);
+        assert!(arbiter.admit_input("first").await);
+        assert_eq!(changes(&mut observer), vec![some("first"), some("second"), None, some("first")]);
+    }
+
+    #[tokio::test]
+    async fn test_take_over_and_leave() {
+        let sessions = no_sessions();
+        let (events, _) = broadcast::channel(16);
+        let arbiter = ControlArbiter::new(sessions.clone(), events.clone(), ControlConfig::default());
+        let first = viewer(&sessions, "first").await;
+        let second = viewer(&sessions, "second").await;
+        let third = viewer(&sessions, "third").await;
+
+        arbiter.handle(&first, &ControlPayload::Request).await.unwrap();
+        arbiter.handle(&second, &ControlPayload::TakeOver).await.unwrap();
+        assert_eq!(arbiter.controller().as_deref(), Some("second"));
+
+        // The oldest request inherits control when the controller leaves
+        arbiter.handle(&third, &ControlPayload::Request).await.unwrap();
+        arbiter.handle(&first, &ControlPayload::Request).await.unwrap();
+        sessions.write().await.remove("second");
+        arbiter.leave("second").await;
+        assert_eq!(arbiter.controller().as_deref(), Some("third"));
+
+        let strict = ControlArbiter::new(sessions, events, ControlConfig { allow_take_over: false });
+        strict.handle(&first, &ControlPayload::Request).await.unwrap();
+        let refused = strict.handle(&third, &ControlPayload::TakeOver).await.unwrap_err();
+        assert_eq!(refused.code(), ErrorCode::PolicyViolation);
+        assert_eq!(strict.role("first"), ViewerRole::Controller);
+    }
+}
@@ -0,0 +1,699 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session driver
+//!
+//! Pumps messages between an established [`ProtocolSession`] and its
+//! transport connection: the outbound queue is written to the transport and
+//! everything read from the transport is delivered to the inbound queue.
+//!
+//! The driver also keeps the session alive. A Heartbeat is sent every
+//! `heartbeat_interval` seconds and the peer answers with Pong. After one
+//! missed acknowledgement the session is `Suspended`; after
+//! `max_missed_heartbeats` the peer is considered dead and the session is
+//! closed.
+//!
+//! Delivery never waits for the application, which may read its inbound
+//! queue slowly or not at all: video and input make room by dropping their
+//! oldest message, and a control message finding the queue full is answered
+//! with an Error (see [`crate::queue`]). Heartbeats keep flowing either way.
+//!
+//! Pings are answered here and every Pong feeds the session's RTT and clock
+//! offset estimate (see [`crate::timing`]), so keepalive rounds measure the
+//! link as a side effect.
+//!
+//! On a server the driver applies input control (see [`crate::control`]):
+//! Control messages go to the arbiter and input from view-only sessions is
+//! dropped. On a client, control updates are published as session events.
+//!
+//! A session outlives its connection: the driver only borrows the outbound
+//! queue, so a resumed session continues on a new driver with its queues and
+//! state intact.
+
+use crate::control::ControlArbiter;
+use crate::messages::{ControlPayload, ErrorPayload, GoodbyePayload, MessagePayload, MessageType, PongPayload, ProtocolMessage};
+use crate::session::{ProtocolSession, SessionEvent, SessionMap, SessionState};
+use crate::timing;
+use crate::transport::TransportConnection;
+use crate::{ProtocolError, ProtocolResult};
+use soft_kvm_core::ErrorCode;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+/// Heartbeat acknowledgement tracking
+#[derive(Debug, Default)]
+struct Keepalive {
+    awaiting_ack: bool,
+    missed: u32,
+}
+
+/// Why a driver stopped pumping a connection
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    /// Either side ended the session with Goodbye, or the session was closed
+    Closed,
+    /// The session was resumed on another connection
+    Superseded,
+    /// The transport failed or the peer stopped answering heartbeats
+    Lost(ProtocolError),
+}
+
+/// Drives a single session over a transport connection
+pub struct SessionDriver {
+    session: ProtocolSession,
+    connection: Box<dyn TransportConnection>,
+    sessions: Option<SessionMap>,
+    events: Option<broadcast::Sender<SessionEvent>>,
+    control: Option<ControlArbiter>,
+    keepalive: Keepalive,
+    attached: u64,
+    goodbye: Option<GoodbyePayload>,
+}
+
+impl SessionDriver {
+    /// Create a new session driver
+    pub fn new(session: ProtocolSession, connection: Box<dyn TransportConnection>) -> Self {
+        SessionDriver {
+            session,
+            connection,
+            sessions: None,
+            events: None,
+            control: None,
+            keepalive: Keepalive::default(),
+            attached: 0,
+            goodbye: None,
+        }
+    }
+
+    /// Remove the session from this table once the driver stops
+    pub fn with_sessions(mut self, sessions: SessionMap) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Publish lifecycle events on this channel
+    pub fn with_events(mut self, events: broadcast::Sender<SessionEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Arbitrate input control through this arbiter (server side)
+    pub fn with_control(mut self, control: ControlArbiter) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Run the driver on a background task
+    pub fn spawn(self) -> tokio::task::JoinHandle<ProtocolResult<()>> {
+        tokio::spawn(self.run())
+    }
+
+    /// Run until either side closes the session, the peer stops answering
+    /// heartbeats or the transport fails.
+    ///
+    /// A resumable session whose transport is lost stays `Suspended` for
+    /// `resume_timeout` seconds; if the peer resumes it in that window the
+    /// new connection's driver takes over and this one returns quietly.
+    pub async fn run(mut self) -> ProtocolResult<()> {
+        let error = match self.drive().await {
+            Outcome::Closed => return self.finish(Ok(())).await,
+            Outcome::Superseded => return Ok(()),
+            Outcome::Lost(e) => e,
+        };
+
+        if self.session.state().await != SessionState::Suspended {
+            return self.finish(Err(error)).await;
+        }
+
+        info!("Session {} waiting to be resumed: {}", self.session.session_id(), error);
+        let mut generation = self.session.subscribe_generation();
+        let attached = self.attached;
+        let window = Duration::from_secs(self.session.config().resume_timeout);
+        let resumed = matches!(
+            tokio::time::timeout(window, generation.wait_for(|current| *current != attached)).await,
+            Ok(Ok(_))
+        );
+        if resumed {
+            Ok(())
+        } else {
+            self.finish(Err(error)).await
+        }
+    }
+
+    /// Pump the current connection until it ends
+    pub(crate) async fn drive(&mut self) -> Outcome {
+        let mut outbound = self.session.lock_outbound().await;
+        let mut generation = self.session.subscribe_generation();
+        self.attached = *generation.borrow_and_update();
+        let attached = self.attached;
+
+        if self.session.state().await == SessionState::Suspended {
+            self.resume().await;
+        }
+
+        let heartbeat_interval = self.session.config().heartbeat_interval;
+        let period = Duration::from_secs(heartbeat_interval.max(1));
+        let mut keepalive = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        keepalive.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        let outcome = loop {
+            tokio::select! {
+                message = outbound.recv() => {
+                    let Some(message) = message else {
+                        break Outcome::Closed;
+                    };
+                    let is_goodbye = matches!(message.message_type(), MessageType::Goodbye);
+                    if let Err(e) = self.connection.send(message).await {
+                        break Outcome::Lost(e);
+                    }
+                    if is_goodbye {
+                        debug!("Goodbye sent for session {}", self.session.session_id());
+                        break Outcome::Closed;
+                    }
+                }
+
+                received = self.connection.receive() => {
+                    match received {
+                        Ok(Some(message)) => match self.handle_incoming(message).await {
+                            Ok(true) => {}
+                            Ok(false) => break Outcome::Closed,
+                            Err(e) => break Outcome::Lost(e),
+                        },
+                        Ok(None) => {
+                            info!("Transport closed for session {}", self.session.session_id());
+                            break Outcome::Lost(ProtocolError::Transport("Connection closed".to_string()));
+                        }
+                        // Idle read timeouts are not fatal; liveness is tracked by heartbeats
+                        Err(ProtocolError::Timeout) => {}
+                        Err(e) => break Outcome::Lost(e),
+                    }
+                }
+
+                _ = keepalive.tick(), if heartbeat_interval > 0 => {
+                    if let Err(e) = self.handle_keepalive().await {
+                        break Outcome::Lost(e);
+                    }
+                }
+
+                Ok(()) = generation.changed() => {
+                    if *generation.borrow_and_update() != attached {
+                        info!("Session {} resumed on another connection", self.session.session_id());
+                        break Outcome::Superseded;
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = self.connection.close().await {
+            debug!("Failed to close transport for session {}: {}", self.session.session_id(), e);
+        }
+
+        // Suspend while still holding the outbound queue, so a driver taking
+        // over on a resumed connection always runs after this
+        if let Outcome::Lost(e) = &outcome {
+            warn!("Session {} connection lost: {}", self.session.session_id(), e);
+            if self.session.is_resumable().await && self.session.generation() == attached {
+                self.suspend().await;
+            }
+        }
+        drop(outbound);
+
+        outcome
+    }
+
+    /// Mark the session suspended while the peer is unreachable
+    async fn suspend(&self) {
+        if matches!(self.session.state().await, SessionState::Active | SessionState::Authenticating) {
+            self.session.set_state(SessionState::Suspended).await;
+            self.emit(SessionEvent::Suspended {
+                session_id: self.session.session_id().to_string(),
+                peer_id: self.session.peer_info().peer_id.clone(),
+            });
+        }
+    }
+
+    /// Continue the session on a new connection
+    pub(crate) fn reattach(&mut self, session: ProtocolSession, connection: Box<dyn TransportConnection>) {
+        self.session = session;
+        self.connection = connection;
+        self.keepalive = Keepalive::default();
+    }
+
+    /// End the session for good
+    pub(crate) async fn finish(self, result: ProtocolResult<()>) -> ProtocolResult<()> {
+        if let Err(e) = &result {
+            warn!("Session {} driver stopped: {}", self.session.session_id(), e);
+        }
+
+        self.session.close_inbound().await;
+        self.session.set_state(SessionState::Closed).await;
+
+        if let Some(sessions) = &self.sessions {
+            if sessions.write().await.remove(self.session.session_id()).is_some() {
+                debug!("Evicted session {}", self.session.session_id());
+            }
+        }
+        if let Some(control) = &self.control {
+            control.leave(self.session.session_id()).await;
+        }
+        let (code, reason) = match (&result, &self.goodbye) {
+            (Err(e), _) => (e.code(), e.to_string()),
+            (Ok(()), Some(goodbye)) if goodbye.code != ErrorCode::NormalClosure => (goodbye.code, goodbye.reason.clone()),
+            (Ok(()), _) => (ErrorCode::NormalClosure, "Session closed".to_string()),
+        };
+        self.emit(SessionEvent::Disconnected {
+            session_id: self.session.session_id().to_string(),
+            peer_id: self.session.peer_info().peer_id.clone(),
+            code,
+            reason,
+        });
+
+        result
+    }
+
+    /// Account for the previous heartbeat and send the next one
+    async fn handle_keepalive(&mut self) -> ProtocolResult<()> {
+        if self.keepalive.awaiting_ack {
+            self.keepalive.missed += 1;
+            debug!("Session {} missed {} heartbeat(s)", self.session.session_id(), self.keepalive.missed);
+
+            if self.keepalive.missed >= self.session.config().max_missed_heartbeats.max(1) {
+                return Err(ProtocolError::Session(format!(
+                    "Peer missed {} heartbeats",
+                    self.keepalive.missed
+                )));
+            }
+
+            if self.keepalive.missed == 1 {
+                warn!("Session {} suspended: peer is not answering heartbeats", self.session.session_id());
+                self.session.set_state(SessionState::Suspended).await;
+                self.emit(SessionEvent::Suspended {
+                    session_id: self.session.session_id().to_string(),
+                    peer_id: self.session.peer_info().peer_id.clone(),
+                });
+            }
+        }
+
+        self.session.send_heartbeat().await?;
+        self.keepalive.awaiting_ack = true;
+        Ok(())
+    }
+
+    /// Record a heartbeat acknowledgement
+    async fn handle_ack(&mut self) {
+        let was_suspended = self.keepalive.missed > 0;
+        self.keepalive = Keepalive::default();
+
+        if was_suspended && self.session.state().await == SessionState::Suspended {
+            self.resume().await;
+        }
+    }
+
+    /// Mark a suspended session active again
+    async fn resume(&self) {
+        info!("Session {} resumed", self.session.session_id());
+        self.session.set_state(SessionState::Active).await;
+        self.emit(SessionEvent::Resumed {
+            session_id: self.session.session_id().to_string(),
+            peer_id: self.session.peer_info().peer_id.clone(),
+        });
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        if let Some(events) = &self.events {
+            // No subscribers is fine
+            let _ = events.send(event);
+        }
+    }
+
+    /// Check if input from the peer may reach the application
+    async fn admit_input(&self) -> bool {
+        match &self.control {
+            Some(control) => control.admit_input(self.session.session_id()).await,
+            None => true,
+        }
+    }
+
+    /// Publish a control update received from the server
+    fn emit_control(&self, update: &ControlPayload) {
+        match update {
+            ControlPayload::Changed { controller, previous } => self.emit(SessionEvent::ControlChanged {
+                controller: controller.clone(),
+                previous: previous.clone(),
+            }),
+            ControlPayload::Requested { session_id, peer_name } => self.emit(SessionEvent::ControlRequested {
+                session_id: session_id.clone(),
+                peer_name: peer_name.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    /// Hand a message to the application without waiting for it to keep up.
+    ///
+    /// A control message the inbound queue has no room for is refused with
+    /// an Error, written straight to the transport rather than queued
+    /// behind outbound traffic.
+    async fn deliver(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
+        let message_id = message.header.message_id.clone();
+        match self.session.deliver(message).await {
+            Err(ProtocolError::Refused { code, reason }) => {
+                warn!("{}", reason);
+                let mut refusal = ProtocolMessage::new(
+                    MessageType::Error,
+                    MessagePayload::Error(ErrorPayload { error_code: code, error_message: reason, details: None }),
+                )
+                .with_session(self.session.session_id().to_string());
+                refusal.header.correlation_id = Some(message_id);
+                self.connection.send(refusal).await
+            }
+            result => result,
+        }
+    }
+
+    /// Handle a message read from the transport.
+    ///
+    /// Returns `false` once the peer has ended the session.
+    async fn handle_incoming(&mut self, message: ProtocolMessage) -> ProtocolResult<bool> {
+        let received = timing::now_micros();
+        match &message.payload {
+            MessagePayload::Heartbeat(_) => {
+                self.session.handle_heartbeat(&message, received).await?;
+                Ok(true)
+            }
+            // Answered here rather than by the application, so time spent in
+            // the inbound queue doesn't count as network delay
+            MessagePayload::Ping(ping) => {
+                let pong = PongPayload::answer(ping.originate, received);
+                self.session.send_message(message.reply(MessageType::Pong, MessagePayload::Pong(pong))).await?;
+                Ok(true)
+            }
+            MessagePayload::Pong(pong) => {
+                self.session.record_pong(pong, received).await;
+                // Pongs answering our heartbeats are consumed here; correlated
+                // ones answer a Ping request and are delivered
+                if self.keepalive.awaiting_ack && message.correlation_id().is_none() {
+                    self.session.update_activity().await;
+                    self.handle_ack().await;
+                    return Ok(true);
+                }
+                self.deliver(message).await?;
+                Ok(true)
+            }
+            MessagePayload::Goodbye(goodbye) => {
+                info!("Peer closed session {}: {} ({})", self.session.session_id(), goodbye.reason, goodbye.code);
+                self.goodbye = Some(goodbye.clone());
+                self.session.set_state(SessionState::Closing).await;
+                self.deliver(message).await?;
+                Ok(false)
+            }
+            MessagePayload::Control(request) => {
+                match &self.control {
+                    Some(control) => {
+                        if let Err(e) = control.handle(&self.session, request).await {
+                            debug!("Control request from session {} refused: {}", self.session.session_id(), e);
+                            self.session.send_error(e.code(), e.to_string()).await?;
+                        }
+                    }
+                    None => {
+                        self.emit_control(request);
+                        self.deliver(message).await?;
+                    }
+                }
+                Ok(true)
+            }
+            MessagePayload::InputEvent(_) if !self.admit_input().await => {
+                debug!("Dropping input from view-only session {}", self.session.session_id());
+                Ok(true)
+            }
+            _ => {
+                if let Err(e) = self.session.check_capability(message.message_type()) {
+                    warn!("Dropping {:?} on session {}: {}", message.message_type(), self.session.session_id(), e);
+                    self.session.send_error(e.code(), e.to_string()).await?;
+                    return Ok(true);
+                }
+                self.deliver(message).await?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::tests::{connection_pair, new_session, no_sessions, test_config};
+    use crate::handshake::{client_handshake, server_handshake};
+    use crate::messages::{ClipboardPayload, InputEventPayload, PingPayload};
+    use crate::memory::{LinkConditions, MemoryTransportFactory};
+    use crate::queue::QueueConfig;
+    use crate::session::SessionMap;
+    use crate::ProtocolConfig;
+    use soft_kvm_core::{Capability, CapabilitySet, KeyboardEvent};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_driver_rejects_unnegotiated_capability() {
+        let server_config = ProtocolConfig {
+            capabilities: CapabilitySet::new(vec![Capability::Metrics]),
+            ..test_config()
+        };
+        let client_config = test_config();
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-gate", &server_config);
+        let handshake_config = server_config.clone();
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &handshake_config, &no_sessions()).await.unwrap();
+            SessionDriver::new(server_session.clone(), server_conn).spawn();
+            server_session
+        });
+
+        let mut client_session = new_session("client-session", &client_config);
+        client_handshake(client_conn.as_mut(), &mut client_session, &client_config).await.unwrap();
+        let server_session = server.await.unwrap();
+
+        // Bypass the client session's own check and write straight to the transport
+        let clipboard = ProtocolMessage::new(
+            MessageType::ClipboardData,
+            MessagePayload::ClipboardData(ClipboardPayload {
+                data_type: "text".to_string(),
+                data: b"secret".to_vec(),
+            }),
+        );
+        client_conn.send(clipboard).await.unwrap();
+
+        let reply = client_conn.receive().await.unwrap().unwrap();
+        assert!(matches!(reply.payload, MessagePayload::Error(ref e) if e.error_code == ErrorCode::UnsupportedData));
+        assert!(server_session.try_receive_message().await.is_none());
+
+        // Negotiated traffic still flows
+        let metrics = ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest);
+        client_conn.send(metrics).await.unwrap();
+        let delivered = server_session.receive_message().await.unwrap();
+        assert_eq!(delivered.message_type(), &MessageType::MetricsRequest);
+    }
+
+    async fn active_session(session_id: &str, config: &ProtocolConfig) -> ProtocolSession {
+        let mut session = new_session(session_id, config);
+        session.set_authenticated(true);
+        session.set_state(SessionState::Active).await;
+        session
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_suspends_and_evicts_silent_peer() {
+        let config = ProtocolConfig {
+            heartbeat_interval: 5,
+            max_missed_heartbeats: 3,
+            ..test_config()
+        };
+        let (server_conn, mut peer) = MemoryTransportFactory::default().pair();
+        let session = active_session("server-session-keepalive", &config).await;
+
+        let sessions: SessionMap = Arc::new(RwLock::new(std::collections::HashMap::new()));
+        sessions.write().await.insert(session.session_id().to_string(), session.clone());
+        let (events_tx, mut events) = broadcast::channel(16);
+
+        let driver = SessionDriver::new(session.clone(), Box::new(server_conn))
+            .with_sessions(sessions.clone())
+            .with_events(events_tx)
+            .spawn();
+
+        // The peer stays silent: the first missed ack suspends the session
+        let heartbeat = peer.receive().await.unwrap().unwrap();
+        assert_eq!(heartbeat.message_type(), &MessageType::Heartbeat);
+        assert!(matches!(events.recv().await.unwrap(), SessionEvent::Suspended { .. }));
+        assert_eq!(session.state().await, SessionState::Suspended);
+
+        // A late Pong brings it back
+        peer.receive().await.unwrap().unwrap();
+        let late_pong = MessagePayload::Pong(PongPayload::answer(heartbeat.header.timestamp.timestamp_micros(), timing::now_micros()));
+        peer.send(ProtocolMessage::new(MessageType::Pong, late_pong)).await.unwrap();
+        assert!(matches!(events.recv().await.unwrap(), SessionEvent::Resumed { .. }));
+        assert_eq!(session.state().await, SessionState::Active);
+
+        // Then the peer goes away for good
+        assert!(matches!(events.recv().await.unwrap(), SessionEvent::Suspended { .. }));
+        match events.recv().await.unwrap() {
+            SessionEvent::Disconnected { session_id, reason, .. } => {
+                assert_eq!(session_id, "server-session-keepalive");
+                assert!(reason.contains("missed 3 heartbeats"), "{}", reason);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        assert!(driver.await.unwrap().is_err());
+        assert_eq!(session.state().await, SessionState::Closed);
+        assert!(sessions.read().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_answered_by_peer_driver() {
+        let config = ProtocolConfig {
+            heartbeat_interval: 5,
+            max_missed_heartbeats: 2,
+            ..test_config()
+        };
+        let (server_conn, client_conn) = MemoryTransportFactory::default().pair();
+        let server_session = active_session("server-session-alive", &config).await;
+        let client_session = active_session("server-session-alive", &config).await;
+
+        let (events_tx, mut events) = broadcast::channel(16);
+        SessionDriver::new(server_session.clone(), Box::new(server_conn)).with_events(events_tx).spawn();
+        SessionDriver::new(client_session.clone(), Box::new(client_conn)).spawn();
+
+        // Several heartbeat periods pass without the session being suspended
+        tokio::time::sleep(Duration::from_secs(23)).await;
+        assert_eq!(server_session.state().await, SessionState::Active);
+        assert!(matches!(events.try_recv(), Err(broadcast::error::TryRecvError::Empty)));
+        // Each acknowledged heartbeat was also a clock sample
+        assert_eq!(server_session.clock().await.unwrap().samples, 4);
+
+        client_session.close().await.unwrap();
+        match events.recv().await.unwrap() {
+            SessionEvent::Disconnected { code, reason, .. } => {
+                assert_eq!(code, ErrorCode::NormalClosure);
+                assert_eq!(reason, "Session closed");
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unread_inbound_queue_does_not_stall_keepalive() {
+        let config = ProtocolConfig {
+            heartbeat_interval: 5,
+            max_missed_heartbeats: 2,
+            queue: QueueConfig { control_capacity: 4, input_capacity: 4, ..QueueConfig::default() },
+            ..test_config()
+        };
+        let (server_conn, mut peer) = MemoryTransportFactory::default().pair();
+        let mut session = active_session("server-session-unread", &config).await;
+        let mut peer_info = session.peer_info().clone();
+        peer_info.capabilities = CapabilitySet::full();
+        session.set_peer_info(peer_info);
+        let (events_tx, mut events) = broadcast::channel(16);
+        SessionDriver::new(session.clone(), Box::new(server_conn)).with_events(events_tx).spawn();
+
+        // Nobody reads the session's inbound queue
+        let press = KeyboardEvent::KeyPress { key_code: 0x41, modifiers: 0 };
+        for _ in 0..10 {
+            peer.send(ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest)).await.unwrap();
+            peer.send(ProtocolMessage::new(MessageType::InputEvent, MessagePayload::InputEvent(InputEventPayload::keyboard(&press))))
+                .await
+                .unwrap();
+        }
+
+        // Control overflow is refused and heartbeats keep coming, for several periods
+        let (mut refused, mut heartbeats) = (0, 0);
+        while heartbeats < 4 {
+            let message = tokio::time::timeout(Duration::from_secs(10), peer.receive()).await.unwrap().unwrap().unwrap();
+            match &message.payload {
+                MessagePayload::Error(error) => {
+                    assert_eq!(error.error_code, ErrorCode::ServiceUnavailable);
+                    assert!(message.correlation_id().is_some());
+                    refused += 1;
+                }
+                MessagePayload::Heartbeat(_) => {
+                    let pong = PongPayload::answer(message.header.timestamp.timestamp_micros(), timing::now_micros());
+                    peer.send(ProtocolMessage::new(MessageType::Pong, MessagePayload::Pong(pong))).await.unwrap();
+                    heartbeats += 1;
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert_eq!(refused, 6);
+        assert_eq!(session.state().await, SessionState::Active);
+        assert!(matches!(events.try_recv(), Err(broadcast::error::TryRecvError::Empty)));
+
+        // Input made room by dropping its oldest events
+        let inbound = session.stats().await.inbound_queue;
+        assert_eq!((inbound.control, inbound.input, inbound.dropped_input), (4, 4, 6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_goodbye_code_is_reported() {
+        let config = test_config();
+        let (server_conn, client_conn) = MemoryTransportFactory::default().pair();
+        let server_session = active_session("server-session-kicked", &config).await;
+        let client_session = active_session("server-session-kicked", &config).await;
+
+        let (events_tx, mut events) = broadcast::channel(16);
+        SessionDriver::new(server_session.clone(), Box::new(server_conn)).spawn();
+        let client = SessionDriver::new(client_session.clone(), Box::new(client_conn)).with_events(events_tx).spawn();
+
+        server_session.close_with(ErrorCode::Kicked, "Controller switched to desk-2".to_string()).await.unwrap();
+        match events.recv().await.unwrap() {
+            SessionEvent::Disconnected { code, reason, .. } => {
+                assert_eq!(code, ErrorCode::Kicked);
+                assert_eq!(reason, "Controller switched to desk-2");
+                assert!(!code.is_retryable());
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(client.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ping_measures_round_trip() {
+        let config = test_config();
+        let network = MemoryTransportFactory::new(LinkConditions {
+            latency: Duration::from_millis(20),
+            ..LinkConditions::default()
+        });
+        let (server_conn, client_conn) = network.pair();
+        let server_session = active_session("server-session-ping", &config).await;
+        let client_session = active_session("server-session-ping", &config).await;
+        SessionDriver::new(server_session.clone(), Box::new(server_conn)).spawn();
+        SessionDriver::new(client_session.clone(), Box::new(client_conn)).spawn();
+        assert!(client_session.stats().await.rtt_ms.is_none());
+
+        // The peer's driver answers; the correlated Pong is still delivered
+        let ping = ProtocolMessage::new(MessageType::Ping, MessagePayload::Ping(PingPayload::now()));
+        let ping_id = ping.header.message_id.clone();
+        client_session.send_message(ping).await.unwrap();
+        let pong = client_session.receive_message().await.unwrap();
+        assert_eq!(pong.correlation_id(), Some(ping_id.as_str()));
+
+        let stats = client_session.stats().await;
+        let rtt = stats.rtt_ms.unwrap();
+        assert!((40.0..200.0).contains(&rtt), "rtt {}ms", rtt);
+        // Both ends share a clock
+        assert!(stats.clock_offset_ms.unwrap().abs() < 20.0);
+        assert!(server_session.try_receive_message().await.is_none());
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Message fragmentation and size limits
+//!
+//! Transports that carry discrete frames split an encoded message larger
+//! than `TransportConfig::buffer_size` into chunks and reassemble them on
+//! the other side, so clipboard images and keyframes get through without
+//! raising the frame size. Each chunk is a frame of its own:
+//!
+//! ```text
+//! offset size  field
+//! 0      2     magic "SF"
+//! 2      4     message sequence number
+//! 6      4     chunk index
+//! 10     4     chunk count
+//! 14     4     total message length
+//! 18     ..    chunk data
+//! ```
+//!
+//! Chunks of one message are sent back to back and must arrive in order. A
+//! message is never larger than `TransportConfig::max_message_size`: the
+//! sender refuses it, and the receiver checks every frame and every
+//! declared total before buffering or decoding anything. A message whose
+//! chunks stop arriving is dropped after `TransportConfig::reassembly_timeout`,
+//! or earlier if too many messages are incomplete at once.
+
+use crate::codec;
+use crate::messages::ProtocolMessage;
+use crate::transport::TransportConfig;
+use crate::{ProtocolError, ProtocolResult};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Magic bytes at the start of every chunk
+pub const FRAGMENT_MAGIC: [u8; 2] = *b"SF";
+
+/// Size of the chunk header
+pub const FRAGMENT_HEADER_LEN: usize = 18;
+
+/// Messages being reassembled at once
+const MAX_PARTIAL_MESSAGES: usize = 4;
+
+/// Reject a message larger than the configured maximum
+pub fn check_size(len: usize, max_message_size: usize) -> ProtocolResult<()> {
+    if len > max_message_size {
+        return Err(ProtocolError::Codec(format!(
+            "Message size {} exceeds maximum {}",
+            len, max_message_size
+        )));
+    }
+    Ok(())
+}
+
+/// Splits outgoing frames into chunks
+#[derive(Debug)]
+pub struct Fragmenter {
+    next_sequence: u32,
+    frame_size: usize,
+    max_message_size: usize,
+}
+
+impl Fragmenter {
+    /// Create a fragmenter for one connection
+    pub fn new(config: &TransportConfig) -> Self {
+        Fragmenter {
+            next_sequence: 0,
+            // Leave room for at least one byte of data per chunk
+            frame_size: config.buffer_size.max(FRAGMENT_HEADER_LEN + 1),
+            max_message_size: config.max_message_size,
+        }
+    }
+
+    /// Split an encoded message into frames of at most `buffer_size` bytes.
+    ///
+    /// A message that already fits is returned as is.
+    pub fn split(&mut self, frame: Vec<u8>) -> ProtocolResult<Vec<Vec<u8>>> {
+        check_size(frame.len(), self.max_message_size)?;
+        if frame.len() <= self.frame_size {
+            return Ok(vec![frame]);
+        }
+
+        let total = u32::try_from(frame.len())
+            .map_err(|_| ProtocolError::Codec(format!("Message too large: {} bytes", frame.len())))?;
+        let chunk_size = self.frame_size - FRAGMENT_HEADER_LEN;
+        let count = frame.len().div_ceil(chunk_size) as u32;
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        Ok(frame
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, data)| {
+                let mut chunk = Vec::with_capacity(FRAGMENT_HEADER_LEN + data.len());
+                chunk.extend_from_slice(&FRAGMENT_MAGIC);
+                chunk.extend_from_slice(&sequence.to_be_bytes());
+                chunk.extend_from_slice(&(index as u32).to_be_bytes());
+                chunk.extend_from_slice(&count.to_be_bytes());
+                chunk.extend_from_slice(&total.to_be_bytes());
+                chunk.extend_from_slice(data);
+                chunk
+            })
+            .collect())
+    }
+}
+
+/// Message being reassembled
+#[derive(Debug)]
+struct Partial {
+    data: Vec<u8>,
+    total: usize,
+    count: u32,
+    next_index: u32,
+    deadline: Instant,
+}
+
+/// Reassembles and decodes incoming frames
+#[derive(Debug)]
+pub struct Reassembler {
+    partial: HashMap<u32, Partial>,
+    max_message_size: usize,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    /// Create a reassembler for one connection
+    pub fn new(config: &TransportConfig) -> Self {
+        Reassembler {
+            partial: HashMap::new(),
+            max_message_size: config.max_message_size,
+            timeout: Duration::from_secs(config.reassembly_timeout),
+        }
+    }
+
+    /// Largest message accepted
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Messages currently being reassembled
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Feed one received frame.
+    ///
+    /// Returns the decoded message once it is complete, or `None` while
+    /// chunks are still missing.
+    pub fn push(&mut self, frame: Vec<u8>) -> ProtocolResult<Option<ProtocolMessage>> {
+        if !frame.starts_with(&FRAGMENT_MAGIC) {
+            return self.decode(&frame).map(Some);
+        }
+
+        match self.push_chunk(&frame)? {
+            Some(message) => self.decode(&message).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn decode(&self, frame: &[u8]) -> ProtocolResult<ProtocolMessage> {
+        check_size(frame.len(), self.max_message_size)?;
+        codec::decode_any_with_limit(frame, self.max_message_size)
+    }
+
+    fn push_chunk(&mut self, frame: &[u8]) -> ProtocolResult<Option<Vec<u8>>> {
+        if frame.len() < FRAGMENT_HEADER_LEN {
+            return Err(ProtocolError::Codec("Truncated message chunk".to_string()));
+        }
+        let field = |offset: usize| u32::from_be_bytes([frame[offset], frame[offset + 1], frame[offset + 2], frame[offset + 3]]);
+        let sequence = field(2);
+        let index = field(6);
+        let count = field(10);
+        let total = field(14) as usize;
+        let data = &frame[FRAGMENT_HEADER_LEN..];
+
+        // Checked before anything is buffered
+        check_size(total, self.max_message_size)?;
+        if count == 0 || index >= count {
+            return Err(ProtocolError::Codec(format!("Invalid chunk {} of {}", index, count)));
+        }
+
+        let now = Instant::now();
+        self.partial.retain(|sequence, partial| {
+            let alive = partial.deadline > now;
+            if !alive {
+                warn!("Dropped incomplete message {} after {} of {} chunks", sequence, partial.next_index, partial.count);
+            }
+            alive
+        });
+
+        if index == 0 {
+            if self.partial.len() >= MAX_PARTIAL_MESSAGES && !self.partial.contains_key(&sequence) {
+                // Make room by giving up on the oldest message
+                if let Some(oldest) = self.partial.iter().min_by_key(|(_, partial)| partial.deadline).map(|(sequence, _)| *sequence) {
+                    warn!("Dropped incomplete message {} to make room for {}", oldest, sequence);
+                    self.partial.remove(&oldest);
+                }
+            }
+            self.partial.insert(sequence, Partial {
+                data: Vec::new(),
+                total,
+                count,
+                next_index: 0,
+                deadline: now + self.timeout,
+            });
+        }
+
+        let partial = match self.partial.get_mut(&sequence) {
+            Some(partial) if partial.next_index == index && partial.count == count && partial.total == total => partial,
+            _ => {
+                // A chunk before it was lost; the message can't be completed
+                warn!("Dropped message {} at out of order chunk {} of {}", sequence, index, count);
+                self.partial.remove(&sequence);
+                return Ok(None);
+            }
+        };
+
+        if partial.data.len() + data.len() > partial.total {
+            self.partial.remove(&sequence);
+            return Err(ProtocolError::Codec(format!("Message {} overruns its declared size", sequence)));
+        }
+        partial.data.extend_from_slice(data);
+        partial.next_index += 1;
+
+        if partial.next_index < partial.count {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&sequence).unwrap();
+        if partial.data.len() != partial.total {
+            return Err(ProtocolError::Codec(format!(
+                "Message {} is {} bytes, expected {}",
+                sequence,
+                partial.data.len(),
+                partial.total
+            )));
+        }
+        Ok(Some(partial.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecKind;
+    use crate::messages::{ClipboardPayload, MessagePayload, MessageType};
+
+    fn config() -> TransportConfig {
+        TransportConfig {
+            buffer_size: 1024,
+            max_message_size: 64 * 1024,
+            ..TransportConfig::default()
+        }
+    }
+
+    fn clipboard(size: usize) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::ClipboardData,
+            MessagePayload::ClipboardData(ClipboardPayload {
+                data_type: "image/png".to_string(),
+                data: (0..size).map(|i| i as u8).collect(),
+            }),
+        )
+    }
+
+    fn clipboard_data(message: &ProtocolMessage) -> &[u8] {
+        match &message.payload {
+            MessagePayload::ClipboardData(clipboard) => &clipboard.data,
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_large_message_round_trip() {
+        let config = config();
+        let mut fragmenter = Fragmenter::new(&config);
+        let mut reassembler = Reassembler::new(&config);
+
+        let message = clipboard(10_000);
+        let chunks = fragmenter.split(CodecKind::Binary.codec().encode(&message).unwrap()).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= config.buffer_size));
+
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert!(reassembler.push(chunk.clone()).unwrap().is_none());
+        }
+        assert_eq!(reassembler.pending(), 1);
+        let received = reassembler.push(last.clone()).unwrap().unwrap();
+        assert_eq!(clipboard_data(&received), clipboard_data(&message));
+        assert_eq!(reassembler.pending(), 0);
+
+        // Small messages pass through untouched
+        let small = CodecKind::Binary.codec().encode(&clipboard(10)).unwrap();
+        assert_eq!(fragmenter.split(small.clone()).unwrap(), vec![small]);
+    }
+
+    #[test]
+    fn test_oversized_messages_are_rejected() {
+        let config = config();
+        let mut fragmenter = Fragmenter::new(&config);
+        let frame = CodecKind::Binary.codec().encode(&clipboard(100_000)).unwrap();
+        assert!(fragmenter.split(frame.clone()).is_err());
+
+        // The receiver refuses a whole frame or a declared total over the limit
+        let mut reassembler = Reassembler::new(&config);
+        assert!(reassembler.push(frame).is_err());
+
+        let mut chunk = FRAGMENT_MAGIC.to_vec();
+        chunk.extend_from_slice(&0u32.to_be_bytes());
+        chunk.extend_from_slice(&0u32.to_be_bytes());
+        chunk.extend_from_slice(&4096u32.to_be_bytes());
+        chunk.extend_from_slice(&u32::MAX.to_be_bytes());
+        chunk.extend_from_slice(&[0; 16]);
+        assert!(reassembler.push(chunk).is_err());
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_lost_chunk_drops_the_message() {
+        let config = config();
+        let mut fragmenter = Fragmenter::new(&config);
+        let mut reassembler = Reassembler::new(&config);
+
+        let chunks = fragmenter.split(CodecKind::Binary.codec().encode(&clipboard(5_000)).unwrap()).unwrap();
+        assert!(reassembler.push(chunks[0].clone()).unwrap().is_none());
+        assert!(reassembler.push(chunks[2].clone()).unwrap().is_none());
+        assert_eq!(reassembler.pending(), 0);
+
+        // The next message is unaffected
+        let message = clipboard(5_000);
+        let mut received = None;
+        for chunk in fragmenter.split(CodecKind::Binary.codec().encode(&message).unwrap()).unwrap() {
+            received = reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(clipboard_data(&received.unwrap()), clipboard_data(&message));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_message_times_out() {
+        let config = config();
+        let mut fragmenter = Fragmenter::new(&config);
+        let mut reassembler = Reassembler::new(&config);
+
+        for _ in 0..MAX_PARTIAL_MESSAGES {
+            let chunks = fragmenter.split(CodecKind::Binary.codec().encode(&clipboard(5_000)).unwrap()).unwrap();
+            reassembler.push(chunks[0].clone()).unwrap();
+        }
+        let chunks = fragmenter.split(CodecKind::Binary.codec().encode(&clipboard(5_000)).unwrap()).unwrap();
+        assert!(reassembler.push(chunks[0].clone()).unwrap().is_none());
+        assert_eq!(reassembler.pending(), MAX_PARTIAL_MESSAGES);
+
+        tokio::time::advance(Duration::from_secs(config.reassembly_timeout + 1)).await;
+        assert!(reassembler.push(chunks[0].clone()).unwrap().is_none());
+        assert_eq!(reassembler.pending(), 1);
+    }
+}
@@ -0,0 +1,780 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session handshake
+//!
+//! ```text
+//! client                                server        session state
+//!   | --- Hello ----------------------->  |           Connecting
+//!   | <-------------------- Welcome ----  |           Authenticating
+//!   | --- AuthRequest ----------------->  |
+//!   | <--------------- AuthResponse ----  |           Active
+//! ```
+//!
+//! Hello carries the range of protocol versions the client speaks and the
+//! server picks the highest one it also speaks (see `crate::version`).
+//!
+//! Each step must complete within `ProtocolConfig::handshake_timeout`. On
+//! failure the server reports the reason with an Error message and both
+//! sides move the session to `Closed`.
+
+use crate::codec::CodecKind;
+use crate::compression::CompressionKind;
+use crate::messages::*;
+use crate::session::{PeerInfo, ProtocolSession, SessionMap, SessionState};
+use crate::transport::TransportConnection;
+use crate::version::{Feature, ProtocolVersion, VersionRange};
+use crate::{ProtocolConfig, ProtocolError, ProtocolResult};
+use soft_kvm_core::{CapabilitySet, ErrorCode};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Auth method used when the server does not require credentials
+pub const AUTH_METHOD_NONE: &str = "none";
+
+/// Auth method carrying a shared token in `credentials.token`
+pub const AUTH_METHOD_TOKEN: &str = "token";
+
+/// Run the server side of the handshake on a freshly accepted connection.
+///
+/// On success the session is `Active`, authenticated and carries the peer
+/// information announced by the client. When the client presents a resume
+/// token, `session` is replaced by the matching session from `sessions`,
+/// which keeps its ID, negotiated capabilities and queues.
+pub async fn server_handshake(
+    connection: &mut dyn TransportConnection,
+    session: &mut ProtocolSession,
+    config: &ProtocolConfig,
+    sessions: &SessionMap,
+) -> ProtocolResult<()> {
+    let result = run_server_handshake(connection, session, config, sessions).await;
+
+    if let Err(e) = &result {
+        warn!("Handshake failed for session {}: {}", session.session_id(), e);
+        let error = ProtocolMessage::new(MessageType::Error, MessagePayload::Error(ErrorPayload::from_error(e)))
+            .with_session(session.session_id().to_string());
+        let _ = connection.send(error).await;
+        session.set_state(SessionState::Closed).await;
+    }
+
+    result
+}
+
+async fn run_server_handshake(
+    connection: &mut dyn TransportConnection,
+    session: &mut ProtocolSession,
+    config: &ProtocolConfig,
+    sessions: &SessionMap,
+) -> ProtocolResult<()> {
+    session.set_state(SessionState::Connecting).await;
+
+    // Hello
+    let hello = match receive_step(connection, config).await?.payload {
+        MessagePayload::Hello(hello) => hello,
+        other => return Err(unexpected("Hello", &other)),
+    };
+
+    let resumed = match &hello.resume_token {
+        Some(token) => Some(find_resumable(sessions, token, &hello.client_info.client_id).await?),
+        None => None,
+    };
+    let protocol_version = negotiate_version(config, &hello, resumed.as_ref())?;
+
+    // The server's intersection is authoritative; the client adopts it as-is.
+    // A resumed session keeps what was negotiated originally.
+    let negotiated_capabilities = match &resumed {
+        Some(existing) => existing.capabilities().clone(),
+        None => CapabilitySet::negotiate(&config.capabilities, &hello.capabilities),
+    };
+    let session_id = match &resumed {
+        Some(existing) => existing.session_id().to_string(),
+        None => session.session_id().to_string(),
+    };
+    let codec = CodecKind::negotiate(&hello.codecs, &config.codecs);
+    let compression = if config.compression_enabled && protocol_version.supports(Feature::Compression) {
+        CompressionKind::negotiate(&hello.compression, &config.compressors)
+    } else {
+        None
+    };
+
+    let mut peer_info = session.peer_info().clone();
+    peer_info.peer_id = hello.client_info.client_id.clone();
+    peer_info.peer_name = hello.client_info.client_name.clone();
+    peer_info.capabilities = negotiated_capabilities.clone();
+    peer_info.last_seen = chrono::Utc::now();
+    session.set_peer_info(peer_info);
+    session.set_protocol_version(protocol_version);
+
+    // Welcome
+    let welcome = ProtocolMessage::new(
+        MessageType::Welcome,
+        MessagePayload::Welcome(WelcomePayload {
+            server_info: ServerInfo {
+                server_id: config.node_id.clone(),
+                server_name: config.node_name.clone(),
+                protocol_version: protocol_version.to_string(),
+            },
+            session_id: session_id.clone(),
+            negotiated_capabilities,
+            codec,
+            compression,
+            resumed: resumed.is_some(),
+            input_port: config.input_channel.advertised_port(),
+        }),
+    )
+    .with_session(session_id.clone());
+    connection.send(welcome).await?;
+    connection.set_codec(codec.codec_with(compression, config.compression_threshold));
+    session.set_state(SessionState::Authenticating).await;
+
+    // AuthRequest
+    let auth = match receive_step(connection, config).await?.payload {
+        MessagePayload::AuthRequest(auth) => auth,
+        other => return Err(unexpected("AuthRequest", &other)),
+    };
+    let denied = verify_credentials(config, &auth).err();
+
+    // The session token is also the resume token. Every resume issues a new
+    // one, so a token seen in a Hello is no good once it has been used.
+    let session_token = denied.is_none().then(|| uuid::Uuid::new_v4().to_string());
+    let response = ProtocolMessage::new(
+        MessageType::AuthResponse,
+        MessagePayload::AuthResponse(AuthResponsePayload {
+            success: denied.is_none(),
+            session_token: session_token.clone(),
+            error_message: denied.as_ref().map(|e| e.to_string()),
+        }),
+    )
+    .with_session(session_id.clone());
+    connection.send(response).await?;
+
+    if let Some(e) = denied {
+        return Err(e);
+    }
+
+    match resumed {
+        Some(mut existing) => {
+            existing.set_peer_info(PeerInfo {
+                address: session.peer_info().address.clone(),
+                last_seen: chrono::Utc::now(),
+                ..existing.peer_info().clone()
+            });
+            existing.set_resume_token(session_token).await;
+            // Stops the driver of the old connection if it has not noticed the loss yet
+            existing.supersede();
+            *session = existing;
+            info!("Session {} resumed by {}", session.session_id(), session.peer_info().peer_id);
+        }
+        None => {
+            session.set_authenticated(true);
+            session.set_resume_token(session_token).await;
+            session.set_state(SessionState::Active).await;
+            info!(
+                "Session {} active for {} ({})",
+                session.session_id(),
+                session.peer_info().peer_name,
+                session.peer_info().peer_id
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the live session a resume token was issued for
+async fn find_resumable(sessions: &SessionMap, token: &str, client_id: &str) -> ProtocolResult<ProtocolSession> {
+    for candidate in sessions.read().await.values() {
+        let Some(issued) = candidate.resume_token().await else {
+            continue;
+        };
+        if !constant_time_eq(issued.as_bytes(), token.as_bytes()) {
+            continue;
+        }
+
+        if candidate.peer_info().peer_id != client_id {
+            return Err(ProtocolError::Authentication("Resume token was issued to another client".to_string()));
+        }
+        if matches!(candidate.state().await, SessionState::Active | SessionState::Suspended) {
+            return Ok(candidate.clone());
+        }
+        break;
+    }
+
+    Err(ProtocolError::Refused {
+        code: ErrorCode::SessionNotFound,
+        reason: "Unknown or expired resume token".to_string(),
+    })
+}
+
+/// Run the client side of the handshake on a freshly opened connection.
+///
+/// On success the session carries the server assigned ID and is `Active`.
+/// A session that already holds a resume token asks the server to resume
+/// it; its state is left to the driver and is untouched if resuming fails.
+pub async fn client_handshake(
+    connection: &mut dyn TransportConnection,
+    session: &mut ProtocolSession,
+    config: &ProtocolConfig,
+) -> ProtocolResult<WelcomePayload> {
+    let resume_token = session.resume_token().await;
+    let resuming = resume_token.is_some();
+
+    let result = run_client_handshake(connection, session, config, resume_token).await;
+    if let Err(e) = &result {
+        warn!("Handshake with server failed: {}", e);
+        if !resuming {
+            session.set_state(SessionState::Closed).await;
+        }
+    }
+    result
+}
+
+async fn run_client_handshake(
+    connection: &mut dyn TransportConnection,
+    session: &mut ProtocolSession,
+    config: &ProtocolConfig,
+    resume_token: Option<String>,
+) -> ProtocolResult<WelcomePayload> {
+    let resuming = resume_token.is_some();
+    if !resuming {
+        session.set_state(SessionState::Connecting).await;
+    }
+
+    // Hello
+    let hello = ProtocolMessage::new(
+        MessageType::Hello,
+        MessagePayload::Hello(HelloPayload {
+            protocol_version: config.version.clone(),
+            min_protocol_version: Some(config.min_version.clone()),
+            client_info: ClientInfo {
+                client_id: config.node_id.clone(),
+                client_name: config.node_name.clone(),
+                platform: std::env::consts::OS.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            capabilities: config.capabilities.clone(),
+            codecs: config.codecs.clone(),
+            resume_token,
+            compression: if config.compression_enabled {
+                config.compressors.clone()
+            } else {
+                Vec::new()
+            },
+        }),
+    );
+    connection.send(hello).await?;
+
+    // Welcome
+    let welcome = match receive_step(connection, config).await?.payload {
+        MessagePayload::Welcome(welcome) => welcome,
+        other => return Err(unexpected("Welcome", &other)),
+    };
+    let protocol_version = accept_version(config, &welcome.server_info.protocol_version)?;
+    if resuming && (!welcome.resumed || welcome.session_id != session.session_id()) {
+        return Err(ProtocolError::Session(format!("Server did not resume session {}", session.session_id())));
+    }
+    connection.set_codec(welcome.codec.codec_with(welcome.compression, config.compression_threshold));
+
+    session.set_session_id(welcome.session_id.clone());
+    session.set_protocol_version(protocol_version);
+    session.set_peer_info(PeerInfo {
+        peer_id: welcome.server_info.server_id.clone(),
+        peer_name: welcome.server_info.server_name.clone(),
+        capabilities: welcome.negotiated_capabilities.clone(),
+        last_seen: chrono::Utc::now(),
+        ..session.peer_info().clone()
+    });
+    if !resuming {
+        session.set_state(SessionState::Authenticating).await;
+    }
+
+    // AuthRequest
+    let (auth_method, credentials) = match &config.auth_token {
+        Some(token) => (AUTH_METHOD_TOKEN, serde_json::json!({ "token": token })),
+        None => (AUTH_METHOD_NONE, serde_json::Value::Null),
+    };
+    let auth = ProtocolMessage::new(
+        MessageType::AuthRequest,
+        MessagePayload::AuthRequest(AuthRequestPayload {
+            auth_method: auth_method.to_string(),
+            credentials,
+        }),
+    )
+    .with_session(welcome.session_id.clone());
+    connection.send(auth).await?;
+
+    // AuthResponse
+    let response = match receive_step(connection, config).await?.payload {
+        MessagePayload::AuthResponse(response) => response,
+        other => return Err(unexpected("AuthResponse", &other)),
+    };
+    if !response.success {
+        return Err(ProtocolError::Authentication(
+            response.error_message.unwrap_or_else(|| "Authentication rejected".to_string()),
+        ));
+    }
+
+    session.set_authenticated(true);
+    session.set_resume_token(response.session_token).await;
+    if !resuming {
+        session.set_state(SessionState::Active).await;
+    }
+    info!("Session {} established with {}", welcome.session_id, welcome.server_info.server_name);
+
+    Ok(welcome)
+}
+
+/// Wait for the next handshake message within the handshake timeout
+async fn receive_step(
+    connection: &mut dyn TransportConnection,
+    config: &ProtocolConfig,
+) -> ProtocolResult<ProtocolMessage> {
+    let timeout = Duration::from_secs(config.handshake_timeout);
+    let message = tokio::time::timeout(timeout, connection.receive())
+        .await
+        .map_err(|_| ProtocolError::Timeout)??
+        .ok_or_else(|| ProtocolError::Transport("Connection closed during handshake".to_string()))?;
+
+    debug!("Handshake received {:?}", message.message_type());
+    Ok(message)
+}
+
+/// Pick the protocol version for a session: the highest version both sides
+/// speak, or the version a resumed session already runs if the client still
+/// speaks it
+fn negotiate_version(
+    config: &ProtocolConfig,
+    hello: &HelloPayload,
+    resumed: Option<&ProtocolSession>,
+) -> ProtocolResult<ProtocolVersion> {
+    let ours = config.version_range()?;
+    let mismatch = |expected: String| ProtocolError::VersionMismatch {
+        expected,
+        got: match &hello.min_protocol_version {
+            Some(min) => format!("{} - {}", min, hello.protocol_version),
+            None => hello.protocol_version.clone(),
+        },
+    };
+    let theirs = VersionRange::parse(hello.min_protocol_version.as_deref(), &hello.protocol_version)
+        .map_err(|_| mismatch(ours.to_string()))?;
+
+    let version = match resumed {
+        Some(existing) if theirs.contains(&existing.protocol_version()) => existing.protocol_version(),
+        Some(existing) => return Err(mismatch(existing.protocol_version().to_string())),
+        None => ours.negotiate(&theirs).ok_or_else(|| mismatch(ours.to_string()))?,
+    };
+    debug!("Negotiated protocol version {} (ours {}, theirs {})", version, ours, theirs);
+    Ok(version)
+}
+
+/// Check that the version chosen by the server is one we speak
+fn accept_version(config: &ProtocolConfig, chosen: &str) -> ProtocolResult<ProtocolVersion> {
+    let ours = config.version_range()?;
+    let mismatch = || ProtocolError::VersionMismatch {
+        expected: ours.to_string(),
+        got: chosen.to_string(),
+    };
+
+    let version = chosen.parse().map_err(|_| mismatch())?;
+    if ours.contains(&version) {
+        Ok(version)
+    } else {
+        Err(mismatch())
+    }
+}
+
+/// Verify client credentials against the configured token
+fn verify_credentials(config: &ProtocolConfig, auth: &AuthRequestPayload) -> ProtocolResult<()> {
+    let Some(expected) = &config.auth_token else {
+        return Ok(());
+    };
+
+    if auth.auth_method != AUTH_METHOD_TOKEN {
+        return Err(ProtocolError::Authentication(format!("Unsupported auth method: {}", auth.auth_method)));
+    }
+
+    let token = auth.credentials.get("token").and_then(|token| token.as_str()).unwrap_or_default();
+    if constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(ProtocolError::Authentication("Invalid credentials".to_string()))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Map an unexpected payload (including a peer Error) to a protocol error
+fn unexpected(expected: &str, payload: &MessagePayload) -> ProtocolError {
+    match payload {
+        MessagePayload::Error(error) => ProtocolError::from(error.clone()),
+        other => ProtocolError::InvalidMessageType(format!(
+            "Expected {} during handshake, got {:?}",
+            expected,
+            other.message_type()
+        )),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transport::{TransportConfig, TransportListener};
+    use crate::websocket::{WebSocketConnection, WebSocketListener};
+    use soft_kvm_core::{Capability, CapabilityKind, InputDeviceClass, NetworkAddress};
+
+    pub(crate) fn test_config() -> ProtocolConfig {
+        ProtocolConfig {
+            handshake_timeout: 1,
+            ..ProtocolConfig::default()
+        }
+    }
+
+    pub(crate) fn new_session(session_id: &str, config: &ProtocolConfig) -> ProtocolSession {
+        let peer_info = PeerInfo {
+            peer_id: String::new(),
+            peer_name: String::new(),
+            address: NetworkAddress::localhost(0),
+            capabilities: CapabilitySet::default(),
+            authenticated: false,
+            last_seen: chrono::Utc::now(),
+        };
+        ProtocolSession::new(session_id.to_string(), peer_info, config.clone())
+    }
+
+    pub(crate) fn no_sessions() -> SessionMap {
+        std::sync::Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::new()))
+    }
+
+    /// Open a loopback WebSocket pair
+    pub(crate) async fn connection_pair() -> (Box<dyn TransportConnection>, Box<dyn TransportConnection>) {
+        let mut listener = WebSocketListener::new(TransportConfig::default(), "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let client = WebSocketConnection::connect(addr, TransportConfig::default()).await.unwrap();
+        (accept.await.unwrap(), Box::new(client))
+    }
+
+    #[tokio::test]
+    async fn test_handshake_reaches_active() {
+        let config = test_config();
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-1", &config);
+        let server_config = config.clone();
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await.unwrap();
+            server_session
+        });
+
+        let mut client_session = new_session("client-session", &config);
+        let welcome = client_handshake(client_conn.as_mut(), &mut client_session, &config).await.unwrap();
+        let server_session = server.await.unwrap();
+
+        assert_eq!(welcome.codec, CodecKind::Binary);
+        assert_eq!(welcome.server_info.protocol_version, crate::PROTOCOL_VERSION);
+        assert_eq!(client_session.protocol_version(), ProtocolVersion::current());
+        assert_eq!(client_session.session_id(), "server-session-1");
+        assert_eq!(client_session.state().await, SessionState::Active);
+        assert_eq!(server_session.state().await, SessionState::Active);
+        assert!(server_session.is_authenticated());
+        assert_eq!(server_session.peer_info().peer_id, config.node_id);
+        assert_eq!(server_session.peer_info().capabilities, config.capabilities);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_negotiates_capabilities() {
+        let server_config = test_config();
+        let client_config = ProtocolConfig {
+            capabilities: CapabilitySet::new(vec![
+                Capability::Input { devices: vec![InputDeviceClass::Touch, InputDeviceClass::Mouse] },
+                Capability::Metrics,
+            ]),
+            ..test_config()
+        };
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-caps", &server_config);
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await.unwrap();
+            server_session
+        });
+
+        let mut client_session = new_session("client-session", &client_config);
+        let welcome = client_handshake(client_conn.as_mut(), &mut client_session, &client_config).await.unwrap();
+        let server_session = server.await.unwrap();
+
+        let expected = CapabilitySet::new(vec![
+            Capability::Input { devices: vec![InputDeviceClass::Mouse] },
+            Capability::Metrics,
+        ]);
+        assert_eq!(welcome.negotiated_capabilities, expected);
+        assert_eq!(server_session.capabilities(), &expected);
+        assert_eq!(client_session.capabilities(), &expected);
+        assert!(!client_session.capabilities().supports(CapabilityKind::Video));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_state_transitions() {
+        let config = test_config();
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-2", &config);
+        let observer = server_session.clone();
+        let server_config = config.clone();
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await
+        });
+
+        assert_eq!(observer.state().await, SessionState::Connecting);
+
+        let hello = ProtocolMessage::new(
+            MessageType::Hello,
+            MessagePayload::Hello(HelloPayload {
+                protocol_version: config.version.clone(),
+                min_protocol_version: None,
+                client_info: ClientInfo {
+                    client_id: "manual-client".to_string(),
+                    client_name: "Manual Client".to_string(),
+                    platform: "test".to_string(),
+                    version: "0.1.0".to_string(),
+                },
+                capabilities: CapabilitySet::new(vec![Capability::Metrics]),
+                codecs: vec![CodecKind::Json],
+                resume_token: None,
+                compression: Vec::new(),
+            }),
+        );
+        client_conn.send(hello).await.unwrap();
+
+        let welcome = client_conn.receive().await.unwrap().unwrap();
+        assert!(matches!(welcome.payload, MessagePayload::Welcome(ref w) if w.codec == CodecKind::Json));
+        assert_eq!(observer.state().await, SessionState::Authenticating);
+
+        let auth = ProtocolMessage::new(
+            MessageType::AuthRequest,
+            MessagePayload::AuthRequest(AuthRequestPayload {
+                auth_method: AUTH_METHOD_NONE.to_string(),
+                credentials: serde_json::Value::Null,
+            }),
+        );
+        client_conn.send(auth).await.unwrap();
+
+        let response = client_conn.receive().await.unwrap().unwrap();
+        assert!(matches!(response.payload, MessagePayload::AuthResponse(ref r) if r.success && r.session_token.is_some()));
+        server.await.unwrap().unwrap();
+        assert_eq!(observer.state().await, SessionState::Active);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_resumes_suspended_session() {
+        let config = test_config();
+        let sessions = no_sessions();
+
+        // Establish the original session
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+        let mut original = new_session("server-session-resume", &config);
+        let (server_config, registry) = (config.clone(), sessions.clone());
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut original, &server_config, &registry).await.unwrap();
+            original
+        });
+        let mut client_session = new_session("client-session", &config);
+        client_handshake(client_conn.as_mut(), &mut client_session, &config).await.unwrap();
+        let original = server.await.unwrap();
+        sessions.write().await.insert(original.session_id().to_string(), original.clone());
+        original.set_state(SessionState::Suspended).await;
+
+        let token = client_session.resume_token().await;
+        assert!(token.is_some());
+        assert_eq!(token, original.resume_token().await);
+
+        // Resume it on a new connection
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+        let mut fresh = new_session("server-session-other", &config);
+        let (server_config, registry) = (config.clone(), sessions.clone());
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut fresh, &server_config, &registry).await.unwrap();
+            fresh
+        });
+        let welcome = client_handshake(client_conn.as_mut(), &mut client_session, &config).await.unwrap();
+        let resumed = server.await.unwrap();
+
+        assert!(welcome.resumed);
+        assert_eq!(welcome.session_id, "server-session-resume");
+        assert_eq!(resumed.session_id(), "server-session-resume");
+        assert_eq!(resumed.capabilities(), original.capabilities());
+        assert_eq!(resumed.generation(), 1);
+
+        // The resume issued a new token and retired the old one
+        let renewed = client_session.resume_token().await;
+        assert!(renewed.is_some());
+        assert_ne!(renewed, token);
+        assert_eq!(renewed, resumed.resume_token().await);
+        assert!(matches!(
+            find_resumable(&sessions, token.as_deref().unwrap(), &config.node_id).await,
+            Err(ProtocolError::Refused { code: ErrorCode::SessionNotFound, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_unknown_resume_token() {
+        let config = test_config();
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-stale", &config);
+        let server_config = config.clone();
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await
+        });
+
+        let mut client_session = new_session("server-session-gone", &config);
+        client_session.set_state(SessionState::Suspended).await;
+        client_session.set_resume_token(Some("stale-token".to_string())).await;
+        let result = client_handshake(client_conn.as_mut(), &mut client_session, &config).await;
+
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(ProtocolError::Refused { code: ErrorCode::SessionNotFound, .. })
+        ));
+        assert!(matches!(result, Err(ProtocolError::Remote { code: ErrorCode::SessionNotFound, .. })));
+        // A failed resume leaves the state to the reconnect logic
+        assert_eq!(client_session.state().await, SessionState::Suspended);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_version_mismatch() {
+        let config = test_config();
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-3", &config);
+        let observer = server_session.clone();
+        let server_config = config.clone();
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await
+        });
+
+        let client_config = ProtocolConfig {
+            version: "2.1.0".to_string(),
+            min_version: "2.0.0".to_string(),
+            ..config.clone()
+        };
+        let mut client_session = new_session("client-session", &client_config);
+        let client_result = client_handshake(client_conn.as_mut(), &mut client_session, &client_config).await;
+
+        assert!(matches!(server.await.unwrap(), Err(ProtocolError::VersionMismatch { .. })));
+        // The client learns why and that retrying won't help
+        let client_error = client_result.unwrap_err();
+        assert_eq!(client_error.code(), ErrorCode::VersionMismatch);
+        assert!(!client_error.is_retryable());
+        assert_eq!(observer.state().await, SessionState::Closed);
+        assert_eq!(client_session.state().await, SessionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_downgrades_to_common_version() {
+        let server_config = test_config();
+        let client_config = ProtocolConfig {
+            version: "1.1.0".to_string(),
+            ..test_config()
+        };
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-legacy", &server_config);
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await.unwrap();
+            server_session
+        });
+
+        let mut client_session = new_session("client-session", &client_config);
+        let welcome = client_handshake(client_conn.as_mut(), &mut client_session, &client_config).await.unwrap();
+        let server_session = server.await.unwrap();
+
+        let older = ProtocolVersion::new(1, 1, 0);
+        assert_eq!(welcome.server_info.protocol_version, "1.1.0");
+        assert_eq!(server_session.protocol_version(), older);
+        assert_eq!(client_session.protocol_version(), older);
+        // Nothing newer than 1.1 is used on the session
+        let control = ProtocolMessage::new(MessageType::Control, MessagePayload::Control(ControlPayload::Request));
+        assert!(matches!(
+            server_session.send_message(control).await,
+            Err(ProtocolError::FeatureNotNegotiated { feature: Feature::Control, .. })
+        ));
+        let ping = ProtocolMessage::new(MessageType::Ping, MessagePayload::Ping(PingPayload::now()));
+        assert!(server_session.send_message(ping).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_protocol_1_0() {
+        let server_config = test_config();
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-1-0", &server_config);
+        let server = tokio::spawn(async move {
+            server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await
+        });
+
+        let client_config = ProtocolConfig {
+            version: "1.0.0".to_string(),
+            min_version: "1.0.0".to_string(),
+            ..test_config()
+        };
+        let mut client_session = new_session("client-session", &client_config);
+        let client_result = client_handshake(client_conn.as_mut(), &mut client_session, &client_config).await;
+
+        assert!(matches!(server.await.unwrap(), Err(ProtocolError::VersionMismatch { .. })));
+        assert_eq!(client_result.unwrap_err().code(), ErrorCode::VersionMismatch);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_auth_failure() {
+        let config = ProtocolConfig {
+            auth_token: Some("secret".to_string()),
+            ..test_config()
+        };
+        let (mut server_conn, mut client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-4", &config);
+        let server_config = config.clone();
+        let server = tokio::spawn(async move {
+            let result = server_handshake(server_conn.as_mut(), &mut server_session, &server_config, &no_sessions()).await;
+            (result, server_session)
+        });
+
+        let client_config = ProtocolConfig { auth_token: Some("wrong".to_string()), ..config.clone() };
+        let mut client_session = new_session("client-session", &client_config);
+        let client_result = client_handshake(client_conn.as_mut(), &mut client_session, &client_config).await;
+
+        let (server_result, server_session) = server.await.unwrap();
+        assert!(matches!(server_result, Err(ProtocolError::Authentication(_))));
+        assert!(matches!(client_result, Err(ProtocolError::Authentication(_))));
+        assert!(!server_session.is_authenticated());
+        assert_eq!(server_session.state().await, SessionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_times_out_without_hello() {
+        let config = test_config();
+        let (mut server_conn, _client_conn) = connection_pair().await;
+
+        let mut server_session = new_session("server-session-5", &config);
+        let result = server_handshake(server_conn.as_mut(), &mut server_session, &config, &no_sessions()).await;
+
+        assert!(matches!(result, Err(ProtocolError::Timeout)));
+        assert_eq!(server_session.state().await, SessionState::Closed);
+    }
+}
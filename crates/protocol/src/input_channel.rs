@@ -0,0 +1,1253 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Low-latency input channel
+//!
+//! Keyboard and mouse events travel over UDP next to the control WebSocket,
+//! so a retransmitted video frame never holds up a key press. The channel
+//! has no handshake of its own: its keys are derived from the session token
+//! issued in AuthResponse, and every datagram is sealed with
+//! ChaCha20-Poly1305. The token must stay secret, so the server only offers
+//! the channel when the control connection runs over TLS.
+//!
+//! Datagrams carry a sequence number, and anything not newer than the last
+//! accepted datagram is dropped. The sequence number is also the nonce;
+//! every copy of a channel's keys shares the counters, and a resume issues
+//! a new session token and with it new keys, so no nonce is used twice
+//! under one key. A lost key release would leave the key stuck, so every
+//! datagram also carries a snapshot of the keys and buttons held by the
+//! sender; the receiver synthesizes whatever press or release it missed.
+//! Snapshots are repeated on a timer while anything is held.
+//!
+//! If the server does not answer the probe, input keeps flowing over the
+//! WebSocket session.
+
+use crate::codec::{BinaryCodec, MessageCodec};
+use crate::messages::{InputEventPayload, MessagePayload, MessageType, ProtocolMessage};
+use crate::session::ProtocolSession;
+use crate::transport::{TransportConfig, TransportConnection, TransportFactory, TransportListener};
+use crate::{ProtocolError, ProtocolResult};
+use async_trait::async_trait;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf;
+use soft_kvm_core::{KeyboardEvent, MouseButton, MouseEvent};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+const MAGIC: [u8; 2] = *b"SI";
+const WIRE_VERSION: u8 = 1;
+const HEADER_LEN: usize = 2 + 1 + 8 + 8; // magic, version, channel ID, sequence
+const MAX_DATAGRAM: usize = 1200; // below the path MTU of any sane network
+
+const KIND_PROBE: u8 = 0;
+const KIND_PROBE_ACK: u8 = 1;
+const KIND_EVENT: u8 = 2;
+const KIND_SNAPSHOT: u8 = 3;
+
+/// Empty snapshots sent after the last key is released
+const RELEASE_REPEATS: u32 = 3;
+
+/// Decrypted datagrams buffered per connection
+const INCOMING_CAPACITY: usize = 256;
+
+/// Input channel configuration
+#[derive(Debug, Clone)]
+pub struct InputChannelConfig {
+    pub enabled: bool, // only offered when the control connection uses TLS
+    pub port: u16, // server UDP port, 0 picks one when the server starts
+    pub probe_timeout_ms: u64,
+    pub probe_attempts: u32,
+    pub snapshot_interval_ms: u64,
+}
+
+impl Default for InputChannelConfig {
+    fn default() -> Self {
+        InputChannelConfig {
+            enabled: true,
+            port: 0,
+            probe_timeout_ms: 250,
+            probe_attempts: 3,
+            snapshot_interval_ms: 100,
+        }
+    }
+}
+
+impl InputChannelConfig {
+    /// Port announced in Welcome, once the server has bound one
+    pub fn advertised_port(&self) -> Option<u16> {
+        (self.enabled && self.port != 0).then_some(self.port)
+    }
+}
+
+/// Keys of one session's input channel
+#[derive(Clone)]
+pub struct InputKeys {
+    session_id: String,
+    channel_id: u64,
+    client_to_server: [u8; 32],
+    server_to_client: [u8; 32],
+    /// Last sequence number sealed in each direction, shared by every clone
+    sequences: [Arc<AtomicU64>; 2],
+}
+
+impl std::fmt::Debug for InputKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InputKeys")
+            .field("session_id", &self.session_id)
+            .field("channel_id", &self.channel_id)
+            .finish_non_exhaustive()
+    }
+}
+
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+impl InputKeys {
+    /// Derive the channel keys from a session's token with HKDF-SHA256
+    pub fn derive(session_token: &str, session_id: &str) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, session_id.as_bytes()).extract(session_token.as_bytes());
+        let expand = |label: &[u8], out: &mut [u8]| {
+            prk.expand(&[label], OutputLen(out.len()))
+                .and_then(|okm| okm.fill(out))
+                .expect("HKDF output is far below its length limit");
+        };
+
+        let mut channel_id = [0u8; 8];
+        let mut client_to_server = [0u8; 32];
+        let mut server_to_client = [0u8; 32];
+        expand(b"soft-kvm input channel id", &mut channel_id);
+        expand(b"soft-kvm input client to server", &mut client_to_server);
+        expand(b"soft-kvm input server to client", &mut server_to_client);
+
+        InputKeys {
+            session_id: session_id.to_string(),
+            channel_id: u64::from_be_bytes(channel_id),
+            client_to_server,
+            server_to_client,
+            sequences: Default::default(),
+        }
+    }
+
+    /// Session the channel belongs to
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Channel identifier sent in the clear with every datagram
+    pub fn channel_id(&self) -> u64 {
+        self.channel_id
+    }
+
+    fn key(&self, direction: Direction) -> LessSafeKey {
+        let bytes = match direction {
+            Direction::ClientToServer => &self.client_to_server,
+            Direction::ServerToClient => &self.server_to_client,
+        };
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, bytes).expect("ChaCha20 keys are 32 bytes"))
+    }
+}
+
+/// Keys of the sessions a server accepts input for, by channel ID
+pub type InputKeyRing = Arc<RwLock<HashMap<u64, InputKeys>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Seals outgoing datagrams for one direction of a channel
+struct Sealer {
+    key: LessSafeKey,
+    channel_id: u64,
+    sequence: Arc<AtomicU64>,
+}
+
+impl Sealer {
+    fn new(keys: &InputKeys, direction: Direction) -> Self {
+        // Continuing the session's count keeps a re-created channel from
+        // reusing a nonce or looking like a replay to the peer
+        Sealer {
+            key: keys.key(direction),
+            channel_id: keys.channel_id,
+            sequence: keys.sequences[direction as usize].clone(),
+        }
+    }
+
+    fn seal(&self, kind: u8, body: &[u8]) -> Vec<u8> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let header = encode_header(self.channel_id, sequence);
+
+        let mut sealed = Vec::with_capacity(1 + body.len() + CHACHA20_POLY1305.tag_len());
+        sealed.push(kind);
+        sealed.extend_from_slice(body);
+        self.key
+            .seal_in_place_append_tag(nonce(sequence), Aad::from(header), &mut sealed)
+            .expect("datagram fits in a single AEAD message");
+
+        let mut datagram = header.to_vec();
+        datagram.extend_from_slice(&sealed);
+        datagram
+    }
+}
+
+fn encode_header(channel_id: u64, sequence: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..2].copy_from_slice(&MAGIC);
+    header[2] = WIRE_VERSION;
+    header[3..11].copy_from_slice(&channel_id.to_be_bytes());
+    header[11..].copy_from_slice(&sequence.to_be_bytes());
+    header
+}
+
+/// Read the channel ID and sequence number from a datagram header
+fn decode_header(datagram: &[u8]) -> ProtocolResult<(u64, u64)> {
+    if datagram.len() < HEADER_LEN || datagram[..2] != MAGIC {
+        return Err(ProtocolError::Codec("Not an input channel datagram".to_string()));
+    }
+    if datagram[2] != WIRE_VERSION {
+        return Err(ProtocolError::Codec(format!("Unsupported input channel version {}", datagram[2])));
+    }
+    let channel_id = u64::from_be_bytes(datagram[3..11].try_into().unwrap());
+    let sequence = u64::from_be_bytes(datagram[11..HEADER_LEN].try_into().unwrap());
+    Ok((channel_id, sequence))
+}
+
+fn nonce(sequence: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Authenticate and decrypt a datagram, returning its sequence, kind and body
+fn open(key: &LessSafeKey, datagram: &[u8]) -> ProtocolResult<(u64, u8, Vec<u8>)> {
+    let (_, sequence) = decode_header(datagram)?;
+    let header: [u8; HEADER_LEN] = datagram[..HEADER_LEN].try_into().unwrap();
+
+    let mut sealed = datagram[HEADER_LEN..].to_vec();
+    let plaintext = key
+        .open_in_place(nonce(sequence), Aad::from(header), &mut sealed)
+        .map_err(|_| ProtocolError::Authentication("Input datagram failed authentication".to_string()))?;
+    let (&kind, body) = plaintext
+        .split_first()
+        .ok_or_else(|| ProtocolError::Codec("Empty input datagram".to_string()))?;
+    Ok((sequence, kind, body.to_vec()))
+}
+
+/// Keys and mouse buttons held at one instant
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyStateSnapshot {
+    pub keys: BTreeSet<u32>,
+    pub buttons: BTreeSet<MouseButton>,
+}
+
+impl KeyStateSnapshot {
+    /// Check if nothing is held
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.buttons.is_empty()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // At most a few dozen keys can be physically held at once
+        let keys: Vec<u32> = self.keys.iter().copied().take(u8::MAX as usize).collect();
+        buf.push(keys.len() as u8);
+        for key in keys {
+            buf.extend_from_slice(&key.to_be_bytes());
+        }
+        buf.push(self.buttons.len() as u8);
+        buf.extend(self.buttons.iter().map(|button| button_id(*button)));
+    }
+
+    fn decode(data: &[u8]) -> ProtocolResult<Self> {
+        let truncated = || ProtocolError::Codec("Truncated key state snapshot".to_string());
+
+        let (&key_count, rest) = data.split_first().ok_or_else(truncated)?;
+        let key_bytes = rest.get(..key_count as usize * 4).ok_or_else(truncated)?;
+        let keys = key_bytes
+            .chunks_exact(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        let rest = &rest[key_bytes.len()..];
+        let (&button_count, rest) = rest.split_first().ok_or_else(truncated)?;
+        let buttons = rest
+            .get(..button_count as usize)
+            .ok_or_else(truncated)?
+            .iter()
+            .filter_map(|id| button_from_id(*id))
+            .collect();
+
+        Ok(KeyStateSnapshot { keys, buttons })
+    }
+}
+
+fn button_id(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 0,
+        MouseButton::Right => 1,
+        MouseButton::Middle => 2,
+        MouseButton::Button4 => 3,
+        MouseButton::Button5 => 4,
+    }
+}
+
+fn button_from_id(id: u8) -> Option<MouseButton> {
+    match id {
+        0 => Some(MouseButton::Left),
+        1 => Some(MouseButton::Right),
+        2 => Some(MouseButton::Middle),
+        3 => Some(MouseButton::Button4),
+        4 => Some(MouseButton::Button5),
+        _ => None,
+    }
+}
+
+/// Tracks which keys and buttons are held, from the events seen so far
+#[derive(Debug, Clone, Default)]
+pub struct KeyState {
+    held: KeyStateSnapshot,
+}
+
+impl KeyState {
+    /// Update the held keys from an input event
+    pub fn observe(&mut self, event: &InputEventPayload) {
+        if let Some(keyboard) = event.keyboard_event() {
+            match keyboard {
+                KeyboardEvent::KeyPress { key_code, .. } => self.held.keys.insert(key_code),
+                KeyboardEvent::KeyRelease { key_code, .. } => self.held.keys.remove(&key_code),
+            };
+        } else if let Some(mouse) = event.mouse_event() {
+            match mouse {
+                MouseEvent::MouseButtonPress { button } => {
+                    self.held.buttons.insert(button);
+                }
+                MouseEvent::MouseButtonRelease { button } => {
+                    self.held.buttons.remove(&button);
+                }
+                MouseEvent::MouseMove { .. } | MouseEvent::MouseScroll { .. } => {}
+            }
+        }
+    }
+
+    /// Get the keys and buttons currently held
+    pub fn snapshot(&self) -> KeyStateSnapshot {
+        self.held.clone()
+    }
+
+    /// Adopt the sender's snapshot, returning the events that were missed.
+    ///
+    /// Applying the same snapshot again yields nothing.
+    pub fn reconcile(&mut self, snapshot: &KeyStateSnapshot) -> Vec<InputEventPayload> {
+        let mut missed = Vec::new();
+
+        for &key_code in self.held.keys.difference(&snapshot.keys) {
+            missed.push(InputEventPayload::keyboard(&KeyboardEvent::KeyRelease { key_code, modifiers: 0 }));
+        }
+        for &button in self.held.buttons.difference(&snapshot.buttons) {
+            missed.push(InputEventPayload::mouse(&MouseEvent::MouseButtonRelease { button }));
+        }
+        for &key_code in snapshot.keys.difference(&self.held.keys) {
+            missed.push(InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code, modifiers: 0 }));
+        }
+        for &button in snapshot.buttons.difference(&self.held.buttons) {
+            missed.push(InputEventPayload::mouse(&MouseEvent::MouseButtonPress { button }));
+        }
+
+        self.held = snapshot.clone();
+        missed
+    }
+}
+
+/// A decrypted, in-order datagram
+#[derive(Debug)]
+enum Incoming {
+    Event(Box<ProtocolMessage>, KeyStateSnapshot),
+    Snapshot(KeyStateSnapshot),
+}
+
+fn decode_incoming(kind: u8, body: &[u8]) -> ProtocolResult<Option<Incoming>> {
+    match kind {
+        KIND_EVENT => {
+            let length = body
+                .get(..4)
+                .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+                .ok_or_else(|| ProtocolError::Codec("Truncated input datagram".to_string()))?;
+            let frame = body
+                .get(4..4 + length)
+                .ok_or_else(|| ProtocolError::Codec("Truncated input datagram".to_string()))?;
+            let message = BinaryCodec.decode(frame)?;
+            if !matches!(message.payload, MessagePayload::InputEvent(_)) {
+                return Err(ProtocolError::InvalidMessageType(format!("{:?} on input channel", message.message_type())));
+            }
+            let snapshot = KeyStateSnapshot::decode(&body[4 + length..])?;
+            Ok(Some(Incoming::Event(Box::new(message), snapshot)))
+        }
+        KIND_SNAPSHOT => Ok(Some(Incoming::Snapshot(KeyStateSnapshot::decode(body)?))),
+        _ => Ok(None),
+    }
+}
+
+/// Where a connection sends its datagrams
+#[derive(Debug, Clone)]
+enum Peer {
+    /// Client socket connected to the server
+    Connected(SocketAddr),
+    /// Shared server socket; follows the client if its address changes
+    Routed(Arc<Mutex<SocketAddr>>),
+}
+
+impl Peer {
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Peer::Connected(addr) => *addr,
+            Peer::Routed(addr) => *addr.lock().unwrap(),
+        }
+    }
+
+    async fn send(&self, socket: &UdpSocket, datagram: &[u8]) -> std::io::Result<()> {
+        match self {
+            Peer::Connected(_) => socket.send(datagram).await?,
+            Peer::Routed(_) => socket.send_to(datagram, self.addr()).await?,
+        };
+        Ok(())
+    }
+}
+
+/// Encrypted datagram connection carrying input events of one session
+pub struct UdpInputConnection {
+    socket: Arc<UdpSocket>,
+    peer: Peer,
+    keys: InputKeys,
+    sealer: Arc<Sealer>,
+    incoming: mpsc::Receiver<Incoming>,
+    sent: Arc<Mutex<KeyState>>,
+    received: KeyState,
+    pending: VecDeque<ProtocolMessage>,
+    tasks: Vec<JoinHandle<()>>,
+    alive: bool,
+}
+
+impl UdpInputConnection {
+    /// Open the input channel to a server and wait for it to answer a probe
+    pub async fn connect(addr: SocketAddr, keys: InputKeys, config: &InputChannelConfig) -> ProtocolResult<Self> {
+        let bind_addr: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let socket = UdpSocket::bind(bind_addr).await
+            .map_err(|e| ProtocolError::Transport(format!("Failed to bind input channel socket: {}", e)))?;
+        socket.connect(addr).await
+            .map_err(|e| ProtocolError::Transport(format!("Failed to connect input channel to {}: {}", addr, e)))?;
+
+        let sealer = Arc::new(Sealer::new(&keys, Direction::ClientToServer));
+        let opener = keys.key(Direction::ServerToClient);
+        let timeout = Duration::from_millis(config.probe_timeout_ms);
+
+        let mut last_sequence = None;
+        for attempt in 1..=config.probe_attempts {
+            socket.send(&sealer.seal(KIND_PROBE, &[])).await
+                .map_err(|e| ProtocolError::Transport(format!("Input channel probe failed: {}", e)))?;
+
+            match tokio::time::timeout(timeout, wait_for_ack(&socket, &opener)).await {
+                Ok(result) => {
+                    last_sequence = Some(result?);
+                    break;
+                }
+                Err(_) => debug!("Input channel probe {} to {} timed out", attempt, addr),
+            }
+        }
+        let last_sequence = last_sequence
+            .ok_or_else(|| ProtocolError::Transport(format!("No answer from input channel at {}", addr)))?;
+
+        let socket = Arc::new(socket);
+        let (sender, incoming) = mpsc::channel(INCOMING_CAPACITY);
+        let reader = tokio::spawn(read_connected(socket.clone(), opener, last_sequence, sender));
+
+        let mut connection = Self::new(socket, Peer::Connected(addr), keys, sealer, incoming, config);
+        connection.tasks.push(reader);
+        Ok(connection)
+    }
+
+    fn new(
+        socket: Arc<UdpSocket>,
+        peer: Peer,
+        keys: InputKeys,
+        sealer: Arc<Sealer>,
+        incoming: mpsc::Receiver<Incoming>,
+        config: &InputChannelConfig,
+    ) -> Self {
+        let sent = Arc::new(Mutex::new(KeyState::default()));
+        let snapshots = tokio::spawn(repeat_snapshots(
+            socket.clone(),
+            peer.clone(),
+            sealer.clone(),
+            sent.clone(),
+            Duration::from_millis(config.snapshot_interval_ms.max(1)),
+        ));
+
+        UdpInputConnection {
+            socket,
+            peer,
+            keys,
+            sealer,
+            incoming,
+            sent,
+            received: KeyState::default(),
+            pending: VecDeque::new(),
+            tasks: vec![snapshots],
+            alive: true,
+        }
+    }
+
+    /// Get the keys the channel was opened with
+    pub fn keys(&self) -> &InputKeys {
+        &self.keys
+    }
+
+    fn tag(&self, payload: InputEventPayload) -> ProtocolMessage {
+        ProtocolMessage::new(MessageType::InputEvent, MessagePayload::InputEvent(payload))
+            .with_session(self.keys.session_id.clone())
+    }
+}
+
+impl Drop for UdpInputConnection {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl TransportConnection for UdpInputConnection {
+    async fn send(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
+        if !self.alive {
+            return Err(ProtocolError::Transport("Input channel closed".to_string()));
+        }
+        let MessagePayload::InputEvent(event) = &message.payload else {
+            return Err(ProtocolError::InvalidMessageType(format!(
+                "{:?} cannot be sent on the input channel",
+                message.message_type()
+            )));
+        };
+
+        let snapshot = {
+            let mut sent = self.sent.lock().unwrap();
+            sent.observe(event);
+            sent.snapshot()
+        };
+
+        let frame = BinaryCodec.encode(&message)?;
+        let mut body = Vec::with_capacity(4 + frame.len() + 16);
+        body.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        body.extend_from_slice(&frame);
+        snapshot.encode(&mut body);
+
+        let datagram = self.sealer.seal(KIND_EVENT, &body);
+        if datagram.len() > MAX_DATAGRAM {
+            return Err(ProtocolError::Transport(format!("Input event too large for a datagram: {} bytes", datagram.len())));
+        }
+
+        self.peer.send(&self.socket, &datagram).await
+            .map_err(|e| ProtocolError::Transport(format!("Failed to send input datagram: {}", e)))
+    }
+
+    async fn receive(&mut self) -> ProtocolResult<Option<ProtocolMessage>> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(Some(message));
+            }
+
+            let snapshot = match self.incoming.recv().await {
+                Some(Incoming::Event(message, snapshot)) => {
+                    if let MessagePayload::InputEvent(event) = &message.payload {
+                        self.received.observe(event);
+                    }
+                    self.pending.push_back(message.with_session(self.keys.session_id.clone()));
+                    snapshot
+                }
+                Some(Incoming::Snapshot(snapshot)) => snapshot,
+                None => {
+                    self.alive = false;
+                    return Ok(None);
+                }
+            };
+
+            for missed in self.received.reconcile(&snapshot) {
+                debug!("Recovered lost input on channel {:016x}: {}", self.keys.channel_id, missed.data);
+                let message = self.tag(missed);
+                self.pending.push_back(message);
+            }
+        }
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.alive = false;
+        self.incoming.close();
+        for task in &self.tasks {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer.addr())
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive
+    }
+}
+
+/// Wait for an authenticated probe acknowledgement, returning its sequence
+async fn wait_for_ack(socket: &UdpSocket, opener: &LessSafeKey) -> ProtocolResult<u64> {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let len = socket.recv(&mut buf).await
+            .map_err(|e| ProtocolError::Transport(format!("Input channel probe failed: {}", e)))?;
+        match open(opener, &buf[..len]) {
+            Ok((sequence, KIND_PROBE_ACK, _)) => return Ok(sequence),
+            Ok(_) => {}
+            Err(e) => debug!("Ignoring datagram while probing: {}", e),
+        }
+    }
+}
+
+/// Read datagrams from a connected client socket
+async fn read_connected(socket: Arc<UdpSocket>, opener: LessSafeKey, mut last_sequence: u64, sender: mpsc::Sender<Incoming>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let len = match socket.recv(&mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                debug!("Input channel receive failed: {}", e);
+                continue;
+            }
+        };
+        let (sequence, kind, body) = match open(&opener, &buf[..len]) {
+            Ok(opened) => opened,
+            Err(e) => {
+                debug!("Dropping input datagram: {}", e);
+                continue;
+            }
+        };
+        if sequence <= last_sequence {
+            continue;
+        }
+        last_sequence = sequence;
+
+        match decode_incoming(kind, &body) {
+            Ok(Some(incoming)) => {
+                if sender.send(incoming).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => debug!("Dropping input datagram: {}", e),
+        }
+    }
+}
+
+/// Repeat the held key state while anything is held, and a few times after
+async fn repeat_snapshots(
+    socket: Arc<UdpSocket>,
+    peer: Peer,
+    sealer: Arc<Sealer>,
+    sent: Arc<Mutex<KeyState>>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut release_repeats = 0;
+
+    loop {
+        ticker.tick().await;
+        let snapshot = sent.lock().unwrap().snapshot();
+
+        if snapshot.is_empty() {
+            if release_repeats == 0 {
+                continue;
+            }
+            release_repeats -= 1;
+        } else {
+            release_repeats = RELEASE_REPEATS;
+        }
+
+        let mut body = Vec::new();
+        snapshot.encode(&mut body);
+        if let Err(e) = peer.send(&socket, &sealer.seal(KIND_SNAPSHOT, &body)).await {
+            debug!("Failed to send key state snapshot: {}", e);
+        }
+    }
+}
+
+/// Server side of the input channel.
+///
+/// One UDP socket serves every session; datagrams are routed by channel ID
+/// to the connection of the session whose keys authenticate them.
+pub struct UdpInputListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<UdpInputConnection>,
+    router: JoinHandle<()>,
+}
+
+impl UdpInputListener {
+    /// Bind the input channel socket
+    pub async fn bind(addr: SocketAddr, keyring: InputKeyRing, config: InputChannelConfig) -> ProtocolResult<Self> {
+        let socket = UdpSocket::bind(addr).await
+            .map_err(|e| ProtocolError::Transport(format!("Failed to bind input channel on {}: {}", addr, e)))?;
+        let local_addr = socket.local_addr()
+            .map_err(|e| ProtocolError::Transport(e.to_string()))?;
+        info!("Input channel listening on {}", local_addr);
+
+        let (sender, accepted) = mpsc::channel(16);
+        let router = tokio::spawn(route(Arc::new(socket), keyring, config, sender));
+
+        Ok(UdpInputListener { local_addr, accepted, router })
+    }
+
+    /// Accept the input channel of the next session that probes it
+    pub async fn accept_channel(&mut self) -> ProtocolResult<UdpInputConnection> {
+        self.accepted.recv().await
+            .ok_or_else(|| ProtocolError::Transport("Input channel listener closed".to_string()))
+    }
+}
+
+impl Drop for UdpInputListener {
+    fn drop(&mut self) {
+        self.router.abort();
+    }
+}
+
+#[async_trait]
+impl TransportListener for UdpInputListener {
+    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
+        Ok(Box::new(self.accept_channel().await?))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.local_addr)
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.router.abort();
+        self.accepted.close();
+        Ok(())
+    }
+}
+
+/// Per-channel state of the server router
+struct Route {
+    opener: LessSafeKey,
+    last_sequence: u64,
+    peer: Arc<Mutex<SocketAddr>>,
+    sealer: Arc<Sealer>,
+    sender: mpsc::Sender<Incoming>,
+}
+
+async fn route(
+    socket: Arc<UdpSocket>,
+    keyring: InputKeyRing,
+    config: InputChannelConfig,
+    accepted: mpsc::Sender<UdpInputConnection>,
+) {
+    let mut routes: HashMap<u64, Route> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Input channel receive failed: {}", e);
+                continue;
+            }
+        };
+        let datagram = &buf[..len];
+        let Ok((channel_id, _)) = decode_header(datagram) else {
+            continue;
+        };
+
+        if routes.get(&channel_id).is_some_and(|route| route.sender.is_closed()) {
+            routes.remove(&channel_id);
+        }
+
+        // A channel only gets a route once a datagram authenticates
+        let (opened, new_connection) = match routes.get(&channel_id) {
+            Some(route) => (open(&route.opener, datagram), None),
+            None => {
+                let Some(keys) = keyring.read().await.get(&channel_id).cloned() else {
+                    continue;
+                };
+                let opened = open(&keys.key(Direction::ClientToServer), datagram);
+                if opened.is_err() {
+                    continue;
+                }
+
+                let peer = Arc::new(Mutex::new(from));
+                let sealer = Arc::new(Sealer::new(&keys, Direction::ServerToClient));
+                let (sender, incoming) = mpsc::channel(INCOMING_CAPACITY);
+                routes.insert(channel_id, Route {
+                    opener: keys.key(Direction::ClientToServer),
+                    last_sequence: 0,
+                    peer: peer.clone(),
+                    sealer: sealer.clone(),
+                    sender,
+                });
+                let connection = UdpInputConnection::new(
+                    socket.clone(),
+                    Peer::Routed(peer),
+                    keys,
+                    sealer,
+                    incoming,
+                    &config,
+                );
+                (opened, Some(connection))
+            }
+        };
+
+        let route = routes.get_mut(&channel_id).unwrap();
+        let (sequence, kind, body) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                debug!("Dropping input datagram from {}: {}", from, e);
+                continue;
+            }
+        };
+        if sequence <= route.last_sequence {
+            debug!("Dropping stale input datagram {} on channel {:016x}", sequence, channel_id);
+            continue;
+        }
+        route.last_sequence = sequence;
+        *route.peer.lock().unwrap() = from;
+
+        if let Some(connection) = new_connection {
+            info!("Input channel opened for session {} from {}", connection.keys().session_id(), from);
+            if accepted.send(connection).await.is_err() {
+                break;
+            }
+        }
+
+        if kind == KIND_PROBE {
+            let ack = route.sealer.seal(KIND_PROBE_ACK, &[]);
+            if let Err(e) = socket.send_to(&ack, from).await {
+                debug!("Failed to acknowledge input channel probe: {}", e);
+            }
+            continue;
+        }
+
+        match decode_incoming(kind, &body) {
+            // Input is better dropped than queued behind a stalled consumer
+            Ok(Some(incoming)) => {
+                if let Err(mpsc::error::TrySendError::Full(_)) = route.sender.try_send(incoming) {
+                    warn!("Input channel {:016x} backlogged, dropping datagram", channel_id);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => debug!("Dropping input datagram from {}: {}", from, e),
+        }
+    }
+}
+
+/// Transport factory for the datagram input channel
+pub struct UdpInputFactory {
+    config: InputChannelConfig,
+    keys: Option<InputKeys>,
+    keyring: InputKeyRing,
+}
+
+impl UdpInputFactory {
+    /// Factory for a client opening the channel of one session
+    pub fn client(keys: InputKeys, config: InputChannelConfig) -> Self {
+        UdpInputFactory {
+            config,
+            keys: Some(keys),
+            keyring: InputKeyRing::default(),
+        }
+    }
+
+    /// Factory for a server accepting the channels of the sessions in a key ring
+    pub fn server(keyring: InputKeyRing, config: InputChannelConfig) -> Self {
+        UdpInputFactory { config, keys: None, keyring }
+    }
+}
+
+#[async_trait]
+impl TransportFactory for UdpInputFactory {
+    async fn create_listener(&self, addr: SocketAddr, _config: TransportConfig) -> ProtocolResult<Box<dyn TransportListener>> {
+        let listener = UdpInputListener::bind(addr, self.keyring.clone(), self.config.clone()).await?;
+        Ok(Box::new(listener))
+    }
+
+    async fn create_connection(&self, addr: SocketAddr, _config: TransportConfig) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let keys = self.keys.clone()
+            .ok_or_else(|| ProtocolError::Transport("Input channel keys required to connect".to_string()))?;
+        let connection = UdpInputConnection::connect(addr, keys, &self.config).await?;
+        Ok(Box::new(connection))
+    }
+}
+
+/// Path input events currently take
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputTransport {
+    Datagram,
+    WebSocket,
+}
+
+/// Client side input path.
+///
+/// Sends over the datagram channel once the server has answered its probe,
+/// and over the WebSocket session before that or when UDP is blocked. A
+/// resume issues a new session token; the channel keyed from the old one
+/// is then closed and the server probed again with the new keys.
+pub struct InputChannel {
+    session: ProtocolSession,
+    /// Open datagram channel and the session token it is keyed from
+    datagram: Arc<tokio::sync::Mutex<Option<(String, UdpInputConnection)>>>,
+    probe: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// Server input port and settings, kept to probe again after a resume
+    target: Option<(SocketAddr, InputChannelConfig)>,
+}
+
+impl InputChannel {
+    /// Create an input path that uses the WebSocket session
+    pub fn new(session: ProtocolSession) -> Self {
+        InputChannel {
+            session,
+            datagram: Arc::new(tokio::sync::Mutex::new(None)),
+            probe: std::sync::Mutex::new(None),
+            target: None,
+        }
+    }
+
+    /// Probe the server's input channel in the background and switch to it
+    /// once it answers. The keys are derived from the session token.
+    pub fn upgrade(&mut self, addr: SocketAddr, config: InputChannelConfig) {
+        self.target = Some((addr, config));
+        self.reprobe();
+    }
+
+    /// Probe the server with keys from the current session token, replacing
+    /// any probe still running
+    fn reprobe(&self) {
+        let Some((addr, config)) = self.target.clone() else {
+            return;
+        };
+        let session = self.session.clone();
+        let datagram = self.datagram.clone();
+        let probe = tokio::spawn(async move {
+            let Some(token) = session.resume_token().await else {
+                return;
+            };
+            let keys = InputKeys::derive(&token, session.session_id());
+            match UdpInputConnection::connect(addr, keys, &config).await {
+                Ok(connection) => {
+                    info!("Sending input over the datagram channel to {}", addr);
+                    *datagram.lock().await = Some((token, connection));
+                }
+                Err(e) => warn!("Input channel at {} unavailable, sending input over the WebSocket: {}", addr, e),
+            }
+        });
+        if let Some(previous) = self.probe.lock().unwrap().replace(probe) {
+            previous.abort();
+        }
+    }
+
+    /// Wait for a pending probe to finish and report the resulting path
+    pub async fn ready(&mut self) -> InputTransport {
+        if let Some(probe) = self.probe.get_mut().unwrap().take() {
+            let _ = probe.await;
+        }
+        self.transport().await
+    }
+
+    /// Get the path input events currently take
+    pub async fn transport(&self) -> InputTransport {
+        match self.datagram.lock().await.as_ref() {
+            Some(_) => InputTransport::Datagram,
+            None => InputTransport::WebSocket,
+        }
+    }
+
+    /// Send an input event
+    pub async fn send(&self, event: InputEventPayload) -> ProtocolResult<()> {
+        let message = ProtocolMessage::new(MessageType::InputEvent, MessagePayload::InputEvent(event))
+            .with_session(self.session.session_id().to_string());
+        self.session.check_capability(message.message_type())?;
+
+        let token = self.session.resume_token().await;
+        let mut datagram = self.datagram.lock().await;
+        if datagram.as_ref().is_some_and(|(keyed_from, _)| token.as_ref() != Some(keyed_from)) {
+            debug!("Session token changed, reopening the input channel");
+            if let Some((_, mut connection)) = datagram.take() {
+                let _ = connection.close().await;
+            }
+            self.reprobe();
+        }
+        if let Some((_, connection)) = datagram.as_mut() {
+            match connection.send(message.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("Input channel failed, falling back to the WebSocket: {}", e);
+                    if let Some((_, mut connection)) = datagram.take() {
+                        let _ = connection.close().await;
+                    }
+                }
+            }
+        }
+        drop(datagram);
+
+        self.session.send_message(message).await
+    }
+
+    /// Stop probing and close the datagram channel
+    pub async fn close(&mut self) {
+        if let Some(probe) = self.probe.get_mut().unwrap().take() {
+            probe.abort();
+        }
+        if let Some((_, mut connection)) = self.datagram.lock().await.take() {
+            let _ = connection.close().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::tests::{new_session, test_config};
+
+    fn press(key_code: u32) -> ProtocolMessage {
+        input_message(InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code, modifiers: 0 }))
+    }
+
+    fn release(key_code: u32) -> ProtocolMessage {
+        input_message(InputEventPayload::keyboard(&KeyboardEvent::KeyRelease { key_code, modifiers: 0 }))
+    }
+
+    fn input_message(event: InputEventPayload) -> ProtocolMessage {
+        ProtocolMessage::new(MessageType::InputEvent, MessagePayload::InputEvent(event))
+    }
+
+    fn keyboard(message: &ProtocolMessage) -> KeyboardEvent {
+        match &message.payload {
+            MessagePayload::InputEvent(event) => event.keyboard_event().unwrap(),
+            other => panic!("expected an input event, got {:?}", other),
+        }
+    }
+
+    fn event_body(message: &ProtocolMessage, snapshot: &KeyStateSnapshot) -> Vec<u8> {
+        let frame = BinaryCodec.encode(message).unwrap();
+        let mut body = (frame.len() as u32).to_be_bytes().to_vec();
+        body.extend_from_slice(&frame);
+        snapshot.encode(&mut body);
+        body
+    }
+
+    async fn listener_with(keys: &InputKeys) -> UdpInputListener {
+        let keyring = InputKeyRing::default();
+        keyring.write().await.insert(keys.channel_id(), keys.clone());
+        UdpInputListener::bind("127.0.0.1:0".parse().unwrap(), keyring, InputChannelConfig::default())
+            .await
+            .unwrap()
+    }
+
+    async fn receive(connection: &mut UdpInputConnection) -> ProtocolMessage {
+        tokio::time::timeout(Duration::from_secs(5), connection.receive())
+            .await
+            .expect("timed out waiting for input")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_keys_depend_on_token_and_direction() {
+        let keys = InputKeys::derive("token", "session-1");
+        let again = InputKeys::derive("token", "session-1");
+        let other = InputKeys::derive("other-token", "session-1");
+
+        assert_eq!(keys.channel_id(), again.channel_id());
+        assert_eq!(keys.client_to_server, again.client_to_server);
+        assert_ne!(keys.channel_id(), other.channel_id());
+        assert_ne!(keys.client_to_server, other.client_to_server);
+        assert_ne!(keys.client_to_server, keys.server_to_client);
+    }
+
+    #[test]
+    fn test_reopened_channel_never_reuses_a_nonce() {
+        let keys = InputKeys::derive("token", "session-1");
+        let sequence = |datagram: Vec<u8>| decode_header(&datagram).unwrap().1;
+        let first = sequence(Sealer::new(&keys, Direction::ClientToServer).seal(KIND_PROBE, &[]));
+
+        // The channel reopened after a resume, on the same keys
+        let resumed = keys.clone();
+        let second = sequence(Sealer::new(&resumed, Direction::ClientToServer).seal(KIND_PROBE, &[]));
+        assert!(second > first);
+
+        // Each direction has its own key and count
+        assert_eq!(sequence(Sealer::new(&keys, Direction::ServerToClient).seal(KIND_PROBE_ACK, &[])), 1);
+    }
+
+    #[test]
+    fn test_sealed_datagram_hides_and_authenticates_payload() {
+        let keys = InputKeys::derive("token", "session-1");
+        let sealer = Sealer::new(&keys, Direction::ClientToServer);
+        let opener = keys.key(Direction::ClientToServer);
+
+        let body = event_body(&press(0x41), &KeyStateSnapshot::default());
+        let datagram = sealer.seal(KIND_EVENT, &body);
+        assert!(!datagram.windows(8).any(|window| window == b"keyboard"));
+
+        let (sequence, kind, opened) = open(&opener, &datagram).unwrap();
+        assert_eq!(decode_header(&datagram).unwrap(), (keys.channel_id(), sequence));
+        assert_eq!(kind, KIND_EVENT);
+        assert_eq!(opened, body);
+
+        // Flipping a bit anywhere, or using the other direction's key, fails
+        for index in [0, HEADER_LEN - 1, HEADER_LEN + 3, datagram.len() - 1] {
+            let mut tampered = datagram.clone();
+            tampered[index] ^= 1;
+            assert!(open(&opener, &tampered).is_err(), "byte {} not authenticated", index);
+        }
+        assert!(open(&keys.key(Direction::ServerToClient), &datagram).is_err());
+        assert!(open(&InputKeys::derive("other", "session-1").key(Direction::ClientToServer), &datagram).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_recovers_lost_events_idempotently() {
+        let mut sender = KeyState::default();
+        let mut receiver = KeyState::default();
+        let left = MouseEvent::MouseButtonPress { button: MouseButton::Left };
+
+        for event in [
+            InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code: 1, modifiers: 0 }),
+            InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code: 2, modifiers: 0 }),
+            InputEventPayload::mouse(&left),
+        ] {
+            sender.observe(&event);
+            receiver.observe(&event);
+        }
+
+        // The release of key 1 and the press of key 3 are lost
+        sender.observe(&InputEventPayload::keyboard(&KeyboardEvent::KeyRelease { key_code: 1, modifiers: 0 }));
+        sender.observe(&InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code: 3, modifiers: 0 }));
+
+        let snapshot = sender.snapshot();
+        let missed: Vec<String> = receiver
+            .reconcile(&snapshot)
+            .iter()
+            .map(|event| format!("{:?}", event.keyboard_event().unwrap()))
+            .collect();
+        assert_eq!(missed, vec![
+            "KeyRelease { key_code: 1, modifiers: 0 }".to_string(),
+            "KeyPress { key_code: 3, modifiers: 0 }".to_string(),
+        ]);
+        assert!(receiver.reconcile(&snapshot).is_empty());
+        assert_eq!(receiver.snapshot().buttons, BTreeSet::from([MouseButton::Left]));
+
+        let mut encoded = Vec::new();
+        snapshot.encode(&mut encoded);
+        assert_eq!(KeyStateSnapshot::decode(&encoded).unwrap(), snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_input_events_cross_the_datagram_channel() {
+        let keys = InputKeys::derive("token", "session-1");
+        let mut listener = listener_with(&keys).await;
+        let addr = listener.local_addr().unwrap();
+
+        let factory = UdpInputFactory::client(keys.clone(), InputChannelConfig::default());
+        let mut client = factory.create_connection(addr, TransportConfig::default()).await.unwrap();
+        let mut server = listener.accept_channel().await.unwrap();
+        assert_eq!(server.keys().session_id(), "session-1");
+
+        client.send(press(0x41)).await.unwrap();
+        client.send(release(0x41)).await.unwrap();
+
+        let received = receive(&mut server).await;
+        assert_eq!(received.session_id().map(String::as_str), Some("session-1"));
+        assert!(matches!(keyboard(&received), KeyboardEvent::KeyPress { key_code: 0x41, .. }));
+        assert!(matches!(keyboard(&receive(&mut server).await), KeyboardEvent::KeyRelease { key_code: 0x41, .. }));
+
+        let video = ProtocolMessage::new(MessageType::VideoStop, MessagePayload::VideoStop);
+        assert!(matches!(client.send(video).await, Err(ProtocolError::InvalidMessageType(_))));
+    }
+
+    #[tokio::test]
+    async fn test_lost_release_and_replay_are_handled() {
+        let keys = InputKeys::derive("token", "session-1");
+        let mut listener = listener_with(&keys).await;
+        let addr = listener.local_addr().unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sealer = Sealer::new(&keys, Direction::ClientToServer);
+        let held = KeyStateSnapshot { keys: BTreeSet::from([0x41]), ..Default::default() };
+
+        let press_datagram = sealer.seal(KIND_EVENT, &event_body(&press(0x41), &held));
+        socket.send_to(&press_datagram, addr).await.unwrap();
+        let mut server = listener.accept_channel().await.unwrap();
+        assert!(matches!(keyboard(&receive(&mut server).await), KeyboardEvent::KeyPress { key_code: 0x41, .. }));
+
+        // The release is lost and the press replayed; the next snapshot
+        // still releases the key exactly once
+        let _lost = sealer.seal(KIND_EVENT, &event_body(&release(0x41), &KeyStateSnapshot::default()));
+        socket.send_to(&press_datagram, addr).await.unwrap();
+        let mut empty = Vec::new();
+        KeyStateSnapshot::default().encode(&mut empty);
+        socket.send_to(&sealer.seal(KIND_SNAPSHOT, &empty), addr).await.unwrap();
+        socket.send_to(&sealer.seal(KIND_SNAPSHOT, &empty), addr).await.unwrap();
+        socket.send_to(&sealer.seal(KIND_EVENT, &event_body(&press(0x42), &KeyStateSnapshot {
+            keys: BTreeSet::from([0x42]),
+            ..Default::default()
+        })), addr).await.unwrap();
+
+        assert!(matches!(keyboard(&receive(&mut server).await), KeyboardEvent::KeyRelease { key_code: 0x41, .. }));
+        assert!(matches!(keyboard(&receive(&mut server).await), KeyboardEvent::KeyPress { key_code: 0x42, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_input_falls_back_to_websocket_when_udp_is_blocked() {
+        let config = test_config();
+        let mut session = new_session("session-1", &config);
+        let mut peer_info = session.peer_info().clone();
+        peer_info.capabilities = soft_kvm_core::CapabilitySet::full();
+        peer_info.authenticated = true;
+        session.set_peer_info(peer_info);
+
+        // A socket that swallows every datagram, like a firewall would
+        let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        session.set_resume_token(Some("token".to_string())).await;
+        let channel_config = InputChannelConfig {
+            probe_timeout_ms: 20,
+            probe_attempts: 2,
+            ..InputChannelConfig::default()
+        };
+
+        let mut channel = InputChannel::new(session.clone());
+        channel.upgrade(blackhole.local_addr().unwrap(), channel_config);
+        assert_eq!(channel.ready().await, InputTransport::WebSocket);
+
+        channel.send(InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code: 0x41, modifiers: 0 })).await.unwrap();
+        let mut outbound = session.lock_outbound().await;
+        let sent = outbound.try_recv().unwrap();
+        assert!(matches!(keyboard(&sent), KeyboardEvent::KeyPress { key_code: 0x41, .. }));
+    }
+}
@@ -16,17 +16,33 @@
 //!
 //! KVM共有プロトコルの実装
 
+pub mod codec;
+pub mod compression;
+pub mod control;
+pub mod driver;
+pub mod fragment;
+pub mod handshake;
+pub mod input_channel;
 pub mod messages;
+pub mod memory;
+pub mod quic;
+pub mod queue;
+pub mod recording;
+pub mod reconnect;
+pub mod relay;
+pub mod router;
 pub mod transport;
 pub mod websocket;
 pub mod session;
+pub mod timing;
+pub mod version;
 
 use soft_kvm_core::*;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use std::net::SocketAddr;
 use tracing::{debug, info, warn, error};
-use crate::transport::TransportFactory;
+use crate::transport::{TransportConnection, TransportFactory, TransportListener};
 
 /// Protocol result type
 pub type ProtocolResult<T> = Result<T, ProtocolError>;
@@ -58,73 +74,282 @@ pub enum ProtocolError {
     #[error("Invalid message type: {0}")]
     InvalidMessageType(String),
 
+    #[error("Codec error: {0}")]
+    Codec(String),
+
+    #[error("Capability not negotiated: {0:?}")]
+    CapabilityNotNegotiated(CapabilityKind),
+
+    #[error("{feature:?} is not available in protocol version {version}")]
+    FeatureNotNegotiated { feature: version::Feature, version: version::ProtocolVersion },
+
+    #[error("Peer reported {code}: {message}")]
+    Remote { code: ErrorCode, message: String },
+
+    #[error("Refused with {code}: {reason}")]
+    Refused { code: ErrorCode, reason: String },
+
     #[error("Generic protocol error: {0}")]
     Generic(String),
 }
 
+impl ProtocolError {
+    /// Error code reported to the peer for this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::Transport(_) | ProtocolError::WebSocket(_) => ErrorCode::ServiceUnavailable,
+            ProtocolError::Serialization(_) | ProtocolError::Codec(_) => ErrorCode::InvalidMessage,
+            ProtocolError::Authentication(_) => ErrorCode::AuthFailed,
+            ProtocolError::Session(_) | ProtocolError::InvalidMessageType(_) => ErrorCode::ProtocolViolation,
+            ProtocolError::Timeout => ErrorCode::Timeout,
+            ProtocolError::VersionMismatch { .. } => ErrorCode::VersionMismatch,
+            ProtocolError::CapabilityNotNegotiated(_) | ProtocolError::FeatureNotNegotiated { .. } => {
+                ErrorCode::UnsupportedData
+            }
+            ProtocolError::Remote { code, .. } | ProtocolError::Refused { code, .. } => *code,
+            ProtocolError::Generic(_) => ErrorCode::InternalError,
+        }
+    }
+
+    /// Error for a code received from the peer
+    pub fn from_code(code: ErrorCode, message: String) -> Self {
+        match code {
+            ErrorCode::AuthFailed => ProtocolError::Authentication(message),
+            code => ProtocolError::Remote { code, message },
+        }
+    }
+
+    /// Check if trying again later may succeed
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_retryable()
+    }
+}
+
+impl From<messages::ErrorPayload> for ProtocolError {
+    fn from(error: messages::ErrorPayload) -> Self {
+        ProtocolError::from_code(error.error_code, error.error_message)
+    }
+}
+
+impl From<ProtocolError> for KvmError {
+    fn from(error: ProtocolError) -> Self {
+        match error {
+            ProtocolError::Serialization(e) => KvmError::Serialization(e),
+            ProtocolError::Authentication(message) => KvmError::Security(message),
+            ProtocolError::Timeout => KvmError::Timeout,
+            ProtocolError::Remote { code, message } => KvmError::Remote { code, message },
+            ProtocolError::Transport(_) | ProtocolError::WebSocket(_) => KvmError::Network(error.to_string()),
+            other => KvmError::Network(other.to_string()),
+        }
+    }
+}
+
 /// Protocol version
-pub const PROTOCOL_VERSION: &str = "1.0.0";
+pub const PROTOCOL_VERSION: &str = "1.2.0";
+
+/// Buffered session events per subscriber
+const SESSION_EVENT_CAPACITY: usize = 64;
 
 /// Protocol configuration
 #[derive(Debug, Clone)]
 pub struct ProtocolConfig {
-    pub version: String,
-    pub max_message_size: usize,
-    pub heartbeat_interval: u64, // seconds
+    pub version: String,     // newest protocol version spoken
+    pub min_version: String, // oldest protocol version spoken, see `version`
+    pub max_message_size: usize, // bytes, after encoding; larger messages are refused
+    pub heartbeat_interval: u64, // seconds, 0 disables keepalive
+    pub max_missed_heartbeats: u32,
     pub session_timeout: u64,    // seconds
+    pub resume_timeout: u64,     // seconds a lost session can be resumed, 0 disables
+    pub reconnect: reconnect::ReconnectPolicy,
     pub compression_enabled: bool,
+    pub compressors: Vec<compression::CompressionKind>, // in order of preference
+    pub compression_threshold: usize,                  // bytes
+    pub codecs: Vec<codec::CodecKind>, // in order of preference
+    pub node_id: String,
+    pub node_name: String,
+    pub capabilities: CapabilitySet,
+    pub auth_token: Option<String>,
+    pub handshake_timeout: u64, // seconds
+    pub request_timeout: u64,   // seconds to wait for a correlated response
+    pub input_channel: input_channel::InputChannelConfig,
+    pub queue: queue::QueueConfig, // per-session outbound and inbound queues
+    pub record_dir: Option<std::path::PathBuf>, // record every connection here, see `recording`
+    pub control: control::ControlConfig,        // input arbitration between viewers
 }
 
 impl Default for ProtocolConfig {
     fn default() -> Self {
         ProtocolConfig {
             version: PROTOCOL_VERSION.to_string(),
+            min_version: version::MIN_PROTOCOL_VERSION.to_string(),
             max_message_size: 1024 * 1024, // 1MB
             heartbeat_interval: 30,
+            max_missed_heartbeats: 3,
             session_timeout: 300, // 5 minutes
+            resume_timeout: 60,
+            reconnect: reconnect::ReconnectPolicy::default(),
             compression_enabled: true,
+            compressors: compression::CompressionKind::supported(),
+            compression_threshold: 1024,
+            codecs: codec::CodecKind::supported(),
+            node_id: uuid::Uuid::new_v4().to_string(),
+            node_name: "Soft KVM".to_string(),
+            capabilities: CapabilitySet::full(),
+            auth_token: None,
+            handshake_timeout: 10,
+            request_timeout: 10,
+            input_channel: input_channel::InputChannelConfig::default(),
+            queue: queue::QueueConfig::default(),
+            record_dir: None,
+            control: control::ControlConfig::default(),
         }
     }
 }
 
+impl ProtocolConfig {
+    /// Range of protocol versions this node speaks
+    pub fn version_range(&self) -> ProtocolResult<version::VersionRange> {
+        version::VersionRange::parse(Some(&self.min_version), &self.version)
+    }
+}
+
+/// Transport settings with the protocol's message size limit applied
+fn transport_config(config: &ProtocolConfig, transport: &transport::TransportConfig) -> transport::TransportConfig {
+    transport::TransportConfig {
+        max_message_size: config.max_message_size,
+        ..transport.clone()
+    }
+}
+
 /// Protocol Server
 pub struct ProtocolServer {
     config: ProtocolConfig,
+    transport: Arc<dyn TransportFactory>,
+    transport_config: transport::TransportConfig,
     listener: Option<Arc<tokio::sync::Mutex<Box<dyn transport::TransportListener>>>>,
-    sessions: Arc<RwLock<std::collections::HashMap<String, session::ProtocolSession>>>,
+    sessions: session::SessionMap,
+    events: tokio::sync::broadcast::Sender<session::SessionEvent>,
+    input_keys: input_channel::InputKeyRing,
+    control: control::ControlArbiter,
     shutdown_sender: tokio::sync::broadcast::Sender<()>,
 }
 
 impl ProtocolServer {
     /// Create a new protocol server
     pub fn new(config: ProtocolConfig) -> ProtocolResult<Self> {
+        let (events, _) = tokio::sync::broadcast::channel(SESSION_EVENT_CAPACITY);
+        Self::with_registry(config, Arc::new(RwLock::new(std::collections::HashMap::new())), events)
+    }
+
+    /// Create a server that registers sessions in a shared table and
+    /// publishes lifecycle events on a shared channel
+    pub fn with_registry(
+        config: ProtocolConfig,
+        sessions: session::SessionMap,
+        events: tokio::sync::broadcast::Sender<session::SessionEvent>,
+    ) -> ProtocolResult<Self> {
         let (shutdown_sender, _) = tokio::sync::broadcast::channel(1);
+        let control = control::ControlArbiter::new(sessions.clone(), events.clone(), config.control.clone());
 
         Ok(ProtocolServer {
             config,
+            transport: Arc::new(websocket::WebSocketFactory::new(transport::TransportConfig::default())),
+            transport_config: transport::TransportConfig::default(),
             listener: None,
-            sessions: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            sessions,
+            events,
+            input_keys: input_channel::InputKeyRing::default(),
+            control,
             shutdown_sender,
         })
     }
 
+    /// Accept connections through another transport (WebSocket by default)
+    pub fn with_transport(mut self, factory: Arc<dyn TransportFactory>, config: transport::TransportConfig) -> Self {
+        self.transport = factory;
+        self.transport_config = config;
+        self
+    }
+
+    /// Subscribe to session lifecycle events
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<session::SessionEvent> {
+        self.events.subscribe()
+    }
+
     /// Start the server
     pub async fn start(&mut self, addr: SocketAddr) -> ProtocolResult<()> {
         info!("Starting protocol server on {}", addr);
 
         // Create transport listener
-        let transport_config = transport::TransportConfig::default();
-        let factory = websocket::WebSocketFactory::new(transport_config.clone());
-        let listener = factory.create_listener(addr, transport_config).await?;
+        let transport = recording::recording_transport(self.transport.clone(), &self.config);
+        let listener = transport.create_listener(addr, transport_config(&self.config, &self.transport_config)).await?;
 
+        let input_addr = SocketAddr::new(listener.local_addr().unwrap_or(addr).ip(), self.config.input_channel.port);
         self.listener = Some(Arc::new(tokio::sync::Mutex::new(listener)));
 
+        // Its keys come from the session token, which must not cross the
+        // network in the clear
+        if self.config.input_channel.enabled && !self.transport_config.tls.enabled {
+            warn!("Input channel needs TLS on the control connection, input will use the WebSocket");
+            self.config.input_channel.enabled = false;
+        }
+
+        // The input channel is optional; clients fall back to the WebSocket
+        if self.config.input_channel.enabled {
+            match input_channel::UdpInputListener::bind(input_addr, self.input_keys.clone(), self.config.input_channel.clone()).await {
+                Ok(input_listener) => {
+                    // Announced to clients in Welcome
+                    self.config.input_channel.port = input_listener.local_addr().map_or(0, |addr| addr.port());
+                    self.start_input_loop(input_listener);
+                }
+                Err(e) => {
+                    warn!("Input channel unavailable, input will use the WebSocket: {}", e);
+                    self.config.input_channel.enabled = false;
+                }
+            }
+        }
+
         // Start accepting connections
         self.start_accept_loop();
 
         Ok(())
     }
 
+    /// Get the address the server is listening on
+    pub async fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.lock().await.local_addr(),
+            None => None,
+        }
+    }
+
+    /// Session currently driving input
+    pub fn controller(&self) -> Option<String> {
+        self.control.controller()
+    }
+
+    /// Role of a session
+    pub fn role(&self, session_id: &str) -> control::ViewerRole {
+        self.control.role(session_id)
+    }
+
+    /// Give input control to a session, or to nobody
+    pub async fn set_controller(&self, session_id: Option<String>) {
+        self.control.set_controller(session_id).await;
+    }
+
+    /// Disconnect a viewer, telling it why
+    pub async fn kick(&self, session_id: &str, reason: String) -> ProtocolResult<()> {
+        let session = self.sessions.read().await.get(session_id).cloned()
+            .ok_or_else(|| ProtocolError::Session(format!("No session {}", session_id)))?;
+        session.close_with(ErrorCode::Kicked, reason).await
+    }
+
+    /// Get a snapshot of the established sessions
+    pub async fn sessions(&self) -> Vec<session::ProtocolSession> {
+        self.sessions.read().await.values().cloned().collect()
+    }
+
     /// Stop the server
     pub async fn stop(&mut self) -> ProtocolResult<()> {
         info!("Stopping protocol server");
@@ -153,6 +378,9 @@ impl ProtocolServer {
     fn start_accept_loop(&self) {
         let listener = self.listener.as_ref().unwrap().clone();
         let sessions = self.sessions.clone();
+        let events = self.events.clone();
+        let input_keys = self.input_keys.clone();
+        let control = self.control.clone();
         let config = self.config.clone();
         let mut shutdown_receiver = self.shutdown_sender.subscribe();
 
@@ -166,33 +394,16 @@ impl ProtocolServer {
                         let connection = result;
                         match connection {
                             Ok(conn) => {
-                                let session_id = format!("server-session-{}", uuid::Uuid::new_v4());
-                                let remote_addr = conn.remote_addr()
-                                    .unwrap_or_else(|| "127.0.0.1:0".parse().unwrap());
-
-                                let peer_info = session::PeerInfo {
-                                    peer_id: "client".to_string(),
-                                    peer_name: "KVM Client".to_string(),
-                                    address: NetworkAddress {
-                                        ip: remote_addr.ip().to_string(),
-                                        port: remote_addr.port() as u16,
-                                    },
-                                    capabilities: vec!["video".to_string(), "input".to_string()],
-                                    authenticated: false,
-                                    last_seen: chrono::Utc::now(),
-                                };
-
-                                let session = session::ProtocolSession::new(
-                                    session_id.clone(),
-                                    peer_info,
+                                // Listeners upgrade each connection on its own task; the
+                                // Hello exchange gets one too, so a slow client cannot stall accept
+                                tokio::spawn(Self::establish_session(
+                                    conn,
+                                    sessions.clone(),
+                                    events.clone(),
+                                    input_keys.clone(),
+                                    control.clone(),
                                     config.clone(),
-                                );
-
-                                let mut sessions_write = sessions.write().await;
-                                sessions_write.insert(session_id.clone(), session);
-                                drop(sessions_write);
-
-                                info!("Accepted new connection: {}", session_id);
+                                ));
                             }
                             Err(e) => {
                                 error!("Failed to accept connection: {}", e);
@@ -208,54 +419,240 @@ impl ProtocolServer {
             }
         });
     }
+
+    /// Accept input channels and feed their events to the owning sessions
+    fn start_input_loop(&self, mut listener: input_channel::UdpInputListener) {
+        let sessions = self.sessions.clone();
+        let control = self.control.clone();
+        let mut shutdown_receiver = self.shutdown_sender.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    result = listener.accept_channel() => match result {
+                        Ok(conn) => {
+                            tokio::spawn(Self::forward_input(conn, sessions.clone(), control.clone()));
+                        }
+                        Err(e) => {
+                            error!("Failed to accept input channel: {}", e);
+                            break;
+                        }
+                    },
+                    _ = shutdown_receiver.recv() => break,
+                }
+            }
+        });
+    }
+
+    /// Deliver input events from a datagram channel to its session
+    async fn forward_input(
+        mut conn: input_channel::UdpInputConnection,
+        sessions: session::SessionMap,
+        control: control::ControlArbiter,
+    ) {
+        let session_id = conn.keys().session_id().to_string();
+        while let Ok(Some(message)) = conn.receive().await {
+            let Some(session) = sessions.read().await.get(&session_id).cloned() else {
+                break;
+            };
+            if let Err(e) = session.check_capability(message.message_type()) {
+                debug!("Dropping input for session {}: {}", session_id, e);
+                continue;
+            }
+            if !control.admit_input(&session_id).await {
+                debug!("Dropping input from view-only session {}", session_id);
+                continue;
+            }
+            if session.deliver(message).await.is_err() {
+                break;
+            }
+        }
+
+        debug!("Input channel for session {} closed", session_id);
+        let _ = conn.close().await;
+    }
+
+    /// Run the handshake on an accepted connection and start driving the session
+    async fn establish_session(
+        mut conn: Box<dyn transport::TransportConnection>,
+        sessions: session::SessionMap,
+        events: tokio::sync::broadcast::Sender<session::SessionEvent>,
+        input_keys: input_channel::InputKeyRing,
+        control: control::ControlArbiter,
+        config: ProtocolConfig,
+    ) {
+        let session_id = format!("server-session-{}", uuid::Uuid::new_v4());
+        let remote_addr = conn.remote_addr()
+            .unwrap_or_else(|| "127.0.0.1:0".parse().unwrap());
+
+        // Peer identity is filled in from the client's Hello
+        let peer_info = session::PeerInfo {
+            peer_id: String::new(),
+            peer_name: String::new(),
+            address: NetworkAddress {
+                ip: remote_addr.ip().to_string(),
+                port: remote_addr.port(),
+            },
+            capabilities: CapabilitySet::default(),
+            authenticated: false,
+            last_seen: chrono::Utc::now(),
+        };
+
+        let mut session = session::ProtocolSession::new(session_id.clone(), peer_info, config.clone());
+
+        if let Err(e) = handshake::server_handshake(conn.as_mut(), &mut session, &config, &sessions).await {
+            warn!("Rejected connection from {}: {}", remote_addr, e);
+            let _ = conn.close().await;
+            return;
+        }
+
+        // A resumed session replaces its entry under the original ID
+        sessions.write().await.insert(session.session_id().to_string(), session.clone());
+        info!("Accepted new connection: {} from {}", session.session_id(), remote_addr);
+
+        // The input channel is keyed from the session token; a resume
+        // issues a new token, so the channel keyed from the old one goes
+        let input_channel_id = match session.resume_token().await {
+            Some(token) if config.input_channel.enabled => {
+                let keys = input_channel::InputKeys::derive(&token, session.session_id());
+                let channel_id = keys.channel_id();
+                let mut keyring = input_keys.write().await;
+                keyring.retain(|_, other| other.session_id() != keys.session_id());
+                keyring.insert(channel_id, keys);
+                Some(channel_id)
+            }
+            _ => None,
+        };
+
+        // Tell a new viewer who has input control; a resumed one was sent
+        // every change through its queues, which outlive the connection
+        if session.session_id() == session_id {
+            control.announce_to(&session).await;
+        }
+
+        let session_id = session.session_id().to_string();
+        let driver = driver::SessionDriver::new(session, conn)
+            .with_sessions(sessions.clone())
+            .with_events(events)
+            .with_control(control)
+            .spawn();
+        let _ = driver.await;
+
+        // A resumed session is still registered and keeps its channel
+        if let Some(channel_id) = input_channel_id {
+            if !sessions.read().await.contains_key(&session_id) {
+                input_keys.write().await.remove(&channel_id);
+            }
+        }
+    }
 }
 
 /// Protocol Client
 pub struct ProtocolClient {
     config: ProtocolConfig,
+    transport: Arc<dyn TransportFactory>,
+    transport_config: transport::TransportConfig,
     session: Option<session::ProtocolSession>,
-    transport_connection: Option<Box<dyn transport::TransportConnection>>,
+    input: Option<input_channel::InputChannel>,
+    events: tokio::sync::broadcast::Sender<session::SessionEvent>,
+    shutdown: Arc<tokio::sync::Notify>,
+    driver_handle: Option<tokio::task::JoinHandle<ProtocolResult<()>>>,
 }
 
 impl ProtocolClient {
     /// Create a new protocol client
     pub fn new(config: ProtocolConfig) -> Self {
+        let (events, _) = tokio::sync::broadcast::channel(SESSION_EVENT_CAPACITY);
+        Self::with_events(config, events)
+    }
+
+    /// Create a client that publishes lifecycle events on a shared channel
+    pub fn with_events(config: ProtocolConfig, events: tokio::sync::broadcast::Sender<session::SessionEvent>) -> Self {
         ProtocolClient {
             config,
+            transport: Arc::new(websocket::WebSocketFactory::new(transport::TransportConfig::default())),
+            transport_config: transport::TransportConfig::default(),
             session: None,
-            transport_connection: None,
+            input: None,
+            events,
+            shutdown: Arc::new(tokio::sync::Notify::new()),
+            driver_handle: None,
         }
     }
 
-    /// Connect to a server
+    /// Connect through another transport (WebSocket by default)
+    pub fn with_transport(mut self, factory: Arc<dyn TransportFactory>, config: transport::TransportConfig) -> Self {
+        self.transport = factory;
+        self.transport_config = config;
+        self
+    }
+
+    /// Subscribe to session lifecycle events
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<session::SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Connect to a server and complete the handshake.
+    ///
+    /// If the connection is lost later, the client reconnects and resumes
+    /// the session according to `ProtocolConfig::reconnect`.
     pub async fn connect(&mut self, addr: SocketAddr) -> ProtocolResult<()> {
         info!("Connecting to server at {}", addr);
 
-        // Create transport connection
-        let transport_config = transport::TransportConfig::default();
-        let connection = websocket::WebSocketConnection::connect(addr, transport_config).await?;
-
-        self.transport_connection = Some(Box::new(connection));
+        // Create transport connection; reconnects go through the same factory
+        let factory = recording::recording_transport(self.transport.clone(), &self.config);
+        let transport_config = transport_config(&self.config, &self.transport_config);
+        let connect = move || {
+            let factory = factory.clone();
+            let transport_config = transport_config.clone();
+            async move { factory.create_connection(addr, transport_config).await }
+        };
+        let mut connection = connect().await?;
 
-        // Create session
+        // Create session; the server assigns the real session ID in Welcome
         let peer_info = session::PeerInfo {
-            peer_id: "server".to_string(),
-            peer_name: "KVM Server".to_string(),
+            peer_id: String::new(),
+            peer_name: String::new(),
             address: NetworkAddress {
                 ip: addr.ip().to_string(),
-                port: addr.port() as u16,
+                port: addr.port(),
             },
-            capabilities: vec!["video".to_string(), "input".to_string()],
+            capabilities: CapabilitySet::default(),
             authenticated: false,
             last_seen: chrono::Utc::now(),
         };
 
-        let session = session::ProtocolSession::new(
+        let mut session = session::ProtocolSession::new(
             "client-session".to_string(),
             peer_info,
             self.config.clone(),
         );
 
+        let welcome = match handshake::client_handshake(connection.as_mut(), &mut session, &self.config).await {
+            Ok(welcome) => welcome,
+            Err(e) => {
+                let _ = connection.close().await;
+                return Err(e);
+            }
+        };
+
+        // Input goes over the WebSocket until the datagram channel answers;
+        // without TLS the token it is keyed from was sent in the clear
+        let mut input = input_channel::InputChannel::new(session.clone());
+        let datagram_allowed = self.config.input_channel.enabled && self.transport_config.tls.enabled;
+        if let (true, Some(port)) = (datagram_allowed, welcome.input_port) {
+            input.upgrade(SocketAddr::new(addr.ip(), port), self.config.input_channel.clone());
+        }
+        self.input = Some(input);
+
+        let driver = driver::SessionDriver::new(session.clone(), connection).with_events(self.events.clone());
+        self.driver_handle = Some(tokio::spawn(reconnect::supervise(
+            driver,
+            session.clone(),
+            self.config.clone(),
+            self.shutdown.clone(),
+            connect,
+        )));
         self.session = Some(session);
 
         Ok(())
@@ -265,31 +662,82 @@ impl ProtocolClient {
     pub async fn disconnect(&mut self) -> ProtocolResult<()> {
         info!("Disconnecting from server");
 
-        if let Some(mut connection) = self.transport_connection.take() {
-            connection.close().await?;
+        if let Some(mut input) = self.input.take() {
+            input.close().await;
         }
-
         if let Some(session) = self.session.take() {
             session.close().await?;
         }
+        // Interrupts a reconnect in progress
+        self.shutdown.notify_one();
+
+        // The driver exits once Goodbye has been written
+        if let Some(handle) = self.driver_handle.take() {
+            match handle.await {
+                Ok(result) => result?,
+                Err(e) => warn!("Session driver task failed: {}", e),
+            }
+        }
 
         Ok(())
     }
 
     /// Send a message
     pub async fn send_message(&mut self, message: messages::ProtocolMessage) -> ProtocolResult<()> {
-        if let Some(connection) = &mut self.transport_connection {
-            connection.send(message).await?;
-            Ok(())
+        if let Some(session) = &self.session {
+            let session_id = session.session_id().to_string();
+            session.send_message(message.with_session(session_id)).await
         } else {
             Err(ProtocolError::Transport("Not connected".to_string()))
         }
     }
 
+    /// Ask the controller for input control
+    pub async fn request_control(&self) -> ProtocolResult<()> {
+        self.send_control(messages::ControlPayload::Request).await
+    }
+
+    /// Take input control without asking, if the server allows it
+    pub async fn take_control(&self) -> ProtocolResult<()> {
+        self.send_control(messages::ControlPayload::TakeOver).await
+    }
+
+    /// Give up input control
+    pub async fn release_control(&self) -> ProtocolResult<()> {
+        self.send_control(messages::ControlPayload::Release).await
+    }
+
+    /// Hand input control to another viewer
+    pub async fn grant_control(&self, session_id: String) -> ProtocolResult<()> {
+        self.send_control(messages::ControlPayload::Grant { session_id }).await
+    }
+
+    async fn send_control(&self, payload: messages::ControlPayload) -> ProtocolResult<()> {
+        let session = self.session.as_ref().ok_or_else(|| ProtocolError::Transport("Not connected".to_string()))?;
+        let message = messages::ProtocolMessage::new(messages::MessageType::Control, messages::MessagePayload::Control(payload))
+            .with_session(session.session_id().to_string());
+        session.send_message(message).await
+    }
+
+    /// Send an input event over the lowest latency path available
+    pub async fn send_input(&self, event: messages::InputEventPayload) -> ProtocolResult<()> {
+        match &self.input {
+            Some(input) => input.send(event).await,
+            None => Err(ProtocolError::Transport("Not connected".to_string())),
+        }
+    }
+
+    /// Get the input path of the current session
+    pub fn input_channel(&mut self) -> Option<&mut input_channel::InputChannel> {
+        self.input.as_mut()
+    }
+
     /// Receive a message
+    ///
+    /// Returns `None` once the connection to the server has ended.
     pub async fn receive_message(&mut self) -> ProtocolResult<Option<messages::ProtocolMessage>> {
-        if let Some(connection) = &mut self.transport_connection {
-            connection.receive().await
+        if let Some(session) = &self.session {
+            Ok(session.receive_message().await)
         } else {
             Err(ProtocolError::Transport("Not connected".to_string()))
         }
@@ -304,7 +752,8 @@ impl ProtocolClient {
 /// Protocol manager
 pub struct ProtocolManager {
     config: ProtocolConfig,
-    sessions: Arc<RwLock<std::collections::HashMap<String, session::ProtocolSession>>>,
+    sessions: session::SessionMap,
+    events: tokio::sync::broadcast::Sender<session::SessionEvent>,
     server: Option<ProtocolServer>,
     client: Option<ProtocolClient>,
 }
@@ -312,9 +761,12 @@ pub struct ProtocolManager {
 impl ProtocolManager {
     /// Create a new protocol manager
     pub fn new(config: ProtocolConfig) -> Self {
+        let (events, _) = tokio::sync::broadcast::channel(SESSION_EVENT_CAPACITY);
+
         ProtocolManager {
             config,
             sessions: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            events,
             server: None,
             client: None,
         }
@@ -351,6 +803,11 @@ impl ProtocolManager {
         &self.config
     }
 
+    /// Subscribe to session lifecycle events from the server and client
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<session::SessionEvent> {
+        self.events.subscribe()
+    }
+
     /// Create a new session
     pub async fn create_session(&self, session_id: String, peer_info: session::PeerInfo) -> ProtocolResult<()> {
         let session = session::ProtocolSession::new(session_id.clone(), peer_info, self.config.clone());
@@ -405,12 +862,21 @@ impl ProtocolManager {
 
     /// Create and start a server
     pub async fn create_server(&mut self, addr: SocketAddr) -> ProtocolResult<()> {
-        let mut server = ProtocolServer::new(self.config.clone())?;
+        // Sessions accepted by the server share this manager's table
+        let mut server = ProtocolServer::with_registry(self.config.clone(), self.sessions.clone(), self.events.clone())?;
         server.start(addr).await?;
         self.server = Some(server);
         Ok(())
     }
 
+    /// Get the address the server is listening on
+    pub async fn server_addr(&self) -> Option<SocketAddr> {
+        match &self.server {
+            Some(server) => server.local_addr().await,
+            None => None,
+        }
+    }
+
     /// Stop the server
     pub async fn stop_server(&mut self) -> ProtocolResult<()> {
         if let Some(mut server) = self.server.take() {
@@ -421,7 +887,7 @@ impl ProtocolManager {
 
     /// Create and connect a client
     pub async fn create_client(&mut self, addr: SocketAddr) -> ProtocolResult<()> {
-        let mut client = ProtocolClient::new(self.config.clone());
+        let mut client = ProtocolClient::with_events(self.config.clone(), self.events.clone());
         client.connect(addr).await?;
         self.client = Some(client);
         Ok(())
@@ -472,12 +938,8 @@ pub async fn test_protocol_basic() -> ProtocolResult<()> {
     server.start(addr).await?;
 
     // Get the actual port the server is listening on
-    let server_addr = if let Some(listener) = &server.listener {
-        let listener_guard = listener.lock().await;
-        listener_guard.local_addr().unwrap_or_else(|| "127.0.0.1:8080".parse().unwrap())
-    } else {
-        "127.0.0.1:8080".parse().unwrap()
-    };
+    let server_addr = server.local_addr().await
+        .unwrap_or_else(|| "127.0.0.1:8080".parse().unwrap());
 
     info!("Server started on {}", server_addr);
 
@@ -489,17 +951,8 @@ pub async fn test_protocol_basic() -> ProtocolResult<()> {
 
     // Send a test message
     let test_message = messages::ProtocolMessage::new(
-        messages::MessageType::Hello,
-        messages::MessagePayload::Hello(messages::HelloPayload {
-            protocol_version: "1.0.0".to_string(),
-            client_info: messages::ClientInfo {
-                client_id: "test-client".to_string(),
-                client_name: "Test KVM Client".to_string(),
-                platform: "test".to_string(),
-                version: "1.0.0".to_string(),
-            },
-            capabilities: vec!["video".to_string(), "input".to_string()],
-        }),
+        messages::MessageType::Ping,
+        messages::MessagePayload::Ping(messages::PingPayload::now()),
     );
 
     client.send_message(test_message).await?;
@@ -531,7 +984,451 @@ mod tests {
     async fn test_protocol_config_default() {
         let config = ProtocolConfig::default();
         assert_eq!(config.version, PROTOCOL_VERSION);
+        assert!(config.version_range().unwrap().contains(&version::ProtocolVersion::current()));
         assert!(config.compression_enabled);
         assert_eq!(config.heartbeat_interval, 30);
     }
+
+    #[tokio::test]
+    async fn test_client_server_handshake() {
+        let server_config = ProtocolConfig {
+            node_name: "Test Server".to_string(),
+            ..ProtocolConfig::default()
+        };
+        let mut server = ProtocolServer::new(server_config.clone()).unwrap();
+        server.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = server.local_addr().await.unwrap();
+
+        let client_config = ProtocolConfig {
+            node_name: "Test Client".to_string(),
+            ..ProtocolConfig::default()
+        };
+        let mut client = ProtocolClient::new(client_config.clone());
+        client.connect(addr).await.unwrap();
+
+        let client_session = client.session().unwrap();
+        assert!(client_session.is_active().await);
+        assert_eq!(client_session.peer_info().peer_name, "Test Server");
+
+        // The server registers the session right after the handshake
+        wait_for_server_session(&server).await;
+        let sessions = server.sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), client_session.session_id());
+        assert_eq!(sessions[0].peer_info().peer_id, client_config.node_id);
+        assert_eq!(sessions[0].peer_info().peer_name, "Test Client");
+        assert!(sessions[0].is_active().await);
+
+        client.disconnect().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_manager_evicts_disconnected_sessions() {
+        let mut manager = ProtocolManager::default();
+        let mut events = manager.subscribe_events();
+        manager.create_server("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = manager.server_addr().await.unwrap();
+
+        let mut client = ProtocolClient::new(ProtocolConfig::default());
+        client.connect(addr).await.unwrap();
+        let session_id = client.session().unwrap().session_id().to_string();
+
+        for _ in 0..50 {
+            if manager.active_sessions().await == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(manager.get_session(&session_id).await.is_some());
+
+        client.disconnect().await.unwrap();
+
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event, session::SessionEvent::Disconnected { session_id: ref id, .. } if *id == session_id));
+        assert_eq!(manager.active_sessions().await, 0);
+
+        manager.stop_server().await.unwrap();
+    }
+
+    /// TCP proxy whose connections can be cut to simulate a network drop
+    async fn cuttable_proxy(target: SocketAddr) -> (SocketAddr, tokio::sync::broadcast::Sender<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (cut, _) = tokio::sync::broadcast::channel(1);
+        let cut_sender = cut.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                let mut cut_receiver = cut_sender.subscribe();
+                tokio::spawn(async move {
+                    let mut outbound = tokio::net::TcpStream::connect(target).await.unwrap();
+                    tokio::select! {
+                        _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {}
+                        _ = cut_receiver.recv() => {}
+                    }
+                });
+            }
+        });
+
+        (addr, cut)
+    }
+
+    async fn wait_for_event(
+        events: &mut tokio::sync::broadcast::Receiver<session::SessionEvent>,
+        matches: impl Fn(&session::SessionEvent) -> bool,
+    ) -> session::SessionEvent {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let event = events.recv().await.unwrap();
+                if matches(&event) {
+                    return event;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for session event")
+    }
+
+    /// Poll until the server has registered a session
+    async fn wait_for_server_session(server: &ProtocolServer) -> session::ProtocolSession {
+        for _ in 0..50 {
+            if let Some(session) = server.sessions().await.into_iter().next() {
+                return session;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for the server to register a session");
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnects_and_resumes_session() {
+        let config = ProtocolConfig {
+            reconnect: reconnect::ReconnectPolicy {
+                initial_backoff_ms: 20,
+                max_backoff_ms: 200,
+                ..reconnect::ReconnectPolicy::default()
+            },
+            ..ProtocolConfig::default()
+        };
+        let mut server = ProtocolServer::new(config.clone()).unwrap();
+        let mut server_events = server.subscribe_events();
+        server.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let (proxy_addr, cut) = cuttable_proxy(server.local_addr().await.unwrap()).await;
+
+        let mut client = ProtocolClient::new(config);
+        let mut client_events = client.subscribe_events();
+        client.connect(proxy_addr).await.unwrap();
+        let session_id = client.session().unwrap().session_id().to_string();
+
+        // Start a video stream before the drop
+        let video_start = messages::ProtocolMessage::new(
+            messages::MessageType::VideoStart,
+            messages::MessagePayload::VideoStart(messages::VideoStartPayload {
+                resolution: VideoResolution { width: 1920, height: 1080 },
+                fps: 60,
+                quality: "high".to_string(),
+                codec: "h264".to_string(),
+            }),
+        );
+        client.send_message(video_start).await.unwrap();
+
+        let server_session = wait_for_server_session(&server).await;
+        let received = server_session.receive_message().await.unwrap();
+        assert_eq!(received.message_type(), &messages::MessageType::VideoStart);
+        let capabilities = server_session.capabilities().clone();
+
+        // Drop the network
+        cut.send(()).unwrap();
+        wait_for_event(&mut server_events, |e| matches!(e, session::SessionEvent::Suspended { .. })).await;
+        wait_for_event(&mut client_events, |e| matches!(e, session::SessionEvent::Resumed { .. })).await;
+        wait_for_event(&mut server_events, |e| matches!(e, session::SessionEvent::Resumed { .. })).await;
+
+        // Same session, same negotiated state, and traffic flows again
+        let sessions = server.sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), session_id);
+        assert_eq!(client.session().unwrap().session_id(), session_id);
+        assert_eq!(sessions[0].capabilities(), &capabilities);
+        assert_eq!(sessions[0].video_stream().await.map(|video| video.fps), Some(60));
+        assert!(client.session().unwrap().is_active().await);
+
+        client.send_message(messages::ProtocolMessage::new(
+            messages::MessageType::MetricsRequest,
+            messages::MessagePayload::MetricsRequest,
+        )).await.unwrap();
+        let received = server_session.receive_message().await.unwrap();
+        assert_eq!(received.message_type(), &messages::MessageType::MetricsRequest);
+
+        client.disconnect().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_reconnects_over_memory_link() {
+        let config = ProtocolConfig {
+            reconnect: reconnect::ReconnectPolicy {
+                initial_backoff_ms: 20,
+                max_backoff_ms: 200,
+                ..reconnect::ReconnectPolicy::default()
+            },
+            input_channel: input_channel::InputChannelConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..ProtocolConfig::default()
+        };
+        let network = memory::MemoryTransportFactory::new(memory::LinkConditions {
+            latency: std::time::Duration::from_millis(30),
+            jitter: std::time::Duration::from_millis(10),
+            ..memory::LinkConditions::default()
+        });
+
+        let mut server = ProtocolServer::new(config.clone())
+            .unwrap()
+            .with_transport(Arc::new(network.clone()), transport::TransportConfig::default());
+        let mut server_events = server.subscribe_events();
+        server.start("127.0.0.1:9100".parse().unwrap()).await.unwrap();
+
+        let mut client = ProtocolClient::new(config)
+            .with_transport(Arc::new(network.clone()), transport::TransportConfig::default());
+        let mut client_events = client.subscribe_events();
+        client.connect(server.local_addr().await.unwrap()).await.unwrap();
+        let session_id = client.session().unwrap().session_id().to_string();
+
+        network.sever_all();
+        wait_for_event(&mut client_events, |e| matches!(e, session::SessionEvent::Resumed { .. })).await;
+        wait_for_event(&mut server_events, |e| matches!(e, session::SessionEvent::Resumed { .. })).await;
+
+        let sessions = server.sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), session_id);
+
+        client.send_message(messages::ProtocolMessage::new(
+            messages::MessageType::MetricsRequest,
+            messages::MessagePayload::MetricsRequest,
+        )).await.unwrap();
+        let received = sessions[0].receive_message().await.unwrap();
+        assert_eq!(received.message_type(), &messages::MessageType::MetricsRequest);
+
+        client.disconnect().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_only_the_controller_drives_input() {
+        let config = ProtocolConfig {
+            input_channel: input_channel::InputChannelConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..ProtocolConfig::default()
+        };
+        let network = memory::MemoryTransportFactory::default();
+        let mut server = ProtocolServer::new(config.clone())
+            .unwrap()
+            .with_transport(Arc::new(network.clone()), transport::TransportConfig::default());
+        let mut server_events = server.subscribe_events();
+        server.start("127.0.0.1:9300".parse().unwrap()).await.unwrap();
+        let addr = server.local_addr().await.unwrap();
+
+        let mut first = ProtocolClient::new(config.clone())
+            .with_transport(Arc::new(network.clone()), transport::TransportConfig::default());
+        let mut second = ProtocolClient::new(config)
+            .with_transport(Arc::new(network), transport::TransportConfig::default());
+        let mut first_events = first.subscribe_events();
+        let mut second_events = second.subscribe_events();
+        first.connect(addr).await.unwrap();
+        second.connect(addr).await.unwrap();
+        let first_id = first.session().unwrap().session_id().to_string();
+        let second_id = second.session().unwrap().session_id().to_string();
+        let key = |key_code| messages::InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code, modifiers: 0 });
+        let next_key = |session: session::ProtocolSession| async move {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(5), session.receive_message())
+                .await
+                .unwrap()
+                .unwrap();
+            match message.payload {
+                messages::MessagePayload::InputEvent(event) => match event.keyboard_event() {
+                    Some(KeyboardEvent::KeyPress { key_code, .. }) => key_code,
+                    other => panic!("expected a key press, got {:?}", other),
+                },
+                other => panic!("expected an input event, got {:?}", other),
+            }
+        };
+
+        // The first viewer to send input takes control
+        first.send_input(key(0x41)).await.unwrap();
+        wait_for_event(&mut server_events, |e| {
+            matches!(e, session::SessionEvent::ControlChanged { controller: Some(id), .. } if *id == first_id)
+        })
+        .await;
+        let sessions = server.sessions().await;
+        let server_session = |id: &str| sessions.iter().find(|s| s.session_id() == id).unwrap().clone();
+        assert_eq!(next_key(server_session(&first_id)).await, 0x41);
+        assert_eq!(server.role(&second_id), control::ViewerRole::ViewOnly);
+
+        // The second viewer's input is dropped until the controller grants control
+        second.send_input(key(0x42)).await.unwrap();
+        second.request_control().await.unwrap();
+        wait_for_event(&mut first_events, |e| {
+            matches!(e, session::SessionEvent::ControlRequested { session_id, .. } if *session_id == second_id)
+        })
+        .await;
+        first.grant_control(second_id.clone()).await.unwrap();
+        wait_for_event(&mut second_events, |e| {
+            matches!(e, session::SessionEvent::ControlChanged { controller: Some(id), .. } if *id == second_id)
+        })
+        .await;
+        second.send_input(key(0x43)).await.unwrap();
+        assert_eq!(next_key(server_session(&second_id)).await, 0x43);
+        assert_eq!(server.controller(), Some(second_id));
+
+        first.disconnect().await.unwrap();
+        second.disconnect().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_input_uses_datagram_channel_when_offered() {
+        // Never without TLS, which protects the token the channel is keyed from
+        for (enabled, tls) in [(true, true), (false, true), (true, false)] {
+            let server_config = ProtocolConfig {
+                input_channel: input_channel::InputChannelConfig {
+                    enabled,
+                    ..Default::default()
+                },
+                ..ProtocolConfig::default()
+            };
+            let transport_config = transport::TransportConfig {
+                tls: if tls { websocket::tests::self_signed_tls() } else { transport::TlsConfig::default() },
+                ..transport::TransportConfig::default()
+            };
+            let mut server = ProtocolServer::new(server_config)
+                .unwrap()
+                .with_transport(Arc::new(websocket::WebSocketFactory::new(transport_config.clone())), transport_config.clone());
+            server.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+            let mut client = ProtocolClient::new(ProtocolConfig::default())
+                .with_transport(Arc::new(websocket::WebSocketFactory::new(transport_config.clone())), transport_config);
+            client.connect(server.local_addr().await.unwrap()).await.unwrap();
+            let expected = if enabled && tls {
+                input_channel::InputTransport::Datagram
+            } else {
+                input_channel::InputTransport::WebSocket
+            };
+            assert_eq!(client.input_channel().unwrap().ready().await, expected);
+
+            let server_session = wait_for_server_session(&server).await;
+
+            let press = KeyboardEvent::KeyPress { key_code: 0x41, modifiers: 0 };
+            client.send_input(messages::InputEventPayload::keyboard(&press)).await.unwrap();
+
+            let received = tokio::time::timeout(std::time::Duration::from_secs(5), server_session.receive_message())
+                .await
+                .unwrap()
+                .unwrap();
+            match received.payload {
+                messages::MessagePayload::InputEvent(event) => {
+                    assert!(matches!(event.keyboard_event(), Some(KeyboardEvent::KeyPress { key_code: 0x41, .. })));
+                }
+                other => panic!("expected an input event, got {:?}", other),
+            }
+
+            client.disconnect().await.unwrap();
+            server.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_input_channel_is_rekeyed_after_a_resume() {
+        let config = ProtocolConfig {
+            reconnect: reconnect::ReconnectPolicy {
+                initial_backoff_ms: 20,
+                max_backoff_ms: 200,
+                ..reconnect::ReconnectPolicy::default()
+            },
+            ..ProtocolConfig::default()
+        };
+        let transport_config = transport::TransportConfig {
+            tls: websocket::tests::self_signed_tls(),
+            ..transport::TransportConfig::default()
+        };
+        let mut server = ProtocolServer::new(config.clone())
+            .unwrap()
+            .with_transport(Arc::new(websocket::WebSocketFactory::new(transport_config.clone())), transport_config.clone());
+        server.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let (proxy_addr, cut) = cuttable_proxy(server.local_addr().await.unwrap()).await;
+
+        let mut client = ProtocolClient::new(config)
+            .with_transport(Arc::new(websocket::WebSocketFactory::new(transport_config.clone())), transport_config);
+        let mut client_events = client.subscribe_events();
+        client.connect(proxy_addr).await.unwrap();
+        assert_eq!(client.input_channel().unwrap().ready().await, input_channel::InputTransport::Datagram);
+        let server_session = wait_for_server_session(&server).await;
+        let token = client.session().unwrap().resume_token().await;
+
+        cut.send(()).unwrap();
+        wait_for_event(&mut client_events, |e| matches!(e, session::SessionEvent::Resumed { .. })).await;
+        assert_ne!(client.session().unwrap().resume_token().await, token);
+
+        // The first key press notices the new token and goes over the
+        // WebSocket while the channel is probed again with the new keys
+        for key_code in [0x41, 0x42] {
+            let press = KeyboardEvent::KeyPress { key_code, modifiers: 0 };
+            client.send_input(messages::InputEventPayload::keyboard(&press)).await.unwrap();
+            let received = tokio::time::timeout(std::time::Duration::from_secs(5), server_session.receive_message())
+                .await
+                .unwrap()
+                .unwrap();
+            match received.payload {
+                messages::MessagePayload::InputEvent(event) => match event.keyboard_event() {
+                    Some(KeyboardEvent::KeyPress { key_code: received, .. }) => assert_eq!(received, key_code),
+                    other => panic!("expected a key press, got {:?}", other),
+                },
+                other => panic!("expected an input event, got {:?}", other),
+            }
+            assert_eq!(client.input_channel().unwrap().ready().await, input_channel::InputTransport::Datagram);
+        }
+
+        client.disconnect().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_server_over_quic() {
+        let transport_config = transport::TransportConfig {
+            tls: websocket::tests::self_signed_tls(),
+            ..transport::TransportConfig::default()
+        };
+        let mut server = ProtocolServer::new(ProtocolConfig::default())
+            .unwrap()
+            .with_transport(Arc::new(quic::QuicFactory::new()), transport_config.clone());
+        server.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = server.local_addr().await.unwrap();
+
+        let mut client = ProtocolClient::new(ProtocolConfig::default())
+            .with_transport(Arc::new(quic::QuicFactory::new()), transport_config);
+        client.connect(addr).await.unwrap();
+        assert!(client.session().unwrap().is_active().await);
+
+        client.send_message(messages::ProtocolMessage::new(
+            messages::MessageType::MetricsRequest,
+            messages::MessagePayload::MetricsRequest,
+        )).await.unwrap();
+
+        let received = wait_for_server_session(&server).await.receive_message().await.unwrap();
+        assert_eq!(received.message_type(), &messages::MessageType::MetricsRequest);
+
+        client.disconnect().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_protocol_basic_round_trip() {
+        test_protocol_basic().await.unwrap();
+    }
 }
@@ -0,0 +1,546 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory transport
+//!
+//! `MemoryTransportFactory` implements the transport traits over channels,
+//! so handshakes, keepalives and reconnects can be tested without sockets
+//! and on tokio's paused clock. `LinkConditions` add latency, jitter, loss
+//! and a bandwidth limit to every link; each link direction draws from its
+//! own generator seeded from `LinkConditions::seed`, so the same messages are
+//! delayed and dropped on every run.
+//!
+//! Messages are encoded with the connection's codec and fragmented like on
+//! a WebSocket on the way through, so codec negotiation and reassembly are
+//! exercised as well. Each fragment is delayed and dropped on its own.
+
+use crate::codec::{CodecKind, MessageCodec};
+use crate::fragment::{Fragmenter, Reassembler};
+use crate::messages::ProtocolMessage;
+use crate::transport::{TransportConfig, TransportConnection, TransportFactory, TransportListener};
+use crate::{ProtocolError, ProtocolResult};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tracing::debug;
+
+/// First port handed out for listeners on port 0 and for clients
+const FIRST_PORT: u16 = 40000;
+
+/// Network conditions applied to each direction of a link
+#[derive(Debug, Clone)]
+pub struct LinkConditions {
+    pub latency: Duration,
+    pub jitter: Duration,       // extra delay, uniform in [0, jitter]
+    pub loss: f64,              // probability that a message is dropped
+    pub bandwidth: Option<u64>, // bytes per second, None for unlimited
+    pub seed: u64,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        LinkConditions {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            bandwidth: None,
+            seed: 0,
+        }
+    }
+}
+
+type Frame = (Instant, Vec<u8>);
+
+/// Listeners and live links of one in-memory network
+struct Network {
+    listeners: Mutex<HashMap<SocketAddr, (mpsc::UnboundedSender<MemoryConnection>, TransportConfig)>>,
+    links: Mutex<Vec<Weak<watch::Sender<bool>>>>,
+    conditions: Mutex<LinkConditions>,
+    next_port: AtomicU16,
+    next_link: AtomicU64,
+}
+
+impl Network {
+    fn new(conditions: LinkConditions) -> Self {
+        Network {
+            listeners: Mutex::new(HashMap::new()),
+            links: Mutex::new(Vec::new()),
+            conditions: Mutex::new(conditions),
+            next_port: AtomicU16::new(FIRST_PORT),
+            next_link: AtomicU64::new(0),
+        }
+    }
+
+    fn allocate_addr(&self) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), self.next_port.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn conditions(&self) -> LinkConditions {
+        self.conditions.lock().unwrap().clone()
+    }
+}
+
+/// One end of an in-memory link
+pub struct MemoryConnection {
+    network: Arc<Network>,
+    remote_addr: SocketAddr,
+    outbound: Option<mpsc::UnboundedSender<Frame>>,
+    inbound: mpsc::UnboundedReceiver<Frame>,
+    severed: watch::Receiver<bool>,
+    // Keeps the link registered while either end is alive
+    _link: Arc<watch::Sender<bool>>,
+    rng: StdRng,
+    busy_until: Instant,
+    last_delivery: Instant,
+    codec: Arc<dyn MessageCodec>,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+}
+
+impl MemoryConnection {
+    fn pair(
+        network: &Arc<Network>,
+        client: (SocketAddr, &TransportConfig),
+        server: (SocketAddr, &TransportConfig),
+    ) -> (Self, Self) {
+        let link_id = network.next_link.fetch_add(1, Ordering::Relaxed);
+        let seed = network.conditions().seed;
+        let (link, severed) = watch::channel(false);
+        let link = Arc::new(link);
+        network.links.lock().unwrap().push(Arc::downgrade(&link));
+
+        let (client_tx, server_rx) = mpsc::unbounded_channel();
+        let (server_tx, client_rx) = mpsc::unbounded_channel();
+        let now = Instant::now();
+        let end = |remote_addr, config, outbound, inbound, direction: u64| MemoryConnection {
+            network: network.clone(),
+            remote_addr,
+            outbound: Some(outbound),
+            inbound,
+            severed: severed.clone(),
+            _link: link.clone(),
+            rng: StdRng::seed_from_u64(seed ^ (link_id << 1 | direction)),
+            busy_until: now,
+            last_delivery: now,
+            codec: CodecKind::Json.codec(),
+            fragmenter: Fragmenter::new(config),
+            reassembler: Reassembler::new(config),
+        };
+
+        let ((client_addr, client_config), (server_addr, server_config)) = (client, server);
+        (
+            end(server_addr, client_config, client_tx, client_rx, 0),
+            end(client_addr, server_config, server_tx, server_rx, 1),
+        )
+    }
+
+    fn is_severed(&self) -> bool {
+        *self.severed.borrow()
+    }
+
+    /// Put one frame on the link
+    async fn transmit(&mut self, frame: Vec<u8>, conditions: &LinkConditions) -> ProtocolResult<()> {
+        // Wait for the previous frame to leave, then occupy the link for
+        // as long as this one takes at the configured bandwidth
+        let start = self.busy_until.max(Instant::now());
+        tokio::time::sleep_until(start).await;
+        let transmit = conditions.bandwidth
+            .filter(|bandwidth| *bandwidth > 0)
+            .map_or(Duration::ZERO, |bandwidth| Duration::from_secs_f64(frame.len() as f64 / bandwidth as f64));
+        self.busy_until = start + transmit;
+
+        if conditions.loss > 0.0 && self.rng.gen_bool(conditions.loss.min(1.0)) {
+            debug!("Dropped {} byte frame to {}", frame.len(), self.remote_addr);
+            return Ok(());
+        }
+
+        let jitter = if conditions.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=conditions.jitter)
+        };
+        // Delivered in order, like the stream transports
+        let deliver_at = (self.busy_until + conditions.latency + jitter).max(self.last_delivery);
+        self.last_delivery = deliver_at;
+
+        self.outbound.as_ref().unwrap().send((deliver_at, frame))
+            .map_err(|_| ProtocolError::Transport("Connection closed by peer".to_string()))
+    }
+}
+
+#[async_trait]
+impl TransportConnection for MemoryConnection {
+    async fn send(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
+        if self.is_severed() {
+            return Err(ProtocolError::Transport("Link severed".to_string()));
+        }
+        if self.outbound.is_none() {
+            return Err(ProtocolError::Transport("Connection closed".to_string()));
+        }
+
+        let frame = self.codec.encode(&message)?;
+        let conditions = self.network.conditions();
+
+        for frame in self.fragmenter.split(frame)? {
+            self.transmit(frame, &conditions).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> ProtocolResult<Option<ProtocolMessage>> {
+        let mut severed = self.severed.clone();
+        let deliver = async {
+            while let Some((deliver_at, frame)) = self.inbound.recv().await {
+                tokio::time::sleep_until(deliver_at).await;
+                if let Some(message) = self.reassembler.push(frame)? {
+                    return Ok(Some(message));
+                }
+            }
+            Ok(None)
+        };
+
+        tokio::select! {
+            biased;
+            _ = severed.wait_for(|severed| *severed) => Err(ProtocolError::Transport("Link severed".to_string())),
+            result = deliver => result,
+        }
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        // The peer drains what is in flight, then sees the end of the stream
+        self.outbound = None;
+        self.inbound.close();
+        Ok(())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.outbound.as_ref().is_some_and(|outbound| !outbound.is_closed()) && !self.is_severed()
+    }
+
+    fn set_codec(&mut self, codec: Arc<dyn MessageCodec>) {
+        self.codec = codec;
+    }
+}
+
+/// Listener on an in-memory network
+pub struct MemoryListener {
+    network: Arc<Network>,
+    addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<MemoryConnection>,
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.network.listeners.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[async_trait]
+impl TransportListener for MemoryListener {
+    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let connection = self.incoming.recv().await
+            .ok_or_else(|| ProtocolError::Transport("Listener closed".to_string()))?;
+        Ok(Box::new(connection))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.network.listeners.lock().unwrap().remove(&self.addr);
+        self.incoming.close();
+        Ok(())
+    }
+}
+
+/// Factory for an in-memory network.
+///
+/// Clones share the network, so a server and its clients must be created
+/// from clones of the same factory.
+#[derive(Clone)]
+pub struct MemoryTransportFactory {
+    network: Arc<Network>,
+}
+
+impl Default for MemoryTransportFactory {
+    fn default() -> Self {
+        Self::new(LinkConditions::default())
+    }
+}
+
+impl MemoryTransportFactory {
+    /// Create a network whose links have the given conditions
+    pub fn new(conditions: LinkConditions) -> Self {
+        MemoryTransportFactory {
+            network: Arc::new(Network::new(conditions)),
+        }
+    }
+
+    /// Get the current link conditions
+    pub fn conditions(&self) -> LinkConditions {
+        self.network.conditions()
+    }
+
+    /// Change the link conditions; live links pick them up on their next send
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        *self.network.conditions.lock().unwrap() = conditions;
+    }
+
+    /// Cut every live link, as if the network dropped
+    pub fn sever_all(&self) {
+        let mut links = self.network.links.lock().unwrap();
+        for link in links.drain(..).filter_map(|link| link.upgrade()) {
+            link.send_replace(true);
+        }
+    }
+
+    /// Create a connected pair without a listener
+    pub fn pair(&self) -> (MemoryConnection, MemoryConnection) {
+        let config = TransportConfig::default();
+        let client_addr = self.network.allocate_addr();
+        let server_addr = self.network.allocate_addr();
+        MemoryConnection::pair(&self.network, (client_addr, &config), (server_addr, &config))
+    }
+}
+
+#[async_trait]
+impl TransportFactory for MemoryTransportFactory {
+    async fn create_listener(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportListener>> {
+        let addr = if addr.port() == 0 {
+            SocketAddr::new(addr.ip(), self.network.next_port.fetch_add(1, Ordering::Relaxed))
+        } else {
+            addr
+        };
+
+        let mut listeners = self.network.listeners.lock().unwrap();
+        if listeners.contains_key(&addr) {
+            return Err(ProtocolError::Transport(format!("Address already in use: {}", addr)));
+        }
+        let (sender, incoming) = mpsc::unbounded_channel();
+        listeners.insert(addr, (sender, config));
+
+        Ok(Box::new(MemoryListener {
+            network: self.network.clone(),
+            addr,
+            incoming,
+        }))
+    }
+
+    async fn create_connection(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let (listener, listener_config) = self.network.listeners.lock().unwrap().get(&addr).cloned()
+            .ok_or_else(|| ProtocolError::Transport(format!("Connection refused: {}", addr)))?;
+
+        let client_addr = self.network.allocate_addr();
+        let (client, server) = MemoryConnection::pair(&self.network, (client_addr, &config), (addr, &listener_config));
+        listener.send(server)
+            .map_err(|_| ProtocolError::Transport(format!("Connection refused: {}", addr)))?;
+        Ok(Box::new(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{HeartbeatPayload, MessagePayload, MessageType, VideoFramePayload};
+    use crate::queue::QueueSender;
+    use crate::transport::{MessageHandler, TransportManager};
+
+    fn heartbeat(sequence_number: u64) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::Heartbeat,
+            MessagePayload::Heartbeat(HeartbeatPayload { sequence_number }),
+        )
+    }
+
+    fn sequence(message: &ProtocolMessage) -> u64 {
+        match &message.payload {
+            MessagePayload::Heartbeat(heartbeat) => heartbeat.sequence_number,
+            other => panic!("expected a heartbeat, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_on_paused_clock() {
+        let factory = MemoryTransportFactory::new(LinkConditions {
+            latency: Duration::from_millis(40),
+            ..LinkConditions::default()
+        });
+        let (mut client, mut server) = factory.pair();
+
+        let start = Instant::now();
+        client.send(heartbeat(1)).await.unwrap();
+        server.receive().await.unwrap().unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(40));
+
+        // Closing delivers what is in flight, then ends the stream
+        client.send(heartbeat(2)).await.unwrap();
+        client.close().await.unwrap();
+        assert_eq!(sequence(&server.receive().await.unwrap().unwrap()), 2);
+        assert!(server.receive().await.unwrap().is_none());
+    }
+
+    /// Send 100 heartbeats over a lossy, jittery link and record what arrives when
+    async fn lossy_run(seed: u64) -> Vec<(u64, Duration)> {
+        let factory = MemoryTransportFactory::new(LinkConditions {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(20),
+            loss: 0.3,
+            seed,
+            ..LinkConditions::default()
+        });
+        let (mut client, mut server) = factory.pair();
+
+        let start = Instant::now();
+        for sequence_number in 0..100 {
+            client.send(heartbeat(sequence_number)).await.unwrap();
+        }
+        client.close().await.unwrap();
+
+        let mut arrivals = Vec::new();
+        while let Some(message) = server.receive().await.unwrap() {
+            arrivals.push((sequence(&message), start.elapsed()));
+        }
+        arrivals
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loss_and_jitter_are_deterministic() {
+        let first = lossy_run(7).await;
+        assert!(first.len() > 50 && first.len() < 90, "{} delivered", first.len());
+        assert!(first.windows(2).all(|pair| pair[0].0 < pair[1].0 && pair[0].1 <= pair[1].1));
+
+        assert_eq!(lossy_run(7).await, first);
+        assert_ne!(lossy_run(8).await, first);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth_paces_video_frames() {
+        let factory = MemoryTransportFactory::new(LinkConditions {
+            bandwidth: Some(1_000_000),
+            ..LinkConditions::default()
+        });
+        let (mut client, mut server) = factory.pair();
+        client.set_codec(CodecKind::Binary.codec());
+
+        let start = Instant::now();
+        let sender = tokio::spawn(async move {
+            for frame_number in 0..10 {
+                let frame = ProtocolMessage::new(
+                    MessageType::VideoFrame,
+                    MessagePayload::VideoFrame(VideoFramePayload {
+                        frame_number,
+                        timestamp: 0,
+                        width: 1280,
+                        height: 720,
+                        format: "h264".to_string(),
+                        data: vec![0; 100_000],
+                    }),
+                );
+                client.send(frame).await.unwrap();
+            }
+            client
+        });
+
+        // Each ~100KB frame occupies the 1MB/s link for ~100ms
+        let mut arrivals = Vec::new();
+        for _ in 0..10 {
+            server.receive().await.unwrap().unwrap();
+            arrivals.push(start.elapsed());
+        }
+        sender.await.unwrap();
+
+        for (index, arrival) in arrivals.iter().enumerate() {
+            let expected = Duration::from_millis(100 * (index as u64 + 1));
+            assert!(*arrival >= expected && *arrival < expected + Duration::from_millis(5), "frame {} at {:?}", index, arrival);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listener_addresses() {
+        let factory = MemoryTransportFactory::default();
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+
+        assert!(factory.create_connection(addr, TransportConfig::default()).await.is_err());
+
+        let listener = factory.create_listener(addr, TransportConfig::default()).await.unwrap();
+        assert_eq!(listener.local_addr(), Some(addr));
+        assert!(factory.create_listener(addr, TransportConfig::default()).await.is_err());
+
+        drop(listener);
+        assert!(factory.create_connection(addr, TransportConfig::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sever_all_cuts_live_links() {
+        let factory = MemoryTransportFactory::default();
+        let (mut client, mut server) = factory.pair();
+
+        factory.sever_all();
+        assert!(!client.is_alive());
+        assert!(client.send(heartbeat(1)).await.is_err());
+        assert!(server.receive().await.is_err());
+
+        // New links are unaffected
+        let (mut client, mut server) = factory.pair();
+        client.send(heartbeat(2)).await.unwrap();
+        assert_eq!(sequence(&server.receive().await.unwrap().unwrap()), 2);
+    }
+
+    /// Echoes every message back to the sender
+    #[derive(Clone)]
+    struct Echo;
+
+    #[async_trait]
+    impl MessageHandler for Echo {
+        async fn handle_message(&mut self, message: ProtocolMessage, sender: QueueSender) -> ProtocolResult<()> {
+            sender.send(message).await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transport_manager_round_trip() {
+        let factory = MemoryTransportFactory::new(LinkConditions {
+            latency: Duration::from_millis(25),
+            ..LinkConditions::default()
+        });
+        let addr: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+
+        let server = TransportManager::new(factory.clone(), TransportConfig::default());
+        server.listen(addr, Echo).await.unwrap();
+
+        let client = TransportManager::new(factory, TransportConfig::default());
+        let mut received = client.connect(addr, Echo).await.unwrap();
+
+        let start = Instant::now();
+        client.send_to(addr, heartbeat(42)).await.unwrap();
+        let echoed = received.recv().await.unwrap();
+        assert_eq!(sequence(&echoed), 42);
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
@@ -14,11 +14,14 @@
 
 //! Protocol message definitions
 
+use crate::codec::CodecKind;
+use crate::compression::CompressionKind;
+use crate::{ProtocolError, ProtocolResult};
 use serde::{Deserialize, Serialize};
 use soft_kvm_core::*;
 
 /// Message types
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MessageType {
     // Control messages
     Hello,
@@ -48,6 +51,9 @@ pub enum MessageType {
     MetricsResponse,
     Ping,
     Pong,
+
+    // Multi-viewer
+    Control,
 }
 
 /// Protocol message header
@@ -57,7 +63,13 @@ pub struct MessageHeader {
     pub message_id: String,
     pub timestamp: chrono::DateTime<chrono::Utc>,
     pub session_id: Option<String>,
+    /// ID of the request this message answers
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    /// Set to ask for compression; on a received message, whether the
+    /// payload was compressed on the wire
     pub compression: bool,
+    /// Encoded payload size before compression, filled in by the codec
     pub payload_size: usize,
 }
 
@@ -97,16 +109,32 @@ pub enum MessagePayload {
     // Monitoring payloads
     MetricsRequest,
     MetricsResponse(MetricsPayload),
-    Ping,
-    Pong,
+    Ping(PingPayload),
+    Pong(PongPayload),
+
+    // Multi-viewer payloads
+    Control(ControlPayload),
 }
 
 /// Hello message payload
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HelloPayload {
+    /// Newest protocol version the client speaks
     pub protocol_version: String,
+    /// Oldest protocol version the client speaks; absent means only `protocol_version`
+    #[serde(default)]
+    pub min_protocol_version: Option<String>,
     pub client_info: ClientInfo,
-    pub capabilities: Vec<String>,
+    pub capabilities: CapabilitySet,
+    /// Wire codecs supported by the client, in order of preference
+    #[serde(default)]
+    pub codecs: Vec<CodecKind>,
+    /// Session token from a previous AuthResponse, to resume that session
+    #[serde(default)]
+    pub resume_token: Option<String>,
+    /// Payload compressors supported by the client, in order of preference
+    #[serde(default)]
+    pub compression: Vec<CompressionKind>,
 }
 
 /// Client information
@@ -123,7 +151,20 @@ pub struct ClientInfo {
 pub struct WelcomePayload {
     pub server_info: ServerInfo,
     pub session_id: String,
-    pub negotiated_capabilities: Vec<String>,
+    /// Authoritative capability set chosen by the server
+    pub negotiated_capabilities: CapabilitySet,
+    /// Wire codec both peers switch to after this message
+    #[serde(default)]
+    pub codec: CodecKind,
+    /// Payload compression used from then on, if any
+    #[serde(default)]
+    pub compression: Option<CompressionKind>,
+    /// Whether an existing session was resumed
+    #[serde(default)]
+    pub resumed: bool,
+    /// UDP port of the input channel, if the server offers one
+    #[serde(default)]
+    pub input_port: Option<u16>,
 }
 
 /// Server information
@@ -131,6 +172,7 @@ pub struct WelcomePayload {
 pub struct ServerInfo {
     pub server_id: String,
     pub server_name: String,
+    /// Protocol version chosen for the session
     pub protocol_version: String,
 }
 
@@ -138,7 +180,7 @@ pub struct ServerInfo {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GoodbyePayload {
     pub reason: String,
-    pub code: u32,
+    pub code: ErrorCode, // NormalClosure unless the session was ended for a reason
 }
 
 /// Heartbeat payload
@@ -147,14 +189,57 @@ pub struct HeartbeatPayload {
     pub sequence_number: u64,
 }
 
+/// Ping payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingPayload {
+    pub originate: i64, // microseconds since UNIX epoch, sender's clock
+}
+
+/// Pong payload; the timestamps of an NTP-style exchange (see `crate::timing`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PongPayload {
+    pub originate: i64, // copied from the Ping or Heartbeat being answered
+    pub receive: i64,   // when that message arrived, responder's clock
+    pub transmit: i64,  // when this Pong was sent, responder's clock
+}
+
+impl PingPayload {
+    /// Ping stamped with the current time
+    pub fn now() -> Self {
+        PingPayload { originate: crate::timing::now_micros() }
+    }
+}
+
+impl PongPayload {
+    /// Answer a message sent at `originate` that arrived at `receive`
+    pub fn answer(originate: i64, receive: i64) -> Self {
+        PongPayload {
+            originate,
+            receive,
+            transmit: crate::timing::now_micros(),
+        }
+    }
+}
+
 /// Error payload
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorPayload {
-    pub error_code: u32,
+    pub error_code: ErrorCode,
     pub error_message: String,
     pub details: Option<String>,
 }
 
+impl ErrorPayload {
+    /// Report a local error to the peer
+    pub fn from_error(error: &crate::ProtocolError) -> Self {
+        ErrorPayload {
+            error_code: error.code(),
+            error_message: error.to_string(),
+            details: None,
+        }
+    }
+}
+
 /// Authentication request payload
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthRequestPayload {
@@ -166,6 +251,7 @@ pub struct AuthRequestPayload {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthResponsePayload {
     pub success: bool,
+    /// Issued on success; presented in Hello to resume the session
     pub session_token: Option<String>,
     pub error_message: Option<String>,
 }
@@ -197,6 +283,43 @@ pub struct InputEventPayload {
     pub data: serde_json::Value, // Flexible input data
 }
 
+impl InputEventPayload {
+    /// `event_type` of a serialized `KeyboardEvent`
+    pub const KEYBOARD: &'static str = "keyboard";
+    /// `event_type` of a serialized `MouseEvent`
+    pub const MOUSE: &'static str = "mouse";
+
+    /// Wrap a keyboard event
+    pub fn keyboard(event: &KeyboardEvent) -> Self {
+        InputEventPayload {
+            event_type: Self::KEYBOARD.to_string(),
+            data: serde_json::to_value(event).unwrap_or_default(),
+        }
+    }
+
+    /// Wrap a mouse event
+    pub fn mouse(event: &MouseEvent) -> Self {
+        InputEventPayload {
+            event_type: Self::MOUSE.to_string(),
+            data: serde_json::to_value(event).unwrap_or_default(),
+        }
+    }
+
+    /// Get the keyboard event, if this is one
+    pub fn keyboard_event(&self) -> Option<KeyboardEvent> {
+        (self.event_type == Self::KEYBOARD)
+            .then(|| serde_json::from_value(self.data.clone()).ok())
+            .flatten()
+    }
+
+    /// Get the mouse event, if this is one
+    pub fn mouse_event(&self) -> Option<MouseEvent> {
+        (self.event_type == Self::MOUSE)
+            .then(|| serde_json::from_value(self.data.clone()).ok())
+            .flatten()
+    }
+}
+
 /// Clipboard payload
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClipboardPayload {
@@ -211,7 +334,7 @@ pub struct ServiceAnnouncementPayload {
     pub service_name: String,
     pub service_type: ServiceType,
     pub address: NetworkAddress,
-    pub capabilities: Vec<String>,
+    pub capabilities: CapabilitySet,
 }
 
 /// Service query payload
@@ -227,6 +350,23 @@ pub struct ServiceResponsePayload {
     pub services: Vec<ServiceAnnouncementPayload>,
 }
 
+/// Input control payload (see `crate::control`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlPayload {
+    /// Ask the controller for input control
+    Request,
+    /// Hand control to another session (controller only)
+    Grant { session_id: String },
+    /// Take control without asking
+    TakeOver,
+    /// Give up control
+    Release,
+    /// A viewer asks for control (server to controller)
+    Requested { session_id: String, peer_name: String },
+    /// Control moved; `None` means nobody has it (server to all viewers)
+    Changed { controller: Option<String>, previous: Option<String> },
+}
+
 /// Metrics payload
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetricsPayload {
@@ -234,6 +374,104 @@ pub struct MetricsPayload {
     pub metrics: serde_json::Value,
 }
 
+impl MessageType {
+    /// Stable identifier used by the binary codec
+    pub fn wire_id(&self) -> u8 {
+        match self {
+            MessageType::Hello => 0,
+            MessageType::Welcome => 1,
+            MessageType::Goodbye => 2,
+            MessageType::Heartbeat => 3,
+            MessageType::Error => 4,
+            MessageType::AuthRequest => 5,
+            MessageType::AuthResponse => 6,
+            MessageType::VideoStart => 7,
+            MessageType::VideoStop => 8,
+            MessageType::VideoFrame => 9,
+            MessageType::InputEvent => 10,
+            MessageType::ClipboardData => 11,
+            MessageType::ServiceAnnouncement => 12,
+            MessageType::ServiceQuery => 13,
+            MessageType::ServiceResponse => 14,
+            MessageType::MetricsRequest => 15,
+            MessageType::MetricsResponse => 16,
+            MessageType::Ping => 17,
+            MessageType::Pong => 18,
+            MessageType::Control => 19,
+        }
+    }
+
+    /// Look up a message type by its binary codec identifier
+    pub fn from_wire_id(id: u8) -> ProtocolResult<Self> {
+        let message_type = match id {
+            0 => MessageType::Hello,
+            1 => MessageType::Welcome,
+            2 => MessageType::Goodbye,
+            3 => MessageType::Heartbeat,
+            4 => MessageType::Error,
+            5 => MessageType::AuthRequest,
+            6 => MessageType::AuthResponse,
+            7 => MessageType::VideoStart,
+            8 => MessageType::VideoStop,
+            9 => MessageType::VideoFrame,
+            10 => MessageType::InputEvent,
+            11 => MessageType::ClipboardData,
+            12 => MessageType::ServiceAnnouncement,
+            13 => MessageType::ServiceQuery,
+            14 => MessageType::ServiceResponse,
+            15 => MessageType::MetricsRequest,
+            16 => MessageType::MetricsResponse,
+            17 => MessageType::Ping,
+            18 => MessageType::Pong,
+            19 => MessageType::Control,
+            _ => return Err(ProtocolError::InvalidMessageType(format!("Unknown wire id {}", id))),
+        };
+        Ok(message_type)
+    }
+
+    /// Capability that must be negotiated before this message may be exchanged
+    pub fn required_capability(&self) -> Option<CapabilityKind> {
+        match self {
+            MessageType::VideoStart | MessageType::VideoStop | MessageType::VideoFrame => Some(CapabilityKind::Video),
+            MessageType::InputEvent => Some(CapabilityKind::Input),
+            MessageType::ClipboardData => Some(CapabilityKind::Clipboard),
+            MessageType::ServiceAnnouncement | MessageType::ServiceQuery | MessageType::ServiceResponse => {
+                Some(CapabilityKind::Discovery)
+            }
+            MessageType::MetricsRequest | MessageType::MetricsResponse => Some(CapabilityKind::Metrics),
+            _ => None,
+        }
+    }
+}
+
+impl MessagePayload {
+    /// Message type matching this payload
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            MessagePayload::Hello(_) => MessageType::Hello,
+            MessagePayload::Welcome(_) => MessageType::Welcome,
+            MessagePayload::Goodbye(_) => MessageType::Goodbye,
+            MessagePayload::Heartbeat(_) => MessageType::Heartbeat,
+            MessagePayload::Error(_) => MessageType::Error,
+            MessagePayload::AuthRequest(_) => MessageType::AuthRequest,
+            MessagePayload::AuthResponse(_) => MessageType::AuthResponse,
+            MessagePayload::VideoStart(_) => MessageType::VideoStart,
+            MessagePayload::VideoStop => MessageType::VideoStop,
+            MessagePayload::VideoFrame(_) => MessageType::VideoFrame,
+            MessagePayload::InputEvent(_) => MessageType::InputEvent,
+            MessagePayload::ClipboardData(_) => MessageType::ClipboardData,
+            MessagePayload::ServiceAnnouncement(_) => MessageType::ServiceAnnouncement,
+            MessagePayload::ServiceQuery(_) => MessageType::ServiceQuery,
+            MessagePayload::ServiceResponse(_) => MessageType::ServiceResponse,
+            MessagePayload::MetricsRequest => MessageType::MetricsRequest,
+            MessagePayload::MetricsResponse(_) => MessageType::MetricsResponse,
+            MessagePayload::Ping(_) => MessageType::Ping,
+            MessagePayload::Pong(_) => MessageType::Pong,
+            MessagePayload::Control(_) => MessageType::Control,
+        }
+    }
+}
+
 impl ProtocolMessage {
     /// Create a new protocol message
     pub fn new(message_type: MessageType, payload: MessagePayload) -> Self {
@@ -242,6 +480,7 @@ impl ProtocolMessage {
             message_id: uuid::Uuid::new_v4().to_string(),
             timestamp: chrono::Utc::now(),
             session_id: None,
+            correlation_id: None,
             compression: false,
             payload_size: 0, // Will be calculated when serialized
         };
@@ -255,6 +494,14 @@ impl ProtocolMessage {
         self
     }
 
+    /// Create a reply to this message, correlated with its message ID
+    pub fn reply(&self, message_type: MessageType, payload: MessagePayload) -> ProtocolMessage {
+        let mut reply = ProtocolMessage::new(message_type, payload);
+        reply.header.session_id = self.header.session_id.clone();
+        reply.header.correlation_id = Some(self.header.message_id.clone());
+        reply
+    }
+
     /// Set compression flag
     pub fn with_compression(mut self, compression: bool) -> Self {
         self.header.compression = compression;
@@ -271,6 +518,11 @@ impl ProtocolMessage {
         self.header.session_id.as_ref()
     }
 
+    /// Get the ID of the request this message answers
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.header.correlation_id.as_deref()
+    }
+
     /// Check if message is compressed
     pub fn is_compressed(&self) -> bool {
         self.header.compression
@@ -284,6 +536,7 @@ impl Default for MessageHeader {
             message_id: uuid::Uuid::new_v4().to_string(),
             timestamp: chrono::Utc::now(),
             session_id: None,
+            correlation_id: None,
             compression: false,
             payload_size: 0,
         }
@@ -298,13 +551,17 @@ mod tests {
     fn test_protocol_message_creation() {
         let payload = MessagePayload::Hello(HelloPayload {
             protocol_version: "1.0.0".to_string(),
+            min_protocol_version: None,
             client_info: ClientInfo {
                 client_id: "test-client".to_string(),
                 client_name: "Test Client".to_string(),
                 platform: "linux".to_string(),
                 version: "1.0.0".to_string(),
             },
-            capabilities: vec!["video".to_string(), "input".to_string()],
+            capabilities: CapabilitySet::full(),
+            codecs: CodecKind::supported(),
+            resume_token: None,
+            compression: CompressionKind::supported(),
         });
 
         let message = ProtocolMessage::new(MessageType::Hello, payload);
@@ -0,0 +1,524 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded message queues
+//!
+//! Messages are queued per traffic class, each with its own capacity. The
+//! receiver always takes control messages first, then input, then video, so
+//! a backlog of frames never delays a keystroke or a heartbeat.
+//!
+//! A full control or input queue makes senders wait (backpressure). A full
+//! video queue drops its oldest frame instead: a late frame is worth less
+//! than the next one, and the sender must not stall behind a slow viewer.
+//!
+//! Whoever fills a queue from the network must not wait at all, or it stops
+//! answering heartbeats. `QueueSender::send_lossy` drops the oldest input
+//! event as well and refuses a message for a full control queue.
+
+use crate::messages::{MessageType, ProtocolMessage};
+use crate::{ProtocolError, ProtocolResult};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// Traffic class of a queued message, in order of priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Control,
+    Input,
+    Video,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::Control, Priority::Input, Priority::Video];
+
+    /// Get the traffic class of a message type
+    pub fn of(message_type: &MessageType) -> Self {
+        match message_type {
+            MessageType::InputEvent => Priority::Input,
+            MessageType::VideoFrame => Priority::Video,
+            _ => Priority::Control,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Queue capacities per traffic class
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub control_capacity: usize,
+    pub input_capacity: usize,
+    pub video_capacity: usize, // frames kept before the oldest is dropped
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            control_capacity: 256,
+            input_capacity: 1024,
+            video_capacity: 4,
+        }
+    }
+}
+
+impl QueueConfig {
+    fn capacity(&self, priority: Priority) -> usize {
+        let capacity = match priority {
+            Priority::Control => self.control_capacity,
+            Priority::Input => self.input_capacity,
+            Priority::Video => self.video_capacity,
+        };
+        capacity.max(1)
+    }
+}
+
+/// Queue depth snapshot
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueStats {
+    pub control: usize,
+    pub input: usize,
+    pub video: usize,
+    pub dropped_video: u64,
+    pub dropped_input: u64,
+}
+
+impl QueueStats {
+    /// Total number of queued messages
+    pub fn len(&self) -> usize {
+        self.control + self.input + self.video
+    }
+
+    /// Check if nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Error returned by `QueueReceiver::try_recv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+/// Error returned by `QueueSender::send_lossy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError {
+    Full,
+    Closed,
+}
+
+struct State {
+    queues: [VecDeque<ProtocolMessage>; 3],
+    senders: usize,
+    closed: bool,
+    dropped_video: u64,
+    dropped_input: u64,
+}
+
+struct Shared {
+    config: QueueConfig,
+    state: Mutex<State>,
+    not_empty: Notify,
+    not_full: [Notify; 3],
+}
+
+impl Shared {
+    fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            control: state.queues[Priority::Control.index()].len(),
+            input: state.queues[Priority::Input.index()].len(),
+            video: state.queues[Priority::Video.index()].len(),
+            dropped_video: state.dropped_video,
+            dropped_input: state.dropped_input,
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        for notify in &self.not_full {
+            notify.notify_waiters();
+        }
+        self.not_empty.notify_one();
+    }
+}
+
+/// Create a bounded priority queue
+pub fn channel(config: QueueConfig) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        config,
+        state: Mutex::new(State {
+            queues: Default::default(),
+            senders: 1,
+            closed: false,
+            dropped_video: 0,
+            dropped_input: 0,
+        }),
+        not_empty: Notify::new(),
+        not_full: Default::default(),
+    });
+
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+/// Sending half of a priority queue
+pub struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for QueueSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueSender").field("stats", &self.shared.stats()).finish()
+    }
+}
+
+impl Clone for QueueSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        QueueSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.not_empty.notify_one();
+        }
+    }
+}
+
+impl QueueSender {
+    /// Queue a message, waiting while its control or input queue is full.
+    ///
+    /// Video frames never wait; the oldest queued frame is dropped instead.
+    pub async fn send(&self, message: ProtocolMessage) -> ProtocolResult<()> {
+        let priority = Priority::of(message.message_type());
+        let capacity = self.shared.config.capacity(priority);
+        let mut message = Some(message);
+
+        loop {
+            let not_full = self.shared.not_full[priority.index()].notified();
+            tokio::pin!(not_full);
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return Err(ProtocolError::Transport("Queue closed".to_string()));
+                }
+
+                let queue = &mut state.queues[priority.index()];
+                if priority == Priority::Video && queue.len() >= capacity {
+                    queue.pop_front();
+                    state.dropped_video += 1;
+                    debug!("Video queue full, dropped oldest frame");
+                }
+
+                let queue = &mut state.queues[priority.index()];
+                if queue.len() < capacity {
+                    queue.push_back(message.take().unwrap());
+                    drop(state);
+                    self.shared.not_empty.notify_one();
+                    return Ok(());
+                }
+
+                // Register before unlocking so a concurrent receive can't be missed
+                not_full.as_mut().enable();
+            }
+            not_full.await;
+        }
+    }
+
+    /// Queue a message without waiting; fails if its queue is full
+    pub fn try_send(&self, message: ProtocolMessage) -> ProtocolResult<()> {
+        let priority = Priority::of(message.message_type());
+        let capacity = self.shared.config.capacity(priority);
+
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(ProtocolError::Transport("Queue closed".to_string()));
+        }
+
+        if priority == Priority::Video && state.queues[priority.index()].len() >= capacity {
+            state.queues[priority.index()].pop_front();
+            state.dropped_video += 1;
+        }
+        let queue = &mut state.queues[priority.index()];
+        if queue.len() >= capacity {
+            return Err(ProtocolError::Transport(format!("{:?} queue full", priority)));
+        }
+        queue.push_back(message);
+        drop(state);
+
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Queue a message without waiting, dropping the oldest video frame or
+    /// input event to make room. Only a full control queue refuses it.
+    pub fn send_lossy(&self, message: ProtocolMessage) -> Result<(), TrySendError> {
+        let priority = Priority::of(message.message_type());
+        let capacity = self.shared.config.capacity(priority);
+
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(TrySendError::Closed);
+        }
+
+        if state.queues[priority.index()].len() >= capacity {
+            match priority {
+                Priority::Control => return Err(TrySendError::Full),
+                Priority::Input => state.dropped_input += 1,
+                Priority::Video => state.dropped_video += 1,
+            }
+            state.queues[priority.index()].pop_front();
+            debug!("{:?} queue full, dropped oldest message", priority);
+        }
+        state.queues[priority.index()].push_back(message);
+        drop(state);
+
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Check if the receiver has gone away
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    /// Get the current queue depth
+    pub fn stats(&self) -> QueueStats {
+        self.shared.stats()
+    }
+
+    /// Get a handle that reports the queue depth without keeping it open
+    pub fn monitor(&self) -> QueueMonitor {
+        QueueMonitor { shared: self.shared.clone() }
+    }
+}
+
+/// Receiving half of a priority queue
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for QueueReceiver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueReceiver").field("stats", &self.shared.stats()).finish()
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+impl QueueReceiver {
+    /// Receive the highest priority message, waiting until one is queued.
+    ///
+    /// Returns `None` once every sender is gone and the queue is drained.
+    pub async fn recv(&mut self) -> Option<ProtocolMessage> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Some(message),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => self.shared.not_empty.notified().await,
+            }
+        }
+    }
+
+    /// Receive the highest priority message without waiting
+    pub fn try_recv(&mut self) -> Result<ProtocolMessage, TryRecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        for priority in Priority::ALL {
+            if let Some(message) = state.queues[priority.index()].pop_front() {
+                drop(state);
+                self.shared.not_full[priority.index()].notify_one();
+                return Ok(message);
+            }
+        }
+
+        if state.senders == 0 || state.closed {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Stop accepting messages; queued ones can still be received
+    pub fn close(&mut self) {
+        self.shared.close();
+    }
+
+    /// Get the current queue depth
+    pub fn stats(&self) -> QueueStats {
+        self.shared.stats()
+    }
+}
+
+/// Read-only view of a queue's depth
+#[derive(Clone)]
+pub struct QueueMonitor {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for QueueMonitor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueMonitor").field("stats", &self.shared.stats()).finish()
+    }
+}
+
+impl QueueMonitor {
+    /// Get the current queue depth
+    pub fn stats(&self) -> QueueStats {
+        self.shared.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{HeartbeatPayload, InputEventPayload, MessagePayload, VideoFramePayload};
+    use soft_kvm_core::KeyboardEvent;
+    use std::time::Duration;
+
+    fn control(sequence_number: u64) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::Heartbeat,
+            MessagePayload::Heartbeat(HeartbeatPayload { sequence_number }),
+        )
+    }
+
+    fn input() -> ProtocolMessage {
+        let press = KeyboardEvent::KeyPress { key_code: 0x41, modifiers: 0 };
+        ProtocolMessage::new(MessageType::InputEvent, MessagePayload::InputEvent(InputEventPayload::keyboard(&press)))
+    }
+
+    fn frame(frame_number: u64) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::VideoFrame,
+            MessagePayload::VideoFrame(VideoFramePayload {
+                frame_number,
+                timestamp: 0,
+                width: 640,
+                height: 480,
+                format: "h264".to_string(),
+                data: vec![0; 16],
+            }),
+        )
+    }
+
+    fn frame_number(message: &ProtocolMessage) -> u64 {
+        match &message.payload {
+            MessagePayload::VideoFrame(frame) => frame.frame_number,
+            other => panic!("expected a video frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_control_and_input_overtake_video() {
+        let (sender, mut receiver) = channel(QueueConfig::default());
+        sender.send(frame(1)).await.unwrap();
+        sender.send(frame(2)).await.unwrap();
+        sender.send(input()).await.unwrap();
+        sender.send(control(1)).await.unwrap();
+
+        let order: Vec<_> = (0..4).map(|_| Priority::of(receiver.try_recv().unwrap().message_type())).collect();
+        assert_eq!(order, vec![Priority::Control, Priority::Input, Priority::Video, Priority::Video]);
+        assert_eq!(receiver.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[tokio::test]
+    async fn test_video_drops_oldest_frame() {
+        let (sender, mut receiver) = channel(QueueConfig { video_capacity: 3, ..QueueConfig::default() });
+        for number in 0..10 {
+            sender.send(frame(number)).await.unwrap();
+        }
+
+        let stats = sender.stats();
+        assert_eq!(stats.video, 3);
+        assert_eq!(stats.dropped_video, 7);
+
+        let kept: Vec<_> = (0..3).map(|_| frame_number(&receiver.try_recv().unwrap())).collect();
+        assert_eq!(kept, vec![7, 8, 9]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_control_queue_applies_backpressure() {
+        let (sender, mut receiver) = channel(QueueConfig { control_capacity: 2, ..QueueConfig::default() });
+        sender.send(control(1)).await.unwrap();
+        sender.send(control(2)).await.unwrap();
+        assert!(sender.try_send(control(3)).is_err());
+
+        let blocked = tokio::time::timeout(Duration::from_secs(1), sender.send(control(3))).await;
+        assert!(blocked.is_err());
+
+        let waiting = {
+            let sender = sender.clone();
+            tokio::spawn(async move { sender.send(control(3)).await })
+        };
+        tokio::task::yield_now().await;
+        receiver.recv().await.unwrap();
+        waiting.await.unwrap().unwrap();
+        assert_eq!(sender.stats().control, 2);
+    }
+
+    #[test]
+    fn test_lossy_send_never_waits() {
+        let (sender, mut receiver) = channel(QueueConfig {
+            control_capacity: 1,
+            input_capacity: 2,
+            video_capacity: 1,
+        });
+        for _ in 0..5 {
+            sender.send_lossy(input()).unwrap();
+            sender.send_lossy(frame(1)).unwrap();
+        }
+        sender.send_lossy(control(1)).unwrap();
+        assert_eq!(sender.send_lossy(control(2)), Err(TrySendError::Full));
+
+        let stats = sender.stats();
+        assert_eq!((stats.control, stats.input, stats.video), (1, 2, 1));
+        assert_eq!((stats.dropped_input, stats.dropped_video), (3, 4));
+
+        receiver.close();
+        assert_eq!(sender.send_lossy(input()), Err(TrySendError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_close_in_either_direction() {
+        let (sender, mut receiver) = channel(QueueConfig::default());
+        let monitor = sender.monitor();
+        sender.send(control(1)).await.unwrap();
+        drop(sender);
+
+        // Queued messages drain before the end of the stream
+        assert!(receiver.recv().await.is_some());
+        assert!(receiver.recv().await.is_none());
+        assert!(monitor.stats().is_empty());
+
+        let (sender, receiver) = channel(QueueConfig::default());
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert!(sender.send(control(1)).await.is_err());
+    }
+}
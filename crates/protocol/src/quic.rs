@@ -0,0 +1,718 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! QUIC transport implementation
+//!
+//! Each class of traffic gets its own stream so that none can stall
+//! another: control messages share one bidirectional stream opened by the
+//! client, input events travel on a dedicated high-priority unidirectional
+//! stream, and every video frame is sent on a fresh unidirectional stream so
+//! a lost packet delays only the frame it belongs to. Streams carry
+//! length-prefixed codec frames; QUIC already splits them into packets, so
+//! messages are not fragmented, but `TransportConfig::max_message_size` is
+//! enforced before a frame is read.
+//!
+//! QUIC always runs over TLS 1.3, so `TlsConfig::enabled` must be set. A
+//! factory keeps one client endpoint and TLS session cache, which lets a
+//! reconnecting client send its Hello as 0-RTT data. 0-RTT data can be
+//! replayed by an attacker, so only a Hello without a resume token, of at
+//! most `MAX_EARLY_DATA` bytes, goes out early; everything else waits for
+//! the handshake to complete. The server never reads a stream before its
+//! handshake completes, which a replayed flight cannot do.
+
+use crate::codec::{self, CodecKind, MessageCodec};
+use crate::fragment;
+use crate::messages::{MessagePayload, MessageType, ProtocolMessage};
+use crate::transport::{TransportConfig, TransportConnection, TransportFactory, TransportListener};
+use crate::websocket::{create_client_config, create_server_config};
+use crate::{ProtocolError, ProtocolResult};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// ALPN protocol identifier
+const ALPN: &[u8] = b"soft-kvm/1";
+
+/// First byte of a unidirectional stream, naming what it carries
+const STREAM_INPUT: u8 = 0;
+const STREAM_VIDEO: u8 = 1;
+
+/// Send priorities; higher is sent first
+const PRIORITY_INPUT: i32 = 1;
+const PRIORITY_VIDEO: i32 = -1;
+
+/// Concurrent video frames in flight per connection
+const MAX_UNI_STREAMS: u32 = 256;
+
+/// Received messages buffered per connection
+const INCOMING_CAPACITY: usize = 256;
+
+/// Time allowed for queued data to be acknowledged on close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most bytes a client sends as 0-RTT data
+const MAX_EARLY_DATA: usize = 16 * 1024;
+
+fn quic_transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_concurrent_uni_streams(MAX_UNI_STREAMS.into());
+    transport.keep_alive_interval(Some(Duration::from_secs(10)));
+    Arc::new(transport)
+}
+
+fn require_tls(config: &TransportConfig) -> ProtocolResult<()> {
+    if config.tls.enabled {
+        Ok(())
+    } else {
+        Err(ProtocolError::Transport("QUIC requires TLS to be enabled".to_string()))
+    }
+}
+
+fn transport_error(context: &str, error: impl std::fmt::Display) -> ProtocolError {
+    ProtocolError::Transport(format!("{}: {}", context, error))
+}
+
+/// QUIC connection
+pub struct QuicConnection {
+    connection: quinn::Connection,
+    control: quinn::SendStream,
+    input: Option<quinn::SendStream>,
+    incoming: mpsc::Receiver<ProtocolResult<ProtocolMessage>>,
+    readers: Vec<JoinHandle<()>>,
+    config: TransportConfig,
+    codec: Arc<dyn MessageCodec>,
+    zero_rtt: bool,
+    /// Resolves once a 0-RTT handshake completes
+    handshake: Option<quinn::ZeroRttAccepted>,
+    /// Bytes sent as 0-RTT data so far
+    early_data: usize,
+    is_alive: bool,
+}
+
+impl QuicConnection {
+    fn new(
+        connection: quinn::Connection,
+        control: quinn::SendStream,
+        control_recv: quinn::RecvStream,
+        config: TransportConfig,
+        handshake: Option<quinn::ZeroRttAccepted>,
+    ) -> Self {
+        let (sender, incoming) = mpsc::channel(INCOMING_CAPACITY);
+        let readers = vec![
+            tokio::spawn(read_frames(control_recv, sender.clone(), config.max_message_size)),
+            tokio::spawn(accept_streams(connection.clone(), sender, config.max_message_size)),
+        ];
+
+        QuicConnection {
+            connection,
+            control,
+            input: None,
+            incoming,
+            readers,
+            config,
+            codec: CodecKind::Json.codec(),
+            zero_rtt: handshake.is_some(),
+            handshake,
+            early_data: 0,
+            is_alive: true,
+        }
+    }
+
+    /// Open a QUIC connection to a server
+    pub async fn connect(addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Self> {
+        QuicFactory::new().connect(addr, config).await
+    }
+
+    /// Check if the connection was set up with 0-RTT data
+    pub fn is_zero_rtt(&self) -> bool {
+        self.zero_rtt
+    }
+
+    /// Whether `message` of `size` bytes may still go out as 0-RTT data;
+    /// otherwise wait for the handshake to complete
+    async fn wait_unless_early(&mut self, message: &ProtocolMessage, size: usize) {
+        let Some(handshake) = self.handshake.as_mut() else {
+            return;
+        };
+        // A Hello with a resume token could be replayed to take over the session
+        let replay_safe = matches!(&message.payload, MessagePayload::Hello(hello) if hello.resume_token.is_none());
+        if replay_safe && self.early_data + size <= MAX_EARLY_DATA {
+            self.early_data += size;
+            return;
+        }
+
+        if !handshake.await {
+            debug!("0-RTT data rejected by {}", self.connection.remote_address());
+        }
+        self.handshake = None;
+    }
+
+    async fn input_stream(&mut self) -> ProtocolResult<&mut quinn::SendStream> {
+        if self.input.is_none() {
+            let mut stream = self.connection.open_uni().await
+                .map_err(|e| transport_error("Failed to open input stream", e))?;
+            let _ = stream.set_priority(PRIORITY_INPUT);
+            stream.write_all(&[STREAM_INPUT]).await
+                .map_err(|e| transport_error("Failed to open input stream", e))?;
+            self.input = Some(stream);
+        }
+        Ok(self.input.as_mut().unwrap())
+    }
+}
+
+impl Drop for QuicConnection {
+    fn drop(&mut self) {
+        for reader in &self.readers {
+            reader.abort();
+        }
+    }
+}
+
+fn length_prefixed(frame: &[u8]) -> ProtocolResult<Vec<u8>> {
+    let length = u32::try_from(frame.len())
+        .map_err(|_| ProtocolError::Codec(format!("Frame too large: {} bytes", frame.len())))?;
+
+    let mut buf = Vec::with_capacity(4 + frame.len());
+    buf.extend_from_slice(&length.to_be_bytes());
+    buf.extend_from_slice(frame);
+    Ok(buf)
+}
+
+/// Read one length-prefixed frame of at most `limit` bytes, or `None` at
+/// the end of the stream
+async fn read_frame(stream: &mut quinn::RecvStream, limit: usize) -> ProtocolResult<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(()) => {}
+        Err(quinn::ReadExactError::FinishedEarly) => return Ok(None),
+        Err(quinn::ReadExactError::ReadError(e)) => return closed_or_error(e),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    fragment::check_size(length, limit)?;
+
+    let mut frame = vec![0u8; length];
+    match stream.read_exact(&mut frame).await {
+        Ok(()) => Ok(Some(frame)),
+        Err(quinn::ReadExactError::FinishedEarly) => {
+            Err(ProtocolError::Transport("Stream ended in the middle of a frame".to_string()))
+        }
+        Err(quinn::ReadExactError::ReadError(e)) => closed_or_error(e),
+    }
+}
+
+/// A closed connection ends the stream; anything else is an error
+fn closed_or_error<T>(error: quinn::ReadError) -> ProtocolResult<Option<T>> {
+    match error {
+        quinn::ReadError::ConnectionLost(
+            quinn::ConnectionError::ApplicationClosed(_) | quinn::ConnectionError::LocallyClosed,
+        ) => Ok(None),
+        e => Err(transport_error("QUIC read failed", e)),
+    }
+}
+
+/// Forward every frame of a stream until it ends
+async fn read_frames(mut stream: quinn::RecvStream, sender: mpsc::Sender<ProtocolResult<ProtocolMessage>>, limit: usize) {
+    loop {
+        let result = match read_frame(&mut stream, limit).await {
+            // The peer may still be on the bootstrap codec, so detect it per frame
+            Ok(Some(frame)) => codec::decode_any_with_limit(&frame, limit),
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let failed = result.is_err();
+        if sender.send(result).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Accept the peer's input and video streams
+async fn accept_streams(connection: quinn::Connection, sender: mpsc::Sender<ProtocolResult<ProtocolMessage>>, limit: usize) {
+    loop {
+        let mut stream = match connection.accept_uni().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Stopped accepting streams from {}: {}", connection.remote_address(), e);
+                return;
+            }
+        };
+
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let mut kind = [0u8; 1];
+            if stream.read_exact(&mut kind).await.is_err() {
+                return;
+            }
+            match kind[0] {
+                STREAM_INPUT => read_frames(stream, sender, limit).await,
+                STREAM_VIDEO => {
+                    // One frame per stream; a late frame never blocks a newer one
+                    let result = match stream.read_to_end(limit).await {
+                        Ok(frame) => codec::decode_any_with_limit(&frame, limit),
+                        Err(e) => Err(transport_error("Failed to read video frame", e)),
+                    };
+                    let _ = sender.send(result).await;
+                }
+                other => warn!("Ignoring QUIC stream of unknown kind {}", other),
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl TransportConnection for QuicConnection {
+    async fn send(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
+        if !self.is_alive {
+            return Err(ProtocolError::Transport("Connection closed".to_string()));
+        }
+        let frame = self.codec.encode(&message)?;
+        fragment::check_size(frame.len(), self.config.max_message_size)?;
+        self.wait_unless_early(&message, frame.len()).await;
+
+        match message.message_type() {
+            MessageType::VideoFrame => {
+                let mut stream = self.connection.open_uni().await
+                    .map_err(|e| transport_error("Failed to open video stream", e))?;
+                let _ = stream.set_priority(PRIORITY_VIDEO);
+                stream.write_all(&[STREAM_VIDEO]).await
+                    .map_err(|e| transport_error("Failed to send video frame", e))?;
+                stream.write_all(&frame).await
+                    .map_err(|e| transport_error("Failed to send video frame", e))?;
+                // Dropping the stream finishes it
+            }
+            MessageType::InputEvent => {
+                let frame = length_prefixed(&frame)?;
+                self.input_stream().await?.write_all(&frame).await
+                    .map_err(|e| transport_error("Failed to send input event", e))?;
+            }
+            _ => {
+                let frame = length_prefixed(&frame)?;
+                self.control.write_all(&frame).await
+                    .map_err(|e| transport_error("Failed to send message", e))?;
+            }
+        }
+
+        debug!("Sent message to {}", self.connection.remote_address());
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> ProtocolResult<Option<ProtocolMessage>> {
+        if !self.is_alive {
+            return Ok(None);
+        }
+
+        let timeout_duration = Duration::from_secs(self.config.read_timeout);
+        match tokio::time::timeout(timeout_duration, self.incoming.recv()).await {
+            Ok(Some(Ok(message))) => Ok(Some(message)),
+            Ok(Some(Err(e))) => {
+                self.is_alive = false;
+                Err(e)
+            }
+            Ok(None) => {
+                info!("QUIC connection to {} ended", self.connection.remote_address());
+                self.is_alive = false;
+                Ok(None)
+            }
+            Err(_) => Err(ProtocolError::Timeout),
+        }
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        if self.is_alive {
+            self.is_alive = false;
+
+            // Let queued control messages (Goodbye) reach the peer first
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, self.control.finish()).await;
+            self.connection.close(0u32.into(), b"closed");
+            info!("Closed QUIC connection to {}", self.connection.remote_address());
+        }
+        Ok(())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.connection.remote_address())
+    }
+
+    fn is_alive(&self) -> bool {
+        self.is_alive && self.connection.close_reason().is_none()
+    }
+
+    fn set_codec(&mut self, codec: Arc<dyn MessageCodec>) {
+        debug!("Switching codec for {} to {:?}", self.connection.remote_address(), codec.kind());
+        self.codec = codec;
+    }
+}
+
+/// QUIC listener
+///
+/// Handshakes run on a task per connection, like `WebSocketListener`, and
+/// finished connections queue up for `accept`.
+pub struct QuicListener {
+    endpoint: quinn::Endpoint,
+    connections: mpsc::Receiver<QuicConnection>,
+    acceptor: JoinHandle<()>,
+}
+
+impl QuicListener {
+    /// Create a new QUIC listener
+    pub async fn new(config: TransportConfig, addr: SocketAddr) -> ProtocolResult<Self> {
+        require_tls(&config)?;
+
+        let mut crypto = create_server_config(&config.tls).await?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        // QUIC only allows 0 or u32::MAX here (RFC 9001 4.6.1); early data is
+        // bounded by flow control, and clients send at most MAX_EARLY_DATA
+        crypto.max_early_data_size = u32::MAX;
+
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        server_config.transport_config(quic_transport_config());
+
+        let endpoint = quinn::Endpoint::server(server_config, addr)
+            .map_err(|e| transport_error("Failed to bind listener", e))?;
+        info!("QUIC listener bound to {}", addr);
+
+        let (sender, connections) = mpsc::channel(config.max_connections.max(1));
+        let acceptor = tokio::spawn(Self::accept_loop(endpoint.clone(), config, sender));
+
+        Ok(QuicListener { endpoint, connections, acceptor })
+    }
+
+    /// Hand each incoming connection to its own handshake task, at most
+    /// `max_connections` at once
+    async fn accept_loop(endpoint: quinn::Endpoint, config: TransportConfig, sender: mpsc::Sender<QuicConnection>) {
+        let handshakes = Arc::new(Semaphore::new(config.max_connections.max(1)));
+        while let Some(connecting) = endpoint.accept().await {
+            let addr = connecting.remote_address();
+            let Ok(permit) = handshakes.clone().try_acquire_owned() else {
+                warn!("Too many pending handshakes, refusing connection from {}", addr);
+                continue;
+            };
+
+            let config = config.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                // A misbehaving peer must not stop the listener
+                let timeout_duration = Duration::from_secs(config.read_timeout);
+                match tokio::time::timeout(timeout_duration, Self::handshake(connecting, config)).await {
+                    Ok(Ok(connection)) => {
+                        info!("Accepted QUIC connection from {}", addr);
+                        // Fails only once the listener is gone
+                        let _ = sender.send(connection).await;
+                    }
+                    Ok(Err(e)) => warn!("Rejected connection from {}: {}", addr, e),
+                    Err(_) => warn!("Handshake with {} timed out", addr),
+                }
+            });
+        }
+    }
+
+    async fn handshake(connecting: quinn::Connecting, config: TransportConfig) -> ProtocolResult<QuicConnection> {
+        // Waiting for the full handshake means replayed 0-RTT data is never read
+        let connection = connecting.await
+            .map_err(|e| transport_error("QUIC handshake failed", e))?;
+        // The control stream appears once the client sends its Hello
+        let (control, control_recv) = connection.accept_bi().await
+            .map_err(|e| transport_error("No control stream", e))?;
+        Ok(QuicConnection::new(connection, control, control_recv, config, None))
+    }
+}
+
+impl Drop for QuicListener {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+#[async_trait]
+impl TransportListener for QuicListener {
+    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let connection = self.connections.recv().await
+            .ok_or_else(|| ProtocolError::Transport("QUIC endpoint closed".to_string()))?;
+        Ok(Box::new(connection))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.endpoint.local_addr().ok()
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.endpoint.close(0u32.into(), b"server closed");
+        self.acceptor.abort();
+        self.connections.close();
+        info!("QUIC listener closed");
+        Ok(())
+    }
+}
+
+/// QUIC factory
+///
+/// Client connections share one endpoint so TLS sessions can be resumed.
+#[derive(Default)]
+pub struct QuicFactory {
+    client: Mutex<Option<quinn::Endpoint>>,
+}
+
+impl QuicFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn client_endpoint(&self, addr: SocketAddr, config: &TransportConfig) -> ProtocolResult<quinn::Endpoint> {
+        let mut client = self.client.lock().await;
+        if let Some(endpoint) = client.as_ref() {
+            return Ok(endpoint.clone());
+        }
+
+        let mut crypto = create_client_config(&config.tls).await?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        crypto.enable_early_data = true;
+
+        let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+        client_config.transport_config(quic_transport_config());
+
+        let bind_addr: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let mut endpoint = quinn::Endpoint::client(bind_addr)
+            .map_err(|e| transport_error("Failed to bind client endpoint", e))?;
+        endpoint.set_default_client_config(client_config);
+
+        *client = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn connect(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<QuicConnection> {
+        require_tls(&config)?;
+        let endpoint = self.client_endpoint(addr, &config).await?;
+
+        let connecting = endpoint.connect(addr, &addr.ip().to_string())
+            .map_err(|e| transport_error("Failed to connect", e))?;
+        let (connection, handshake) = match connecting.into_0rtt() {
+            Ok((connection, accepted)) => (connection, Some(accepted)),
+            Err(connecting) => {
+                let connection = connecting.await
+                    .map_err(|e| transport_error("QUIC handshake failed", e))?;
+                (connection, None)
+            }
+        };
+
+        let (control, control_recv) = connection.open_bi().await
+            .map_err(|e| transport_error("Failed to open control stream", e))?;
+        info!("Connected to {} over QUIC (0-RTT: {})", addr, handshake.is_some());
+
+        Ok(QuicConnection::new(connection, control, control_recv, config, handshake))
+    }
+}
+
+#[async_trait]
+impl TransportFactory for QuicFactory {
+    async fn create_listener(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportListener>> {
+        let listener = QuicListener::new(config, addr).await?;
+        Ok(Box::new(listener))
+    }
+
+    async fn create_connection(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let connection = self.connect(addr, config).await?;
+        Ok(Box::new(connection))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{
+        ClientInfo, HeartbeatPayload, HelloPayload, InputEventPayload, VideoFramePayload,
+    };
+    use crate::websocket::tests::self_signed_tls;
+    use soft_kvm_core::{CapabilitySet, KeyboardEvent};
+
+    fn tls_config() -> TransportConfig {
+        TransportConfig {
+            tls: self_signed_tls(),
+            ..TransportConfig::default()
+        }
+    }
+
+    fn video_frame(frame_number: u64, size: usize) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::VideoFrame,
+            MessagePayload::VideoFrame(VideoFramePayload {
+                frame_number,
+                timestamp: 0,
+                width: 1920,
+                height: 1080,
+                format: "h264".to_string(),
+                data: vec![0xAB; size],
+            }),
+        )
+    }
+
+    fn heartbeat(sequence_number: u64) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::Heartbeat,
+            MessagePayload::Heartbeat(HeartbeatPayload { sequence_number }),
+        )
+    }
+
+    fn hello(resume_token: Option<String>) -> ProtocolMessage {
+        ProtocolMessage::new(
+            MessageType::Hello,
+            MessagePayload::Hello(HelloPayload {
+                protocol_version: crate::PROTOCOL_VERSION.to_string(),
+                min_protocol_version: None,
+                client_info: ClientInfo {
+                    client_id: "quic-client".to_string(),
+                    client_name: "QUIC Client".to_string(),
+                    platform: "test".to_string(),
+                    version: "0.1.0".to_string(),
+                },
+                capabilities: CapabilitySet::default(),
+                codecs: vec![CodecKind::Json],
+                resume_token,
+                compression: Vec::new(),
+            }),
+        )
+    }
+
+    async fn listen(config: TransportConfig) -> (QuicListener, SocketAddr) {
+        let listener = QuicListener::new(config, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn test_quic_requires_tls() {
+        let result = QuicListener::new(TransportConfig::default(), "127.0.0.1:0".parse().unwrap()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quic_loopback_carries_every_message_class() {
+        let config = tls_config();
+        let (mut listener, addr) = listen(config.clone()).await;
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let factory = QuicFactory::new();
+        let mut client = factory.create_connection(addr, config).await.unwrap();
+        client.send(heartbeat(1)).await.unwrap();
+        let mut server = accept.await.unwrap();
+
+        // Control, input and video each take their own stream
+        client.set_codec(CodecKind::Binary.codec());
+        client.send(video_frame(1, 256 * 1024)).await.unwrap();
+        let press = KeyboardEvent::KeyPress { key_code: 0x41, modifiers: 0 };
+        client.send(ProtocolMessage::new(
+            MessageType::InputEvent,
+            MessagePayload::InputEvent(InputEventPayload::keyboard(&press)),
+        )).await.unwrap();
+        client.send(heartbeat(2)).await.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let message = tokio::time::timeout(Duration::from_secs(5), server.receive())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push(message);
+        }
+
+        // Control messages stay in order relative to each other
+        let heartbeats: Vec<u64> = received.iter().filter_map(|message| match &message.payload {
+            MessagePayload::Heartbeat(heartbeat) => Some(heartbeat.sequence_number),
+            _ => None,
+        }).collect();
+        assert_eq!(heartbeats, vec![1, 2]);
+        assert!(received.iter().any(|message| matches!(
+            &message.payload,
+            MessagePayload::VideoFrame(frame) if frame.data.len() == 256 * 1024
+        )));
+        assert!(received.iter().any(|message| message.message_type() == &MessageType::InputEvent));
+
+        // And back from the server
+        server.send(video_frame(7, 1024)).await.unwrap();
+        let message = client.receive().await.unwrap().unwrap();
+        assert!(matches!(message.payload, MessagePayload::VideoFrame(frame) if frame.frame_number == 7));
+
+        client.close().await.unwrap();
+        let end = tokio::time::timeout(Duration::from_secs(5), server.receive()).await.unwrap();
+        assert!(matches!(end, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_does_not_stall_accept() {
+        let config = tls_config();
+        let (mut listener, addr) = listen(config.clone()).await;
+
+        // Completes the QUIC handshake but never opens the control stream
+        let factory = QuicFactory::new();
+        let _silent = factory.connect(addr, config.clone()).await.unwrap();
+
+        let mut client = QuicFactory::new().connect(addr, config).await.unwrap();
+        client.send(heartbeat(1)).await.unwrap();
+        let mut server = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await
+            .expect("silent client stalled accept")
+            .unwrap();
+        let message = server.receive().await.unwrap().unwrap();
+        assert!(matches!(message.payload, MessagePayload::Heartbeat(heartbeat) if heartbeat.sequence_number == 1));
+    }
+
+    #[tokio::test]
+    async fn test_quic_reconnect_uses_zero_rtt() {
+        let config = tls_config();
+        let (mut listener, addr) = listen(config.clone()).await;
+        tokio::spawn(async move {
+            while let Ok(mut connection) = listener.accept().await {
+                tokio::spawn(async move {
+                    while let Ok(Some(message)) = connection.receive().await {
+                        let _ = connection.send(message).await;
+                    }
+                });
+            }
+        });
+
+        let factory = QuicFactory::new();
+        let mut first = factory.connect(addr, config.clone()).await.unwrap();
+        assert!(!first.is_zero_rtt());
+        first.send(heartbeat(1)).await.unwrap();
+        first.receive().await.unwrap().unwrap();
+        first.close().await.unwrap();
+
+        // The session ticket from the first connection allows 0-RTT
+        let mut second = factory.connect(addr, config.clone()).await.unwrap();
+        assert!(second.is_zero_rtt());
+        second.send(hello(None)).await.unwrap();
+        assert!(second.early_data > 0);
+        second.send(heartbeat(2)).await.unwrap();
+        let echoed = second.receive().await.unwrap().unwrap();
+        assert!(matches!(echoed.payload, MessagePayload::Hello(_)));
+        let echoed = second.receive().await.unwrap().unwrap();
+        assert!(matches!(echoed.payload, MessagePayload::Heartbeat(heartbeat) if heartbeat.sequence_number == 2));
+        second.close().await.unwrap();
+
+        // A resume token must not be replayable, so it waits for the handshake
+        let mut third = factory.connect(addr, config).await.unwrap();
+        assert!(third.is_zero_rtt());
+        third.send(hello(Some("token".to_string()))).await.unwrap();
+        assert_eq!(third.early_data, 0);
+        assert!(third.handshake.is_none());
+        let echoed = third.receive().await.unwrap().unwrap();
+        assert!(matches!(echoed.payload, MessagePayload::Hello(hello) if hello.resume_token.is_some()));
+        third.close().await.unwrap();
+    }
+}
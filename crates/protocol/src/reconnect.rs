@@ -0,0 +1,219 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client reconnection
+//!
+//! When the transport of a client session is lost, the client reconnects
+//! with exponential backoff and presents the resume token it received in
+//! AuthResponse. The server then re-attaches the suspended session, so the
+//! negotiated capabilities, queued messages and video stream state survive.
+
+use crate::driver::{Outcome, SessionDriver};
+use crate::handshake::client_handshake;
+use crate::session::{ProtocolSession, SessionState};
+use crate::transport::TransportConnection;
+use crate::{ProtocolConfig, ProtocolError, ProtocolResult};
+use rand::Rng;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Client reconnection policy
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub enabled: bool,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub max_attempts: u32, // 0 retries forever
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            enabled: true,
+            initial_backoff_ms: 250,
+            max_backoff_ms: 30_000,
+            max_attempts: 10,
+        }
+    }
+}
+
+/// Exponential backoff with jitter.
+///
+/// Attempt `n` waits a random duration between half and all of
+/// `initial * 2^n`, capped at the maximum.
+#[derive(Debug)]
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Create a backoff for a policy
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Backoff { policy, attempt: 0 }
+    }
+
+    /// Number of delays handed out so far
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.policy.max_attempts > 0 && self.attempt >= self.policy.max_attempts {
+            return None;
+        }
+
+        let ceiling = self.policy.initial_backoff_ms
+            .saturating_mul(1u64 << self.attempt.min(32))
+            .min(self.policy.max_backoff_ms);
+        self.attempt += 1;
+
+        let delay = rand::thread_rng().gen_range(ceiling / 2..=ceiling);
+        Some(Duration::from_millis(delay))
+    }
+}
+
+/// Drive a client session, reconnecting whenever its transport is lost
+pub(crate) async fn supervise<C, F>(
+    mut driver: SessionDriver,
+    mut session: ProtocolSession,
+    config: ProtocolConfig,
+    shutdown: Arc<Notify>,
+    mut connect: C,
+) -> ProtocolResult<()>
+where
+    C: FnMut() -> F + Send,
+    F: Future<Output = ProtocolResult<Box<dyn TransportConnection>>> + Send,
+{
+    loop {
+        let error = match driver.drive().await {
+            Outcome::Lost(e) => e,
+            Outcome::Closed | Outcome::Superseded => return driver.finish(Ok(())).await,
+        };
+
+        if !config.reconnect.enabled || !session.is_resumable().await || is_closed(&session).await {
+            return driver.finish(Err(error)).await;
+        }
+
+        warn!("Lost connection for session {}: {}", session.session_id(), error);
+
+        match reconnect(&mut session, &config, &shutdown, &mut connect).await {
+            Ok(connection) => driver.reattach(session.clone(), connection),
+            // Closed by the user while reconnecting
+            Err(_) if is_closed(&session).await => return driver.finish(Ok(())).await,
+            Err(e) => return driver.finish(Err(e)).await,
+        }
+    }
+}
+
+/// Reconnect and resume the session, backing off between attempts
+async fn reconnect<C, F>(
+    session: &mut ProtocolSession,
+    config: &ProtocolConfig,
+    shutdown: &Notify,
+    connect: &mut C,
+) -> ProtocolResult<Box<dyn TransportConnection>>
+where
+    C: FnMut() -> F,
+    F: Future<Output = ProtocolResult<Box<dyn TransportConnection>>>,
+{
+    let mut backoff = Backoff::new(config.reconnect.clone());
+
+    while let Some(delay) = backoff.next() {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.notified() => {
+                return Err(ProtocolError::Session("Reconnect cancelled".to_string()));
+            }
+        }
+        if is_closed(session).await {
+            return Err(ProtocolError::Session("Session closed while reconnecting".to_string()));
+        }
+
+        info!("Reconnecting session {} (attempt {})", session.session_id(), backoff.attempts());
+        let mut connection = match connect().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Reconnect attempt {} failed: {}", backoff.attempts(), e);
+                continue;
+            }
+        };
+
+        match client_handshake(connection.as_mut(), session, config).await {
+            Ok(welcome) if welcome.resumed => {
+                info!("Session {} resumed", session.session_id());
+                return Ok(connection);
+            }
+            Ok(_) => {
+                let _ = connection.close().await;
+                return Err(ProtocolError::Session("Server did not resume the session".to_string()));
+            }
+            Err(e) if e.is_retryable() => {
+                warn!("Reconnect attempt {} failed: {}", backoff.attempts(), e);
+                let _ = connection.close().await;
+            }
+            Err(e) => {
+                let _ = connection.close().await;
+                return Err(e);
+            }
+        }
+    }
+
+    Err(ProtocolError::Session(format!("Gave up reconnecting after {} attempts", backoff.attempts())))
+}
+
+async fn is_closed(session: &ProtocolSession) -> bool {
+    matches!(session.state().await, SessionState::Closing | SessionState::Closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_with_jitter_and_cap() {
+        let policy = ReconnectPolicy {
+            enabled: true,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+            max_attempts: 6,
+        };
+
+        let delays: Vec<u64> = Backoff::new(policy).map(|delay| delay.as_millis() as u64).collect();
+
+        assert_eq!(delays.len(), 6);
+        for (attempt, delay) in delays.iter().enumerate() {
+            let ceiling = (100u64 << attempt).min(1_000);
+            assert!(*delay >= ceiling / 2 && *delay <= ceiling, "attempt {}: {}ms", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn test_backoff_unlimited_attempts() {
+        let policy = ReconnectPolicy {
+            max_attempts: 0,
+            ..ReconnectPolicy::default()
+        };
+
+        let delay = Backoff::new(policy).nth(100).unwrap();
+        assert!(delay <= Duration::from_millis(30_000));
+    }
+}
@@ -0,0 +1,810 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session traffic recording and replay
+//!
+//! When `ProtocolConfig::record_dir` is set, [`RecordingFactory`] wraps the
+//! transport and writes every message a connection sends or receives to a
+//! file of its own, with the direction and a monotonic offset from when the
+//! connection opened. [`ReplayFactory`] feeds the received side of a
+//! recording back into a `ProtocolClient` or `ProtocolServer` with the
+//! original timing; what the replayed side sends is dropped. The
+//! `soft-kvm-recording` tool dumps and filters recordings as JSON.
+//!
+//! Input sent over the datagram channel (`crate::input_channel`) bypasses the
+//! transport and is not recorded.
+//!
+//! File layout (all integers big endian):
+//!
+//! ```text
+//! offset size  field
+//! 0      4     magic "SKRC"
+//! 4      1     format version
+//! 5      8     start time (microseconds since UNIX epoch)
+//! 13     1     peer address length, followed by the address
+//! ```
+//!
+//! followed by one record per message:
+//!
+//! ```text
+//! 0      1     direction (0: sent, 1: received)
+//! 1      8     offset (microseconds since the start)
+//! 9      4     frame length, followed by the frame
+//! ```
+//!
+//! Frames use the binary codec with zstd, whatever the connection negotiated.
+//! Encoding and writing happen on a thread of their own fed by a bounded queue,
+//! and records are flushed whenever the queue drains; a recording cut short
+//! by a crash reads up to the last complete record. A connection whose
+//! recorder falls `RECORD_QUEUE_CAPACITY` messages behind stops recording
+//! rather than slowing down.
+//!
+//! Recordings are created readable by the owner only, and session tokens
+//! and credentials are replaced by `REDACTED` before they are written.
+
+use crate::codec::{self, CompressingCodec, MessageCodec};
+use crate::compression::{CompressionKind, MAX_DECOMPRESSED_SIZE};
+use crate::messages::{MessagePayload, MessageType, ProtocolMessage};
+use crate::transport::{TransportConfig, TransportConnection, TransportFactory, TransportListener};
+use crate::{ProtocolConfig, ProtocolError, ProtocolResult};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{info, trace, warn};
+
+/// Magic bytes at the start of every recording
+pub const RECORDING_MAGIC: [u8; 4] = *b"SKRC";
+
+/// Current recording format version
+pub const RECORDING_FORMAT_VERSION: u8 = 1;
+
+/// File extension of recordings
+pub const RECORDING_EXTENSION: &str = "skrec";
+
+/// Size of a record header
+const RECORD_HEADER_LEN: usize = 13;
+
+/// Frames smaller than this are stored uncompressed
+const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Messages queued for the writer of one connection
+const RECORD_QUEUE_CAPACITY: usize = 1024;
+
+/// Stands in for session tokens and credentials in recordings
+pub const REDACTED: &str = "<redacted>";
+
+/// Direction of a recorded message, seen from the recording side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn wire_id(&self) -> u8 {
+        match self {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        }
+    }
+
+    fn from_wire_id(id: u8) -> ProtocolResult<Self> {
+        match id {
+            0 => Ok(Direction::Sent),
+            1 => Ok(Direction::Received),
+            other => Err(ProtocolError::Codec(format!("Unknown recorded direction {}", other))),
+        }
+    }
+}
+
+impl FromStr for Direction {
+    type Err = ProtocolError;
+
+    fn from_str(direction: &str) -> ProtocolResult<Self> {
+        match direction.to_ascii_lowercase().as_str() {
+            "sent" => Ok(Direction::Sent),
+            "received" => Ok(Direction::Received),
+            _ => Err(ProtocolError::Generic(format!("Unknown direction: {}", direction))),
+        }
+    }
+}
+
+/// A message in a recording
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedMessage {
+    /// Microseconds since the recording started
+    pub offset_us: u64,
+    pub direction: Direction,
+    pub message: ProtocolMessage,
+}
+
+impl RecordedMessage {
+    /// Time since the recording started
+    pub fn offset(&self) -> Duration {
+        Duration::from_micros(self.offset_us)
+    }
+}
+
+/// A recording read back from a file
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub started_at: DateTime<Utc>,
+    pub peer: Option<String>,
+    pub messages: Vec<RecordedMessage>,
+}
+
+impl Recording {
+    /// Read a recording file
+    pub fn open(path: &Path) -> ProtocolResult<Self> {
+        let file = File::open(path).map_err(|e| io_error(path, e))?;
+        Self::read(BufReader::new(file))
+    }
+
+    /// Read a recording
+    pub fn read(mut reader: impl Read) -> ProtocolResult<Self> {
+        let mut header = [0u8; 14];
+        reader.read_exact(&mut header)
+            .map_err(|_| ProtocolError::Codec("Truncated recording header".to_string()))?;
+        if header[..4] != RECORDING_MAGIC {
+            return Err(ProtocolError::Codec("Not a Soft KVM recording".to_string()));
+        }
+        if header[4] != RECORDING_FORMAT_VERSION {
+            return Err(ProtocolError::Codec(format!("Unsupported recording format version {}", header[4])));
+        }
+        let started_us = i64::from_be_bytes(header[5..13].try_into().unwrap());
+        let mut peer = vec![0u8; header[13] as usize];
+        reader.read_exact(&mut peer)
+            .map_err(|_| ProtocolError::Codec("Truncated recording header".to_string()))?;
+
+        let mut messages = Vec::new();
+        while let Some(record) = read_record(&mut reader)? {
+            messages.push(record);
+        }
+
+        Ok(Recording {
+            started_at: Utc.timestamp_micros(started_us).single().unwrap_or_default(),
+            peer: (!peer.is_empty()).then(|| String::from_utf8_lossy(&peer).into_owned()),
+            messages,
+        })
+    }
+}
+
+/// Read the next record, or `None` at the end of the recording
+fn read_record(reader: &mut impl Read) -> ProtocolResult<Option<RecordedMessage>> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    if reader.read(&mut header[..1]).map_err(|e| io_error("recording", e))? == 0 {
+        return Ok(None);
+    }
+    if reader.read_exact(&mut header[1..]).is_err() {
+        warn!("Recording ends with a partial record");
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(header[9..13].try_into().unwrap()) as usize;
+    if len > MAX_DECOMPRESSED_SIZE {
+        return Err(ProtocolError::Codec(format!("Recorded frame of {} bytes is too large", len)));
+    }
+    let mut frame = vec![0u8; len];
+    if reader.read_exact(&mut frame).is_err() {
+        warn!("Recording ends with a partial record");
+        return Ok(None);
+    }
+
+    Ok(Some(RecordedMessage {
+        offset_us: u64::from_be_bytes(header[1..9].try_into().unwrap()),
+        direction: Direction::from_wire_id(header[0])?,
+        message: codec::decode_any(&frame)?,
+    }))
+}
+
+/// Writes the messages of one connection to a recording
+pub struct Recorder {
+    writer: Box<dyn Write + Send + Sync>,
+    codec: CompressingCodec,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Start a recording in a new file in `dir`
+    pub fn create(dir: &Path, peer: Option<SocketAddr>) -> ProtocolResult<(Self, PathBuf)> {
+        std::fs::create_dir_all(dir).map_err(|e| io_error(dir, e))?;
+        let name = format!(
+            "{}-{}.{}",
+            Utc::now().format("%Y%m%d-%H%M%S"),
+            &uuid::Uuid::new_v4().simple().to_string()[..8],
+            RECORDING_EXTENSION
+        );
+        let path = dir.join(name);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path).map_err(|e| io_error(&path, e))?;
+        Ok((Self::new(BufWriter::new(file), peer)?, path))
+    }
+
+    /// Start a recording on a writer
+    pub fn new(mut writer: impl Write + Send + Sync + 'static, peer: Option<SocketAddr>) -> ProtocolResult<Self> {
+        let peer = peer.map(|addr| addr.to_string()).unwrap_or_default();
+        let mut header = Vec::with_capacity(14 + peer.len());
+        header.extend_from_slice(&RECORDING_MAGIC);
+        header.push(RECORDING_FORMAT_VERSION);
+        header.extend_from_slice(&Utc::now().timestamp_micros().to_be_bytes());
+        header.push(peer.len() as u8);
+        header.extend_from_slice(peer.as_bytes());
+        writer.write_all(&header).and_then(|_| writer.flush()).map_err(|e| io_error("recording", e))?;
+
+        Ok(Recorder {
+            writer: Box::new(writer),
+            codec: CompressingCodec::new(CompressionKind::Zstd, COMPRESSION_THRESHOLD),
+            started: Instant::now(),
+        })
+    }
+
+    /// Append a message, redacted
+    pub fn record(&mut self, direction: Direction, message: &ProtocolMessage) -> ProtocolResult<()> {
+        let offset = self.started.elapsed().as_micros() as u64;
+        self.write(direction, offset, message)?;
+        self.flush()
+    }
+
+    /// Append a message recorded `offset_us` after the start, without flushing
+    fn write(&mut self, direction: Direction, offset_us: u64, message: &ProtocolMessage) -> ProtocolResult<()> {
+        let frame = self.codec.encode(&redact(message))?;
+        let len = u32::try_from(frame.len())
+            .map_err(|_| ProtocolError::Codec(format!("Frame too large to record: {} bytes", frame.len())))?;
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0] = direction.wire_id();
+        header[1..9].copy_from_slice(&offset_us.to_be_bytes());
+        header[9..13].copy_from_slice(&len.to_be_bytes());
+        self.writer.write_all(&header)
+            .and_then(|_| self.writer.write_all(&frame))
+            .map_err(|e| io_error("recording", e))
+    }
+
+    fn flush(&mut self) -> ProtocolResult<()> {
+        self.writer.flush().map_err(|e| io_error("recording", e))
+    }
+}
+
+/// `message` without session tokens or credentials
+fn redact(message: &ProtocolMessage) -> Cow<'_, ProtocolMessage> {
+    let sensitive = match &message.payload {
+        MessagePayload::Hello(hello) => hello.resume_token.is_some(),
+        MessagePayload::AuthRequest(auth) => !auth.credentials.is_null(),
+        MessagePayload::AuthResponse(response) => response.session_token.is_some(),
+        _ => false,
+    };
+    if !sensitive {
+        return Cow::Borrowed(message);
+    }
+
+    let mut redacted = message.clone();
+    match &mut redacted.payload {
+        MessagePayload::Hello(hello) => hello.resume_token = Some(REDACTED.to_string()),
+        MessagePayload::AuthRequest(auth) => auth.credentials = serde_json::Value::String(REDACTED.to_string()),
+        MessagePayload::AuthResponse(response) => response.session_token = Some(REDACTED.to_string()),
+        _ => {}
+    }
+    Cow::Owned(redacted)
+}
+
+/// A message waiting for the writer
+struct QueuedRecord {
+    direction: Direction,
+    offset_us: u64,
+    message: ProtocolMessage,
+}
+
+/// Write queued messages until the queue closes or writing fails
+fn write_records(mut recorder: Recorder, mut queue: mpsc::Receiver<QueuedRecord>, peer: Option<SocketAddr>) {
+    while let Some(record) = queue.blocking_recv() {
+        let written = recorder.write(record.direction, record.offset_us, &record.message)
+            .and_then(|_| if queue.is_empty() { recorder.flush() } else { Ok(()) });
+        if let Err(e) = written {
+            warn!("Stopped recording connection to {:?}: {}", peer, e);
+            return;
+        }
+    }
+}
+
+fn io_error(path: impl AsRef<Path>, e: std::io::Error) -> ProtocolError {
+    ProtocolError::Generic(format!("{}: {}", path.as_ref().display(), e))
+}
+
+/// Connection that records its traffic
+pub struct RecordingConnection {
+    inner: Box<dyn TransportConnection>,
+    queue: Option<mpsc::Sender<QueuedRecord>>,
+    /// Closed once the writer has written everything queued
+    written: Option<oneshot::Receiver<()>>,
+    started: Instant,
+}
+
+impl RecordingConnection {
+    /// Record the traffic of a connection
+    pub fn new(inner: Box<dyn TransportConnection>, recorder: Recorder) -> Self {
+        Self::spawn(inner, move || Ok(recorder))
+    }
+
+    /// Record the traffic of a connection in a new file in `dir`
+    fn create(inner: Box<dyn TransportConnection>, dir: PathBuf) -> Self {
+        let peer = inner.remote_addr();
+        Self::spawn(inner, move || {
+            let (recorder, path) = Recorder::create(&dir, peer)?;
+            info!("Recording connection to {:?} in {}", peer, path.display());
+            Ok(recorder)
+        })
+    }
+
+    /// Start the writer thread with the recorder `open` returns
+    fn spawn(inner: Box<dyn TransportConnection>, open: impl FnOnce() -> ProtocolResult<Recorder> + Send + 'static) -> Self {
+        let peer = inner.remote_addr();
+        let (queue, records) = mpsc::channel(RECORD_QUEUE_CAPACITY);
+        let (done, written) = oneshot::channel::<()>();
+        // A thread of its own rather than spawn_blocking, which would hold
+        // a pool thread for as long as the connection lives
+        let spawned = std::thread::Builder::new()
+            .name("soft-kvm-recorder".to_string())
+            .spawn(move || {
+                let _done = done;
+                match open() {
+                    Ok(recorder) => write_records(recorder, records, peer),
+                    Err(e) => warn!("Not recording connection to {:?}: {}", peer, e),
+                }
+            });
+        if let Err(e) = &spawned {
+            warn!("Not recording connection to {:?}: {}", peer, e);
+        }
+
+        RecordingConnection {
+            inner,
+            queue: spawned.is_ok().then_some(queue),
+            written: Some(written),
+            started: Instant::now(),
+        }
+    }
+
+    /// Queue a message for the writer; a recorder that fails or falls
+    /// behind is dropped rather than failing or slowing the connection
+    fn record(&mut self, direction: Direction, message: &ProtocolMessage) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let record = QueuedRecord {
+            direction,
+            offset_us: self.started.elapsed().as_micros() as u64,
+            message: message.clone(),
+        };
+        match queue.try_send(record) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Recorder of connection to {:?} fell behind, stopped recording", self.inner.remote_addr());
+                self.queue = None;
+            }
+            // The writer already logged why it stopped
+            Err(mpsc::error::TrySendError::Closed(_)) => self.queue = None,
+        }
+    }
+}
+
+#[async_trait]
+impl TransportConnection for RecordingConnection {
+    async fn send(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
+        self.record(Direction::Sent, &message);
+        self.inner.send(message).await
+    }
+
+    async fn receive(&mut self) -> ProtocolResult<Option<ProtocolMessage>> {
+        let message = self.inner.receive().await?;
+        if let Some(message) = &message {
+            self.record(Direction::Received, message);
+        }
+        Ok(message)
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        // Let the writer finish what is queued
+        self.queue = None;
+        if let Some(written) = self.written.take() {
+            let _ = written.await;
+        }
+        self.inner.close().await
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn set_codec(&mut self, codec: Arc<dyn MessageCodec>) {
+        self.inner.set_codec(codec);
+    }
+}
+
+/// Record every connection in `dir`
+fn record_connection(dir: &Path, connection: Box<dyn TransportConnection>) -> Box<dyn TransportConnection> {
+    Box::new(RecordingConnection::create(connection, dir.to_path_buf()))
+}
+
+/// Transport factory recording every connection of another factory
+pub struct RecordingFactory {
+    inner: Arc<dyn TransportFactory>,
+    dir: PathBuf,
+}
+
+impl RecordingFactory {
+    /// Record connections made through `inner` in `dir`
+    pub fn new(inner: Arc<dyn TransportFactory>, dir: impl Into<PathBuf>) -> Self {
+        RecordingFactory { inner, dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl TransportFactory for RecordingFactory {
+    async fn create_listener(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportListener>> {
+        let inner = self.inner.create_listener(addr, config).await?;
+        Ok(Box::new(RecordingListener { inner, dir: self.dir.clone() }))
+    }
+
+    async fn create_connection(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let connection = self.inner.create_connection(addr, config).await?;
+        Ok(record_connection(&self.dir, connection))
+    }
+}
+
+struct RecordingListener {
+    inner: Box<dyn TransportListener>,
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl TransportListener for RecordingListener {
+    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let connection = self.inner.accept().await?;
+        Ok(record_connection(&self.dir, connection))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.inner.close().await
+    }
+}
+
+/// Wrap a transport in a recorder if `ProtocolConfig::record_dir` is set
+pub(crate) fn recording_transport(factory: Arc<dyn TransportFactory>, config: &ProtocolConfig) -> Arc<dyn TransportFactory> {
+    match &config.record_dir {
+        Some(dir) => Arc::new(RecordingFactory::new(factory, dir.clone())),
+        None => factory,
+    }
+}
+
+/// Connection playing back the received side of a recording
+pub struct ReplayConnection {
+    messages: VecDeque<RecordedMessage>,
+    peer: Option<SocketAddr>,
+    started: Instant,
+    closed: bool,
+}
+
+impl ReplayConnection {
+    /// Start playing back a recording; offsets count from now
+    pub fn new(recording: Recording) -> Self {
+        ReplayConnection {
+            messages: recording.messages.into_iter().filter(|r| r.direction == Direction::Received).collect(),
+            peer: recording.peer.and_then(|peer| peer.parse().ok()),
+            started: Instant::now(),
+            closed: false,
+        }
+    }
+}
+
+#[async_trait]
+impl TransportConnection for ReplayConnection {
+    async fn send(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
+        if self.closed {
+            return Err(ProtocolError::Transport("Replay closed".to_string()));
+        }
+        trace!("Replay dropped sent {:?}", message.message_type());
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> ProtocolResult<Option<ProtocolMessage>> {
+        // Only dequeue once the message is due, so a cancelled receive loses nothing
+        let Some(next) = self.messages.front().filter(|_| !self.closed) else {
+            self.closed = true;
+            return Ok(None);
+        };
+        tokio::time::sleep_until(self.started + next.offset()).await;
+        Ok(self.messages.pop_front().map(|record| record.message))
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.closed = true;
+        Ok(())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    fn is_alive(&self) -> bool {
+        !self.closed
+    }
+}
+
+/// Transport factory replaying a recording once.
+///
+/// Used as a client transport, the first connection plays the recording.
+/// Used as a server transport, the first accepted connection does and the
+/// listener then waits forever.
+pub struct ReplayFactory {
+    recording: Mutex<Option<Recording>>,
+}
+
+impl ReplayFactory {
+    /// Replay a recording
+    pub fn new(recording: Recording) -> Self {
+        ReplayFactory {
+            recording: Mutex::new(Some(recording)),
+        }
+    }
+
+    fn take(&self) -> ProtocolResult<Recording> {
+        self.recording.lock().unwrap().take()
+            .ok_or_else(|| ProtocolError::Transport("Recording has already been replayed".to_string()))
+    }
+}
+
+#[async_trait]
+impl TransportFactory for ReplayFactory {
+    async fn create_listener(&self, addr: SocketAddr, _config: TransportConfig) -> ProtocolResult<Box<dyn TransportListener>> {
+        Ok(Box::new(ReplayListener {
+            recording: Some(self.take()?),
+            addr,
+        }))
+    }
+
+    async fn create_connection(&self, _addr: SocketAddr, _config: TransportConfig) -> ProtocolResult<Box<dyn TransportConnection>> {
+        Ok(Box::new(ReplayConnection::new(self.take()?)))
+    }
+}
+
+struct ReplayListener {
+    recording: Option<Recording>,
+    addr: SocketAddr,
+}
+
+#[async_trait]
+impl TransportListener for ReplayListener {
+    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
+        match self.recording.take() {
+            Some(recording) => Ok(Box::new(ReplayConnection::new(recording))),
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.recording = None;
+        Ok(())
+    }
+}
+
+/// Selects messages of a recording
+#[derive(Debug, Clone, Default)]
+pub struct RecordingFilter {
+    /// Message types to keep; empty keeps all
+    pub message_types: Vec<MessageType>,
+    pub direction: Option<Direction>,
+    pub session_id: Option<String>,
+    /// Keep messages recorded at or after this offset
+    pub from: Option<Duration>,
+    /// Keep messages recorded before this offset
+    pub until: Option<Duration>,
+}
+
+impl RecordingFilter {
+    /// Check if a recorded message passes the filter
+    pub fn matches(&self, record: &RecordedMessage) -> bool {
+        let message = &record.message;
+        (self.message_types.is_empty() || self.message_types.contains(message.message_type()))
+            && self.direction.is_none_or(|direction| direction == record.direction)
+            && self.session_id.as_ref().is_none_or(|id| message.session_id() == Some(id))
+            && self.from.is_none_or(|from| record.offset() >= from)
+            && self.until.is_none_or(|until| record.offset() < until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryTransportFactory;
+    use crate::messages::{AuthResponsePayload, ClipboardPayload, HeartbeatPayload};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("soft-kvm-recording-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn heartbeat(sequence_number: u64) -> ProtocolMessage {
+        ProtocolMessage::new(MessageType::Heartbeat, MessagePayload::Heartbeat(HeartbeatPayload { sequence_number }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_record_and_read_back() {
+        let dir = temp_dir();
+        let (local, mut remote) = MemoryTransportFactory::default().pair();
+        let (recorder, path) = Recorder::create(&dir, local.remote_addr()).unwrap();
+        let mut local = RecordingConnection::new(Box::new(local), recorder);
+
+        local.send(heartbeat(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let clipboard = ProtocolMessage::new(
+            MessageType::ClipboardData,
+            MessagePayload::ClipboardData(ClipboardPayload {
+                data_type: "text".to_string(),
+                data: b"recorded clipboard ".repeat(500),
+            }),
+        )
+        .with_session("session-1".to_string());
+        remote.send(clipboard).await.unwrap();
+        local.receive().await.unwrap().unwrap();
+        local.close().await.unwrap();
+
+        let recording = Recording::open(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024, "clipboard is stored compressed");
+        assert_eq!(recording.peer, local.remote_addr().map(|addr| addr.to_string()));
+        assert_eq!(recording.messages.len(), 2);
+        assert_eq!(recording.messages[0].direction, Direction::Sent);
+        assert_eq!(recording.messages[1].direction, Direction::Received);
+        assert!(recording.messages[1].offset() >= Duration::from_millis(250));
+        assert!(matches!(
+            &recording.messages[1].message.payload,
+            MessagePayload::ClipboardData(data) if data.data.len() == 19 * 500
+        ));
+
+        let filter = RecordingFilter {
+            direction: Some(Direction::Received),
+            session_id: Some("session-1".to_string()),
+            ..RecordingFilter::default()
+        };
+        assert_eq!(recording.messages.iter().filter(|r| filter.matches(r)).count(), 1);
+        let filter = RecordingFilter { until: Some(Duration::from_millis(100)), ..RecordingFilter::default() };
+        assert_eq!(recording.messages.iter().filter(|r| filter.matches(r)).count(), 1);
+
+        // A crash mid-record loses only that record
+        let mut data = std::fs::read(&path).unwrap();
+        data.truncate(data.len() - 3);
+        assert_eq!(Recording::read(data.as_slice()).unwrap().messages.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recording_is_private_and_redacted() {
+        let dir = temp_dir();
+        let (mut recorder, path) = Recorder::create(&dir, None).unwrap();
+        let auth = ProtocolMessage::new(
+            MessageType::AuthResponse,
+            MessagePayload::AuthResponse(AuthResponsePayload {
+                success: true,
+                session_token: Some("secret-token".to_string()),
+                error_message: None,
+            }),
+        );
+        recorder.record(Direction::Received, &auth).unwrap();
+        recorder.record(Direction::Sent, &heartbeat(1)).unwrap();
+        drop(recorder);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let data = std::fs::read(&path).unwrap();
+        assert!(!data.windows(12).any(|window| window == b"secret-token"));
+        let recording = Recording::read(data.as_slice()).unwrap();
+        assert!(matches!(
+            &recording.messages[0].message.payload,
+            MessagePayload::AuthResponse(response) if response.session_token.as_deref() == Some(REDACTED)
+        ));
+
+        // A corrupt length can't force a huge allocation
+        let mut corrupt = data[..14].to_vec();
+        corrupt.extend_from_slice(&[0; 9]);
+        corrupt.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Recording::read(corrupt.as_slice()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_keeps_original_timing() {
+        let recording = Recording {
+            started_at: Utc::now(),
+            peer: None,
+            messages: vec![
+                RecordedMessage { offset_us: 0, direction: Direction::Sent, message: heartbeat(1) },
+                RecordedMessage { offset_us: 100_000, direction: Direction::Received, message: heartbeat(2) },
+                RecordedMessage { offset_us: 300_000, direction: Direction::Received, message: heartbeat(3) },
+            ],
+        };
+        let factory = ReplayFactory::new(recording);
+        let mut replay = factory.create_connection("127.0.0.1:1".parse().unwrap(), TransportConfig::default()).await.unwrap();
+        let started = Instant::now();
+
+        replay.send(heartbeat(9)).await.unwrap();
+        for (sequence, at) in [(2, 100), (3, 300)] {
+            let message = replay.receive().await.unwrap().unwrap();
+            assert!(matches!(message.payload, MessagePayload::Heartbeat(h) if h.sequence_number == sequence));
+            assert_eq!(started.elapsed(), Duration::from_millis(at));
+        }
+        assert!(replay.receive().await.unwrap().is_none());
+        assert!(!replay.is_alive());
+        assert!(factory.create_connection("127.0.0.1:1".parse().unwrap(), TransportConfig::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replayed_handshake_connects_client() {
+        let dir = temp_dir();
+        let network = MemoryTransportFactory::default();
+        let config = ProtocolConfig {
+            input_channel: crate::input_channel::InputChannelConfig { enabled: false, ..Default::default() },
+            ..ProtocolConfig::default()
+        };
+        let mut server = crate::ProtocolServer::new(config.clone())
+            .unwrap()
+            .with_transport(Arc::new(network.clone()), TransportConfig::default());
+        server.start("127.0.0.1:9200".parse().unwrap()).await.unwrap();
+
+        let record_config = ProtocolConfig { record_dir: Some(dir.clone()), ..config.clone() };
+        let mut client = crate::ProtocolClient::new(record_config)
+            .with_transport(Arc::new(network), TransportConfig::default());
+        client.connect(server.local_addr().await.unwrap()).await.unwrap();
+        let session_id = client.session().unwrap().session_id().to_string();
+        client.disconnect().await.unwrap();
+        server.stop().await.unwrap();
+
+        let path = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.messages[0].message.message_type(), &MessageType::Hello);
+
+        // The server is gone; the recording stands in for it
+        let mut replayed = crate::ProtocolClient::new(config)
+            .with_transport(Arc::new(ReplayFactory::new(recording)), TransportConfig::default());
+        replayed.connect("127.0.0.1:9200".parse().unwrap()).await.unwrap();
+        assert_eq!(replayed.session().unwrap().session_id(), session_id);
+        // The connection ends with the recording, so the session may already be lost
+        let _ = replayed.disconnect().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
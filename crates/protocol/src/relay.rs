@@ -0,0 +1,492 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relay between two LAN segments
+//!
+//! mDNS does not cross VLANs, so a viewer on one segment cannot find or reach
+//! a server on another. A [`Relay`] runs on a host with a leg in both: each
+//! [`RelayRoute`] listens on the viewers' side and forwards every connection
+//! to one server on the other side.
+//!
+//! ```text
+//! viewer  <-- hop (viewer_transport) -->  relay  <-- hop (server_transport) -->  server
+//! ```
+//!
+//! Each hop is its own transport connection with its own TLS settings. The
+//! relay does not terminate sessions: Hello, Welcome and everything after pass
+//! through unchanged, so authentication and capabilities are negotiated end
+//! to end. It only follows the codec chosen in Welcome so frames stay compact
+//! on both hops, and withholds the server's datagram input port, which the
+//! viewer could not reach; input then travels over the relayed connection.
+//!
+//! Only viewers in `RelayConfig::allowed_viewers` are accepted, and only the
+//! servers named in a route can be reached. [`Relay::announce`] advertises
+//! every route in discovery on the viewers' side.
+
+use crate::codec::MessageCodec;
+use crate::messages::{MessagePayload, ProtocolMessage};
+use crate::queue::QueueSender;
+use crate::transport::{MessageHandler, TransportConfig, TransportConnection, TransportFactory, TransportListener, TransportManager};
+use crate::{ProtocolError, ProtocolResult};
+use async_trait::async_trait;
+use soft_kvm_core::{ErrorCode, NetworkAddress, ServiceId, ServiceType};
+use soft_kvm_discovery::health::ServiceHealth;
+use soft_kvm_discovery::metadata::ServiceMetadata;
+use soft_kvm_discovery::{ServiceInfo, ServiceResolver, ServiceSource};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// A server reachable through the relay
+#[derive(Debug, Clone)]
+pub struct RelayRoute {
+    /// Name the route is announced under
+    pub name: String,
+    /// Address viewers connect to. Use the address of the viewers' interface
+    /// rather than an unspecified one, since it is what gets announced.
+    pub listen: SocketAddr,
+    /// Server the route forwards to
+    pub server: SocketAddr,
+}
+
+/// Relay settings
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    pub routes: Vec<RelayRoute>,
+    /// Viewers allowed to connect; everyone else is refused
+    pub allowed_viewers: Vec<IpAddr>,
+    /// Transport towards the viewers
+    pub viewer_transport: TransportConfig,
+    /// Transport towards the servers
+    pub server_transport: TransportConfig,
+    /// Smallest payload compressed once Welcome selects compression
+    pub compression_threshold: usize,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            routes: Vec::new(),
+            allowed_viewers: Vec::new(),
+            viewer_transport: TransportConfig::default(),
+            server_transport: TransportConfig::default(),
+            compression_threshold: 1024,
+        }
+    }
+}
+
+/// Relay forwarding viewers on one segment to servers on another
+pub struct Relay {
+    config: RelayConfig,
+    factory: RelayFactory,
+    service_ids: Vec<ServiceId>,
+    listeners: Vec<TransportManager<RelayFactory>>,
+}
+
+impl Relay {
+    /// Create a relay over `factory`
+    pub fn new(config: RelayConfig, factory: Arc<dyn TransportFactory>) -> ProtocolResult<Self> {
+        if config.routes.is_empty() {
+            return Err(ProtocolError::Generic("Relay has no routes".to_string()));
+        }
+        if config.allowed_viewers.is_empty() {
+            return Err(ProtocolError::Generic("Relay allows no viewers".to_string()));
+        }
+        let mut listen = HashSet::new();
+        for route in &config.routes {
+            if !listen.insert(route.listen) {
+                return Err(ProtocolError::Generic(format!("Several relay routes listen on {}", route.listen)));
+            }
+        }
+        if let Some(route) = config.routes.iter().find(|route| listen.contains(&route.server)) {
+            return Err(ProtocolError::Generic(format!("Relay route {} forwards to the relay itself", route.name)));
+        }
+
+        let factory = RelayFactory {
+            inner: factory,
+            compression_threshold: config.compression_threshold,
+        };
+        Ok(Relay {
+            service_ids: config.routes.iter().map(|_| ServiceId(uuid::Uuid::new_v4())).collect(),
+            config,
+            factory,
+            listeners: Vec::new(),
+        })
+    }
+
+    /// Start listening on every route
+    pub async fn start(&mut self) -> ProtocolResult<()> {
+        if !self.listeners.is_empty() {
+            return Err(ProtocolError::Generic("Relay already started".to_string()));
+        }
+        let allowed_viewers: Arc<HashSet<IpAddr>> = Arc::new(self.config.allowed_viewers.iter().copied().collect());
+
+        for route in &self.config.routes {
+            let manager = TransportManager::new(self.factory.clone(), self.config.viewer_transport.clone());
+            let handler = ViewerHandler {
+                route: route.clone(),
+                allowed_viewers: Arc::clone(&allowed_viewers),
+                factory: self.factory.clone(),
+                server_transport: self.config.server_transport.clone(),
+                upstream: None,
+            };
+            if let Err(e) = manager.listen(route.listen, handler).await {
+                self.stop().await?;
+                return Err(e);
+            }
+            info!("Relaying {} from {} to {}", route.name, route.listen, route.server);
+            self.listeners.push(manager);
+        }
+        Ok(())
+    }
+
+    /// Close every relayed connection and stop listening
+    pub async fn stop(&mut self) -> ProtocolResult<()> {
+        for manager in self.listeners.drain(..) {
+            manager.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    /// Number of viewers currently relayed
+    pub async fn connection_count(&self) -> usize {
+        let mut count = 0;
+        for manager in &self.listeners {
+            count += manager.connection_count().await;
+        }
+        count
+    }
+
+    /// Services advertised for the routes, one per route
+    pub fn services(&self) -> Vec<ServiceInfo> {
+        self.config.routes.iter().zip(&self.service_ids).map(|(route, id)| ServiceInfo {
+            id: id.clone(),
+            name: route.name.clone(),
+            service_type: ServiceType::Server,
+            address: NetworkAddress {
+                ip: route.listen.ip().to_string(),
+                port: route.listen.port(),
+            },
+            last_seen: chrono::Utc::now(),
+            ttl: soft_kvm_discovery::DEFAULT_SERVICE_TTL,
+            // Messages pass through, so viewers can use whatever we can parse
+            metadata: ServiceMetadata {
+                min_protocol_version: Some(crate::version::MIN_PROTOCOL_VERSION.to_string()),
+                protocol_version: Some(crate::PROTOCOL_VERSION.to_string()),
+                ..ServiceMetadata::default()
+            },
+            source: ServiceSource::Mdns,
+            health: ServiceHealth::default(),
+        }).collect()
+    }
+
+    /// Announce every route in discovery
+    pub async fn announce(&self, resolver: &ServiceResolver) -> ProtocolResult<()> {
+        for service in self.services() {
+            resolver.register_service(service).await
+                .map_err(|e| ProtocolError::Generic(format!("Failed to announce relay route: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Withdraw the announcements made by [`Relay::announce`]
+    pub async fn withdraw(&self, resolver: &ServiceResolver) -> ProtocolResult<()> {
+        for id in &self.service_ids {
+            resolver.unregister_service(id).await
+                .map_err(|e| ProtocolError::Generic(format!("Failed to withdraw relay route: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Forwards what a viewer sends to its server.
+///
+/// The manager clones the handler for every accepted connection, so each
+/// clone carries the upstream hop of one viewer.
+#[derive(Clone)]
+struct ViewerHandler {
+    route: RelayRoute,
+    allowed_viewers: Arc<HashSet<IpAddr>>,
+    factory: RelayFactory,
+    server_transport: TransportConfig,
+    upstream: Option<Arc<TransportManager<RelayFactory>>>,
+}
+
+impl ViewerHandler {
+    /// Open the hop to the server, forwarding its messages to `viewer`
+    async fn connect_upstream(&mut self, viewer: QueueSender) -> ProtocolResult<Arc<TransportManager<RelayFactory>>> {
+        let manager = Arc::new(TransportManager::new(self.factory.clone(), self.server_transport.clone()));
+        // Everything is forwarded by the handler; nothing reaches the returned receiver
+        let _ = manager.connect(self.route.server, ServerHandler { viewer }).await?;
+        self.upstream = Some(Arc::clone(&manager));
+        Ok(manager)
+    }
+}
+
+#[async_trait]
+impl MessageHandler for ViewerHandler {
+    async fn handle_message(&mut self, message: ProtocolMessage, sender: QueueSender) -> ProtocolResult<()> {
+        let upstream = match &self.upstream {
+            Some(upstream) => Arc::clone(upstream),
+            // Connect on the viewer's first message, its Hello
+            None => self.connect_upstream(sender).await.inspect_err(|e| {
+                warn!("Failed to reach {} for {}: {}", self.route.server, self.route.name, e);
+            })?,
+        };
+        // Fails once the server hung up, which closes the viewer's connection
+        upstream.send_to(self.route.server, message).await
+    }
+
+    async fn on_connection_opened(&mut self, remote_addr: SocketAddr) -> ProtocolResult<()> {
+        if !self.allowed_viewers.contains(&remote_addr.ip()) {
+            warn!("Refusing to relay {} to {}: not an allowed viewer", remote_addr, self.route.name);
+            return Err(ProtocolError::Refused {
+                code: ErrorCode::PolicyViolation,
+                reason: format!("{} may not use the relay", remote_addr.ip()),
+            });
+        }
+        debug!("Relaying {} to {}", remote_addr, self.route.server);
+        Ok(())
+    }
+
+    async fn on_connection_closed(&mut self, remote_addr: SocketAddr) -> ProtocolResult<()> {
+        if let Some(upstream) = self.upstream.take() {
+            debug!("Viewer {} left, closing hop to {}", remote_addr, self.route.server);
+            upstream.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Forwards what a server sends to its viewer
+#[derive(Clone)]
+struct ServerHandler {
+    viewer: QueueSender,
+}
+
+#[async_trait]
+impl MessageHandler for ServerHandler {
+    async fn handle_message(&mut self, mut message: ProtocolMessage, _sender: QueueSender) -> ProtocolResult<()> {
+        if let MessagePayload::Welcome(welcome) = &mut message.payload {
+            // The server's datagram port is not reachable from the viewer's segment
+            welcome.input_port = None;
+        }
+        self.viewer.send(message).await
+    }
+}
+
+/// Codec selected by a Welcome, if `message` is one
+fn welcome_codec(message: &ProtocolMessage, compression_threshold: usize) -> Option<Arc<dyn MessageCodec>> {
+    match &message.payload {
+        MessagePayload::Welcome(welcome) => Some(welcome.codec.codec_with(welcome.compression, compression_threshold)),
+        _ => None,
+    }
+}
+
+/// Connection switching codec when a Welcome passes through it, as the
+/// handshake does for the peers at either end
+struct RelayConnection {
+    inner: Box<dyn TransportConnection>,
+    compression_threshold: usize,
+}
+
+#[async_trait]
+impl TransportConnection for RelayConnection {
+    async fn send(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
+        // The Welcome itself still goes out in the old codec
+        let codec = welcome_codec(&message, self.compression_threshold);
+        self.inner.send(message).await?;
+        if let Some(codec) = codec {
+            self.inner.set_codec(codec);
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> ProtocolResult<Option<ProtocolMessage>> {
+        let message = self.inner.receive().await?;
+        if let Some(codec) = message.as_ref().and_then(|message| welcome_codec(message, self.compression_threshold)) {
+            self.inner.set_codec(codec);
+        }
+        Ok(message)
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.inner.close().await
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+}
+
+/// Transport factory wrapping every connection in a [`RelayConnection`]
+#[derive(Clone)]
+struct RelayFactory {
+    inner: Arc<dyn TransportFactory>,
+    compression_threshold: usize,
+}
+
+#[async_trait]
+impl TransportFactory for RelayFactory {
+    async fn create_listener(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportListener>> {
+        let inner = self.inner.create_listener(addr, config).await?;
+        Ok(Box::new(RelayListener { inner, compression_threshold: self.compression_threshold }))
+    }
+
+    async fn create_connection(&self, addr: SocketAddr, config: TransportConfig) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let inner = self.inner.create_connection(addr, config).await?;
+        Ok(Box::new(RelayConnection { inner, compression_threshold: self.compression_threshold }))
+    }
+}
+
+struct RelayListener {
+    inner: Box<dyn TransportListener>,
+    compression_threshold: usize,
+}
+
+#[async_trait]
+impl TransportListener for RelayListener {
+    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
+        let inner = self.inner.accept().await?;
+        Ok(Box::new(RelayConnection { inner, compression_threshold: self.compression_threshold }))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    async fn close(&mut self) -> ProtocolResult<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryTransportFactory;
+    use crate::{input_channel, ProtocolClient, ProtocolConfig, ProtocolServer};
+    use soft_kvm_core::KeyboardEvent;
+    use soft_kvm_discovery::mdns::MdnsConfig;
+    use std::time::Duration;
+
+    fn protocol_config() -> ProtocolConfig {
+        ProtocolConfig {
+            input_channel: input_channel::InputChannelConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..ProtocolConfig::default()
+        }
+    }
+
+    fn relay_config(server: SocketAddr, listen: SocketAddr, viewer: &str) -> RelayConfig {
+        RelayConfig {
+            routes: vec![RelayRoute {
+                name: "lab-workstation".to_string(),
+                listen,
+                server,
+            }],
+            allowed_viewers: vec![viewer.parse().unwrap()],
+            ..RelayConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_bridges_a_session() {
+        let network = MemoryTransportFactory::default();
+        let mut server = ProtocolServer::new(protocol_config())
+            .unwrap()
+            .with_transport(Arc::new(network.clone()), TransportConfig::default());
+        server.start("10.0.1.5:9400".parse().unwrap()).await.unwrap();
+
+        let listen: SocketAddr = "10.0.2.1:9400".parse().unwrap();
+        let mut relay = Relay::new(relay_config("10.0.1.5:9400".parse().unwrap(), listen, "127.0.0.1"), Arc::new(network.clone())).unwrap();
+        relay.start().await.unwrap();
+
+        let mut client = ProtocolClient::new(protocol_config())
+            .with_transport(Arc::new(network), TransportConfig::default());
+        client.connect(listen).await.unwrap();
+        assert_eq!(relay.connection_count().await, 1);
+
+        // The session is established end to end and input reaches the server
+        let session_id = client.session().unwrap().session_id().to_string();
+        let sessions = server.sessions().await;
+        let server_session = sessions.iter().find(|s| s.session_id() == session_id).unwrap().clone();
+        let key = crate::messages::InputEventPayload::keyboard(&KeyboardEvent::KeyPress { key_code: 0x41, modifiers: 0 });
+        client.send_input(key).await.unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(5), server_session.receive_message()).await.unwrap().unwrap();
+        assert!(matches!(message.payload, MessagePayload::InputEvent(_)));
+
+        client.disconnect().await.unwrap();
+        relay.stop().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_refuses_viewers_not_allowed() {
+        let network = MemoryTransportFactory::default();
+        let mut server = ProtocolServer::new(protocol_config())
+            .unwrap()
+            .with_transport(Arc::new(network.clone()), TransportConfig::default());
+        server.start("10.0.1.5:9401".parse().unwrap()).await.unwrap();
+
+        let listen: SocketAddr = "10.0.2.1:9401".parse().unwrap();
+        let mut relay = Relay::new(relay_config("10.0.1.5:9401".parse().unwrap(), listen, "10.0.2.77"), Arc::new(network.clone())).unwrap();
+        relay.start().await.unwrap();
+
+        let mut client = ProtocolClient::new(protocol_config())
+            .with_transport(Arc::new(network), TransportConfig::default());
+        assert!(client.connect(listen).await.is_err());
+        assert!(server.sessions().await.is_empty());
+
+        relay.stop().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_config_and_announcements() {
+        let network: Arc<dyn TransportFactory> = Arc::new(MemoryTransportFactory::default());
+        let server: SocketAddr = "10.0.1.5:9402".parse().unwrap();
+        let listen: SocketAddr = "10.0.2.1:9402".parse().unwrap();
+
+        let looping = relay_config(listen, listen, "10.0.2.77");
+        assert!(Relay::new(looping, Arc::clone(&network)).is_err());
+        let closed = RelayConfig { allowed_viewers: Vec::new(), ..relay_config(server, listen, "10.0.2.77") };
+        assert!(Relay::new(closed, Arc::clone(&network)).is_err());
+
+        let relay = Relay::new(relay_config(server, listen, "10.0.2.77"), network).unwrap();
+        let resolver = ServiceResolver::new(ServiceType::Server).with_mdns_config(MdnsConfig {
+            port: 25360,
+            interface: std::net::Ipv4Addr::LOCALHOST,
+            ..MdnsConfig::default()
+        });
+        relay.announce(&resolver).await.unwrap();
+        let services = resolver.get_available_services().await;
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].name, "lab-workstation");
+        assert_eq!((services[0].address.ip.as_str(), services[0].address.port), ("10.0.2.1", 9402));
+        // Only versions whose wire format we can actually parse
+        let metadata = &services[0].metadata;
+        assert_eq!(metadata.min_protocol_version.as_deref(), Some("1.1.0"));
+        assert_eq!(metadata.protocol_version.as_deref(), Some(crate::PROTOCOL_VERSION));
+
+        relay.withdraw(&resolver).await.unwrap();
+        assert!(resolver.get_available_services().await.is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Message routing
+//!
+//! `MessageRouter` reads a session's inbound messages and dispatches them to
+//! async handlers registered per `MessageType`. A handler may return a reply,
+//! which is sent back with its `correlation_id` set to the request's
+//! `message_id`; a handler error is answered with a correlated Error message.
+//!
+//! `request` sends a message and waits for the response carrying its ID. An
+//! Error response becomes `ProtocolError::Remote`, and no response within
+//! `ProtocolConfig::request_timeout` becomes `ProtocolError::Timeout`.
+//! Peers older than `Feature::CorrelatedRequests` never correlate their
+//! replies, so requests to them fail immediately.
+
+use crate::messages::{ErrorPayload, MessagePayload, MessageType, ProtocolMessage};
+use crate::session::ProtocolSession;
+use crate::version::Feature;
+use crate::{ProtocolError, ProtocolResult};
+use async_trait::async_trait;
+use soft_kvm_core::ErrorCode;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+/// Error code sent when a handler fails
+const HANDLER_ERROR_CODE: ErrorCode = ErrorCode::InternalError;
+
+/// Handler for one message type
+#[async_trait]
+pub trait RouteHandler: Send + Sync {
+    /// Handle a message, optionally returning a reply
+    async fn handle(&self, message: ProtocolMessage) -> ProtocolResult<Option<ProtocolMessage>>;
+}
+
+#[async_trait]
+impl<F, Fut> RouteHandler for F
+where
+    F: Fn(ProtocolMessage) -> Fut + Send + Sync,
+    Fut: Future<Output = ProtocolResult<Option<ProtocolMessage>>> + Send,
+{
+    async fn handle(&self, message: ProtocolMessage) -> ProtocolResult<Option<ProtocolMessage>> {
+        self(message).await
+    }
+}
+
+/// Dispatches a session's messages to handlers and correlates responses
+pub struct MessageRouter {
+    session: ProtocolSession,
+    handlers: RwLock<HashMap<MessageType, Arc<dyn RouteHandler>>>,
+    pending: Mutex<HashMap<String, oneshot::Sender<ProtocolMessage>>>,
+    timeout: Duration,
+}
+
+/// Removes a pending request however the request ends
+struct PendingGuard<'a> {
+    router: &'a MessageRouter,
+    message_id: String,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.router.pending.lock().unwrap().remove(&self.message_id);
+    }
+}
+
+impl MessageRouter {
+    /// Create a router for a session
+    pub fn new(session: ProtocolSession) -> Self {
+        let timeout = Duration::from_secs(session.config().request_timeout);
+        MessageRouter {
+            session,
+            handlers: RwLock::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+
+    /// Get the session this router serves
+    pub fn session(&self) -> &ProtocolSession {
+        &self.session
+    }
+
+    /// Register the handler for a message type, replacing any previous one
+    pub fn register<H>(&self, message_type: MessageType, handler: H)
+    where
+        H: RouteHandler + 'static,
+    {
+        self.handlers.write().unwrap().insert(message_type, Arc::new(handler));
+    }
+
+    /// Remove the handler for a message type
+    pub fn unregister(&self, message_type: &MessageType) {
+        self.handlers.write().unwrap().remove(message_type);
+    }
+
+    /// Number of requests waiting for a response
+    pub fn pending_requests(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Send a request and wait for its response
+    pub async fn request(&self, message: ProtocolMessage) -> ProtocolResult<ProtocolMessage> {
+        self.request_with_timeout(message, self.timeout).await
+    }
+
+    /// Send a request and wait up to `timeout` for its response
+    pub async fn request_with_timeout(&self, message: ProtocolMessage, timeout: Duration) -> ProtocolResult<ProtocolMessage> {
+        if !self.session.supports(Feature::CorrelatedRequests) {
+            return Err(ProtocolError::FeatureNotNegotiated {
+                feature: Feature::CorrelatedRequests,
+                version: self.session.protocol_version(),
+            });
+        }
+
+        let message_id = message.header.message_id.clone();
+        let (sender, response) = oneshot::channel();
+        self.pending.lock().unwrap().insert(message_id.clone(), sender);
+        let _guard = PendingGuard { router: self, message_id };
+
+        let exchange = async {
+            self.session.send_message(message).await?;
+            response.await
+                .map_err(|_| ProtocolError::Session(format!("Router for session {} stopped", self.session.session_id())))
+        };
+        let response = tokio::time::timeout(timeout, exchange).await
+            .map_err(|_| ProtocolError::Timeout)??;
+
+        match response.payload {
+            MessagePayload::Error(error) => Err(ProtocolError::Remote {
+                code: error.error_code,
+                message: error.error_message,
+            }),
+            _ => Ok(response),
+        }
+    }
+
+    /// Dispatch one inbound message.
+    ///
+    /// Responses complete their pending request and stale responses are
+    /// dropped. Other messages go to their handler; a message without a
+    /// handler is handed back to the caller.
+    pub async fn dispatch(&self, message: ProtocolMessage) -> ProtocolResult<Option<ProtocolMessage>> {
+        if let Some(correlation_id) = message.correlation_id() {
+            let waiter = self.pending.lock().unwrap().remove(correlation_id);
+            match waiter {
+                Some(waiter) => {
+                    // The requester may have given up in the meantime
+                    let _ = waiter.send(message);
+                }
+                None => debug!("Dropping late response to {} on session {}", correlation_id, self.session.session_id()),
+            }
+            return Ok(None);
+        }
+
+        let handler = self.handlers.read().unwrap().get(message.message_type()).cloned();
+        let handler = match handler {
+            Some(handler) => handler,
+            None => return Ok(Some(message)),
+        };
+
+        let header = message.header.clone();
+        let mut reply = match handler.handle(message).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                warn!("Handler for {:?} failed on session {}: {}", header.message_type, self.session.session_id(), e);
+                ProtocolMessage::new(MessageType::Error, MessagePayload::Error(ErrorPayload {
+                    error_code: HANDLER_ERROR_CODE,
+                    error_message: e.to_string(),
+                    details: None,
+                }))
+            }
+        };
+
+        reply.header.correlation_id.get_or_insert(header.message_id);
+        if reply.header.session_id.is_none() {
+            reply.header.session_id = header.session_id;
+        }
+        self.session.send_message(reply).await?;
+        Ok(None)
+    }
+
+    /// Dispatch the session's inbound messages until it closes.
+    ///
+    /// Messages without a handler are logged and dropped; call `dispatch`
+    /// from your own receive loop to handle them instead.
+    pub async fn run(&self) {
+        while let Some(message) = self.session.receive_message().await {
+            match self.dispatch(message).await {
+                Ok(Some(unhandled)) => {
+                    debug!("No handler for {:?} on session {}", unhandled.message_type(), self.session.session_id());
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to dispatch on session {}: {}", self.session.session_id(), e),
+            }
+        }
+
+        // Fail outstanding requests rather than leaving them to time out
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::SessionDriver;
+    use crate::handshake::tests::{new_session, test_config};
+    use crate::memory::MemoryTransportFactory;
+    use crate::messages::{MetricsPayload, ServiceQueryPayload, ServiceResponsePayload};
+    use crate::session::{PeerInfo, SessionState};
+    use crate::ProtocolConfig;
+    use soft_kvm_core::{CapabilitySet, NetworkAddress};
+
+    async fn active_session(config: &ProtocolConfig) -> ProtocolSession {
+        let mut session = new_session("router-session", config);
+        session.set_peer_info(PeerInfo {
+            peer_id: "peer".to_string(),
+            peer_name: "Peer".to_string(),
+            address: NetworkAddress::localhost(0),
+            capabilities: CapabilitySet::full(),
+            authenticated: true,
+            last_seen: chrono::Utc::now(),
+        });
+        session.set_state(SessionState::Active).await;
+        session
+    }
+
+    /// Two routers talking to each other over a memory link
+    async fn connected_routers(config: &ProtocolConfig) -> (Arc<MessageRouter>, Arc<MessageRouter>) {
+        let (left, right) = MemoryTransportFactory::default().pair();
+        let mut routers = Vec::new();
+        for connection in [left, right] {
+            let session = active_session(config).await;
+            SessionDriver::new(session.clone(), Box::new(connection)).spawn();
+            let router = Arc::new(MessageRouter::new(session));
+            let runner = router.clone();
+            tokio::spawn(async move { runner.run().await });
+            routers.push(router);
+        }
+        let right = routers.pop().unwrap();
+        (routers.pop().unwrap(), right)
+    }
+
+    #[tokio::test]
+    async fn test_request_is_answered_by_registered_handler() {
+        let (client, server) = connected_routers(&test_config()).await;
+        server.register(MessageType::MetricsRequest, |_request: ProtocolMessage| async {
+            Ok(Some(ProtocolMessage::new(
+                MessageType::MetricsResponse,
+                MessagePayload::MetricsResponse(MetricsPayload {
+                    timestamp: chrono::Utc::now(),
+                    metrics: serde_json::json!({ "fps": 60 }),
+                }),
+            )))
+        });
+        server.register(MessageType::ServiceQuery, |request: ProtocolMessage| async move {
+            Ok(Some(request.reply(
+                MessageType::ServiceResponse,
+                MessagePayload::ServiceResponse(ServiceResponsePayload { services: Vec::new() }),
+            )))
+        });
+
+        let metrics = ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest);
+        let metrics_id = metrics.header.message_id.clone();
+        let query = ProtocolMessage::new(
+            MessageType::ServiceQuery,
+            MessagePayload::ServiceQuery(ServiceQueryPayload { query_type: "all".to_string(), filters: None }),
+        );
+
+        // Concurrent requests each get their own response
+        let (metrics, query) = tokio::join!(client.request(metrics), client.request(query));
+        let metrics = metrics.unwrap();
+        assert_eq!(metrics.correlation_id(), Some(metrics_id.as_str()));
+        assert!(matches!(metrics.payload, MessagePayload::MetricsResponse(ref m) if m.metrics["fps"] == 60));
+        assert_eq!(query.unwrap().message_type(), &MessageType::ServiceResponse);
+        assert_eq!(client.pending_requests(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unanswered_request_times_out() {
+        let config = ProtocolConfig { request_timeout: 2, ..test_config() };
+        let (client, _server) = connected_routers(&config).await;
+
+        let start = tokio::time::Instant::now();
+        let metrics = ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest);
+        assert!(matches!(client.request(metrics).await, Err(ProtocolError::Timeout)));
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(client.pending_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handler_error_is_returned_to_requester() {
+        let (client, server) = connected_routers(&test_config()).await;
+        server.register(MessageType::MetricsRequest, |_request: ProtocolMessage| async {
+            Err(ProtocolError::Generic("metrics unavailable".to_string()))
+        });
+
+        let metrics = ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest);
+        match client.request(metrics).await {
+            Err(ProtocolError::Remote { code, message }) => {
+                assert_eq!(code, HANDLER_ERROR_CODE);
+                assert!(message.contains("metrics unavailable"), "{}", message);
+            }
+            other => panic!("expected a remote error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unhandled_message_is_handed_back() {
+        let session = active_session(&test_config()).await;
+        let router = MessageRouter::new(session);
+
+        let stop = ProtocolMessage::new(MessageType::VideoStop, MessagePayload::VideoStop);
+        let returned = router.dispatch(stop).await.unwrap();
+        assert_eq!(returned.unwrap().message_type(), &MessageType::VideoStop);
+
+        // A response nobody is waiting for is dropped
+        let request = ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest);
+        let late = request.reply(MessageType::MetricsResponse, MessagePayload::MetricsResponse(MetricsPayload {
+            timestamp: chrono::Utc::now(),
+            metrics: serde_json::Value::Null,
+        }));
+        assert!(router.dispatch(late).await.unwrap().is_none());
+    }
+}
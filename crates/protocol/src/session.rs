@@ -14,15 +14,19 @@
 
 //! Protocol session management
 
+use crate::queue::{self, QueueMonitor, QueueReceiver, QueueSender, QueueStats, TryRecvError, TrySendError};
 use crate::{ProtocolConfig, ProtocolError, ProtocolResult};
 use soft_kvm_core::*;
 use std::sync::Arc;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{RwLock, watch};
 use tracing::{debug, info, warn, error};
 use serde::{Serialize, Deserialize};
 
 // Re-export message types for convenience
 pub use crate::messages::{MessageType, MessagePayload, ProtocolMessage};
+use crate::messages::{PongPayload, VideoStartPayload};
+use crate::timing::{ClockEstimator, ClockSample, ClockStats};
+use crate::version::{Feature, ProtocolVersion};
 
 /// Peer information for session
 #[derive(Debug, Clone)]
@@ -30,7 +34,8 @@ pub struct PeerInfo {
     pub peer_id: String,
     pub peer_name: String,
     pub address: NetworkAddress,
-    pub capabilities: Vec<String>,
+    /// Capabilities negotiated with the peer
+    pub capabilities: CapabilitySet,
     pub authenticated: bool,
     pub last_seen: chrono::DateTime<chrono::Utc>,
 }
@@ -47,32 +52,61 @@ pub enum SessionState {
 }
 
 /// Protocol session
+///
+/// Outgoing messages are queued on the outbound channel and written to the
+/// transport by the session driver; messages read from the transport are
+/// delivered to the inbound channel. The queues outlive any single
+/// transport connection, so a resumed session picks up where it left off.
+///
+/// Both queues are bounded by `ProtocolConfig::queue`; control and input
+/// traffic overtakes queued video frames (see `crate::queue`).
 #[derive(Debug, Clone)]
 pub struct ProtocolSession {
     session_id: String,
     peer_info: PeerInfo,
+    protocol_version: ProtocolVersion,
     config: ProtocolConfig,
     state: Arc<RwLock<SessionState>>,
-    message_sender: mpsc::UnboundedSender<ProtocolMessage>,
-    message_receiver: Arc<RwLock<Option<mpsc::UnboundedReceiver<ProtocolMessage>>>>,
+    outbound_sender: QueueSender,
+    outbound_receiver: Arc<tokio::sync::Mutex<QueueReceiver>>,
+    inbound_sender: Arc<RwLock<Option<QueueSender>>>,
+    inbound_monitor: QueueMonitor,
+    message_receiver: Arc<RwLock<Option<QueueReceiver>>>,
     last_activity: Arc<RwLock<chrono::DateTime<chrono::Utc>>>,
     heartbeat_sequence: Arc<RwLock<u64>>,
+    resume_token: Arc<RwLock<Option<String>>>,
+    video_stream: Arc<RwLock<Option<VideoStartPayload>>>,
+    generation: Arc<watch::Sender<u64>>,
+    clock: Arc<RwLock<ClockEstimator>>,
 }
 
 impl ProtocolSession {
     /// Create a new protocol session
     pub fn new(session_id: String, peer_info: PeerInfo, config: ProtocolConfig) -> Self {
-        let (tx, rx) = mpsc::unbounded_channel();
+        let (outbound_tx, outbound_rx) = queue::channel(config.queue.clone());
+        let (inbound_tx, inbound_rx) = queue::channel(config.queue.clone());
+        let inbound_monitor = inbound_tx.monitor();
+
+        // Until a handshake settles on a version, assume the configured one
+        let protocol_version = config.version.parse().unwrap_or_else(|_| ProtocolVersion::current());
 
         ProtocolSession {
             session_id,
             peer_info,
+            protocol_version,
             config,
             state: Arc::new(RwLock::new(SessionState::Connecting)),
-            message_sender: tx,
-            message_receiver: Arc::new(RwLock::new(Some(rx))),
+            outbound_sender: outbound_tx,
+            outbound_receiver: Arc::new(tokio::sync::Mutex::new(outbound_rx)),
+            inbound_sender: Arc::new(RwLock::new(Some(inbound_tx))),
+            inbound_monitor,
+            message_receiver: Arc::new(RwLock::new(Some(inbound_rx))),
             last_activity: Arc::new(RwLock::new(chrono::Utc::now())),
             heartbeat_sequence: Arc::new(RwLock::new(0)),
+            resume_token: Arc::new(RwLock::new(None)),
+            video_stream: Arc::new(RwLock::new(None)),
+            generation: Arc::new(watch::channel(0).0),
+            clock: Arc::new(RwLock::new(ClockEstimator::new())),
         }
     }
 
@@ -81,11 +115,65 @@ impl ProtocolSession {
         &self.session_id
     }
 
+    /// Replace the session ID with the one assigned by the server
+    pub(crate) fn set_session_id(&mut self, session_id: String) {
+        self.session_id = session_id;
+    }
+
     /// Get peer information
     pub fn peer_info(&self) -> &PeerInfo {
         &self.peer_info
     }
 
+    /// Replace peer information learned during the handshake
+    pub(crate) fn set_peer_info(&mut self, peer_info: PeerInfo) {
+        self.peer_info = peer_info;
+    }
+
+    /// Get the negotiated capability set
+    pub fn capabilities(&self) -> &CapabilitySet {
+        &self.peer_info.capabilities
+    }
+
+    /// Check that a message type is covered by the negotiated capabilities
+    pub fn check_capability(&self, message_type: &MessageType) -> ProtocolResult<()> {
+        match message_type.required_capability() {
+            Some(kind) if !self.capabilities().supports(kind) => Err(ProtocolError::CapabilityNotNegotiated(kind)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the negotiated protocol version
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Record the protocol version chosen during the handshake
+    pub(crate) fn set_protocol_version(&mut self, version: ProtocolVersion) {
+        self.protocol_version = version;
+    }
+
+    /// Check if the negotiated protocol version has a feature
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.protocol_version.supports(feature)
+    }
+
+    /// Check that a message type exists in the negotiated protocol version
+    pub fn check_version(&self, message_type: &MessageType) -> ProtocolResult<()> {
+        match Feature::of(message_type) {
+            Some(feature) if !self.supports(feature) => Err(ProtocolError::FeatureNotNegotiated {
+                feature,
+                version: self.protocol_version,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get protocol configuration
+    pub fn config(&self) -> &ProtocolConfig {
+        &self.config
+    }
+
     /// Get current session state
     pub async fn state(&self) -> SessionState {
         self.state.read().await.clone()
@@ -136,14 +224,93 @@ impl ProtocolSession {
         if !self.is_authenticated() && !matches!(message.message_type(), MessageType::Hello | MessageType::AuthRequest) {
             return Err(ProtocolError::Authentication("Session not authenticated".to_string()));
         }
+        self.check_capability(message.message_type())?;
+        self.check_version(message.message_type())?;
+        self.track_video_stream(&message.payload).await;
 
-        self.message_sender.send(message)
+        self.outbound_sender.send(message).await
             .map_err(|e| ProtocolError::Transport(format!("Failed to send message: {}", e)))?;
 
         self.update_activity().await;
         Ok(())
     }
 
+    /// Lock the outbound queue so a driver can write it to the transport.
+    ///
+    /// Waits until the previous driver, if any, has let go of it.
+    pub(crate) async fn lock_outbound(&self) -> tokio::sync::OwnedMutexGuard<QueueReceiver> {
+        self.outbound_receiver.clone().lock_owned().await
+    }
+
+    /// Deliver a message received from the transport to the inbound queue.
+    ///
+    /// Never waits for the application: a full video or input queue drops
+    /// its oldest message, and a full control queue refuses this one with
+    /// `ErrorCode::ServiceUnavailable`.
+    pub(crate) async fn deliver(&self, message: ProtocolMessage) -> ProtocolResult<()> {
+        self.update_activity().await;
+        self.track_video_stream(&message.payload).await;
+        let sender = self.inbound_sender.read().await.clone()
+            .ok_or_else(|| ProtocolError::Session(format!("Inbound queue closed for session {}", self.session_id)))?;
+        match sender.send_lossy(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full) => Err(ProtocolError::Refused {
+                code: ErrorCode::ServiceUnavailable,
+                reason: format!("Inbound control queue full for session {}", self.session_id),
+            }),
+            Err(TrySendError::Closed) => Err(ProtocolError::Session(format!("Inbound queue closed for session {}", self.session_id))),
+        }
+    }
+
+    /// Get the token the peer presents to resume this session
+    pub async fn resume_token(&self) -> Option<String> {
+        self.resume_token.read().await.clone()
+    }
+
+    /// Set the resume token issued in AuthResponse
+    pub(crate) async fn set_resume_token(&self, token: Option<String>) {
+        *self.resume_token.write().await = token;
+    }
+
+    /// Check if the session can be resumed after its transport is lost
+    pub async fn is_resumable(&self) -> bool {
+        self.config.resume_timeout > 0 && self.resume_token.read().await.is_some()
+    }
+
+    /// Get the video stream started on this session, if any
+    pub async fn video_stream(&self) -> Option<VideoStartPayload> {
+        self.video_stream.read().await.clone()
+    }
+
+    async fn track_video_stream(&self, payload: &MessagePayload) {
+        match payload {
+            MessagePayload::VideoStart(start) => *self.video_stream.write().await = Some(start.clone()),
+            MessagePayload::VideoStop => *self.video_stream.write().await = None,
+            _ => {}
+        }
+    }
+
+    /// Current transport attachment; bumped each time the session is resumed
+    pub(crate) fn generation(&self) -> u64 {
+        *self.generation.borrow()
+    }
+
+    /// Watch for the session being resumed on another transport
+    pub(crate) fn subscribe_generation(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+
+    /// Hand the session over to a new transport, stopping the current driver
+    pub(crate) fn supersede(&self) -> u64 {
+        self.generation.send_modify(|generation| *generation += 1);
+        self.generation()
+    }
+
+    /// Close the inbound queue so pending receivers see the end of the stream
+    pub(crate) async fn close_inbound(&self) {
+        self.inbound_sender.write().await.take();
+    }
+
     /// Try to receive a message from this session
     pub async fn try_receive_message(&self) -> Option<ProtocolMessage> {
         let mut receiver_guard = self.message_receiver.write().await;
@@ -153,8 +320,8 @@ impl ProtocolSession {
                     self.update_activity().await;
                     Some(message)
                 }
-                Err(mpsc::error::TryRecvError::Empty) => None,
-                Err(mpsc::error::TryRecvError::Disconnected) => {
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => {
                     warn!("Message receiver disconnected for session {}", self.session_id);
                     None
                 }
@@ -198,20 +365,44 @@ impl ProtocolSession {
         self.send_message(message).await
     }
 
-    /// Handle incoming heartbeat
-    pub async fn handle_heartbeat(&self, sequence: u64) -> ProtocolResult<()> {
-        debug!("Received heartbeat {} for session {}", sequence, self.session_id);
+    /// Handle incoming heartbeat that arrived at `received` (microseconds)
+    pub async fn handle_heartbeat(&self, heartbeat: &ProtocolMessage, received: i64) -> ProtocolResult<()> {
+        if let MessagePayload::Heartbeat(payload) = &heartbeat.payload {
+            debug!("Received heartbeat {} for session {}", payload.sequence_number, self.session_id);
+        }
         self.update_activity().await;
 
-        // Send pong response
-        let message = ProtocolMessage::new(MessageType::Pong, MessagePayload::Pong)
+        // Send pong response; the heartbeat's own timestamp is the originate
+        // time, so every keepalive round also measures the link
+        let pong = PongPayload::answer(heartbeat.header.timestamp.timestamp_micros(), received);
+        let message = ProtocolMessage::new(MessageType::Pong, MessagePayload::Pong(pong))
             .with_session(self.session_id.clone());
 
         self.send_message(message).await
     }
 
+    /// Record a Pong that arrived at `arrival` (microseconds)
+    pub(crate) async fn record_pong(&self, pong: &PongPayload, arrival: i64) {
+        let sample = ClockSample::from_timestamps(pong.originate, pong.receive, pong.transmit, arrival);
+        debug!("Session {} rtt {}us, peer clock offset {}us", self.session_id, sample.rtt, sample.offset);
+        self.clock.write().await.update(sample);
+    }
+
+    /// Get the smoothed round trip and clock offset to the peer
+    pub async fn clock(&self) -> Option<ClockStats> {
+        self.clock.read().await.stats()
+    }
+
+    /// Fill in the network figures of a metrics report
+    pub async fn update_metrics(&self, metrics: &mut Metrics) {
+        if let Some(clock) = self.clock().await {
+            metrics.network_rtt_ms = Some(clock.rtt_ms);
+            metrics.clock_offset_ms = Some(clock.clock_offset_ms);
+        }
+    }
+
     /// Send error message
-    pub async fn send_error(&self, error_code: u32, error_message: String) -> ProtocolResult<()> {
+    pub async fn send_error(&self, error_code: ErrorCode, error_message: String) -> ProtocolResult<()> {
         let payload = MessagePayload::Error(crate::messages::ErrorPayload {
             error_code,
             error_message,
@@ -226,13 +417,15 @@ impl ProtocolSession {
 
     /// Close the session
     pub async fn close(&self) -> ProtocolResult<()> {
-        info!("Closing session {}", self.session_id);
+        self.close_with(ErrorCode::NormalClosure, "Session closed by server".to_string()).await
+    }
+
+    /// Close the session, telling the peer why
+    pub async fn close_with(&self, code: ErrorCode, reason: String) -> ProtocolResult<()> {
+        info!("Closing session {}: {}", self.session_id, code);
 
         // Send goodbye message
-        let payload = MessagePayload::Goodbye(crate::messages::GoodbyePayload {
-            reason: "Session closed by server".to_string(),
-            code: 1000, // Normal closure
-        });
+        let payload = MessagePayload::Goodbye(crate::messages::GoodbyePayload { reason, code });
 
         let message = ProtocolMessage::new(MessageType::Goodbye, payload)
             .with_session(self.session_id.clone());
@@ -256,17 +449,40 @@ impl ProtocolSession {
 
     /// Get session statistics
     pub async fn stats(&self) -> SessionStats {
+        let clock = self.clock().await;
         SessionStats {
             session_id: self.session_id.clone(),
             state: self.state().await,
             peer_id: self.peer_info.peer_id.clone(),
             last_activity: self.last_activity().await,
             is_authenticated: self.is_authenticated(),
-            message_queue_size: 0, // TODO: Implement queue size tracking
+            message_queue_size: self.outbound_sender.stats().len() + self.inbound_monitor.stats().len(),
+            outbound_queue: self.outbound_sender.stats(),
+            inbound_queue: self.inbound_monitor.stats(),
+            rtt_ms: clock.map(|clock| clock.rtt_ms),
+            clock_offset_ms: clock.map(|clock| clock.clock_offset_ms),
         }
     }
 }
 
+/// Shared table of sessions keyed by session ID
+pub type SessionMap = Arc<RwLock<std::collections::HashMap<String, ProtocolSession>>>;
+
+/// Session lifecycle event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionEvent {
+    /// The peer stopped acknowledging heartbeats
+    Suspended { session_id: String, peer_id: String },
+    /// The peer acknowledged a heartbeat again after being suspended
+    Resumed { session_id: String, peer_id: String },
+    /// The session ended and was removed; `code` tells whether reconnecting may help
+    Disconnected { session_id: String, peer_id: String, code: ErrorCode, reason: String },
+    /// Input control moved between sessions; `None` means nobody has it
+    ControlChanged { controller: Option<String>, previous: Option<String> },
+    /// A viewer asked the controller for input control
+    ControlRequested { session_id: String, peer_name: String },
+}
+
 /// Session statistics
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionStats {
@@ -275,7 +491,11 @@ pub struct SessionStats {
     pub peer_id: String,
     pub last_activity: chrono::DateTime<chrono::Utc>,
     pub is_authenticated: bool,
-    pub message_queue_size: usize,
+    pub message_queue_size: usize, // outbound and inbound combined
+    pub outbound_queue: QueueStats,
+    pub inbound_queue: QueueStats,
+    pub rtt_ms: Option<f64>,          // smoothed round trip, once measured
+    pub clock_offset_ms: Option<f64>, // how far the peer's clock is ahead
 }
 
 #[cfg(test)]
@@ -288,7 +508,7 @@ mod tests {
             peer_id: "test-peer".to_string(),
             peer_name: "Test Peer".to_string(),
             address: NetworkAddress::localhost(8080),
-            capabilities: vec!["video".to_string()],
+            capabilities: CapabilitySet::new(vec![Capability::Metrics]),
             authenticated: false,
             last_seen: chrono::Utc::now(),
         };
@@ -307,7 +527,7 @@ mod tests {
             peer_id: "test-peer".to_string(),
             peer_name: "Test Peer".to_string(),
             address: NetworkAddress::localhost(8080),
-            capabilities: vec!["video".to_string()],
+            capabilities: CapabilitySet::new(vec![Capability::Metrics]),
             authenticated: false,
             last_seen: chrono::Utc::now(),
         };
@@ -328,7 +548,7 @@ mod tests {
             peer_id: "test-peer".to_string(),
             peer_name: "Test Peer".to_string(),
             address: NetworkAddress::localhost(8080),
-            capabilities: vec!["video".to_string()],
+            capabilities: CapabilitySet::new(vec![Capability::Metrics]),
             authenticated: false,
             last_seen: chrono::Utc::now(),
         };
@@ -341,4 +561,75 @@ mod tests {
         session.set_authenticated(true);
         assert!(session.is_authenticated());
     }
+
+    #[tokio::test]
+    async fn test_session_rejects_unnegotiated_capability() {
+        let peer_info = PeerInfo {
+            peer_id: "test-peer".to_string(),
+            peer_name: "Test Peer".to_string(),
+            address: NetworkAddress::localhost(8080),
+            capabilities: CapabilitySet::new(vec![Capability::Metrics]),
+            authenticated: true,
+            last_seen: chrono::Utc::now(),
+        };
+
+        let config = ProtocolConfig::default();
+        let session = ProtocolSession::new("test-session".to_string(), peer_info, config);
+
+        let clipboard = ProtocolMessage::new(
+            MessageType::ClipboardData,
+            MessagePayload::ClipboardData(crate::messages::ClipboardPayload {
+                data_type: "text".to_string(),
+                data: b"hello".to_vec(),
+            }),
+        );
+        assert!(matches!(
+            session.send_message(clipboard).await,
+            Err(ProtocolError::CapabilityNotNegotiated(CapabilityKind::Clipboard))
+        ));
+
+        let metrics = ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest);
+        assert!(session.send_message(metrics).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queue_depth_bounded_for_slow_peer() {
+        let peer_info = PeerInfo {
+            peer_id: "test-peer".to_string(),
+            peer_name: "Test Peer".to_string(),
+            address: NetworkAddress::localhost(8080),
+            capabilities: CapabilitySet::full(),
+            authenticated: true,
+            last_seen: chrono::Utc::now(),
+        };
+
+        let config = ProtocolConfig::default();
+        let session = ProtocolSession::new("test-session".to_string(), peer_info, config);
+
+        // Nobody drains the outbound queue: frames pile up, but only so far
+        for frame_number in 0..100 {
+            let frame = ProtocolMessage::new(
+                MessageType::VideoFrame,
+                MessagePayload::VideoFrame(crate::messages::VideoFramePayload {
+                    frame_number,
+                    timestamp: 0,
+                    width: 1920,
+                    height: 1080,
+                    format: "h264".to_string(),
+                    data: vec![0; 1024],
+                }),
+            );
+            session.send_message(frame).await.unwrap();
+        }
+        session.send_message(ProtocolMessage::new(MessageType::MetricsRequest, MessagePayload::MetricsRequest)).await.unwrap();
+
+        let stats = session.stats().await;
+        assert_eq!(stats.outbound_queue.video, session.config().queue.video_capacity);
+        assert_eq!(stats.outbound_queue.dropped_video, 100 - session.config().queue.video_capacity as u64);
+        assert_eq!(stats.message_queue_size, session.config().queue.video_capacity + 1);
+
+        // The control message goes out ahead of the frames
+        let mut outbound = session.lock_outbound().await;
+        assert_eq!(outbound.try_recv().unwrap().message_type(), &MessageType::MetricsRequest);
+    }
 }
@@ -0,0 +1,158 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Round trip and clock offset estimation
+//!
+//! Every Pong carries the three timestamps of an NTP-style exchange: when
+//! the Ping (or Heartbeat) left the requester (`originate`, t1), when it
+//! reached the responder (`receive`, t2) and when the Pong left the
+//! responder (`transmit`, t3). With the arrival time t4 the requester gets
+//!
+//! ```text
+//! rtt    = (t4 - t1) - (t3 - t2)
+//! offset = ((t2 - t1) + (t3 - t4)) / 2
+//! ```
+//!
+//! where `offset` is how far the peer's clock is ahead of ours. RTT is
+//! smoothed like TCP's SRTT (RFC 6298). Offset samples taken over a slow
+//! round trip are the least accurate, so the offset follows the sample with
+//! the lowest RTT among recent ones.
+//!
+//! All timestamps are microseconds since the UNIX epoch.
+
+use serde::{Deserialize, Serialize};
+
+/// Offset samples considered when picking the best one
+const OFFSET_WINDOW: usize = 8;
+
+/// Current time in microseconds since the UNIX epoch
+pub fn now_micros() -> i64 {
+    chrono::Utc::now().timestamp_micros()
+}
+
+/// One completed four-timestamp exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    pub rtt: i64,    // microseconds
+    pub offset: i64, // microseconds the peer is ahead
+}
+
+impl ClockSample {
+    /// Compute a sample from the four timestamps
+    pub fn from_timestamps(originate: i64, receive: i64, transmit: i64, arrival: i64) -> Self {
+        let rtt = (arrival - originate) - (transmit - receive);
+        ClockSample {
+            rtt: rtt.max(0),
+            offset: ((receive - originate) + (transmit - arrival)) / 2,
+        }
+    }
+}
+
+/// Smoothed estimate reported in session stats
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClockStats {
+    pub rtt_ms: f64,
+    pub rtt_variance_ms: f64,
+    pub clock_offset_ms: f64, // how far the peer's clock is ahead of ours
+    pub samples: u64,
+}
+
+impl ClockStats {
+    /// Convert a peer timestamp (microseconds) to the local clock
+    pub fn to_local(&self, peer_micros: i64) -> i64 {
+        peer_micros - (self.clock_offset_ms * 1000.0).round() as i64
+    }
+}
+
+/// Running RTT and clock offset estimate for one peer
+#[derive(Debug, Clone, Default)]
+pub struct ClockEstimator {
+    srtt: f64,
+    rttvar: f64,
+    recent: Vec<ClockSample>,
+    samples: u64,
+}
+
+impl ClockEstimator {
+    /// Create an empty estimator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a completed exchange
+    pub fn update(&mut self, sample: ClockSample) {
+        let rtt = sample.rtt as f64;
+        if self.samples == 0 {
+            self.srtt = rtt;
+            self.rttvar = rtt / 2.0;
+        } else {
+            self.rttvar = 0.75 * self.rttvar + 0.25 * (self.srtt - rtt).abs();
+            self.srtt = 0.875 * self.srtt + 0.125 * rtt;
+        }
+        self.samples += 1;
+
+        if self.recent.len() == OFFSET_WINDOW {
+            self.recent.remove(0);
+        }
+        self.recent.push(sample);
+    }
+
+    /// Get the current estimate, if any exchange has completed
+    pub fn stats(&self) -> Option<ClockStats> {
+        let best = self.recent.iter().min_by_key(|sample| sample.rtt)?;
+        Some(ClockStats {
+            rtt_ms: self.srtt / 1000.0,
+            rtt_variance_ms: self.rttvar / 1000.0,
+            clock_offset_ms: best.offset as f64 / 1000.0,
+            samples: self.samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_from_timestamps() {
+        // Peer clock 5s ahead, 10ms each way, 2ms processing
+        let t1 = 1_000_000;
+        let t2 = t1 + 10_000 + 5_000_000;
+        let t3 = t2 + 2_000;
+        let t4 = t1 + 22_000;
+
+        let sample = ClockSample::from_timestamps(t1, t2, t3, t4);
+        assert_eq!(sample.rtt, 20_000);
+        assert_eq!(sample.offset, 5_000_000);
+    }
+
+    #[test]
+    fn test_estimator_smooths_rtt_and_prefers_fast_offsets() {
+        let mut estimator = ClockEstimator::new();
+        assert!(estimator.stats().is_none());
+
+        estimator.update(ClockSample { rtt: 20_000, offset: 1_000 });
+        let stats = estimator.stats().unwrap();
+        assert_eq!(stats.rtt_ms, 20.0);
+        assert_eq!(stats.clock_offset_ms, 1.0);
+
+        // A congested exchange nudges the RTT but not the offset
+        estimator.update(ClockSample { rtt: 100_000, offset: 40_000 });
+        let stats = estimator.stats().unwrap();
+        assert_eq!(stats.rtt_ms, 30.0);
+        assert_eq!(stats.clock_offset_ms, 1.0);
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.to_local(5_000), 4_000);
+    }
+}
@@ -14,12 +14,12 @@
 
 //! Transport layer abstraction
 
+use crate::queue::{self, QueueConfig, QueueReceiver, QueueSender};
 use crate::{messages::ProtocolMessage, ProtocolResult};
 use async_trait::async_trait;
 use tracing::{debug, info, warn, error};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc;
 
 /// Transport connection trait
 #[async_trait]
@@ -38,13 +38,19 @@ pub trait TransportConnection: Send + Sync {
 
     /// Check if connection is alive
     fn is_alive(&self) -> bool;
+
+    /// Switch the codec used for outgoing messages.
+    ///
+    /// Called once Hello/Welcome has selected a codec and compression.
+    /// Transports that do not frame messages themselves can ignore it.
+    fn set_codec(&mut self, _codec: std::sync::Arc<dyn crate::codec::MessageCodec>) {}
 }
 
 /// Connection handle for managing connections
 pub struct ConnectionHandle {
     pub remote_addr: SocketAddr,
-    pub sender: mpsc::UnboundedSender<ProtocolMessage>,
-    pub receiver: mpsc::UnboundedReceiver<ProtocolMessage>,
+    pub sender: QueueSender,
+    pub receiver: QueueReceiver,
 }
 
 /// Transport listener trait
@@ -69,6 +75,7 @@ pub struct TlsConfig {
     pub ca_certificate_path: Option<String>,
     pub accept_invalid_certs: bool,
     pub accept_invalid_hostnames: bool,
+    pub pinned_fingerprint: Option<String>, // SHA-256 of the server's DER certificate in hex; replaces CA and hostname checks
 }
 
 /// Transport configuration
@@ -77,9 +84,12 @@ pub struct TransportConfig {
     pub max_connections: usize,
     pub read_timeout: u64,  // seconds
     pub write_timeout: u64, // seconds
-    pub buffer_size: usize,
+    pub buffer_size: usize,       // larger messages are fragmented
+    pub max_message_size: usize,  // largest message sent or accepted
+    pub reassembly_timeout: u64,  // seconds to receive all fragments of a message
     pub compression: bool,
     pub tls: TlsConfig,
+    pub queue: QueueConfig, // per-connection queues in TransportManager
 }
 
 impl Default for TransportConfig {
@@ -89,8 +99,11 @@ impl Default for TransportConfig {
             read_timeout: 30,
             write_timeout: 30,
             buffer_size: 64 * 1024, // 64KB
+            max_message_size: 1024 * 1024, // 1MB
+            reassembly_timeout: 10,
             compression: true,
             tls: TlsConfig::default(),
+            queue: QueueConfig::default(),
         }
     }
 }
@@ -104,6 +117,7 @@ impl Default for TlsConfig {
             ca_certificate_path: None,
             accept_invalid_certs: false,
             accept_invalid_hostnames: false,
+            pinned_fingerprint: None,
         }
     }
 }
@@ -122,7 +136,7 @@ pub trait TransportFactory: Send + Sync {
 #[async_trait]
 pub trait MessageHandler: Send + Sync {
     /// Handle an incoming message
-    async fn handle_message(&mut self, message: ProtocolMessage, sender: mpsc::UnboundedSender<ProtocolMessage>) -> ProtocolResult<()>;
+    async fn handle_message(&mut self, message: ProtocolMessage, sender: QueueSender) -> ProtocolResult<()>;
 
     /// Handle connection opened
     async fn on_connection_opened(&mut self, remote_addr: SocketAddr) -> ProtocolResult<()> {
@@ -144,7 +158,7 @@ pub trait MessageHandler: Send + Sync {
 pub struct TransportManager<F> {
     factory: F,
     config: TransportConfig,
-    connections: std::sync::Arc<tokio::sync::RwLock<std::collections::HashMap<SocketAddr, mpsc::UnboundedSender<ProtocolMessage>>>>,
+    connections: std::sync::Arc<tokio::sync::RwLock<std::collections::HashMap<SocketAddr, QueueSender>>>,
     shutdown_sender: tokio::sync::broadcast::Sender<()>,
 }
 
@@ -192,9 +206,9 @@ where
                                     }
                                 };
 
-                                // Create channels for this connection
-                                let (tx_to_connection, mut rx_to_connection) = mpsc::unbounded_channel::<ProtocolMessage>();
-                                let (tx_from_connection, rx_from_connection) = mpsc::unbounded_channel::<ProtocolMessage>();
+                                // Messages queued here are written to the connection;
+                                // the handler replies through the same channel
+                                let (tx_to_connection, mut rx_to_connection) = queue::channel(config.queue.clone());
 
                                 // Handle connection opened
                                 if let Err(e) = handler.on_connection_opened(remote_addr).await {
@@ -203,7 +217,7 @@ where
                                 }
 
                                 // Store sender for this connection
-                                let tx_for_storage = tx_from_connection.clone();
+                                let tx_for_storage = tx_to_connection.clone();
                                 {
                                     let mut connections = connections.write().await;
                                     connections.insert(remote_addr, tx_for_storage);
@@ -222,7 +236,7 @@ where
                                             receive_result = connection_reader.receive() => {
                                                 match receive_result {
                                                     Ok(Some(message)) => {
-                                                        if let Err(e) = handler_clone.handle_message(message, tx_from_connection.clone()).await {
+                                                        if let Err(e) = handler_clone.handle_message(message, tx_to_connection.clone()).await {
                                                             error!("Failed to handle message: {}", e);
                                                             break;
                                                         }
@@ -288,7 +302,7 @@ where
     }
 
     /// Connect to a remote address
-    pub async fn connect<H>(&self, addr: SocketAddr, mut handler: H) -> ProtocolResult<mpsc::UnboundedReceiver<ProtocolMessage>>
+    pub async fn connect<H>(&self, addr: SocketAddr, mut handler: H) -> ProtocolResult<QueueReceiver>
     where
         H: MessageHandler + Send + Sync + Clone + 'static,
     {
@@ -298,11 +312,12 @@ where
         handler.on_connection_opened(addr).await?;
 
         // Create channels for this connection
-        let (tx_to_connection, mut rx_to_connection) = mpsc::unbounded_channel::<ProtocolMessage>();
-        let (tx_from_connection, rx_from_connection) = mpsc::unbounded_channel::<ProtocolMessage>();
+        let (tx_to_connection, mut rx_to_connection) = queue::channel(self.config.queue.clone());
+        let (tx_from_connection, rx_from_connection) = queue::channel(self.config.queue.clone());
 
-        // Store sender for this connection
-        let tx_for_storage = tx_from_connection.clone();
+        // Store sender for this connection; the handler forwards what it
+        // receives to the returned receiver
+        let tx_for_storage = tx_to_connection.clone();
         {
             let mut connections = self.connections.write().await;
             connections.insert(addr, tx_for_storage);
@@ -373,23 +388,23 @@ where
 
     /// Send message to specific address
     pub async fn send_to(&self, addr: SocketAddr, message: ProtocolMessage) -> ProtocolResult<()> {
-        let connections = self.connections.read().await;
-        if let Some(sender) = connections.get(&addr) {
-            sender.send(message)
-                .map_err(|e| crate::ProtocolError::Transport(format!("Failed to send message: {}", e)))?;
-        } else {
-            return Err(crate::ProtocolError::Transport(format!("No connection to {}", addr)));
-        }
-        Ok(())
+        // Don't hold the table while waiting on a full queue
+        let sender = self.connections.read().await.get(&addr).cloned()
+            .ok_or_else(|| crate::ProtocolError::Transport(format!("No connection to {}", addr)))?;
+        sender.send(message).await
+            .map_err(|e| crate::ProtocolError::Transport(format!("Failed to send message: {}", e)))
     }
 
-    /// Broadcast message to all connections
+    /// Broadcast message to all connections.
+    ///
+    /// Never waits: a connection whose queue is full reports an error
+    /// instead of holding up the others.
     pub async fn broadcast(&self, message: ProtocolMessage) -> ProtocolResult<()> {
         let connections = self.connections.read().await;
         let mut send_errors = Vec::new();
 
         for (addr, sender) in connections.iter() {
-            if let Err(e) = sender.send(message.clone()) {
+            if let Err(e) = sender.try_send(message.clone()) {
                 send_errors.push(format!("{}: {}", addr, e));
             }
         }
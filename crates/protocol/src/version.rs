@@ -0,0 +1,206 @@
+// Copyright 2024 Soft KVM Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protocol version negotiation
+//!
+//! Each peer speaks a range of protocol versions, `ProtocolConfig::min_version`
+//! up to `ProtocolConfig::version`. The client sends both ends of its range in
+//! Hello and the server answers with the highest version in both ranges in
+//! `ServerInfo::protocol_version`. A peer that predates ranges only sends
+//! `protocol_version` and is taken to speak just that version.
+//!
+//! 1.1 changed the wire shape of existing messages: Hello carries a typed
+//! capability list instead of strings, and Ping/Pong carry timestamps. A 1.0
+//! peer can't decode either, so 1.1 is the oldest version spoken.
+//!
+//! Everything added to the protocol since is listed in [`Feature`] with the
+//! version that introduced it. A session only uses a feature when the
+//! negotiated version has it, so old and new peers can share a LAN during a
+//! rollout. Fields added to existing payloads are `#[serde(default)]`, so an
+//! older peer's messages still decode.
+
+use crate::messages::MessageType;
+use crate::{ProtocolError, ProtocolResult};
+use std::fmt;
+use std::str::FromStr;
+
+/// Oldest protocol version this implementation still speaks
+pub const MIN_PROTOCOL_VERSION: &str = "1.1.0";
+
+/// Protocol version (`major.minor.patch`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ProtocolVersion {
+    /// Create a version
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        ProtocolVersion { major, minor, patch }
+    }
+
+    /// Version of this implementation (`PROTOCOL_VERSION`)
+    pub fn current() -> Self {
+        crate::PROTOCOL_VERSION.parse().expect("PROTOCOL_VERSION is a valid version")
+    }
+
+    /// Check if this version has a feature
+    pub fn supports(&self, feature: Feature) -> bool {
+        *self >= feature.since()
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = ProtocolError;
+
+    fn from_str(version: &str) -> ProtocolResult<Self> {
+        let invalid = || ProtocolError::Generic(format!("Invalid protocol version: {}", version));
+        let mut parts = version.split('.').map(|part| part.parse::<u32>().map_err(|_| invalid()));
+        let mut next = || parts.next().unwrap_or_else(|| Err(invalid()));
+        let parsed = ProtocolVersion::new(next()?, next()?, next()?);
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Inclusive range of protocol versions a peer speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min: ProtocolVersion,
+    pub max: ProtocolVersion,
+}
+
+impl VersionRange {
+    /// Parse a range; a missing minimum means only `max` is spoken
+    pub fn parse(min: Option<&str>, max: &str) -> ProtocolResult<Self> {
+        let max: ProtocolVersion = max.parse()?;
+        let min = match min {
+            Some(min) => min.parse()?,
+            None => max,
+        };
+        if min > max {
+            return Err(ProtocolError::Generic(format!("Empty protocol version range {} - {}", min, max)));
+        }
+        Ok(VersionRange { min, max })
+    }
+
+    /// Check if a version is in the range
+    pub fn contains(&self, version: &ProtocolVersion) -> bool {
+        self.min <= *version && *version <= self.max
+    }
+
+    /// Highest version in both ranges
+    pub fn negotiate(&self, other: &VersionRange) -> Option<ProtocolVersion> {
+        let highest = self.max.min(other.max);
+        (highest >= self.min.max(other.min)).then_some(highest)
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.max)
+        } else {
+            write!(f, "{} - {}", self.min, self.max)
+        }
+    }
+}
+
+/// Additions to the protocol since 1.0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// `MessageType::Ping` and timestamped Pongs (see `crate::timing`)
+    TimedPing,
+    /// Payload compression negotiated in Hello/Welcome
+    Compression,
+    /// `MessageHeader::correlation_id` and `MessageRouter::request`
+    CorrelatedRequests,
+    /// `MessageType::Control` and multi-viewer arbitration (see `crate::control`)
+    Control,
+}
+
+impl Feature {
+    /// Version that introduced the feature
+    pub fn since(&self) -> ProtocolVersion {
+        match self {
+            Feature::TimedPing | Feature::Compression | Feature::CorrelatedRequests => ProtocolVersion::new(1, 1, 0),
+            Feature::Control => ProtocolVersion::new(1, 2, 0),
+        }
+    }
+
+    /// Feature a message type belongs to, if it was added after 1.0
+    pub fn of(message_type: &MessageType) -> Option<Feature> {
+        match message_type {
+            MessageType::Ping => Some(Feature::TimedPing),
+            MessageType::Control => Some(Feature::Control),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(min: &str, max: &str) -> VersionRange {
+        VersionRange::parse(Some(min), max).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_order() {
+        let version: ProtocolVersion = "1.10.2".parse().unwrap();
+        assert_eq!(version, ProtocolVersion::new(1, 10, 2));
+        assert_eq!(version.to_string(), "1.10.2");
+        assert!(version > "1.9.7".parse().unwrap());
+
+        for invalid in ["", "1", "1.0", "1.0.0.0", "1.x.0", "v1.0.0"] {
+            assert!(invalid.parse::<ProtocolVersion>().is_err(), "{}", invalid);
+        }
+        assert!(VersionRange::parse(Some("1.2.0"), "1.1.0").is_err());
+    }
+
+    #[test]
+    fn test_negotiate_picks_highest_common_version() {
+        assert_eq!(range("1.0.0", "1.1.0").negotiate(&range("1.0.0", "1.3.0")), Some(ProtocolVersion::new(1, 1, 0)));
+        assert_eq!(range("1.2.0", "2.0.0").negotiate(&range("1.0.0", "1.4.0")), Some(ProtocolVersion::new(1, 4, 0)));
+        assert_eq!(range("1.0.0", "1.1.0").negotiate(&range("2.0.0", "2.1.0")), None);
+
+        // A peer without a range speaks only its version
+        let legacy = VersionRange::parse(None, "1.0.0").unwrap();
+        assert_eq!(range("1.0.0", "1.1.0").negotiate(&legacy), Some(ProtocolVersion::new(1, 0, 0)));
+    }
+
+    #[test]
+    fn test_features_are_gated_by_version() {
+        let legacy = ProtocolVersion::new(1, 0, 0);
+        let current = ProtocolVersion::current();
+        assert_eq!(Feature::of(&MessageType::Ping), Some(Feature::TimedPing));
+        assert_eq!(Feature::of(&MessageType::Heartbeat), None);
+        assert!(!legacy.supports(Feature::Compression));
+        assert!(current.supports(Feature::Compression));
+        assert!(current.supports(Feature::TimedPing));
+        assert!(current.supports(Feature::Control));
+        assert!(!ProtocolVersion::new(1, 1, 0).supports(Feature::Control));
+    }
+}
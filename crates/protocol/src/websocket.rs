@@ -14,21 +14,66 @@
 
 //! WebSocket over TLS transport implementation
 
-use crate::{messages::ProtocolMessage, transport::{TransportConnection, TransportListener, TransportFactory, TransportConfig}, ProtocolResult, ProtocolError};
+use crate::{codec::{CodecKind, MessageCodec}, fragment::{self, Fragmenter, Reassembler}, messages::ProtocolMessage, transport::{TlsConfig, TransportConnection, TransportListener, TransportFactory, TransportConfig}, ProtocolResult, ProtocolError};
 use async_trait::async_trait;
 use futures::{SinkExt, StreamExt};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, connect_async, connect_async_with_config, tungstenite::Message, MaybeTlsStream, Connector};
-use tokio_rustls::TlsAcceptor;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{accept_async_with_config, connect_async_tls_with_config, tungstenite::{protocol::WebSocketConfig, Message}, MaybeTlsStream, Connector, WebSocketStream};
 use rustls::OwnedTrustAnchor;
 
-/// Dangerous TLS configuration for development
+/// Server certificate verifiers replacing the WebPKI checks
 mod dangerous {
-    use rustls::client::ServerCertVerifier;
-    use rustls::{Certificate, ServerName, Error};
+    use rustls::client::{ServerCertVerifier, WebPkiVerifier};
+    use rustls::{Certificate, CertificateError, ServerName, Error};
+
+    /// SHA-256 of a DER certificate as lowercase hex
+    pub fn fingerprint(certificate: &Certificate) -> String {
+        ring::digest::digest(&ring::digest::SHA256, &certificate.0)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Accepts only the certificate with a known fingerprint
+    /// (`TlsConfig::pinned_fingerprint`)
+    pub struct PinnedCertificate {
+        pub fingerprint: String,
+    }
+
+    impl PinnedCertificate {
+        /// Pin a fingerprint, written with or without colons
+        pub fn new(fingerprint: &str) -> Self {
+            PinnedCertificate {
+                fingerprint: fingerprint.replace(':', "").to_ascii_lowercase(),
+            }
+        }
+    }
+
+    impl ServerCertVerifier for PinnedCertificate {
+        fn verify_server_cert(
+            &self,
+            end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: std::time::SystemTime,
+        ) -> Result<rustls::client::ServerCertVerified, Error> {
+            if fingerprint(end_entity) == self.fingerprint {
+                Ok(rustls::client::ServerCertVerified::assertion())
+            } else {
+                Err(Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+            }
+        }
+    }
 
+    /// Accepts any server certificate (`TlsConfig::accept_invalid_certs`)
     pub struct NoCertificateVerification;
 
     impl ServerCertVerifier for NoCertificateVerification {
@@ -44,17 +89,73 @@ mod dangerous {
             Ok(rustls::client::ServerCertVerified::assertion())
         }
     }
+
+    /// Verifies the certificate chain but not the host name
+    /// (`TlsConfig::accept_invalid_hostnames`)
+    pub struct NoHostnameVerification {
+        pub inner: WebPkiVerifier,
+    }
+
+    impl ServerCertVerifier for NoHostnameVerification {
+        fn verify_server_cert(
+            &self,
+            end_entity: &Certificate,
+            intermediates: &[Certificate],
+            server_name: &ServerName,
+            scts: &mut dyn Iterator<Item = &[u8]>,
+            ocsp_response: &[u8],
+            now: std::time::SystemTime,
+        ) -> Result<rustls::client::ServerCertVerified, Error> {
+            match self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now) {
+                Err(Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                    Ok(rustls::client::ServerCertVerified::assertion())
+                }
+                result => result,
+            }
+        }
+    }
 }
 use tracing::{debug, info, warn, error};
 
 /// WebSocket over TLS connection
-pub struct WebSocketConnection {
-    stream: tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>,
+///
+/// Client connections and plain server connections use `MaybeTlsStream`;
+/// server connections accepted over TLS use the rustls server stream.
+pub struct WebSocketConnection<S = MaybeTlsStream<TcpStream>> {
+    stream: WebSocketStream<S>,
     remote_addr: SocketAddr,
     config: TransportConfig,
+    codec: Arc<dyn MessageCodec>,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
     is_alive: bool,
 }
 
+/// Limit what tungstenite buffers to what we would accept anyway
+fn websocket_config(config: &TransportConfig) -> WebSocketConfig {
+    let limit = config.max_message_size.max(config.buffer_size) + fragment::FRAGMENT_HEADER_LEN;
+    WebSocketConfig {
+        max_message_size: Some(limit),
+        max_frame_size: Some(limit),
+        ..WebSocketConfig::default()
+    }
+}
+
+impl<S> WebSocketConnection<S> {
+    /// Wrap an established WebSocket stream
+    fn from_stream(stream: WebSocketStream<S>, remote_addr: SocketAddr, config: TransportConfig) -> Self {
+        WebSocketConnection {
+            stream,
+            remote_addr,
+            fragmenter: Fragmenter::new(&config),
+            reassembler: Reassembler::new(&config),
+            config,
+            codec: CodecKind::Json.codec(),
+            is_alive: true,
+        }
+    }
+}
+
 impl WebSocketConnection {
     /// Create a new WebSocket connection from a raw stream
     pub async fn new(stream: TcpStream, config: TransportConfig) -> ProtocolResult<Self> {
@@ -62,16 +163,11 @@ impl WebSocketConnection {
             .map_err(|e| ProtocolError::Transport(format!("Failed to get peer address: {}", e)))?;
 
         // Accept WebSocket connection
-        let ws_stream = accept_async(tokio_tungstenite::MaybeTlsStream::Plain(stream))
+        let ws_stream = accept_async_with_config(tokio_tungstenite::MaybeTlsStream::Plain(stream), Some(websocket_config(&config)))
             .await
             .map_err(|e| ProtocolError::WebSocket(format!("Failed to accept WebSocket: {}", e)))?;
 
-        Ok(WebSocketConnection {
-            stream: ws_stream,
-            remote_addr,
-            config,
-            is_alive: true,
-        })
+        Ok(WebSocketConnection::from_stream(ws_stream, remote_addr, config))
     }
 
     /// Create a client WebSocket connection with optional TLS
@@ -81,46 +177,136 @@ impl WebSocketConnection {
         let url = url::Url::parse(&url)
             .map_err(|e| ProtocolError::Transport(format!("Invalid URL: {}", e)))?;
 
-        // For now, use the simple connect method
-        // TLS configuration will be handled by the URL scheme (wss://)
-        let (ws_stream, _) = connect_async(url)
+        let connector = if config.tls.enabled {
+            Some(Connector::Rustls(Arc::new(create_client_config(&config.tls).await?)))
+        } else {
+            Some(Connector::Plain)
+        };
+
+        let (ws_stream, _) = connect_async_tls_with_config(url, Some(websocket_config(&config)), true, connector)
             .await
             .map_err(|e| ProtocolError::WebSocket(format!("Failed to connect WebSocket: {}", e)))?;
 
-        Ok(WebSocketConnection {
-            stream: ws_stream,
-            remote_addr: addr,
-            config,
-            is_alive: true,
-        })
+        Ok(WebSocketConnection::from_stream(ws_stream, addr, config))
     }
 }
 
+/// Build the rustls client configuration from transport TLS settings
+pub(crate) async fn create_client_config(tls_config: &TlsConfig) -> ProtocolResult<rustls::ClientConfig> {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+
+    // 追加のCA証明書（自己署名のサーバー証明書など）
+    if let Some(ca_path) = &tls_config.ca_certificate_path {
+        for cert in load_certs(ca_path).await? {
+            root_store.add(&cert)
+                .map_err(|e| ProtocolError::Transport(format!("Invalid CA certificate: {}", e)))?;
+        }
+    }
+
+    let mut client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store.clone())
+        .with_no_client_auth();
+
+    if let Some(fingerprint) = &tls_config.pinned_fingerprint {
+        debug!("Accepting only the server certificate {}", fingerprint);
+        client_config.dangerous()
+            .set_certificate_verifier(Arc::new(dangerous::PinnedCertificate::new(fingerprint)));
+    } else if tls_config.accept_invalid_certs {
+        warn!("TLS certificate verification disabled");
+        client_config.dangerous()
+            .set_certificate_verifier(Arc::new(dangerous::NoCertificateVerification));
+    } else if tls_config.accept_invalid_hostnames {
+        warn!("TLS hostname verification disabled");
+        client_config.dangerous()
+            .set_certificate_verifier(Arc::new(dangerous::NoHostnameVerification {
+                inner: rustls::client::WebPkiVerifier::new(root_store, None),
+            }));
+    }
+
+    Ok(client_config)
+}
+
+/// Load certificates from a PEM file
+pub(crate) async fn load_certs(path: &str) -> ProtocolResult<Vec<rustls::Certificate>> {
+    let cert_data = tokio::fs::read(path)
+        .await
+        .map_err(|e| ProtocolError::Transport(format!("Failed to read certificate file: {}", e)))?;
+
+    let mut reader = std::io::Cursor::new(cert_data);
+    rustls_pemfile::certs(&mut reader)
+        .map_err(|_| ProtocolError::Transport("Failed to parse certificate".to_string()))?
+        .into_iter()
+        .map(|cert| Ok(rustls::Certificate(cert)))
+        .collect()
+}
+
+/// Load a PKCS8 private key from a PEM file
+pub(crate) async fn load_private_key(path: &str) -> ProtocolResult<rustls::PrivateKey> {
+    let key_data = tokio::fs::read(path)
+        .await
+        .map_err(|e| ProtocolError::Transport(format!("Failed to read private key file: {}", e)))?;
+
+    let mut reader = std::io::Cursor::new(key_data);
+    let key = rustls_pemfile::pkcs8_private_keys(&mut reader)
+        .map_err(|_| ProtocolError::Transport("Failed to parse PKCS8 private key".to_string()))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ProtocolError::Transport("No PKCS8 private key found".to_string()))?;
+
+    Ok(rustls::PrivateKey(key))
+}
+
+/// Build the rustls server configuration from transport TLS settings
+pub(crate) async fn create_server_config(tls_config: &TlsConfig) -> ProtocolResult<rustls::ServerConfig> {
+    // 証明書と秘密鍵を読み込み
+    let cert_path = tls_config.certificate_path.as_ref()
+        .ok_or_else(|| ProtocolError::Transport("Certificate path not specified".to_string()))?;
+    let key_path = tls_config.private_key_path.as_ref()
+        .ok_or_else(|| ProtocolError::Transport("Private key path not specified".to_string()))?;
+
+    // 証明書を読み込み
+    let certs = load_certs(cert_path).await?;
+    let key = load_private_key(key_path).await?;
+
+    // TLS設定を作成
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| ProtocolError::Transport(format!("TLS config error: {}", e)))
+}
+
 #[async_trait]
-impl TransportConnection for WebSocketConnection {
+impl<S> TransportConnection for WebSocketConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
     async fn send(&mut self, message: ProtocolMessage) -> ProtocolResult<()> {
         if !self.is_alive {
             return Err(ProtocolError::Transport("Connection is closed".to_string()));
         }
 
-        // Serialize message using JSON
-        let data = serde_json::to_string(&message)
-            .map_err(|e| ProtocolError::Serialization(e))?;
-
-        // Check message size
-        if data.len() > self.config.buffer_size {
-            return Err(ProtocolError::Transport(format!(
-                "Message size {} exceeds buffer size {}",
-                data.len(),
-                self.config.buffer_size
-            )));
+        // Serialize message with the negotiated codec
+        let data = self.codec.encode(&message)?;
+
+        // Messages larger than the buffer go out in chunks, each as a
+        // WebSocket binary message
+        let frames = self.fragmenter.split(data)?;
+        let last = frames.len() - 1;
+        for (index, frame) in frames.into_iter().enumerate() {
+            let ws_message = Message::Binary(frame);
+            let result = if index == last {
+                self.stream.send(ws_message).await
+            } else {
+                self.stream.feed(ws_message).await
+            };
+            result.map_err(|e| ProtocolError::WebSocket(format!("Failed to send message: {}", e)))?;
         }
 
-        // Send as WebSocket binary message
-        let ws_message = Message::Binary(data.into_bytes());
-        self.stream.send(ws_message).await
-            .map_err(|e| ProtocolError::WebSocket(format!("Failed to send message: {}", e)))?;
-
         debug!("Sent message to {}", self.remote_addr);
         Ok(())
     }
@@ -133,55 +319,48 @@ impl TransportConnection for WebSocketConnection {
         // Set read timeout
         let timeout_duration = std::time::Duration::from_secs(self.config.read_timeout);
 
-        match tokio::time::timeout(timeout_duration, self.stream.next()).await {
-            Ok(Some(Ok(message))) => {
-                match message {
-                    Message::Binary(data) => {
-                        // Deserialize message using JSON
-                        let protocol_message: ProtocolMessage = serde_json::from_slice(&data)
-                            .map_err(|e| ProtocolError::Serialization(e))?;
-
-                        debug!("Received message from {}", self.remote_addr);
-                        Ok(Some(protocol_message))
-                    }
-                    Message::Text(text) => {
-                        // Handle text messages if needed (for debugging)
-                        warn!("Received text message from {}, ignoring: {}", self.remote_addr, text);
-                        Ok(None)
-                    }
-                    Message::Ping(_) => {
-                        // Auto-pong is handled by tungstenite
-                        Ok(None)
-                    }
-                    Message::Pong(_) => {
-                        // Pong received
-                        Ok(None)
-                    }
-                    Message::Close(_) => {
-                        info!("WebSocket close frame received from {}", self.remote_addr);
-                        self.is_alive = false;
-                        Ok(None)
-                    }
-                    Message::Frame(_) => {
-                        // Raw frame, ignore for now
-                        Ok(None)
+        // Control frames are handled by tungstenite, keep reading until a
+        // data frame or the end of the stream
+        loop {
+            match tokio::time::timeout(timeout_duration, self.stream.next()).await {
+                Ok(Some(Ok(message))) => {
+                    match message {
+                        Message::Binary(data) => {
+                            // The peer may still be on the bootstrap codec, so detect it per frame
+                            if let Some(protocol_message) = self.reassembler.push(data)? {
+                                debug!("Received message from {}", self.remote_addr);
+                                return Ok(Some(protocol_message));
+                            }
+                        }
+                        Message::Text(text) => {
+                            // Handle text messages if needed (for debugging)
+                            warn!("Received text message from {}, ignoring: {}", self.remote_addr, text);
+                        }
+                        Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {
+                            // Auto-pong is handled by tungstenite
+                        }
+                        Message::Close(_) => {
+                            info!("WebSocket close frame received from {}", self.remote_addr);
+                            self.is_alive = false;
+                            return Ok(None);
+                        }
                     }
                 }
-            }
-            Ok(Some(Err(e))) => {
-                error!("WebSocket error from {}: {}", self.remote_addr, e);
-                self.is_alive = false;
-                Err(ProtocolError::WebSocket(format!("WebSocket error: {}", e)))
-            }
-            Ok(None) => {
-                // Stream ended
-                info!("WebSocket stream ended for {}", self.remote_addr);
-                self.is_alive = false;
-                Ok(None)
-            }
-            Err(_) => {
-                // Timeout
-                Err(ProtocolError::Timeout)
+                Ok(Some(Err(e))) => {
+                    error!("WebSocket error from {}: {}", self.remote_addr, e);
+                    self.is_alive = false;
+                    return Err(ProtocolError::WebSocket(format!("WebSocket error: {}", e)));
+                }
+                Ok(None) => {
+                    // Stream ended
+                    info!("WebSocket stream ended for {}", self.remote_addr);
+                    self.is_alive = false;
+                    return Ok(None);
+                }
+                Err(_) => {
+                    // Timeout
+                    return Err(ProtocolError::Timeout);
+                }
             }
         }
     }
@@ -203,13 +382,22 @@ impl TransportConnection for WebSocketConnection {
     fn is_alive(&self) -> bool {
         self.is_alive
     }
+
+    fn set_codec(&mut self, codec: Arc<dyn MessageCodec>) {
+        debug!("Switching codec for {} to {:?}", self.remote_addr, codec.kind());
+        self.codec = codec;
+    }
 }
 
 /// WebSocket listener with optional TLS
+///
+/// Each accepted TCP connection is upgraded on its own task, so a client
+/// that stalls in the TLS or WebSocket handshake holds up nobody else.
+/// Upgraded connections queue up for `accept`.
 pub struct WebSocketListener {
-    listener: TcpListener,
-    config: TransportConfig,
-    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    local_addr: SocketAddr,
+    connections: mpsc::Receiver<Box<dyn TransportConnection>>,
+    acceptor: JoinHandle<()>,
 }
 
 impl WebSocketListener {
@@ -218,6 +406,8 @@ impl WebSocketListener {
         let listener = TcpListener::bind(addr)
             .await
             .map_err(|e| ProtocolError::Transport(format!("Failed to bind listener: {}", e)))?;
+        let local_addr = listener.local_addr()
+            .map_err(|e| ProtocolError::Transport(format!("Failed to get local address: {}", e)))?;
 
         // TLS設定がある場合はTLSアクセプタを作成
         let tls_acceptor = if config.tls.enabled {
@@ -226,109 +416,132 @@ impl WebSocketListener {
             None
         };
 
-        info!("WebSocket listener bound to {} (TLS: {})", addr, config.tls.enabled);
+        info!("WebSocket listener bound to {} (TLS: {})", local_addr, config.tls.enabled);
+
+        let (sender, connections) = mpsc::channel(config.max_connections.max(1));
+        let acceptor = tokio::spawn(Self::accept_loop(listener, config, tls_acceptor, sender));
 
         Ok(WebSocketListener {
-            listener,
-            config,
-            tls_acceptor,
+            local_addr,
+            connections,
+            acceptor,
         })
     }
 
     /// Create TLS acceptor from configuration
-    async fn create_tls_acceptor(tls_config: &super::transport::TlsConfig) -> ProtocolResult<tokio_rustls::TlsAcceptor> {
-        // 証明書と秘密鍵を読み込み
-        let cert_path = tls_config.certificate_path.as_ref()
-            .ok_or_else(|| ProtocolError::Transport("Certificate path not specified".to_string()))?;
-        let key_path = tls_config.private_key_path.as_ref()
-            .ok_or_else(|| ProtocolError::Transport("Private key path not specified".to_string()))?;
-
-        // 証明書を読み込み
-        let certs = Self::load_certs(cert_path).await?;
-        let key = Self::load_private_key(key_path).await?;
-
-        // TLS設定を作成
-        let mut config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .map_err(|e| ProtocolError::Transport(format!("TLS config error: {}", e)))?;
-
+    async fn create_tls_acceptor(tls_config: &TlsConfig) -> ProtocolResult<tokio_rustls::TlsAcceptor> {
+        let config = create_server_config(tls_config).await?;
         Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
     }
 
-    /// Load certificates from file
-    async fn load_certs(path: &str) -> ProtocolResult<Vec<rustls::Certificate>> {
-        let cert_data = tokio::fs::read(path)
-            .await
-            .map_err(|e| ProtocolError::Transport(format!("Failed to read certificate file: {}", e)))?;
-
-        let mut reader = std::io::Cursor::new(cert_data);
-        rustls_pemfile::certs(&mut reader)
-            .map_err(|_| ProtocolError::Transport("Failed to parse certificate".to_string()))?
-            .into_iter()
-            .map(|cert| Ok(rustls::Certificate(cert)))
-            .collect()
+    /// Accept TCP connections and hand each to its own handshake task. At
+    /// most `max_connections` handshakes run at once; connections beyond
+    /// that are dropped.
+    async fn accept_loop(
+        listener: TcpListener,
+        config: TransportConfig,
+        tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+        sender: mpsc::Sender<Box<dyn TransportConnection>>,
+    ) {
+        let handshakes = Arc::new(Semaphore::new(config.max_connections.max(1)));
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors; back off instead of spinning
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = handshakes.clone().try_acquire_owned() else {
+                warn!("Too many pending handshakes, dropping connection from {}", addr);
+                continue;
+            };
+
+            let config = config.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                // A misbehaving peer must not stop the listener, so handshake
+                // failures are only logged
+                let timeout_duration = std::time::Duration::from_secs(config.read_timeout);
+                match tokio::time::timeout(timeout_duration, Self::handshake(stream, addr, &config, tls_acceptor)).await {
+                    Ok(Ok(Some(connection))) => {
+                        info!("Accepted WebSocket connection from {} (TLS: {})", addr, config.tls.enabled);
+                        // Fails only once the listener is gone
+                        let _ = sender.send(connection).await;
+                    }
+                    Ok(Ok(None)) => debug!("{} closed the connection without sending anything", addr),
+                    Ok(Err(e)) => warn!("Rejected connection from {}: {}", addr, e),
+                    Err(_) => warn!("Handshake with {} timed out", addr),
+                }
+            });
+        }
     }
 
-    /// Load private key from file
-    async fn load_private_key(path: &str) -> ProtocolResult<rustls::PrivateKey> {
-        let key_data = tokio::fs::read(path)
+    /// Run the TLS (if enabled) and WebSocket handshakes on an accepted
+    /// stream. `None` is a peer that closed before sending anything, such
+    /// as a discovery health probe.
+    async fn handshake(
+        stream: TcpStream,
+        addr: SocketAddr,
+        config: &TransportConfig,
+        tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    ) -> ProtocolResult<Option<Box<dyn TransportConnection>>> {
+        let mut first = [0u8; 1];
+        let peeked = stream.peek(&mut first)
             .await
-            .map_err(|e| ProtocolError::Transport(format!("Failed to read private key file: {}", e)))?;
+            .map_err(|e| ProtocolError::Transport(format!("Failed to read from {}: {}", addr, e)))?;
+        if peeked == 0 {
+            return Ok(None);
+        }
 
-        let mut reader = std::io::Cursor::new(key_data);
-        let key = rustls_pemfile::pkcs8_private_keys(&mut reader)
-            .map_err(|_| ProtocolError::Transport("Failed to parse PKCS8 private key".to_string()))?
-            .into_iter()
-            .next()
-            .ok_or_else(|| ProtocolError::Transport("No PKCS8 private key found".to_string()))?;
+        let connection: Box<dyn TransportConnection> = if let Some(acceptor) = tls_acceptor {
+            // TLSハンドシェイク
+            let tls_stream = acceptor.accept(stream)
+                .await
+                .map_err(|e| ProtocolError::Transport(format!("TLS handshake failed: {}", e)))?;
 
-        Ok(rustls::PrivateKey(key))
-    }
-}
+            let ws_stream = accept_async_with_config(tls_stream, Some(websocket_config(config)))
+                .await
+                .map_err(|e| ProtocolError::WebSocket(format!("WebSocket handshake failed: {}", e)))?;
 
-#[async_trait]
-impl TransportListener for WebSocketListener {
-    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
-        let (stream, addr) = self.listener.accept().await
-            .map_err(|e| ProtocolError::Transport(format!("Failed to accept connection: {}", e)))?;
-
-        // TLSが有効な場合はTLSストリームを作成
-        let maybe_tls_stream = if let Some(_acceptor) = &self.tls_acceptor {
-            // TODO: Implement proper TLS server handshake
-            // For now, fall back to plain connection
-            warn!("TLS server not yet implemented, falling back to plain connection");
-            tokio_tungstenite::MaybeTlsStream::Plain(stream)
+            Box::new(WebSocketConnection::from_stream(ws_stream, addr, config.clone()))
         } else {
-            tokio_tungstenite::MaybeTlsStream::Plain(stream)
-        };
+            let ws_stream = accept_async_with_config(MaybeTlsStream::Plain(stream), Some(websocket_config(config)))
+                .await
+                .map_err(|e| ProtocolError::WebSocket(format!("WebSocket handshake failed: {}", e)))?;
 
-        // WebSocket handshake
-        let ws_stream = accept_async(maybe_tls_stream)
-            .await
-            .map_err(|e| ProtocolError::WebSocket(format!("WebSocket handshake failed: {}", e)))?;
-
-        let connection = WebSocketConnection {
-            stream: ws_stream,
-            remote_addr: addr,
-            config: self.config.clone(),
-            is_alive: true,
+            Box::new(WebSocketConnection::from_stream(ws_stream, addr, config.clone()))
         };
 
-        info!("Accepted WebSocket connection from {} (TLS: {})",
-              connection.remote_addr,
-              self.config.tls.enabled);
+        Ok(Some(connection))
+    }
+}
 
-        Ok(Box::new(connection))
+impl Drop for WebSocketListener {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+#[async_trait]
+impl TransportListener for WebSocketListener {
+    async fn accept(&mut self) -> ProtocolResult<Box<dyn TransportConnection>> {
+        self.connections.recv().await
+            .ok_or_else(|| ProtocolError::Transport("Listener is closed".to_string()))
     }
 
     fn local_addr(&self) -> Option<SocketAddr> {
-        self.listener.local_addr().ok()
+        Some(self.local_addr)
     }
 
     async fn close(&mut self) -> ProtocolResult<()> {
-        // TcpListener doesn't have a close method, but we can drop it
+        // Stop accepting; handshakes already running finish on their own
+        self.acceptor.abort();
+        self.connections.close();
         info!("WebSocket listener closed");
         Ok(())
     }
@@ -360,9 +573,106 @@ impl TransportFactory for WebSocketFactory {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::messages::{ClipboardPayload, MessagePayload, MessageType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+
+    const MARKER: &[u8] = b"wss-plaintext-marker-0123456789";
+
+    /// Write a self-signed certificate for 127.0.0.1 and return a server TLS config
+    pub(crate) fn self_signed_tls() -> TlsConfig {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("soft-kvm-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        TlsConfig {
+            enabled: true,
+            certificate_path: Some(cert_path.to_string_lossy().into_owned()),
+            private_key_path: Some(key_path.to_string_lossy().into_owned()),
+            ca_certificate_path: Some(cert_path.to_string_lossy().into_owned()),
+            ..TlsConfig::default()
+        }
+    }
+
+    /// TCP proxy that records every byte sent from the server to the client
+    ///
+    /// Server frames are not masked, so with a plaintext transport the raw
+    /// payload is visible in the captured bytes.
+    async fn recording_proxy(upstream: SocketAddr) -> (SocketAddr, Arc<Mutex<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let captured_clone = captured.clone();
+
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut server = TcpStream::connect(upstream).await.unwrap();
+            let (mut client_read, mut client_write) = client.split();
+            let (mut server_read, mut server_write) = server.split();
+
+            let upstream_copy = tokio::io::copy(&mut client_read, &mut server_write);
+            let downstream_copy = async {
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = server_read.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    captured_clone.lock().await.extend_from_slice(&buf[..n]);
+                    if client_write.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            };
+            let _ = tokio::join!(upstream_copy, downstream_copy);
+        });
+
+        (addr, captured)
+    }
+
+    /// Send a clipboard message carrying `MARKER` through a recording proxy
+    async fn send_marker_through_proxy(tls: TlsConfig) -> Vec<u8> {
+        let config = TransportConfig { tls, ..TransportConfig::default() };
+        let mut listener = WebSocketListener::new(config.clone(), "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (proxy_addr, captured) = recording_proxy(server_addr).await;
+
+        let server = tokio::spawn(async move {
+            let mut connection = listener.accept().await.unwrap();
+            connection.set_codec(CodecKind::Binary.codec());
+            let message = ProtocolMessage::new(
+                MessageType::ClipboardData,
+                MessagePayload::ClipboardData(ClipboardPayload {
+                    data_type: "text".to_string(),
+                    data: MARKER.to_vec(),
+                }),
+            );
+            connection.send(message).await.unwrap();
+            connection
+        });
+
+        let mut client = WebSocketConnection::connect(proxy_addr, config).await.unwrap();
+        let received = client.receive().await.unwrap().unwrap();
+        match received.payload {
+            MessagePayload::ClipboardData(clipboard) => assert_eq!(clipboard.data, MARKER),
+            other => panic!("unexpected payload: {:?}", other),
+        }
+        drop(server.await.unwrap());
+
+        let bytes = captured.lock().await.clone();
+        bytes
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
 
     #[tokio::test]
     async fn test_websocket_factory_creation() {
@@ -386,6 +696,154 @@ mod tests {
         assert_eq!(config.read_timeout, 30);
         assert_eq!(config.write_timeout, 30);
         assert_eq!(config.buffer_size, 64 * 1024);
+        assert_eq!(config.max_message_size, 1024 * 1024);
+        assert_eq!(config.reassembly_timeout, 10);
         assert!(config.compression);
     }
+
+    #[tokio::test]
+    async fn test_large_messages_are_fragmented_and_limited() {
+        let mut listener = WebSocketListener::new(TransportConfig::default(), "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let mut connection = listener.accept().await.unwrap();
+                received.push(connection.receive().await.map(|message| message.map(|message| message.payload)));
+            }
+            received
+        });
+
+        let clipboard = |size: usize| ProtocolMessage::new(
+            MessageType::ClipboardData,
+            MessagePayload::ClipboardData(ClipboardPayload {
+                data_type: "image/png".to_string(),
+                data: (0..size).map(|i| (i % 251) as u8).collect(),
+            }),
+        );
+
+        // Well over the 64KB buffer, under the 1MB limit
+        let mut client = WebSocketConnection::connect(addr, TransportConfig::default()).await.unwrap();
+        client.set_codec(CodecKind::Binary.codec());
+        client.send(clipboard(300 * 1024)).await.unwrap();
+        assert!(client.send(clipboard(2 * 1024 * 1024)).await.is_err());
+
+        // A peer that ignores our limit is cut off
+        let generous = TransportConfig { max_message_size: 4 * 1024 * 1024, ..TransportConfig::default() };
+        let mut hostile = WebSocketConnection::connect(addr, generous).await.unwrap();
+        hostile.set_codec(CodecKind::Binary.codec());
+        hostile.send(clipboard(2 * 1024 * 1024)).await.unwrap();
+
+        let mut received = server.await.unwrap().into_iter();
+        match received.next().unwrap() {
+            Ok(Some(MessagePayload::ClipboardData(clipboard))) => assert_eq!(clipboard.data.len(), 300 * 1024),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(received.next().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wss_loopback_is_encrypted() {
+        let plain = send_marker_through_proxy(TlsConfig::default()).await;
+        assert!(contains(&plain, MARKER), "plaintext transport should expose the payload");
+
+        let encrypted = send_marker_through_proxy(self_signed_tls()).await;
+        // TLS handshake record (content type 22) opens the stream
+        assert_eq!(encrypted.first(), Some(&0x16));
+        assert!(encrypted.len() > MARKER.len());
+        assert!(!contains(&encrypted, MARKER), "payload visible on the wire");
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_does_not_stall_accept() {
+        let config = TransportConfig { tls: self_signed_tls(), ..TransportConfig::default() };
+        let mut listener = WebSocketListener::new(config.clone(), "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Connects but never starts the TLS handshake
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let client = tokio::spawn(WebSocketConnection::connect(addr, config));
+        let accepted = tokio::time::timeout(std::time::Duration::from_secs(5), listener.accept()).await
+            .expect("idle client stalled accept")
+            .unwrap();
+        assert!(accepted.is_alive());
+        assert!(client.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_health_probes_are_quiet_and_do_not_delay_clients() {
+        let config = TransportConfig { tls: self_signed_tls(), ..TransportConfig::default() };
+        let mut listener = WebSocketListener::new(config.clone(), "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Probes connect and close at once, around a real client
+        let probes = tokio::spawn(async move {
+            for _ in 0..10 {
+                drop(TcpStream::connect(addr).await.unwrap());
+            }
+        });
+        let client = tokio::spawn(WebSocketConnection::connect(addr, config.clone()));
+        let accepted = tokio::time::timeout(std::time::Duration::from_secs(5), listener.accept()).await
+            .expect("probes delayed the client")
+            .unwrap();
+        assert!(accepted.is_alive());
+        assert!(client.await.unwrap().is_ok());
+        probes.await.unwrap();
+
+        // A probe is told apart from a failed handshake
+        let raw = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        drop(TcpStream::connect(raw.local_addr().unwrap()).await.unwrap());
+        let (stream, peer) = raw.accept().await.unwrap();
+        let acceptor = WebSocketListener::create_tls_acceptor(&config.tls).await.unwrap();
+        let outcome = WebSocketListener::handshake(stream, peer, &config, Some(acceptor)).await;
+        assert!(matches!(outcome, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_wss_rejects_untrusted_certificate() {
+        let server_tls = self_signed_tls();
+        let config = TransportConfig { tls: server_tls.clone(), ..TransportConfig::default() };
+        let mut listener = WebSocketListener::new(config, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+
+        // Without the CA the handshake must fail...
+        let untrusted = TransportConfig {
+            tls: TlsConfig { enabled: true, ..TlsConfig::default() },
+            ..TransportConfig::default()
+        };
+        assert!(WebSocketConnection::connect(addr, untrusted).await.is_err());
+
+        // ...unless verification is explicitly disabled
+        let insecure = TransportConfig {
+            tls: TlsConfig { enabled: true, accept_invalid_certs: true, ..TlsConfig::default() },
+            ..TransportConfig::default()
+        };
+        assert!(WebSocketConnection::connect(addr, insecure).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wss_accepts_only_the_pinned_certificate() {
+        let server_tls = self_signed_tls();
+        let config = TransportConfig { tls: server_tls.clone(), ..TransportConfig::default() };
+        let mut listener = WebSocketListener::new(config, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while listener.accept().await.is_ok() {}
+        });
+
+        let certificate = &load_certs(server_tls.certificate_path.as_deref().unwrap()).await.unwrap()[0];
+        let pinned = |fingerprint: String| TransportConfig {
+            tls: TlsConfig { enabled: true, pinned_fingerprint: Some(fingerprint), ..TlsConfig::default() },
+            ..TransportConfig::default()
+        };
+        // No CA needed for the pinned certificate...
+        assert!(WebSocketConnection::connect(addr, pinned(dangerous::fingerprint(certificate))).await.is_ok());
+        // ...and any other is refused
+        assert!(WebSocketConnection::connect(addr, pinned("ab".repeat(32))).await.is_err());
+    }
 }
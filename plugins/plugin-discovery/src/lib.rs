@@ -16,19 +16,28 @@
 //!
 //! Tauri plugin for service discovery using mDNS
 
-use tauri::{plugin::Builder, plugin::TauriPlugin, Runtime, Manager};
+use tauri::{plugin::Builder, plugin::TauriPlugin, Emitter, Runtime, Manager};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use soft_kvm_discovery::ServiceResolver;
+use soft_kvm_discovery::{ServiceEvent, ServiceResolver, ServiceSource};
 use soft_kvm_core::{ServiceId, ServiceType, NetworkAddress};
 use soft_kvm_discovery::ServiceInfo;
+use soft_kvm_discovery::beacon::{BeaconConfig, StaticPeer};
+use soft_kvm_discovery::health::ServiceHealth;
+use soft_kvm_discovery::metadata::ServiceMetadata;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DiscoveryConfig {
     pub service_type: ServiceType,
     pub auto_discovery: bool,
     pub discovery_interval: u64, // seconds
+    /// Peers to probe by unicast: `host`, `host:port` or an IPv4 range like `10.0.0.0/24`
+    #[serde(default)]
+    pub static_peers: Vec<String>,
+    /// Probe and announce with UDP broadcasts besides mDNS
+    #[serde(default)]
+    pub broadcast: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -55,9 +64,13 @@ impl Default for DiscoveryState {
     }
 }
 
+/// Event emitted to the UI when a service is added, updated or removed
+pub const SERVICE_EVENT: &str = "discovery://service-event";
+
 /// Initialize discovery
 #[tauri::command]
-async fn init_discovery(
+async fn init_discovery<R: Runtime>(
+    app: tauri::AppHandle<R>,
     config: DiscoveryConfig,
     state: tauri::State<'_, Arc<RwLock<DiscoveryState>>>,
 ) -> Result<String, String> {
@@ -66,7 +79,41 @@ async fn init_discovery(
     let mut discovery_state = state.write().await;
 
     // サービスリゾルバを作成
-    let resolver = ServiceResolver::new(config.service_type.clone());
+    let mut resolver = ServiceResolver::new(config.service_type.clone());
+
+    // マルチキャストが遮断されたネットワーク向け
+    if !config.static_peers.is_empty() || config.broadcast {
+        let peers = config.static_peers.iter()
+            .map(|peer| peer.parse::<StaticPeer>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        resolver = resolver.with_beacon_config(BeaconConfig {
+            broadcast: config.broadcast,
+            peers,
+            ..BeaconConfig::default()
+        });
+    }
+
+    // Forward changes to the UI instead of having it poll get_available_services
+    let mut events = resolver.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let ServiceEvent::Removed(info) = &event {
+                        println!("Service {} is gone", info.name);
+                    }
+                    if let Err(e) = app.emit(SERVICE_EVENT, &event) {
+                        eprintln!("Failed to emit service event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("Dropped {} service events", skipped);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
 
     // 自動発見が有効な場合は開始
     if config.auto_discovery {
@@ -115,6 +162,7 @@ async fn register_service(
     service_name: String,
     service_type: ServiceType,
     address: NetworkAddress,
+    metadata: Option<ServiceMetadata>,
     state: tauri::State<'_, Arc<RwLock<DiscoveryState>>>,
 ) -> Result<String, String> {
     let mut discovery_state = state.write().await;
@@ -129,6 +177,10 @@ async fn register_service(
             service_type: service_type.clone(),
             address: address.clone(),
             last_seen: chrono::Utc::now(),
+            ttl: soft_kvm_discovery::DEFAULT_SERVICE_TTL,
+            metadata: metadata.unwrap_or_default(),
+            source: ServiceSource::Mdns,
+            health: ServiceHealth::default(),
         };
 
         resolver.register_service(service_info.clone()).await
@@ -16,11 +16,11 @@
 //!
 //! Tauri plugin for KVM protocol handling
 
-use tauri::{plugin::Builder, plugin::TauriPlugin, Runtime, Manager};
+use tauri::{plugin::Builder, plugin::TauriPlugin, Emitter, Runtime, Manager};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use soft_kvm_protocol::{ProtocolManager, ProtocolConfig, ProtocolResult, session::PeerInfo, messages::{MessageType, MessagePayload, ProtocolMessage}};
+use soft_kvm_protocol::{ProtocolManager, ProtocolConfig, ProtocolResult, session::{PeerInfo, SessionEvent}, messages::{MessageType, MessagePayload, ProtocolMessage}};
 use soft_kvm_core::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,7 +35,7 @@ pub struct ProtocolPluginConfig {
 impl Default for ProtocolPluginConfig {
     fn default() -> Self {
         ProtocolPluginConfig {
-            version: "1.0.0".to_string(),
+            version: soft_kvm_protocol::PROTOCOL_VERSION.to_string(),
             max_message_size: 1024 * 1024,
             heartbeat_interval: 30,
             session_timeout: 300,
@@ -67,9 +67,13 @@ impl Default for ProtocolPluginState {
     }
 }
 
+/// Event emitted to the UI for session lifecycle changes
+pub const SESSION_EVENT: &str = "protocol://session-event";
+
 /// Initialize protocol
 #[tauri::command]
-async fn init_protocol(
+async fn init_protocol<R: Runtime>(
+    app: tauri::AppHandle<R>,
     config: ProtocolPluginConfig,
     state: tauri::State<'_, Arc<RwLock<ProtocolPluginState>>>,
 ) -> Result<String, String> {
@@ -84,11 +88,39 @@ async fn init_protocol(
         heartbeat_interval: config.heartbeat_interval,
         session_timeout: config.session_timeout,
         compression_enabled: config.compression_enabled,
+        ..ProtocolConfig::default()
     };
 
     // Create protocol manager
     let manager = ProtocolManager::new(protocol_config);
 
+    // Forward session events (suspend, resume, disconnect, input control) to the UI
+    let mut events = manager.subscribe_events();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    match &event {
+                        SessionEvent::Disconnected { session_id, reason, .. } => {
+                            println!("Session {} disconnected: {}", session_id, reason);
+                        }
+                        SessionEvent::ControlChanged { controller, .. } => {
+                            println!("Input control moved to {}", controller.as_deref().unwrap_or("nobody"));
+                        }
+                        _ => {}
+                    }
+                    if let Err(e) = app.emit(SESSION_EVENT, &event) {
+                        eprintln!("Failed to emit session event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("Dropped {} session events", skipped);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
     // Start the manager
     manager.start().await
         .map_err(|e| format!("Failed to start protocol manager: {}", e))?;
@@ -149,7 +181,7 @@ async fn connect_to_protocol_server(
                 ip: socket_addr.ip().to_string(),
                 port: socket_addr.port() as u16,
             },
-            capabilities: vec!["video".to_string(), "input".to_string()],
+            capabilities: CapabilitySet::full(),
             authenticated: false,
             last_seen: chrono::Utc::now(),
         };
@@ -194,14 +226,18 @@ async fn send_protocol_message(
         // Create message payload (simplified)
         let message_payload = match msg_type {
             MessageType::Hello => MessagePayload::Hello(soft_kvm_protocol::messages::HelloPayload {
-                protocol_version: "1.0.0".to_string(),
+                protocol_version: soft_kvm_protocol::PROTOCOL_VERSION.to_string(),
+                min_protocol_version: None,
                 client_info: soft_kvm_protocol::messages::ClientInfo {
                     client_id: "client".to_string(),
                     client_name: "Soft KVM Client".to_string(),
                     platform: "unknown".to_string(),
                     version: "1.0.0".to_string(),
                 },
-                capabilities: vec!["video".to_string(), "input".to_string()],
+                capabilities: CapabilitySet::full(),
+                codecs: soft_kvm_protocol::codec::CodecKind::supported(),
+                resume_token: None,
+                compression: soft_kvm_protocol::compression::CompressionKind::supported(),
             }),
             MessageType::Heartbeat => MessagePayload::Heartbeat(soft_kvm_protocol::messages::HeartbeatPayload {
                 sequence_number: 1,
@@ -30,7 +30,7 @@ async fn test_basic_imports() {
     assert_eq!(config.max_connections, 100);
 
     // Test protocol version
-    assert_eq!(PROTOCOL_VERSION, "1.0.0");
+    assert_eq!(PROTOCOL_VERSION, "1.2.0");
 
     println!("Basic imports test passed!");
 }
@@ -40,13 +40,17 @@ async fn test_protocol_message_creation() {
     // Test basic message creation
     let payload = MessagePayload::Hello(HelloPayload {
         protocol_version: "1.0.0".to_string(),
+        min_protocol_version: None,
         client_info: ClientInfo {
             client_id: "test-client".to_string(),
             client_name: "Test Client".to_string(),
             platform: "test".to_string(),
             version: "1.0.0".to_string(),
         },
-        capabilities: vec!["video".to_string(), "input".to_string()],
+        capabilities: CapabilitySet::full(),
+        codecs: soft_kvm_protocol::codec::CodecKind::supported(),
+        resume_token: None,
+        compression: soft_kvm_protocol::compression::CompressionKind::supported(),
     });
 
     let message = ProtocolMessage::new(MessageType::Hello, payload);
@@ -22,7 +22,7 @@
 )]
 
 use serde_json;
-use tauri::{AppHandle, Manager, State};
+use tauri::{AppHandle, Emitter, Manager, State};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -36,6 +36,7 @@ use soft_kvm_plugin_discovery::init as discovery_plugin_init;
 // Internal crates
 use soft_kvm_core::*;
 use soft_kvm_discovery::*;
+use soft_kvm_discovery::known_hosts::KnownHosts;
 use soft_kvm_protocol::{ProtocolManager, ProtocolConfig};
 
 // アプリケーションのグローバル状態
@@ -49,27 +50,72 @@ struct AppState {
 
 // UI固有のTauriコマンド
 
-/// 利用可能なサーバーを取得（簡易版）
+/// サーバーの追加・更新・削除を通知するイベント
+const SERVER_EVENT: &str = "ui://server-event";
+
+/// サーバー一覧の表示形式
+fn server_json(info: &ServiceInfo) -> serde_json::Value {
+    serde_json::json!({
+        "id": info.id.0.to_string(),
+        "name": info.metadata.hostname.as_deref().unwrap_or(&info.name),
+        "address": format!("{}:{}", info.address.ip, info.address.port),
+        "service_type": info.service_type,
+        "is_expired": info.is_expired(),
+        "reachable": info.health.reachable,
+        "rtt_ms": info.health.rtt_ms,
+        "source": info.source,
+        "metadata": info.metadata,
+    })
+}
+
+/// 利用可能なサーバーを取得
+///
+/// 初回呼び出しで探索を開始し、以降の変化は `SERVER_EVENT` で通知する
 #[tauri::command]
-async fn get_available_servers_ui(_state: State<'_, Arc<RwLock<AppState>>>) -> Result<Vec<serde_json::Value>, String> {
-    // 簡易実装: サンプルサーバーを返す
-    let servers = vec![
-        serde_json::json!({
-            "id": "server-001",
-            "name": "My KVM Server",
-            "address": "192.168.1.100:8080",
-            "service_type": "Server",
-            "is_expired": false,
-        }),
-        serde_json::json!({
-            "id": "server-002",
-            "name": "Office KVM",
-            "address": "192.168.1.101:8080",
-            "service_type": "Server",
-            "is_expired": false,
-        }),
-    ];
+async fn get_available_servers_ui(
+    state: State<'_, Arc<RwLock<AppState>>>,
+    app_handle: AppHandle,
+) -> Result<Vec<serde_json::Value>, String> {
+    let mut app_state = state.write().await;
 
+    if app_state.discovery.is_none() {
+        let mut resolver = ServiceResolver::new(ServiceType::Server);
+
+        // 前回使ったサーバーを探索を待たずに表示する
+        match KnownHosts::default_path().and_then(KnownHosts::load) {
+            Ok(known_hosts) => resolver = resolver.with_known_hosts(known_hosts),
+            Err(e) => eprintln!("Known hosts unavailable: {}", e),
+        }
+
+        let mut events = resolver.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let (kind, info) = match events.recv().await {
+                    Ok(ServiceEvent::Added(info)) => ("added", info),
+                    Ok(ServiceEvent::Updated(info)) => ("updated", info),
+                    Ok(ServiceEvent::Removed(info)) => ("removed", info),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("Dropped {} server events", skipped);
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                let payload = serde_json::json!({ "kind": kind, "server": server_json(&info) });
+                if let Err(e) = app_handle.emit(SERVER_EVENT, payload) {
+                    eprintln!("Failed to emit server event: {}", e);
+                }
+            }
+        });
+
+        resolver.start_discovery().await
+            .map_err(|e| format!("Failed to start discovery: {}", e))?;
+        app_state.discovery = Some(resolver);
+    }
+
+    let servers = match &app_state.discovery {
+        Some(resolver) => resolver.get_available_services().await.iter().map(server_json).collect(),
+        None => Vec::new(),
+    };
     Ok(servers)
 }
 
@@ -86,6 +132,16 @@ async fn connect_to_server_ui(
     {
         let mut app_state = state.write().await;
         app_state.connection_status = format!("Connected to {}", server_address);
+
+        // 次回起動時にすぐ再接続できるよう記録する
+        if let Some(resolver) = &app_state.discovery {
+            let services = resolver.get_available_services().await;
+            if let Some(info) = services.iter().find(|info| format!("{}:{}", info.address.ip, info.address.port) == server_address) {
+                if let Err(e) = resolver.mark_connected(&info.id, info.metadata.cert_fingerprint.clone()) {
+                    eprintln!("Failed to remember {}: {}", info.name, e);
+                }
+            }
+        }
     }
 
     // 接続成功をシミュレート
@@ -166,11 +222,12 @@ async fn init_protocol_ui(state: State<'_, Arc<RwLock<AppState>>>) -> Result<Str
 
     // Protocol Managerを初期化
     let config = ProtocolConfig {
-        version: "1.0.0".to_string(),
+        version: soft_kvm_protocol::PROTOCOL_VERSION.to_string(),
         max_message_size: 1024 * 1024, // 1MB
         heartbeat_interval: 30,
         session_timeout: 300,
         compression_enabled: true,
+        ..ProtocolConfig::default()
     };
 
     let manager = ProtocolManager::new(config);
@@ -225,7 +282,7 @@ async fn connect_to_protocol_server_ui(
                 ip: address.split(':').next().unwrap_or("127.0.0.1").to_string(),
                 port: address.split(':').nth(1).unwrap_or("8080").parse().unwrap_or(8080),
             },
-            capabilities: vec!["video".to_string(), "input".to_string()],
+            capabilities: CapabilitySet::full(),
             authenticated: false,
             last_seen: chrono::Utc::now(),
         };
@@ -272,14 +329,18 @@ async fn send_protocol_message_ui(
         // ペイロードを作成（簡易実装）
         let message_payload = match msg_type {
             MessageType::Hello => MessagePayload::Hello(soft_kvm_protocol::messages::HelloPayload {
-                protocol_version: "1.0.0".to_string(),
+                protocol_version: soft_kvm_protocol::PROTOCOL_VERSION.to_string(),
+                min_protocol_version: None,
                 client_info: soft_kvm_protocol::messages::ClientInfo {
                     client_id: "client".to_string(),
                     client_name: "Soft KVM Client".to_string(),
                     platform: "unknown".to_string(),
                     version: "1.0.0".to_string(),
                 },
-                capabilities: vec!["video".to_string(), "input".to_string()],
+                capabilities: CapabilitySet::full(),
+                codecs: soft_kvm_protocol::codec::CodecKind::supported(),
+                resume_token: None,
+                compression: soft_kvm_protocol::compression::CompressionKind::supported(),
             }),
             MessageType::Heartbeat => MessagePayload::Heartbeat(soft_kvm_protocol::messages::HeartbeatPayload {
                 sequence_number: 1,